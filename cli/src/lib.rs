@@ -7,13 +7,32 @@
 //! - Navigating through the spreadsheet using keyboard commands
 //! - Scrolling to specific cells
 
+use cores::Annotations;
+use cores::CellAddr;
+use cores::Comment;
+use cores::Criterion;
 use cores::Error;
+use cores::EvalOptions;
+use cores::FillDirection;
+use cores::FindOptions;
+use cores::Format;
+use cores::Highlight;
+use cores::Iteration;
+use cores::LookIn;
+use cores::RangeAddr;
+use cores::Rule;
 use cores::Sheet;
+use cores::SortKey;
+use cores::Style;
+use cores::Trace;
+use cores::Validation;
+use cores::Workbook;
 use cores::convert_to_index;
 use std::cmp;
 use std::env;
 use std::io;
 use std::io::Write;
+use std::time::Duration;
 
 /// Controls debug output throughout the application
 const DEBUG: bool = false;
@@ -45,7 +64,8 @@ pub fn column_to_letter(col: usize) -> String {
 ///
 /// This function prints a 10x10 section of the spreadsheet starting from
 /// the specified row and column indices. It formats the output as a table
-/// with row and column headers.
+/// with row and column headers. Rows hidden by a filter are skipped, so ten
+/// visible rows are shown.
 ///
 /// # Parameters
 /// * `sheet` - Reference to the spreadsheet to display
@@ -61,24 +81,341 @@ fn display_sheet(sheet: &Sheet, row: usize, col: usize, rowi: usize, coli: usize
         i += 1;
     }
     println!();
+    // Conditional formatting is worked out once for the shown cells
+    let last_row = (rowi..row)
+        .filter(|&i| !sheet.is_row_hidden(i))
+        .take(10)
+        .last();
+    let highlights = match last_row {
+        Some(last_row) if coli < col => RangeAddr::new(
+            CellAddr::from_index(rowi, coli),
+            CellAddr::from_index(last_row, cmp::min(coli + 10, col) - 1),
+        )
+        .map(|area| sheet.highlights(area))
+        .unwrap_or_default(),
+        _ => vec![],
+    };
     i = rowi;
-    while i < rowi + 10 && i < row {
+    let mut shown = 0;
+    while shown < 10 && i < row {
+        if sheet.is_row_hidden(i) {
+            i += 1;
+            continue;
+        }
         print!("{}\t ", i);
         let mut j = coli;
         while j < coli + 10 && j < col {
-            let value = sheet.get_value(i as i32, j as i32);
-            if sheet.grid[i][j].formula.flag.is_div_by_zero() == 1 {
+            if sheet.grid[(i, j)].formula.flag.is_div_by_zero() == 1 {
                 print!("ERR\t ");
+            } else if sheet.grid[(i, j)].formula.flag.is_overflow() == 1 {
+                print!("#NUM!\t ");
+            } else if sheet.grid[(i, j)].formula.flag.is_timeout() == 1 {
+                print!("#TIMEOUT\t ");
+            } else if sheet.grid[(i, j)].formula.flag.is_ref() == 1 {
+                print!("#REF!\t ");
             } else {
-                print!("{}\t ", value);
+                let addr = CellAddr::from_index(i, j);
+                let highlight = highlights
+                    .binary_search_by_key(&addr, |(other, _)| *other)
+                    .ok()
+                    .map(|at| &highlights[at].1);
+                let text = sheet.formatted_value(i as i32, j as i32);
+                print!("{}\t ", styled(text, sheet.style(i, j), highlight));
             }
             j += 1;
         }
         println!();
+        shown += 1;
         i += 1;
     }
 }
 
+/// Wraps the text of a cell in the ANSI codes for its formatting and the
+/// conditional formatting rules that hold for it.
+///
+/// A data bar colors the background of as much of the cell as its length.
+fn styled(text: String, style: Style, highlight: Option<&Highlight>) -> String {
+    let style = highlight.map_or(style, |highlight| highlight.apply(style));
+    let bar = highlight.and_then(|highlight| highlight.bar);
+    let mut codes = Vec::new();
+    if style.bold {
+        codes.push(String::from("1"));
+    }
+    if style.italic {
+        codes.push(String::from("3"));
+    }
+    if let Some(color) = style.text_color {
+        codes.push(format!("38;2;{};{};{}", color.r, color.g, color.b));
+    }
+    if let Some(color) = style.fill_color {
+        codes.push(format!("48;2;{};{};{}", color.r, color.g, color.b));
+    }
+    let start = format!("\x1b[{}m", codes.join(";"));
+    match bar {
+        Some((length, color)) => {
+            let text = format!("{:<7}", text);
+            let width = text.chars().count() * length as usize / 100;
+            let split = text
+                .char_indices()
+                .nth(width)
+                .map_or(text.len(), |(at, _)| at);
+            format!(
+                "{}\x1b[48;2;{};{};{}m{}\x1b[0m{}{}\x1b[0m",
+                start,
+                color.r,
+                color.g,
+                color.b,
+                &text[..split],
+                start,
+                &text[split..]
+            )
+        }
+        None if codes.is_empty() => text,
+        None => format!("{}{}\x1b[0m", start, text),
+    }
+}
+
+/// Parses the arguments of a row or column insert/delete command.
+///
+/// # Parameters
+/// * `args` - The text after the command name, e.g. `5` or `C 2`
+/// * `columns` - Whether the first argument names a column by its letters
+///
+/// # Returns
+/// The first line and the number of lines, which defaults to 1.
+fn parse_lines(args: &str, columns: bool) -> Option<(usize, usize)> {
+    let mut parts = args.split_whitespace();
+    let first = parts.next()?;
+    let at = if columns {
+        if !first.chars().all(|c| c.is_ascii_uppercase()) {
+            return None;
+        }
+        convert_to_index(format!("{}1", first)).1
+    } else {
+        first.parse::<usize>().ok()?
+    };
+    let count = match parts.next() {
+        Some(count) => count.parse::<usize>().ok()?,
+        None => 1,
+    };
+    if parts.next().is_some() || at == 0 {
+        return None;
+    }
+    Some((at, count))
+}
+
+/// Parses the arguments of a sort command.
+///
+/// # Parameters
+/// * `args` - The text after the command name, e.g. `A1:C10 B desc A header`
+///
+/// # Returns
+/// The range, its key columns in order and whether the range has a header row.
+fn parse_sort(args: &str) -> Option<(RangeAddr, Vec<SortKey>, bool)> {
+    let mut parts = args.split_whitespace();
+    let range = RangeAddr::parse(parts.next()?)?;
+    let mut keys: Vec<SortKey> = Vec::new();
+    let mut header = false;
+    for part in parts {
+        match part {
+            "asc" => keys.last_mut()?.descending = false,
+            "desc" => keys.last_mut()?.descending = true,
+            "header" => header = true,
+            col if col.chars().all(|c| c.is_ascii_uppercase()) => {
+                let (_, col) = convert_to_index(format!("{}1", col));
+                keys.push(SortKey::ascending(col as u32));
+            }
+            _ => return None,
+        }
+    }
+    Some((range, keys, header))
+}
+
+/// Parses the arguments of a filter command setting the criterion of a column.
+///
+/// # Parameters
+/// * `args` - The text after the command name, e.g. `B gt 10`, `C contains 42` or `B all`
+///
+/// # Returns
+/// The column and its new criterion, which is `None` for `all`.
+fn parse_criterion(args: &str) -> Option<(u32, Option<Criterion>)> {
+    let mut parts = args.split_whitespace();
+    let col = parts.next()?;
+    if !col.chars().all(|c| c.is_ascii_uppercase()) {
+        return None;
+    }
+    let (_, col) = convert_to_index(format!("{}1", col));
+    let criterion = match (parts.next()?, parts.next()) {
+        ("all", None) => None,
+        ("eq", Some(value)) => Some(Criterion::Equals(value.parse().ok()?)),
+        ("gt", Some(value)) => Some(Criterion::GreaterThan(value.parse().ok()?)),
+        ("top", Some(count)) => Some(Criterion::TopN(count.parse().ok()?)),
+        ("contains", Some(text)) => Some(Criterion::Contains(text.to_string())),
+        _ => return None,
+    };
+    if parts.next().is_some() {
+        return None;
+    }
+    Some((col as u32, criterion))
+}
+
+/// Parses the arguments of a find command.
+///
+/// Leading option words are `formulas` (search formulas instead of values),
+/// `regex`, `case` (match case), `whole` (match the whole cell) and
+/// `in <range>`; the rest of the line is the pattern.
+///
+/// # Parameters
+/// * `args` - The text after the command name, e.g. `formulas in A1:C10 SUM`
+fn parse_find(args: &str) -> Option<(&str, FindOptions)> {
+    let mut options = FindOptions::default();
+    let mut rest = args.trim_start();
+    loop {
+        let (word, after) = rest.split_once(' ').unwrap_or((rest, ""));
+        match word {
+            "formulas" => options.look_in = LookIn::Formulas,
+            "regex" => options.regex = true,
+            "case" => options.match_case = true,
+            "whole" => options.whole_cell = true,
+            "in" => {
+                let (range, after) = after.trim_start().split_once(' ')?;
+                options.scope = Some(RangeAddr::parse(range)?);
+                rest = after.trim_start();
+                continue;
+            }
+            _ => break,
+        }
+        rest = after.trim_start();
+    }
+    (!rest.is_empty()).then_some((rest, options))
+}
+
+/// Parses the arguments of an `iterate` command, e.g. `100 1` or `off`.
+///
+/// # Returns
+/// The new settings, `None` inside for `off`, or `None` if the arguments are invalid.
+fn parse_iteration(args: &str) -> Option<Option<Iteration>> {
+    let mut words = args.split_whitespace();
+    let first = words.next()?;
+    if first == "off" {
+        return words.next().is_none().then_some(None);
+    }
+    let mut iteration = Iteration {
+        max_iterations: first.parse().ok()?,
+        ..Iteration::default()
+    };
+    if let Some(epsilon) = words.next() {
+        iteration.epsilon = epsilon.parse().ok().filter(|&epsilon: &i32| epsilon >= 0)?;
+    }
+    (words.next().is_none() && iteration.max_iterations > 0).then_some(Some(iteration))
+}
+
+/// Parses the arguments of a format command.
+///
+/// # Parameters
+/// * `args` - The text after the command name, e.g. `B2:B9 currency:$:2,bold` or `C3 clear`
+///
+/// # Returns
+/// The range, a single cell standing for itself, and the attributes to set on it.
+fn parse_format(args: &str) -> Option<(RangeAddr, Vec<Format>)> {
+    let (target, formats) = args.trim().split_once(' ')?;
+    Some((parse_target(target)?, Format::parse_list(formats)?))
+}
+
+/// Parses the arguments of a rule command.
+///
+/// # Parameters
+/// * `args` - The text after the command name, e.g. `A1:A9 gt 100 then fill:#ffc7ce`
+///
+/// # Returns
+/// The range, a single cell standing for itself, and the rule to attach to it.
+fn parse_rule(args: &str) -> Option<(RangeAddr, Rule)> {
+    let (target, rule) = args.trim().split_once(' ')?;
+    Some((parse_target(target)?, rule.parse().ok()?))
+}
+
+/// Parses the arguments of a validate command.
+///
+/// # Parameters
+/// * `args` - The text after the command name, e.g. `B2:B9 whole 1 10` or `C1 warn list A1:A5`
+///
+/// # Returns
+/// The range, a single cell standing for itself, and the validation to attach to it.
+fn parse_validation(args: &str) -> Option<(RangeAddr, Validation)> {
+    let (target, validation) = args.trim().split_once(' ')?;
+    Some((parse_target(target)?, validation.parse().ok()?))
+}
+
+/// Parses a range, or a single cell as a range of one cell.
+fn parse_target(target: &str) -> Option<RangeAddr> {
+    match CellAddr::parse(target) {
+        Some(cell) => RangeAddr::new(cell, cell),
+        None => RangeAddr::parse(target),
+    }
+}
+
+/// Returns the name notes and comments are written under: the user running the CLI.
+fn author() -> String {
+    env::var("USER")
+        .or_else(|_| env::var("USERNAME"))
+        .unwrap_or_else(|_| String::from("cli"))
+}
+
+/// Parses the arguments of a note or comment command.
+///
+/// # Parameters
+/// * `args` - The text after the command name, e.g. `A1 Check the totals`
+///
+/// # Returns
+/// The cell and the text, which may not be empty.
+fn parse_note(args: &str) -> Option<(CellAddr, &str)> {
+    let (cell, text) = args.trim().split_once(' ')?;
+    let text = text.trim();
+    (!text.is_empty()).then_some((CellAddr::parse(cell)?, text))
+}
+
+/// Writes out the note and comments of a cell, one per line, numbering the comments.
+fn describe_annotations(annotations: &Annotations) -> Vec<String> {
+    let line = |comment: &Comment| {
+        format!(
+            "{} ({}): {}",
+            comment.author,
+            comment.written_at(),
+            comment.text
+        )
+    };
+    let mut lines: Vec<String> = annotations
+        .note
+        .iter()
+        .map(|note| format!("note: {}", line(note)))
+        .collect();
+    for (number, comment) in annotations.thread.iter().enumerate() {
+        lines.push(format!("{}: {}", number + 1, line(comment)));
+    }
+    lines
+}
+
+/// Writes out the cells of a trace, e.g. `C1 A3; indirect: B1 A1:A2`.
+fn describe_trace(trace: &Trace) -> String {
+    let names = |cells: &[CellAddr], ranges: &[RangeAddr]| {
+        let names: Vec<String> = cells
+            .iter()
+            .map(|addr| addr.to_string())
+            .chain(ranges.iter().map(|range| range.to_string()))
+            .collect();
+        if names.is_empty() {
+            String::from("none")
+        } else {
+            names.join(" ")
+        }
+    };
+    format!(
+        "{}; indirect: {}",
+        names(&trace.direct, &trace.direct_ranges),
+        names(&trace.transitive, &trace.transitive_ranges)
+    )
+}
+
 /// Main function implementing the command-line interface for the spreadsheet.
 ///
 /// The CLI supports the following commands:
@@ -89,6 +426,58 @@ fn display_sheet(sheet: &Sheet, row: usize, col: usize, rowi: usize, coli: usize
 /// - `scroll_to <cell>`: Jump to the specified cell location
 /// - `disable_output`: Stop displaying the spreadsheet after each command
 /// - `enable_output`: Resume displaying the spreadsheet after each command
+/// - `timeout <seconds>`: Limit how long a single command may recalculate (0 disables the limit)
+/// - `iterate <max_iterations> [epsilon]`: Evaluate circular references repeatedly until they
+///   settle instead of rejecting them; `iterate off` rejects them again
+/// - `undo`: Revert the most recent change
+/// - `redo`: Apply the most recently reverted change again
+/// - `insert_row <row> [count]`: Insert empty rows before the given row
+/// - `delete_row <row> [count]`: Delete rows starting at the given row
+/// - `insert_col <col> [count]`: Insert empty columns before the given column, e.g. `insert_col C`
+/// - `delete_col <col> [count]`: Delete columns starting at the given column
+/// - `fill <range>`: Fill a range from its first cells, continuing series such as 1, 3, 5;
+///   a single row is filled to the right, anything else downwards
+/// - `sort <range> <col> [asc|desc] ... [header]`: Sort the rows of a range by one or more
+///   columns, e.g. `sort A1:C10 B desc A header` keeps row 1 in place
+/// - `filter <range>`: Attach a filter to a header row, e.g. `filter A1:C1`
+/// - `filter <col> eq|gt|top|contains <value>`: Hide the rows whose value in the column does
+///   not match, e.g. `filter B gt 10`; `filter <col> all` shows every value again
+/// - `filter reapply`: Filter the rows again after editing them
+/// - `filter off`: Remove the filter and show every row
+/// - `find [formulas] [regex] [case] [whole] [in <range>] <pattern>`: List the cells whose value,
+///   or formula with `formulas`, contains the pattern and scroll to the first one
+/// - `trace <cell> [depth]`: List the cells the cell reads and the cells reading it, directly
+///   and through other formulas, following at most `depth` steps
+/// - `format <range> <attribute>,...`: Format a cell or range, e.g. `format B2:B9 currency:$:2,bold`;
+///   attributes are `bold`, `italic`, `number:<decimals>[:thousands]`, `percent:<decimals>`,
+///   `currency:<symbol>:<decimals>`, `date:yyyy-mm-dd`, `color:#rrggbb`, `fill:#rrggbb`,
+///   `align:left|center|right` and `border:all|none|top+bottom`; `clear` removes the formatting
+/// - `rule <range> <rule>`: Format the cells of a range by their values, e.g.
+///   `rule A1:A9 gt 100 then fill:#ffc7ce`; conditions are `gt`, `lt`, `eq <value>`,
+///   `between <low> <high>`, `top`, `bottom <count>` and `formula <formula>`, which holds
+///   where the formula, written for the first cell, is not zero; `scale #rrggbb #rrggbb`
+///   colors from the smallest to the largest value and `bar #rrggbb` draws data bars
+/// - `rules`: List the rules of the sheet, numbered in the order they apply
+/// - `delete_rule <number>`: Delete a rule by its number in `rules`
+/// - `validate <range> [warn] <validation>`: Check what is typed into a range, e.g.
+///   `validate B2:B9 whole 1 10`; validations are `whole <min> <max>`, `list 1,2,3`,
+///   `list <range>`, `formula <formula>`, which has to be non-zero, and `length <max>`;
+///   input breaking them is rejected, or only warned about with `warn`
+/// - `validations`: List the validations of the sheet, numbered
+/// - `delete_validation <number>`: Delete a validation by its number in `validations`
+/// - `note <cell> <text>`: Write the note of a cell, replacing the note it had
+/// - `comment <cell> <text>`: Add a comment to the thread of a cell
+/// - `notes [cell]`: Print the note and numbered comments of a cell, or list the cells
+///   having any
+/// - `delete_note <cell>`: Delete the note of a cell
+/// - `delete_comment <cell> <number>`: Delete a comment of a cell by its number in `notes`
+/// - `watch on|off`: Print the cells each command changed
+/// - `sheets`: List the sheets of the workbook, marking the shown one with `*`
+/// - `sheet <name>`: Show another sheet; formulas read other sheets as in `Sheet2!A1`
+/// - `add_sheet <name>`: Add an empty sheet after the last one and show it
+/// - `rename_sheet <name>`: Rename the shown sheet
+/// - `delete_sheet`: Delete the shown sheet; references to it become `#REF!`
+/// - `move_sheet <position>`: Move the shown sheet to a position, counting from 1
 /// - `q`: Quit the application
 /// - `<cell>=<formula>`: Set a formula for the specified cell
 ///
@@ -106,19 +495,24 @@ pub fn run_help(args: Vec<String>) {
     let int2 = int2 + 1;
 
     // Initialize spreadsheet and UI state
-    let mut test_sheet = Sheet::new(int1 as usize, int2 as usize);
+    let mut book = Workbook::new(int1 as usize, int2 as usize);
+    let mut active = 0;
     let mut rowi = 1;
     let mut coli = 1;
     let mut input = String::new();
     let mut display_button = true;
+    // Status of the last command when it names cells, e.g. the cells of a loop
+    let mut status: String;
     let mut massage = "ok";
     let mut time = 0.0;
+    let mut eval_timeout: Option<Duration> = None;
+    let mut watcher = None;
 
     // Main input loop
     while {
         if display_button {
             display_sheet(
-                &test_sheet,
+                book.sheet(active),
                 int1 as usize,
                 int2 as usize,
                 rowi as usize,
@@ -156,14 +550,55 @@ pub fn run_help(args: Vec<String>) {
                 }
                 // Convert the cell reference to indices
                 let (cell_index_row, cell_index_col) = convert_to_index(lhs.to_string());
-                let result =
-                    test_sheet.update_cell_data(cell_index_row, cell_index_col, rhs.to_string());
+                let options = match eval_timeout {
+                    Some(limit) => EvalOptions::with_timeout(limit),
+                    None => EvalOptions::default(),
+                };
+                let result = book.update_cell_data_with(
+                    active,
+                    cell_index_row,
+                    cell_index_col,
+                    rhs.to_string(),
+                    &options,
+                );
                 time = result.time;
                 match result.error {
                     Error::InvalidInput => massage = "invalid input",
                     Error::None => massage = "ok",
-                    Error::CycleDetected => massage = "cycle detected",
+                    Error::CycleDetected => {
+                        status = match book.cycle_path(active, &result) {
+                            Some(path) => format!("cycle detected: {}", path),
+                            None => String::from("cycle detected"),
+                        };
+                        massage = &status;
+                    }
                     Error::DivByZero => massage = "ok",
+                    Error::Overflow => massage = "ok",
+                    Error::Ref => massage = "ok",
+                    Error::Timeout => massage = "timeout",
+                    Error::Cancelled => massage = "cancelled",
+                    Error::ValidationFailed => {
+                        status = match &result.violation {
+                            Some(validation) => format!("rejected: {}", validation),
+                            None => String::from("rejected"),
+                        };
+                        massage = &status;
+                    }
+                }
+                if let Some(validation) = &result.violation
+                    && result.error != Error::ValidationFailed
+                {
+                    status = format!("warning: {}", validation);
+                    massage = &status;
+                }
+                if !result.diverged.is_empty() {
+                    let names: Vec<String> = result
+                        .diverged
+                        .iter()
+                        .map(|addr| addr.to_string())
+                        .collect();
+                    status = format!("did not converge: {}", names.join(" "));
+                    massage = &status;
                 }
             } else {
                 massage = "invalid input";
@@ -185,6 +620,310 @@ pub fn run_help(args: Vec<String>) {
         } else if trimmed == "enable_output" {
             display_button = true
         }
+        // Handle history commands
+        else if trimmed == "undo" {
+            if !book.sheet_mut(active).undo() {
+                massage = "nothing to undo";
+            }
+        } else if trimmed == "redo" {
+            if !book.sheet_mut(active).redo() {
+                massage = "nothing to redo";
+            }
+        }
+        // Handle row and column insert/delete commands
+        else if let Some((command, args)) = trimmed.split_once(' ')
+            && matches!(
+                command,
+                "insert_row" | "delete_row" | "insert_col" | "delete_col"
+            )
+        {
+            let result = match (command, parse_lines(args, command.ends_with("col"))) {
+                (_, None) => Err(Error::InvalidInput),
+                ("insert_row", Some((at, count))) => book.sheet_mut(active).insert_rows(at, count),
+                ("delete_row", Some((at, count))) => book.sheet_mut(active).delete_rows(at, count),
+                ("insert_col", Some((at, count))) => book.sheet_mut(active).insert_cols(at, count),
+                (_, Some((at, count))) => book.sheet_mut(active).delete_cols(at, count),
+            };
+            if result.is_err() {
+                massage = "invalid input";
+            }
+        }
+        // Handle fill command
+        else if let Some(range) = trimmed.strip_prefix("fill ") {
+            match RangeAddr::parse(range.trim()) {
+                Some(range) => {
+                    let direction = if range.start.row == range.end.row {
+                        FillDirection::Right
+                    } else {
+                        FillDirection::Down
+                    };
+                    match book.sheet_mut(active).fill(range, direction) {
+                        Ok(()) => {}
+                        Err(Error::CycleDetected) => massage = "cycle detected",
+                        Err(_) => massage = "invalid input",
+                    }
+                }
+                None => massage = "invalid input",
+            }
+        }
+        // Handle sort command
+        else if let Some(args) = trimmed.strip_prefix("sort ") {
+            let result = match parse_sort(args) {
+                Some((range, keys, header)) => {
+                    book.sheet_mut(active).sort_range(range, &keys, header)
+                }
+                None => Err(Error::InvalidInput),
+            };
+            if result.is_err() {
+                massage = "invalid input";
+            }
+        }
+        // Handle filter commands
+        else if let Some(args) = trimmed.strip_prefix("filter ") {
+            let result = match args.trim() {
+                "off" => {
+                    book.sheet_mut(active).remove_auto_filter();
+                    Ok(())
+                }
+                "reapply" => {
+                    book.sheet_mut(active).reapply_filter();
+                    Ok(())
+                }
+                args => match (RangeAddr::parse(args), parse_criterion(args)) {
+                    (Some(header), _) => book.sheet_mut(active).set_auto_filter(header),
+                    (_, Some((col, criterion))) => {
+                        book.sheet_mut(active).set_filter_criterion(col, criterion)
+                    }
+                    (None, None) => Err(Error::InvalidInput),
+                },
+            };
+            if result.is_err() {
+                massage = "invalid input";
+            }
+        }
+        // Handle find command
+        else if let Some(args) = trimmed.strip_prefix("find ") {
+            match parse_find(args)
+                .map(|(pattern, options)| book.sheet_mut(active).find(pattern, &options))
+            {
+                Some(Ok(found)) => {
+                    let names: Vec<String> = found.iter().map(|addr| addr.to_string()).collect();
+                    println!("found {}: {}", found.len(), names.join(" "));
+                    if let Some(first) = found.first() {
+                        rowi = first.row as i32;
+                        coli = first.col as i32;
+                    }
+                }
+                _ => massage = "invalid input",
+            }
+        }
+        // Handle trace command
+        else if let Some(args) = trimmed.strip_prefix("trace ") {
+            let mut words = args.split_whitespace();
+            let cell = words.next().and_then(CellAddr::parse);
+            let depth = match words.next() {
+                Some(depth) => depth.parse::<usize>().ok(),
+                None => Some(usize::MAX),
+            };
+            match (cell, depth, words.next()) {
+                (Some(cell), Some(depth), None) => {
+                    let precedents = book.sheet_mut(active).precedents(cell, depth);
+                    let dependents = book.sheet_mut(active).dependents(cell, depth);
+                    println!("precedents of {}: {}", cell, describe_trace(&precedents));
+                    println!("dependents of {}: {}", cell, describe_trace(&dependents));
+                }
+                _ => massage = "invalid input",
+            }
+        }
+        // Handle format command
+        else if let Some(args) = trimmed.strip_prefix("format ") {
+            let result = match parse_format(args) {
+                Some((range, formats)) => book.sheet_mut(active).set_format(range, &formats),
+                None => Err(Error::InvalidInput),
+            };
+            if result.is_err() {
+                massage = "invalid input";
+            }
+        }
+        // Handle conditional formatting commands
+        else if let Some(args) = trimmed.strip_prefix("rule ") {
+            let result = match parse_rule(args) {
+                Some((range, rule)) => book.sheet_mut(active).add_rule(range, rule),
+                None => Err(Error::InvalidInput),
+            };
+            if result.is_err() {
+                massage = "invalid input";
+            }
+        } else if trimmed == "rules" {
+            for (number, (range, rule)) in book.sheet_mut(active).rules().iter().enumerate() {
+                println!("{}: {} {}", number + 1, range, rule);
+            }
+        } else if let Some(args) = trimmed.strip_prefix("delete_rule ") {
+            let removed = match args.trim().parse::<usize>() {
+                Ok(number) if number > 0 => book.sheet_mut(active).remove_rule(number - 1),
+                _ => None,
+            };
+            if removed.is_none() {
+                massage = "invalid input";
+            }
+        }
+        // Handle data validation commands
+        else if let Some(args) = trimmed.strip_prefix("validate ") {
+            let result = match parse_validation(args) {
+                Some((range, validation)) => {
+                    book.sheet_mut(active).add_validation(range, validation)
+                }
+                None => Err(Error::InvalidInput),
+            };
+            if result.is_err() {
+                massage = "invalid input";
+            }
+        } else if trimmed == "validations" {
+            let validations = book.sheet_mut(active).validations();
+            for (number, (range, validation)) in validations.iter().enumerate() {
+                println!("{}: {} {}", number + 1, range, validation);
+            }
+        } else if let Some(args) = trimmed.strip_prefix("delete_validation ") {
+            let removed = match args.trim().parse::<usize>() {
+                Ok(number) if number > 0 => book.sheet_mut(active).remove_validation(number - 1),
+                _ => None,
+            };
+            if removed.is_none() {
+                massage = "invalid input";
+            }
+        }
+        // Handle note and comment commands
+        else if let Some(args) = trimmed.strip_prefix("note ") {
+            let result = match parse_note(args) {
+                Some((cell, text)) => book
+                    .sheet_mut(active)
+                    .set_note(cell, Comment::new(&author(), text)),
+                None => Err(Error::InvalidInput),
+            };
+            if result.is_err() {
+                massage = "invalid input";
+            }
+        } else if let Some(args) = trimmed.strip_prefix("comment ") {
+            let result = match parse_note(args) {
+                Some((cell, text)) => book
+                    .sheet_mut(active)
+                    .add_comment(cell, Comment::new(&author(), text)),
+                None => Err(Error::InvalidInput),
+            };
+            if result.is_err() {
+                massage = "invalid input";
+            }
+        } else if trimmed == "notes" {
+            let names: Vec<String> = book
+                .sheet_mut(active)
+                .annotated_cells()
+                .iter()
+                .map(|addr| addr.to_string())
+                .collect();
+            println!("notes: {}", names.join(" "));
+        } else if let Some(args) = trimmed.strip_prefix("notes ") {
+            match CellAddr::parse(args.trim()) {
+                Some(cell) => {
+                    if let Some(annotations) = book.sheet_mut(active).annotations(cell) {
+                        for line in describe_annotations(annotations) {
+                            println!("{}", line);
+                        }
+                    }
+                }
+                None => massage = "invalid input",
+            }
+        } else if let Some(args) = trimmed.strip_prefix("delete_note ") {
+            let removed = CellAddr::parse(args.trim())
+                .and_then(|cell| book.sheet_mut(active).remove_note(cell));
+            if removed.is_none() {
+                massage = "invalid input";
+            }
+        } else if let Some(args) = trimmed.strip_prefix("delete_comment ") {
+            let removed = match args.split_whitespace().collect::<Vec<_>>().as_slice() {
+                [cell, number] => match (CellAddr::parse(cell), number.parse::<usize>()) {
+                    (Some(cell), Ok(number)) if number > 0 => {
+                        book.sheet_mut(active).remove_comment(cell, number - 1)
+                    }
+                    _ => None,
+                },
+                _ => None,
+            };
+            if removed.is_none() {
+                massage = "invalid input";
+            }
+        }
+        // Handle watch command
+        else if trimmed == "watch on" {
+            if watcher.is_none() {
+                watcher = Some(book.sheet_mut(active).subscribe(|cells| {
+                    let names: Vec<String> = cells.iter().map(|addr| addr.to_string()).collect();
+                    println!("changed: {}", names.join(" "));
+                }));
+            }
+        } else if trimmed == "watch off" {
+            if let Some(id) = watcher.take() {
+                // The CLI is the only listener, so the id names no other one
+                for index in 0..book.len() {
+                    book.sheet_mut(index).unsubscribe(id);
+                }
+            }
+        }
+        // Handle iterative calculation command
+        else if let Some(args) = trimmed.strip_prefix("iterate ") {
+            match parse_iteration(args) {
+                Some(iteration) => book.sheet_mut(active).set_iterative_calculation(iteration),
+                None => massage = "invalid input",
+            }
+        }
+        // Handle sheet commands
+        else if trimmed == "sheets" {
+            let names: Vec<String> = book
+                .names()
+                .enumerate()
+                .map(|(index, name)| match index == active {
+                    true => format!("*{}", name),
+                    false => name.to_string(),
+                })
+                .collect();
+            println!("sheets: {}", names.join(", "));
+        } else if let Some(name) = trimmed.strip_prefix("sheet ") {
+            match book.position(name.trim()) {
+                Some(index) => active = index,
+                None => massage = "invalid input",
+            }
+        } else if let Some(name) = trimmed.strip_prefix("add_sheet ") {
+            match book.add_sheet(name.trim()) {
+                Ok(index) => active = index,
+                Err(_) => massage = "invalid input",
+            }
+        } else if let Some(name) = trimmed.strip_prefix("rename_sheet ") {
+            if book.rename_sheet(active, name.trim()).is_err() {
+                massage = "invalid input";
+            }
+        } else if trimmed == "delete_sheet" {
+            match book.delete_sheet(active) {
+                Ok(_) => active = active.min(book.len() - 1),
+                Err(_) => massage = "invalid input",
+            }
+        } else if let Some(position) = trimmed.strip_prefix("move_sheet ") {
+            let to = position
+                .trim()
+                .parse::<usize>()
+                .ok()
+                .and_then(|to| to.checked_sub(1));
+            match to.map(|to| book.move_sheet(active, to).map(|()| to)) {
+                Some(Ok(to)) => active = to,
+                _ => massage = "invalid input",
+            }
+        }
+        // Handle timeout command
+        else if let Some(secs) = trimmed.strip_prefix("timeout ") {
+            match secs.trim().parse::<u64>() {
+                Ok(0) => eval_timeout = None,
+                Ok(secs) => eval_timeout = Some(Duration::from_secs(secs)),
+                Err(_) => massage = "invalid input",
+            }
+        }
         // Handle scroll_to command
         else if trimmed.len() > 9 && &trimmed[0..9] == "scroll_to" {
             let parts: Vec<&str> = trimmed.split(' ').collect();
@@ -208,6 +947,8 @@ pub fn run_help(args: Vec<String>) {
         } else {
             massage = "invalid input";
         }
+        // Carry changes made directly to the shown sheet to the sheets reading it
+        book.refresh();
     }
 }
 
@@ -242,10 +983,10 @@ mod tests {
     #[test]
     fn test_display_sheet() {
         let mut test_sheet = Sheet::new(20, 20);
-        test_sheet.grid[1][1].value = 42;
-        test_sheet.grid[1][2].value = 50;
-        test_sheet.grid[1][3].value = 100;
-        test_sheet.grid[1][4].formula.flag.set_is_div_by_zero(1);
+        test_sheet.grid[(1, 1)].value = 42;
+        test_sheet.grid[(1, 2)].value = 50;
+        test_sheet.grid[(1, 3)].value = 100;
+        test_sheet.grid[(1, 4)].formula.flag.set_is_div_by_zero(1);
         let rowi = 1;
         let coli = 1;
         display_sheet(&test_sheet, 20, 20, rowi as usize, coli as usize);
@@ -0,0 +1,201 @@
+//! Typed cell and range addresses.
+//!
+//! Cells are addressed by their 1-based row and column, each stored as a
+//! `u32`. Nothing packs the two numbers into one integer, so the size of a
+//! sheet is not limited by an encoding shift.
+
+use std::fmt;
+
+/// Address of a single cell, e.g. `B7`.
+///
+/// Addresses order by row first and column second.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash, PartialOrd, Ord, serde::Serialize)]
+pub struct CellAddr {
+    /// Row index (1-based)
+    pub row: u32,
+    /// Column index (1-based, `A` = 1)
+    pub col: u32,
+}
+
+impl CellAddr {
+    /// Creates an address from a row and a column.
+    pub const fn new(row: u32, col: u32) -> Self {
+        Self { row, col }
+    }
+
+    /// Creates an address from `usize` indices as used by `Sheet`.
+    ///
+    /// # Panics
+    /// If either index does not fit in a `u32`.
+    pub fn from_index(row: usize, col: usize) -> Self {
+        Self {
+            row: u32::try_from(row).expect("row index out of range"),
+            col: u32::try_from(col).expect("column index out of range"),
+        }
+    }
+
+    /// Returns the `(row, col)` indices as used by `Sheet`.
+    pub fn index(self) -> (usize, usize) {
+        (self.row as usize, self.col as usize)
+    }
+
+    /// Returns the address moved by `rows` rows and `cols` columns.
+    ///
+    /// # Returns
+    /// `None` if the result lies before row 1 or column 1, or does not fit in a `u32`.
+    pub fn offset(self, rows: i64, cols: i64) -> Option<Self> {
+        let row = u32::try_from(self.row as i64 + rows).ok()?;
+        let col = u32::try_from(self.col as i64 + cols).ok()?;
+        if row == 0 || col == 0 {
+            return None;
+        }
+        Some(Self { row, col })
+    }
+
+    /// Parses a cell name such as `A1` or `XFD1048576`.
+    ///
+    /// # Returns
+    /// `None` unless the name is one or more uppercase letters followed by a
+    /// row number without leading zeros, and both parts fit in a `u32`.
+    pub fn parse(name: &str) -> Option<Self> {
+        let split = name.find(|c: char| !c.is_ascii_uppercase())?;
+        let (letters, digits) = name.split_at(split);
+        if letters.is_empty()
+            || digits.is_empty()
+            || digits.starts_with('0')
+            || !digits.chars().all(|c| c.is_ascii_digit())
+        {
+            return None;
+        }
+
+        let mut col: u32 = 0;
+        for c in letters.bytes() {
+            col = col.checked_mul(26)?.checked_add((c - b'A' + 1) as u32)?;
+        }
+        let row = digits.parse::<u32>().ok()?;
+        Some(Self { row, col })
+    }
+}
+
+impl fmt::Display for CellAddr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}{}", column_name(self.col), self.row)
+    }
+}
+
+/// Converts a 1-based column index to its letters (1 -> A, 27 -> AA).
+pub fn column_name(col: u32) -> String {
+    let mut name = Vec::new();
+    let mut col = col;
+    while col > 0 {
+        let remainder = (col - 1) % 26;
+        name.push(b'A' + remainder as u8);
+        col = (col - 1) / 26;
+    }
+    name.reverse();
+    String::from_utf8(name).unwrap_or_default()
+}
+
+/// Address of a rectangular block of cells, e.g. `A1:C4`.
+///
+/// `start` is always the top-left and `end` the bottom-right corner.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, serde::Serialize)]
+pub struct RangeAddr {
+    /// Top-left cell of the range
+    pub start: CellAddr,
+    /// Bottom-right cell of the range
+    pub end: CellAddr,
+}
+
+impl RangeAddr {
+    /// Creates a range from its corners.
+    ///
+    /// # Returns
+    /// `None` if `start` lies below or to the right of `end`.
+    pub fn new(start: CellAddr, end: CellAddr) -> Option<Self> {
+        if start.row > end.row || start.col > end.col {
+            return None;
+        }
+        Some(Self { start, end })
+    }
+
+    /// Parses a range such as `A1:C4`.
+    pub fn parse(range: &str) -> Option<Self> {
+        let (start, end) = range.split_once(':')?;
+        Self::new(CellAddr::parse(start.trim())?, CellAddr::parse(end.trim())?)
+    }
+
+    /// Returns `true` if `addr` lies inside the range.
+    pub fn contains(&self, addr: CellAddr) -> bool {
+        (self.start.row..=self.end.row).contains(&addr.row)
+            && (self.start.col..=self.end.col).contains(&addr.col)
+    }
+
+    /// Number of cells in the range.
+    pub fn len(&self) -> u64 {
+        (self.end.row - self.start.row + 1) as u64 * (self.end.col - self.start.col + 1) as u64
+    }
+
+    /// Always `false`; a range covers at least one cell.
+    pub fn is_empty(&self) -> bool {
+        false
+    }
+
+    /// Iterates over the cells of the range row by row.
+    pub fn cells(&self) -> impl Iterator<Item = CellAddr> + use<> {
+        let (start, end) = (self.start, self.end);
+        (start.row..=end.row)
+            .flat_map(move |row| (start.col..=end.col).map(move |col| CellAddr::new(row, col)))
+    }
+}
+
+impl fmt::Display for RangeAddr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}", self.start, self.end)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_and_display() {
+        assert_eq!(CellAddr::parse("A1"), Some(CellAddr::new(1, 1)));
+        assert_eq!(CellAddr::parse("ZZ29"), Some(CellAddr::new(29, 702)));
+        let last = CellAddr::parse("XFD1048576").unwrap();
+        assert_eq!(last, CellAddr::new(1_048_576, 16384));
+        assert_eq!(last.to_string(), "XFD1048576");
+        assert_eq!(CellAddr::new(1, 18278).to_string(), "ZZZ1");
+    }
+
+    #[test]
+    fn test_offset() {
+        let b2 = CellAddr::new(2, 2);
+        assert_eq!(b2.offset(3, -1), Some(CellAddr::new(5, 1)));
+        assert_eq!(b2.offset(-2, 0), None);
+        assert_eq!(b2.offset(0, -2), None);
+        assert_eq!(b2.offset(u32::MAX as i64, 0), None);
+    }
+
+    #[test]
+    fn test_parse_invalid() {
+        for name in ["", "A", "1", "A0", "A01", "a1", "1A", "A1B", "A-1"] {
+            assert_eq!(CellAddr::parse(name), None, "{name}");
+        }
+        assert_eq!(CellAddr::parse("A99999999999"), None);
+        assert_eq!(CellAddr::parse("AAAAAAAAAAAAAAAA1"), None);
+    }
+
+    #[test]
+    fn test_range() {
+        let range = RangeAddr::parse("B2:C3").unwrap();
+        assert_eq!(range.to_string(), "B2:C3");
+        assert_eq!(range.len(), 4);
+        assert!(range.contains(CellAddr::new(3, 2)));
+        assert!(!range.contains(CellAddr::new(4, 2)));
+        let cells: Vec<String> = range.cells().map(|addr| addr.to_string()).collect();
+        assert_eq!(cells, ["B2", "C2", "B3", "C3"]);
+        assert_eq!(RangeAddr::parse("C3:B2"), None);
+    }
+}
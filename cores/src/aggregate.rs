@@ -0,0 +1,219 @@
+//! Incrementally maintained aggregates of the ranges read by range functions.
+//!
+//! A range read by `SUM`, `AVG`, `STDEV`, `MIN` or `MAX` keeps running totals of
+//! its cells. When a single cell of the range changes, the totals are adjusted
+//! by the difference instead of scanning the whole rectangle again. The
+//! minimum and maximum also count how often they occur, so they only need a
+//! full rescan once the last occurrence of the extreme value is overwritten.
+
+use crate::sheet::Error;
+
+/// Running totals over the values of one range.
+///
+/// Empty cells count as zero, like they do for the range functions.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Aggregate {
+    /// Number of cells in the range
+    count: i128,
+    /// Sum of the values
+    sum: i128,
+    /// Sum of the squared values
+    sum_sq: i128,
+    /// Smallest value, exact while `min_count` is non-zero
+    min: i32,
+    /// Number of cells holding `min`
+    min_count: u64,
+    /// Largest value, exact while `max_count` is non-zero
+    max: i32,
+    /// Number of cells holding `max`
+    max_count: u64,
+    /// Number of cells holding an error value
+    errors: u64,
+}
+
+impl Aggregate {
+    /// Builds the totals by scanning every cell of a range.
+    ///
+    /// # Parameters
+    /// * `readings` - The value of every cell, or `None` for cells holding an error
+    pub fn scan(readings: impl Iterator<Item = Option<i32>>) -> Self {
+        let mut aggregate = Self {
+            count: 0,
+            sum: 0,
+            sum_sq: 0,
+            min: i32::MAX,
+            min_count: 0,
+            max: i32::MIN,
+            max_count: 0,
+            errors: 0,
+        };
+        for reading in readings {
+            aggregate.count += 1;
+            aggregate.add(reading);
+        }
+        aggregate
+    }
+
+    /// Adjusts the totals after one cell of the range changed.
+    ///
+    /// # Parameters
+    /// * `before` - The previous reading of the cell
+    /// * `after` - The new reading of the cell
+    pub fn update(&mut self, before: Option<i32>, after: Option<i32>) {
+        if before == after {
+            return;
+        }
+        self.subtract(before);
+        self.add(after);
+    }
+
+    /// Returns `true` if computing `cmd` from the totals needs a rescan first.
+    pub fn is_stale(&self, cmd: u8) -> bool {
+        match cmd {
+            0 => self.min_count == 0,
+            1 => self.max_count == 0,
+            _ => false,
+        }
+    }
+
+    /// Computes a range function from the totals.
+    ///
+    /// # Parameters
+    /// * `cmd` - 0 = MIN, 1 = MAX, 2 = SUM, 3 = AVG, 4 = STDEV
+    ///
+    /// # Returns
+    /// `None` if the totals cannot answer: the extreme value is stale, or some
+    /// cell holds an error and the first one in reading order has to be found.
+    pub fn evaluate(&self, cmd: u8) -> Option<Result<i32, Error>> {
+        if self.errors > 0 || self.is_stale(cmd) {
+            return None;
+        }
+        Some(match cmd {
+            0 => Ok(self.min),
+            1 => Ok(self.max),
+            2 => sum_value(self.sum),
+            3 => Ok(average_value(self.sum, self.count)),
+            _ => stddev_value(self.sum, self.sum_sq, self.count),
+        })
+    }
+
+    fn add(&mut self, reading: Option<i32>) {
+        let Some(value) = reading else {
+            self.errors += 1;
+            return;
+        };
+        self.sum += value as i128;
+        self.sum_sq += (value as i128) * (value as i128);
+        // A value at or below a stale minimum is still the new minimum, because
+        // every remaining cell is above the old one
+        if value < self.min {
+            self.min = value;
+            self.min_count = 1;
+        } else if value == self.min {
+            self.min_count += 1;
+        }
+        if value > self.max {
+            self.max = value;
+            self.max_count = 1;
+        } else if value == self.max {
+            self.max_count += 1;
+        }
+    }
+
+    fn subtract(&mut self, reading: Option<i32>) {
+        let Some(value) = reading else {
+            self.errors -= 1;
+            return;
+        };
+        self.sum -= value as i128;
+        self.sum_sq -= (value as i128) * (value as i128);
+        if value == self.min {
+            self.min_count = self.min_count.saturating_sub(1);
+        }
+        if value == self.max {
+            self.max_count = self.max_count.saturating_sub(1);
+        }
+    }
+}
+
+/// Narrows the sum of a range to the value of `SUM`.
+pub fn sum_value(sum: i128) -> Result<i32, Error> {
+    i32::try_from(sum).map_err(|_| Error::Overflow)
+}
+
+/// Computes `AVG` from the sum and the number of cells of a range.
+pub fn average_value(sum: i128, count: i128) -> i32 {
+    if count == 0 {
+        return 0;
+    }
+    // The mean of i32 values always fits in an i32
+    (sum / count) as i32
+}
+
+/// Computes `STDEV` from the sum, the sum of squares and the number of cells of a range.
+pub fn stddev_value(sum: i128, sum_sq: i128, count: i128) -> Result<i32, Error> {
+    if count == 0 {
+        return Ok(0);
+    }
+    let avg = ((sum * sum) as f64) / ((count * count) as f64);
+    let mut x = (sum_sq as f64) / (count as f64);
+    x -= avg;
+    let rounded = x.sqrt().round();
+    if rounded > i32::MAX as f64 {
+        return Err(Error::Overflow);
+    }
+    Ok(rounded as i32)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn scan(values: &[Option<i32>]) -> Aggregate {
+        Aggregate::scan(values.iter().copied())
+    }
+
+    #[test]
+    fn test_update_matches_scan() {
+        let mut values = vec![Some(4), Some(-2), Some(9), Some(0), Some(9)];
+        let mut aggregate = scan(&values);
+        assert_eq!(aggregate.evaluate(2), Some(Ok(20)));
+        assert_eq!(aggregate.evaluate(3), Some(Ok(4)));
+
+        for (index, value) in [(1, Some(7)), (3, None), (0, Some(-5)), (3, Some(2))] {
+            aggregate.update(values[index], value);
+            values[index] = value;
+            let fresh = scan(&values);
+            for cmd in 2..=4 {
+                assert_eq!(aggregate.evaluate(cmd), fresh.evaluate(cmd), "{cmd}");
+            }
+        }
+        assert_eq!(aggregate.evaluate(0), Some(Ok(-5)));
+        assert_eq!(aggregate.evaluate(1), Some(Ok(9)));
+    }
+
+    #[test]
+    fn test_stale_extremes() {
+        let mut aggregate = scan(&[Some(3), Some(8), Some(8), Some(1)]);
+        aggregate.update(Some(8), Some(2));
+        assert_eq!(aggregate.evaluate(1), Some(Ok(8)));
+        aggregate.update(Some(8), Some(2));
+        assert!(aggregate.is_stale(1));
+        assert_eq!(aggregate.evaluate(1), None);
+        assert_eq!(aggregate.evaluate(2), Some(Ok(8)));
+
+        // Lowering a stale minimum makes it exact again
+        aggregate.update(Some(1), Some(5));
+        assert!(aggregate.is_stale(0));
+        aggregate.update(Some(5), Some(-1));
+        assert_eq!(aggregate.evaluate(0), Some(Ok(-1)));
+    }
+
+    #[test]
+    fn test_errors_defer_to_scan() {
+        let mut aggregate = scan(&[Some(1), None]);
+        assert_eq!(aggregate.evaluate(2), None);
+        aggregate.update(None, Some(5));
+        assert_eq!(aggregate.evaluate(2), Some(Ok(6)));
+    }
+}
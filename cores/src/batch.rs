@@ -0,0 +1,345 @@
+//! Batched cell updates.
+//!
+//! A batch collects many edits and applies them together: every formula is
+//! parsed and linked into the dependency graph first, then all affected cells
+//! are recalculated in one topological pass. This keeps loading or pasting
+//! many cells linear instead of recalculating after every single edit.
+
+use crate::addr::CellAddr;
+use crate::eval::EvalOptions;
+use crate::parse::{CommandCall, CommandFlag};
+use crate::sheet::{Error, Sheet, value_error};
+use crate::validation::Alert;
+use fxhash::FxHashSet;
+use std::time;
+
+/// A list of pending cell edits, applied by `Sheet::commit`.
+#[derive(Clone, Debug)]
+pub struct Batch {
+    /// Each edit with whether the validation of the cell checks it
+    edits: Vec<(CellAddr, String, bool)>,
+    /// Whether the validations check the formulas set by `set`
+    checked: bool,
+}
+
+impl Default for Batch {
+    fn default() -> Self {
+        Self {
+            edits: Vec::new(),
+            checked: true,
+        }
+    }
+}
+
+impl Batch {
+    /// Creates an empty batch.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Creates an empty batch the validations do not check, for operations
+    /// that only rearrange or restore what the cells held.
+    pub(crate) fn unchecked() -> Self {
+        Self {
+            checked: false,
+            ..Self::default()
+        }
+    }
+
+    /// Queues a new formula for a cell.
+    ///
+    /// Edits are applied in the order they were queued, so the last edit of a
+    /// cell wins.
+    ///
+    /// # Parameters
+    /// * `row` - Row index of the cell
+    /// * `col` - Column index of the cell
+    /// * `formula` - The formula to assign
+    pub fn set(&mut self, row: usize, col: usize, formula: impl Into<String>) {
+        self.edits
+            .push((CellAddr::from_index(row, col), formula.into(), self.checked));
+    }
+
+    /// Queues clearing a cell; validations never check clearing.
+    pub fn clear(&mut self, row: usize, col: usize) {
+        self.edits
+            .push((CellAddr::from_index(row, col), String::from("0"), false));
+    }
+
+    /// Number of queued edits.
+    pub fn len(&self) -> usize {
+        self.edits.len()
+    }
+
+    /// Returns `true` if no edit is queued.
+    pub fn is_empty(&self) -> bool {
+        self.edits.is_empty()
+    }
+}
+
+/// Result of committing a batch.
+#[derive(Debug)]
+pub struct BatchResult {
+    /// Time taken to apply the batch (in milliseconds)
+    pub time: f64,
+    /// `Error::None` if the batch was applied. `Error::CycleDetected` or
+    /// `Error::Cancelled` if it was rolled back and the sheet is unchanged.
+    pub error: Error,
+    /// Every edited cell that failed, with the reason. Invalid formulas and
+    /// formulas rejected by a validation are skipped while the rest of the
+    /// batch is still applied.
+    pub failed: Vec<(CellAddr, Error)>,
+}
+
+impl Sheet {
+    /// Starts a new batch of edits for this sheet.
+    ///
+    /// Nothing changes until the batch is passed to `commit`.
+    pub fn begin_batch(&self) -> Batch {
+        Batch::new()
+    }
+
+    /// Applies a batch of edits with a single recalculation pass.
+    pub fn commit(&mut self, batch: Batch) -> BatchResult {
+        self.commit_with(batch, &EvalOptions::default())
+    }
+
+    /// Collects edits in a closure and commits them as one batch.
+    ///
+    /// # Example
+    /// ```
+    /// let mut sheet = cores::Sheet::new(10, 10);
+    /// let result = sheet.transaction(|batch| {
+    ///     batch.set(1, 1, "5");
+    ///     batch.set(2, 1, "A1*2");
+    /// });
+    /// assert!(result.failed.is_empty());
+    /// assert_eq!(sheet.get_value(2, 1), 10);
+    /// ```
+    pub fn transaction(&mut self, edits: impl FnOnce(&mut Batch)) -> BatchResult {
+        let mut batch = self.begin_batch();
+        edits(&mut batch);
+        self.commit(batch)
+    }
+
+    /// Applies a batch of edits under the given evaluation limits.
+    ///
+    /// Invalid formulas are reported in `failed` and skipped, like formulas
+    /// the validation of their cell rejects; validations are checked against
+    /// the sheet as it was before the batch. If the edits would
+    /// create a cycle while iterative calculation is off, or the cancel token fires
+    /// during recalculation, every edit of the batch is rolled back. The applied edits form one undo step.
+    ///
+    /// # Parameters
+    /// * `batch` - The edits to apply
+    /// * `options` - Cancellation token, deadline and per-cell budget
+    pub fn commit_with(&mut self, batch: Batch, options: &EvalOptions) -> BatchResult {
+        self.notify(|sheet| sheet.apply_batch(batch, options))
+    }
+
+    fn apply_batch(&mut self, batch: Batch, options: &EvalOptions) -> BatchResult {
+        let start_total = time::Instant::now();
+        let mut failed: Vec<(CellAddr, Error)> = Vec::new();
+
+        // Stage 1: Parse every formula and link it into the dependency graph
+        let mut applied: Vec<(CellAddr, CommandCall, i32)> = Vec::new();
+        let mut targets: Vec<CellAddr> = Vec::new();
+        let mut seen: FxHashSet<CellAddr> = FxHashSet::default();
+        for (addr, formula, checked) in batch.edits {
+            let mut command = self.parse_checked(&formula);
+            if command.flag.error() == 1 {
+                failed.push((addr, Error::InvalidInput));
+                continue;
+            }
+            let (row, col) = addr.index();
+            if checked
+                && self
+                    .violation(row, col, &formula)
+                    .is_some_and(|validation| validation.alert == Alert::Reject)
+            {
+                failed.push((addr, Error::ValidationFailed));
+                continue;
+            }
+            command.flag.set_is_any(1);
+            applied.push((addr, self.formula_of(addr), self.grid[addr].value));
+            self.remove_old_dependicies(row, col, command);
+            if seen.insert(addr) {
+                targets.push(addr);
+            }
+        }
+
+        // Stage 2: One topological order for everything the edits affect
+        let Ok(plan) = self.plan_recalc(&targets) else {
+            let cyclic: Vec<CellAddr> = targets
+                .iter()
+                .copied()
+                .filter(|&addr| self.toposort_all(&[addr]).is_err())
+                .collect();
+            self.rollback(applied);
+            failed.extend(cyclic.into_iter().map(|addr| (addr, Error::CycleDetected)));
+            return BatchResult {
+                time: start_total.elapsed().as_millis() as f64,
+                error: Error::CycleDetected,
+                failed,
+            };
+        };
+
+        // Stage 3: Recalculate once
+        let snapshot: Vec<(CellAddr, i32, CommandFlag)> = plan
+            .cells()
+            .filter(|addr| !seen.contains(addr))
+            .map(|addr| {
+                let cell = &self.grid[addr];
+                (addr, cell.value, cell.formula.flag.clone())
+            })
+            .collect();
+        if self.recalculate(plan, options).is_err() {
+            for (addr, value, flag) in snapshot {
+                self.track(addr, |sheet| {
+                    let cell = &mut sheet.grid[addr];
+                    cell.value = value;
+                    cell.formula.flag.copy_status(&flag);
+                });
+            }
+            self.rollback(applied);
+            return BatchResult {
+                time: start_total.elapsed().as_millis() as f64,
+                error: Error::Cancelled,
+                failed,
+            };
+        }
+
+        // Stage 4: Record the edits as one undo step
+        if self.is_recording() {
+            self.group(|sheet| {
+                for (addr, before, _) in &applied {
+                    let after = sheet.get_formula(addr.row as usize, addr.col as usize);
+                    let before = sheet.formula_text(before);
+                    sheet.record(*addr, before, after);
+                }
+            });
+        }
+
+        // Stage 5: Report edited cells that ended up holding an error value
+        for addr in targets {
+            if let Some(err) = value_error(&self.grid[addr]) {
+                failed.push((addr, err));
+            }
+        }
+
+        BatchResult {
+            time: start_total.elapsed().as_millis() as f64,
+            error: Error::None,
+            failed,
+        }
+    }
+
+    /// Restores the formulas and values saved while applying a batch.
+    fn rollback(&mut self, applied: Vec<(CellAddr, CommandCall, i32)>) {
+        for (addr, command, value) in applied.into_iter().rev() {
+            let (row, col) = addr.index();
+            self.remove_old_dependicies(row, col, command);
+            self.track(addr, |sheet| sheet.grid[addr].value = value);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_batch_single_recalculation() {
+        let mut test_sheet = Sheet::new(10, 10);
+        test_sheet.update_cell_data(3, 1, String::from("SUM(A1:A2)"));
+
+        let mut batch = test_sheet.begin_batch();
+        batch.set(1, 1, "4");
+        batch.set(2, 1, "A1*3");
+        batch.set(1, 2, "A3+1");
+        assert_eq!(batch.len(), 3);
+        let result = test_sheet.commit(batch);
+
+        assert_eq!(result.error, Error::None);
+        assert!(result.failed.is_empty());
+        assert_eq!(test_sheet.get_value(2, 1), 12);
+        assert_eq!(test_sheet.get_value(3, 1), 16);
+        assert_eq!(test_sheet.get_value(1, 2), 17);
+
+        test_sheet.update_cell_data(1, 1, String::from("1"));
+        assert_eq!(test_sheet.get_value(1, 2), 5);
+    }
+
+    #[test]
+    fn test_batch_reports_failed_cells() {
+        let mut test_sheet = Sheet::new(10, 10);
+        let result = test_sheet.transaction(|batch| {
+            batch.set(1, 1, "0");
+            batch.set(1, 2, "5/A1");
+            batch.set(1, 3, "A1+");
+            batch.set(1, 4, "Z99");
+            batch.set(1, 5, "7");
+        });
+
+        assert_eq!(result.error, Error::None);
+        assert_eq!(
+            result.failed,
+            vec![
+                (CellAddr::new(1, 3), Error::InvalidInput),
+                (CellAddr::new(1, 4), Error::InvalidInput),
+                (CellAddr::new(1, 2), Error::DivByZero),
+            ]
+        );
+        assert_eq!(test_sheet.get_value(1, 5), 7);
+    }
+
+    #[test]
+    fn test_batch_rolls_back_on_cycle() {
+        let mut test_sheet = Sheet::new(10, 10);
+        test_sheet.update_cell_data(1, 1, String::from("3"));
+        test_sheet.update_cell_data(2, 1, String::from("A1+1"));
+
+        let result = test_sheet.transaction(|batch| {
+            batch.set(1, 1, "B1");
+            batch.set(1, 2, "A2");
+            batch.set(3, 3, "9");
+        });
+
+        assert_eq!(result.error, Error::CycleDetected);
+        assert_eq!(
+            result.failed,
+            vec![
+                (CellAddr::new(1, 1), Error::CycleDetected),
+                (CellAddr::new(1, 2), Error::CycleDetected),
+            ]
+        );
+        assert_eq!(test_sheet.get_formula(1, 1), "3");
+        assert_eq!(test_sheet.get_formula(1, 2), "0");
+        assert_eq!(test_sheet.get_value(3, 3), 0);
+        test_sheet.update_cell_data(1, 1, String::from("10"));
+        assert_eq!(test_sheet.get_value(2, 1), 11);
+        assert!(test_sheet.grid[(2, 1)].depend.is_empty());
+    }
+
+    #[test]
+    fn test_batch_cancel_restores_sheet() {
+        let mut test_sheet = Sheet::new(10, 10);
+        test_sheet.update_cell_data(1, 1, String::from("2"));
+        test_sheet.update_cell_data(2, 1, String::from("A1*5"));
+
+        let mut batch = Batch::new();
+        batch.set(1, 1, "6");
+        batch.set(1, 1, "7");
+        batch.set(3, 1, "A2+1");
+        let options = EvalOptions::default();
+        options.cancel.cancel();
+        let result = test_sheet.commit_with(batch, &options);
+
+        assert_eq!(result.error, Error::Cancelled);
+        assert_eq!(test_sheet.get_formula(1, 1), "2");
+        assert_eq!(test_sheet.get_value(1, 1), 2);
+        assert_eq!(test_sheet.get_value(2, 1), 10);
+        assert_eq!(test_sheet.get_formula(3, 1), "0");
+        assert_eq!(test_sheet.grid[(2, 1)].depend.len(), 0);
+    }
+}
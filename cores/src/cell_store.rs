@@ -0,0 +1,163 @@
+//! Sparse storage for the cells of a sheet.
+//!
+//! Only cells that were written to occupy memory. Reading a cell that was
+//! never written yields a shared empty cell, so a sheet with millions of
+//! logical cells costs no more than the cells actually in use.
+
+use crate::addr::CellAddr;
+use crate::parse::{CommandCall, CommandFlag, Param};
+use crate::sheet::Cell;
+use fxhash::FxHashMap;
+use std::ops::{Index, IndexMut};
+
+/// The value every cell has before it is written to.
+static EMPTY_CELL: Cell = Cell {
+    value: 0,
+    formula: CommandCall {
+        flag: CommandFlag::new(),
+        param1: Param::Value(0),
+        param2: Param::Value(0),
+    },
+    depend: Vec::new(),
+};
+
+/// Hash based store of the used cells of a sheet.
+///
+/// Cells can be indexed either by `(row, col)` or by `CellAddr`. Indexing
+/// returns the empty cell for unused positions; mutable indexing creates the
+/// cell on first write.
+#[derive(Clone, Default)]
+pub struct CellStore {
+    cells: FxHashMap<CellAddr, Cell>,
+}
+
+impl CellStore {
+    /// Creates a store without any cells.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns the cell at `(row, col)` if it was ever written.
+    pub fn get(&self, row: usize, col: usize) -> Option<&Cell> {
+        self.cells.get(&CellAddr::from_index(row, col))
+    }
+
+    /// Replaces the cell at `(row, col)`.
+    pub fn insert(&mut self, row: usize, col: usize, cell: Cell) {
+        self.cells.insert(CellAddr::from_index(row, col), cell);
+    }
+
+    /// Removes the cell at `(row, col)`, turning it back into an empty cell.
+    pub fn remove(&mut self, row: usize, col: usize) -> Option<Cell> {
+        self.cells.remove(&CellAddr::from_index(row, col))
+    }
+
+    /// Removes every cell.
+    pub fn clear(&mut self) {
+        self.cells.clear();
+    }
+
+    /// Number of cells that are stored.
+    pub fn len(&self) -> usize {
+        self.cells.len()
+    }
+
+    /// Returns `true` if no cell is stored.
+    pub fn is_empty(&self) -> bool {
+        self.cells.is_empty()
+    }
+
+    /// Iterates over the stored cells in no particular order.
+    pub fn iter(&self) -> impl Iterator<Item = (CellAddr, &Cell)> {
+        self.cells.iter().map(|(&addr, cell)| (addr, cell))
+    }
+
+    /// Returns the positions of all stored cells sorted by row, then column.
+    pub fn positions(&self) -> Vec<(usize, usize)> {
+        let mut positions: Vec<CellAddr> = self.cells.keys().copied().collect();
+        positions.sort_unstable();
+        positions.into_iter().map(CellAddr::index).collect()
+    }
+
+    /// Returns the sorted columns of the stored cells in `row`.
+    pub fn cols_in_row(&self, row: usize) -> Vec<usize> {
+        let mut cols: Vec<usize> = self
+            .cells
+            .keys()
+            .filter(|addr| addr.row as usize == row)
+            .map(|addr| addr.col as usize)
+            .collect();
+        cols.sort_unstable();
+        cols
+    }
+
+    /// Returns the sorted rows of the stored cells in `col`.
+    pub fn rows_in_col(&self, col: usize) -> Vec<usize> {
+        let mut rows: Vec<usize> = self
+            .cells
+            .keys()
+            .filter(|addr| addr.col as usize == col)
+            .map(|addr| addr.row as usize)
+            .collect();
+        rows.sort_unstable();
+        rows
+    }
+}
+
+impl Index<(usize, usize)> for CellStore {
+    type Output = Cell;
+
+    fn index(&self, (row, col): (usize, usize)) -> &Cell {
+        &self[CellAddr::from_index(row, col)]
+    }
+}
+
+impl IndexMut<(usize, usize)> for CellStore {
+    fn index_mut(&mut self, (row, col): (usize, usize)) -> &mut Cell {
+        &mut self[CellAddr::from_index(row, col)]
+    }
+}
+
+impl Index<CellAddr> for CellStore {
+    type Output = Cell;
+
+    fn index(&self, addr: CellAddr) -> &Cell {
+        self.cells.get(&addr).unwrap_or(&EMPTY_CELL)
+    }
+}
+
+impl IndexMut<CellAddr> for CellStore {
+    fn index_mut(&mut self, addr: CellAddr) -> &mut Cell {
+        self.cells.entry(addr).or_insert_with(|| EMPTY_CELL.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_reads_do_not_allocate() {
+        let store = CellStore::new();
+        assert_eq!(store[(500, 18000)].value, 0);
+        assert!(store[(500, 18000)].depend.is_empty());
+        assert!(store.is_empty());
+    }
+
+    #[test]
+    fn test_write_and_remove() {
+        let mut store = CellStore::new();
+        store[(3, 2)].value = 7;
+        store[(1, 4)].value = 1;
+        store[(3, 1)].value = 5;
+        assert_eq!(store.len(), 3);
+        assert_eq!(store[(3, 2)].value, 7);
+        assert_eq!(store.cols_in_row(3), vec![1, 2]);
+        assert_eq!(store.rows_in_col(4), vec![1]);
+        assert_eq!(store.positions(), vec![(1, 4), (3, 1), (3, 2)]);
+
+        assert_eq!(store.remove(3, 2).map(|cell| cell.value), Some(7));
+        assert_eq!(store[(3, 2)].value, 0);
+        assert_eq!(store.len(), 2);
+    }
+}
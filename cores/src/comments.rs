@@ -0,0 +1,323 @@
+//! Notes and comments on cells.
+//!
+//! Any cell can carry a plain note and a thread of comments, each written
+//! by an author at a time. A note is replaced when it is written again,
+//! while comments are added to the end of the thread as replies. Both stay
+//! with their cell when rows or columns are inserted or deleted and when the
+//! cell is sorted or moved; deleting the cell deletes them. Writing them and
+//! carrying them along are undone with the rest of the step.
+
+use crate::addr::CellAddr;
+use crate::sheet::{Error, Sheet};
+use crate::style::{DateFormat, NumberFormat};
+use std::collections::BTreeMap;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Days from the first day of the date serials, 1899-12-30, to 1970-01-01.
+const UNIX_EPOCH_SERIAL: u64 = 25569;
+
+/// A note or comment written on a cell.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Comment {
+    pub author: String,
+    /// Seconds since 1970-01-01 UTC
+    pub time: u64,
+    pub text: String,
+}
+
+impl Comment {
+    /// Creates a comment written now.
+    pub fn new(author: &str, text: &str) -> Self {
+        let time = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |since| since.as_secs());
+        Comment {
+            author: author.to_string(),
+            time,
+            text: text.to_string(),
+        }
+    }
+
+    /// Writes out when the comment was written, e.g. `2024-03-15 09:30 UTC`.
+    pub fn written_at(&self) -> String {
+        let serial = (self.time / 86400 + UNIX_EPOCH_SERIAL).min(i32::MAX as u64) as i32;
+        let minutes = self.time % 86400 / 60;
+        format!(
+            "{} {:02}:{:02} UTC",
+            NumberFormat::Date(DateFormat::Iso).apply(serial),
+            minutes / 60,
+            minutes % 60
+        )
+    }
+}
+
+/// The note and comments of one cell.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Annotations {
+    pub note: Option<Comment>,
+    /// Comments in the order they were written
+    pub thread: Vec<Comment>,
+}
+
+impl Annotations {
+    /// Returns `true` if the cell has neither a note nor comments.
+    pub fn is_empty(&self) -> bool {
+        self.note.is_none() && self.thread.is_empty()
+    }
+}
+
+/// The notes and comments of a sheet, by cell.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct Notes {
+    pub(crate) cells: BTreeMap<CellAddr, Annotations>,
+}
+
+impl Notes {
+    /// Moves the notes and comments of cells, dropping those of cells that
+    /// are deleted, where `target` returns `None`.
+    ///
+    /// Cells not moved keep theirs unless a moved cell lands on them.
+    pub(crate) fn relocate(
+        &mut self,
+        moved: impl Fn(CellAddr) -> bool,
+        target: impl Fn(CellAddr) -> Option<CellAddr>,
+    ) {
+        let (leaving, staying): (BTreeMap<_, _>, BTreeMap<_, _>) = std::mem::take(&mut self.cells)
+            .into_iter()
+            .partition(|(addr, _)| moved(*addr));
+        self.cells = staying;
+        for (addr, annotations) in leaving {
+            if let Some(to) = target(addr) {
+                self.cells.insert(to, annotations);
+            }
+        }
+    }
+}
+
+impl Sheet {
+    /// Writes the note of a cell, replacing the note it had.
+    ///
+    /// # Returns
+    /// `Error::InvalidInput` if the cell lies outside the sheet.
+    pub fn set_note(&mut self, addr: CellAddr, note: Comment) -> Result<(), Error> {
+        let before = self.notes.cells.clone();
+        self.annotations_mut(addr)?.note = Some(note);
+        self.record_notes(before);
+        Ok(())
+    }
+
+    /// Adds a comment to the end of the thread of a cell.
+    ///
+    /// # Returns
+    /// `Error::InvalidInput` if the cell lies outside the sheet.
+    pub fn add_comment(&mut self, addr: CellAddr, comment: Comment) -> Result<(), Error> {
+        let before = self.notes.cells.clone();
+        self.annotations_mut(addr)?.thread.push(comment);
+        self.record_notes(before);
+        Ok(())
+    }
+
+    /// Removes the note of a cell.
+    ///
+    /// # Returns
+    /// The removed note, or `None` if the cell had none.
+    pub fn remove_note(&mut self, addr: CellAddr) -> Option<Comment> {
+        let before = self.notes.cells.clone();
+        let annotations = self.notes.cells.get_mut(&addr)?;
+        let note = annotations.note.take()?;
+        if annotations.is_empty() {
+            self.notes.cells.remove(&addr);
+        }
+        self.record_notes(before);
+        Some(note)
+    }
+
+    /// Removes a comment from the thread of a cell by its position.
+    ///
+    /// # Returns
+    /// The removed comment, or `None` if the thread has no comment at that position.
+    pub fn remove_comment(&mut self, addr: CellAddr, index: usize) -> Option<Comment> {
+        let before = self.notes.cells.clone();
+        let annotations = self.notes.cells.get_mut(&addr)?;
+        if index >= annotations.thread.len() {
+            return None;
+        }
+        let comment = annotations.thread.remove(index);
+        if annotations.is_empty() {
+            self.notes.cells.remove(&addr);
+        }
+        self.record_notes(before);
+        Some(comment)
+    }
+
+    /// Returns the note and comments of a cell, if it has any.
+    pub fn annotations(&self, addr: CellAddr) -> Option<&Annotations> {
+        self.notes.cells.get(&addr)
+    }
+
+    /// Returns the cells with a note or comments, row by row.
+    pub fn annotated_cells(&self) -> Vec<CellAddr> {
+        self.notes.cells.keys().copied().collect()
+    }
+
+    /// Carries the notes and comments along with moved cells, as part of the
+    /// current undo step; see `Notes::relocate`.
+    pub(crate) fn relocate_notes(
+        &mut self,
+        moved: impl Fn(CellAddr) -> bool,
+        target: impl Fn(CellAddr) -> Option<CellAddr>,
+    ) {
+        let before = self.is_recording().then(|| self.notes.cells.clone());
+        self.notes.relocate(moved, target);
+        if let Some(before) = before {
+            self.record_notes(before);
+        }
+    }
+
+    fn annotations_mut(&mut self, addr: CellAddr) -> Result<&mut Annotations, Error> {
+        if addr.row == 0
+            || addr.col == 0
+            || addr.row as usize > self.row
+            || addr.col as usize > self.col
+        {
+            return Err(Error::InvalidInput);
+        }
+        Ok(self.notes.cells.entry(addr).or_default())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::addr::RangeAddr;
+    use crate::sort::SortKey;
+
+    fn comment(author: &str, text: &str) -> Comment {
+        Comment {
+            author: author.to_string(),
+            time: 1_710_495_000,
+            text: text.to_string(),
+        }
+    }
+
+    #[test]
+    fn test_notes_and_threads() {
+        let mut test_sheet = Sheet::new(10, 10);
+        let cell = CellAddr::parse("B2").unwrap();
+        test_sheet.set_note(cell, comment("ana", "Budget")).unwrap();
+        test_sheet
+            .set_note(cell, comment("ana", "Budget 2024"))
+            .unwrap();
+        test_sheet
+            .add_comment(cell, comment("raj", "Is this final?"))
+            .unwrap();
+        test_sheet.add_comment(cell, comment("ana", "Yes")).unwrap();
+        let annotations = test_sheet.annotations(cell).unwrap();
+        assert_eq!(annotations.note.as_ref().unwrap().text, "Budget 2024");
+        assert_eq!(annotations.thread.len(), 2);
+        assert_eq!(annotations.thread[0].written_at(), "2024-03-15 09:30 UTC");
+        assert_eq!(
+            test_sheet.set_note(CellAddr::new(11, 1), comment("ana", "x")),
+            Err(Error::InvalidInput)
+        );
+
+        assert!(test_sheet.remove_note(cell).is_some());
+        assert_eq!(test_sheet.remove_comment(cell, 0).unwrap().author, "raj");
+        assert!(test_sheet.remove_comment(cell, 1).is_none());
+        assert!(test_sheet.remove_comment(cell, 0).is_some());
+        assert!(test_sheet.annotated_cells().is_empty());
+    }
+
+    #[test]
+    fn test_notes_follow_cells() {
+        let mut test_sheet = Sheet::new(10, 10);
+        for (row, value) in [(1, "3"), (2, "1"), (3, "2")] {
+            test_sheet.update_cell_data(row, 1, String::from(value));
+        }
+        let note = |row| comment("ana", &format!("row {}", row));
+        for row in 1..=3 {
+            test_sheet
+                .set_note(CellAddr::new(row, 1), note(row))
+                .unwrap();
+        }
+        let text = |test_sheet: &Sheet, name: &str| {
+            let annotations = test_sheet.annotations(CellAddr::parse(name).unwrap());
+            annotations
+                .and_then(|annotations| annotations.note.clone())
+                .map(|note| note.text)
+        };
+
+        test_sheet
+            .sort_range(
+                RangeAddr::parse("A1:A3").unwrap(),
+                &[SortKey::ascending(1)],
+                false,
+            )
+            .unwrap();
+        assert_eq!(text(&test_sheet, "A1").as_deref(), Some("row 2"));
+        assert_eq!(text(&test_sheet, "A3").as_deref(), Some("row 1"));
+
+        test_sheet.insert_rows(1, 1).unwrap();
+        test_sheet.insert_cols(1, 2).unwrap();
+        assert_eq!(text(&test_sheet, "C2").as_deref(), Some("row 2"));
+        test_sheet.delete_rows(2, 1).unwrap();
+        assert_eq!(test_sheet.annotated_cells().len(), 2);
+
+        test_sheet
+            .move_range(
+                RangeAddr::parse("C2:C3").unwrap(),
+                CellAddr::parse("E5").unwrap(),
+            )
+            .unwrap();
+        assert_eq!(text(&test_sheet, "E5").as_deref(), Some("row 3"));
+        assert_eq!(text(&test_sheet, "E6").as_deref(), Some("row 1"));
+        assert_eq!(test_sheet.annotated_cells().len(), 2);
+    }
+
+    #[test]
+    fn test_notes_are_undone() {
+        let mut test_sheet = Sheet::new(10, 10);
+        test_sheet.update_cell_data(1, 1, String::from("2"));
+        test_sheet.update_cell_data(2, 1, String::from("1"));
+        let cell = CellAddr::parse("A1").unwrap();
+        test_sheet.set_note(cell, comment("ana", "two")).unwrap();
+        let note_at = |test_sheet: &Sheet, name: &str| {
+            test_sheet
+                .annotations(CellAddr::parse(name).unwrap())
+                .and_then(|annotations| annotations.note.clone())
+                .map(|note| note.text)
+        };
+
+        test_sheet
+            .sort_range(
+                RangeAddr::parse("A1:A2").unwrap(),
+                &[SortKey::ascending(1)],
+                false,
+            )
+            .unwrap();
+        test_sheet
+            .move_range(
+                RangeAddr::parse("A1:A2").unwrap(),
+                CellAddr::parse("C5").unwrap(),
+            )
+            .unwrap();
+        assert_eq!(note_at(&test_sheet, "C6").as_deref(), Some("two"));
+
+        // Each operation is one step, the notes going back with the values
+        assert!(test_sheet.undo());
+        assert_eq!(note_at(&test_sheet, "A2").as_deref(), Some("two"));
+        assert_eq!(test_sheet.get_value(2, 1), 2);
+        assert!(test_sheet.undo());
+        assert_eq!(note_at(&test_sheet, "A1").as_deref(), Some("two"));
+        assert_eq!(test_sheet.get_value(1, 1), 2);
+        assert!(test_sheet.redo());
+        assert!(test_sheet.redo());
+        assert_eq!(note_at(&test_sheet, "C6").as_deref(), Some("two"));
+
+        // Writing notes is undone too
+        test_sheet.remove_note(CellAddr::parse("C6").unwrap());
+        assert!(test_sheet.annotated_cells().is_empty());
+        assert!(test_sheet.undo());
+        assert_eq!(note_at(&test_sheet, "C6").as_deref(), Some("two"));
+    }
+}
@@ -0,0 +1,511 @@
+//! Conditional formatting.
+//!
+//! A rule attached to a range changes how its cells look depending on their
+//! values: it highlights the cells meeting a condition, colors them on a scale
+//! from the smallest to the largest value, or draws a data bar. Rules are not
+//! stored results; they are worked out from the current values whenever a
+//! front end asks, so they follow every change. Empty cells and cells holding
+//! an error value are never highlighted and do not count towards the smallest,
+//! largest or top values of a range.
+//!
+//! A formula rule holds for the cells where its formula is not zero. The
+//! formula is written for the first cell of the range and shifted for the
+//! others, like a filled formula, so `A1-B1` on `A1:A9` compares every cell
+//! with the cell to its right.
+
+use crate::addr::{CellAddr, RangeAddr};
+use crate::eval::EvalOptions;
+use crate::parse::{CommandCall, Param, unparse_command};
+use crate::sheet::{Error, Sheet, reading};
+use crate::style::{Color, Format, Style};
+use std::fmt;
+use std::str::FromStr;
+
+/// A condition the value of a cell has to meet for a highlight rule to hold.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Condition {
+    /// The value is larger than the given number
+    GreaterThan(i32),
+    /// The value is smaller than the given number
+    LessThan(i32),
+    /// The value lies between the two numbers, both included
+    Between(i32, i32),
+    /// The value equals the given number
+    Equals(i32),
+    /// The value is among the `n` largest values of the range; ties all hold
+    Top(usize),
+    /// The value is among the `n` smallest values of the range; ties all hold
+    Bottom(usize),
+    /// The formula, written for the first cell of the range, is not zero
+    Formula(String),
+}
+
+/// What a rule does to the cells of its range.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Rule {
+    /// The cells meeting the condition get the attributes
+    Highlight {
+        condition: Condition,
+        formats: Vec<Format>,
+    },
+    /// The cells are filled with a color between `low`, for the smallest value
+    /// of the range, and `high`, for the largest
+    ColorScale { low: Color, high: Color },
+    /// The cells show a bar as long as their share of the largest value of the range
+    DataBar { color: Color },
+}
+
+impl FromStr for Rule {
+    type Err = Error;
+
+    /// Parses a rule such as `gt 100 then fill:#ffc7ce`, `between 1 9 then bold`,
+    /// `top 10 then color:#006100`, `formula A1-B1 then italic`,
+    /// `scale #f8696b #63be7b` or `bar #638ec6`.
+    fn from_str(text: &str) -> Result<Self, Error> {
+        let number = |text: &str| text.parse::<i32>().map_err(|_| Error::InvalidInput);
+        let count = |text: &str| text.parse::<usize>().map_err(|_| Error::InvalidInput);
+        let color = |text: &str| Color::parse(text).ok_or(Error::InvalidInput);
+        let words: Vec<&str> = text.split_whitespace().collect();
+        match words.as_slice() {
+            ["scale", low, high] => {
+                return Ok(Rule::ColorScale {
+                    low: color(low)?,
+                    high: color(high)?,
+                });
+            }
+            ["bar", bar] => return Ok(Rule::DataBar { color: color(bar)? }),
+            _ => {}
+        }
+        let (condition, formats) = text.split_once(" then ").ok_or(Error::InvalidInput)?;
+        let condition = match condition.split_whitespace().collect::<Vec<_>>().as_slice() {
+            ["gt", value] => Condition::GreaterThan(number(value)?),
+            ["lt", value] => Condition::LessThan(number(value)?),
+            ["eq", value] => Condition::Equals(number(value)?),
+            ["between", low, high] => Condition::Between(number(low)?, number(high)?),
+            ["top", n] => Condition::Top(count(n)?),
+            ["bottom", n] => Condition::Bottom(count(n)?),
+            ["formula", formula] => Condition::Formula(formula.to_string()),
+            _ => return Err(Error::InvalidInput),
+        };
+        let formats = Format::parse_list(formats.trim()).ok_or(Error::InvalidInput)?;
+        Ok(Rule::Highlight { condition, formats })
+    }
+}
+
+impl fmt::Display for Rule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Rule::Highlight { condition, formats } => {
+                match condition {
+                    Condition::GreaterThan(value) => write!(f, "gt {}", value)?,
+                    Condition::LessThan(value) => write!(f, "lt {}", value)?,
+                    Condition::Equals(value) => write!(f, "eq {}", value)?,
+                    Condition::Between(low, high) => write!(f, "between {} {}", low, high)?,
+                    Condition::Top(n) => write!(f, "top {}", n)?,
+                    Condition::Bottom(n) => write!(f, "bottom {}", n)?,
+                    Condition::Formula(formula) => write!(f, "formula {}", formula)?,
+                }
+                let formats: Vec<String> =
+                    formats.iter().map(|format| format.to_string()).collect();
+                write!(f, " then {}", formats.join(","))
+            }
+            Rule::ColorScale { low, high } => write!(f, "scale {} {}", low, high),
+            Rule::DataBar { color } => write!(f, "bar {}", color),
+        }
+    }
+}
+
+/// A rule attached to a range.
+#[derive(Clone, Debug)]
+pub(crate) struct ConditionalRule {
+    pub(crate) range: RangeAddr,
+    pub(crate) rule: Rule,
+    /// The formula of a formula rule, as written for the first cell of the range
+    pub(crate) formula: Option<CommandCall>,
+}
+
+/// How the rules of a sheet change the look of one cell.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Highlight {
+    /// Attributes set by the rules that hold, in the order of the rules
+    pub formats: Vec<Format>,
+    /// Length of a data bar in percent of the cell width, and its color
+    pub bar: Option<(u8, Color)>,
+}
+
+impl Highlight {
+    /// Returns a style with the attributes of the rules applied on top.
+    pub fn apply(&self, mut style: Style) -> Style {
+        for format in &self.formats {
+            style.apply(*format);
+        }
+        style
+    }
+}
+
+/// The values of a range that rules compare cells against.
+struct Values {
+    /// Values of the non-empty cells without an error, from smallest to largest
+    sorted: Vec<i32>,
+}
+
+impl Values {
+    fn min(&self) -> Option<i32> {
+        self.sorted.first().copied()
+    }
+
+    fn max(&self) -> Option<i32> {
+        self.sorted.last().copied()
+    }
+}
+
+/// Returns the color `share` of the way from `low` to `high`, which are 0 and 1.
+fn blend(low: Color, high: Color, share: f64) -> Color {
+    let mix = |low: u8, high: u8| (low as f64 + (high as f64 - low as f64) * share).round() as u8;
+    Color::new(mix(low.r, high.r), mix(low.g, high.g), mix(low.b, high.b))
+}
+
+impl Sheet {
+    /// Attaches a rule to a range; rules added later apply on top of earlier ones.
+    ///
+    /// # Returns
+    /// `Error::InvalidInput` if the range does not lie inside the sheet, or the
+    /// formula of a formula rule is invalid, reads another sheet or sleeps.
+    pub fn add_rule(&mut self, range: RangeAddr, rule: Rule) -> Result<(), Error> {
+        if range.start.row == 0
+            || range.start.col == 0
+            || range.end.row as usize > self.row
+            || range.end.col as usize > self.col
+        {
+            return Err(Error::InvalidInput);
+        }
+        let formula = match &rule {
+            Rule::Highlight {
+                condition: Condition::Formula(text),
+                ..
+            } => Some(self.parse_anchored(text)?),
+            _ => None,
+        };
+        // The formula is kept as the sheet writes it out
+        let rule = match (rule, &formula) {
+            (Rule::Highlight { formats, .. }, Some(formula)) => Rule::Highlight {
+                condition: Condition::Formula(unparse_command(formula)),
+                formats,
+            },
+            (rule, _) => rule,
+        };
+        let before = self.rules.clone();
+        self.rules.push(ConditionalRule {
+            range,
+            rule,
+            formula,
+        });
+        self.record_rules(before);
+        Ok(())
+    }
+
+    /// Returns the rules of the sheet with their ranges, in the order they apply.
+    pub fn rules(&self) -> Vec<(RangeAddr, Rule)> {
+        self.rules
+            .iter()
+            .map(|rule| (rule.range, rule.rule.clone()))
+            .collect()
+    }
+
+    /// Removes the rule at a position of `rules`.
+    ///
+    /// # Returns
+    /// The removed rule, or `None` if there is no rule at that position.
+    pub fn remove_rule(&mut self, index: usize) -> Option<(RangeAddr, Rule)> {
+        if index >= self.rules.len() {
+            return None;
+        }
+        let before = self.rules.clone();
+        let removed = self.rules.remove(index);
+        self.record_rules(before);
+        Some((removed.range, removed.rule))
+    }
+
+    /// Works out how the rules change the look of the cells of an area.
+    ///
+    /// # Returns
+    /// The cells of the area at least one rule applies to, row by row.
+    pub fn highlights(&self, area: RangeAddr) -> Vec<(CellAddr, Highlight)> {
+        let mut highlights: Vec<(CellAddr, Highlight)> = vec![];
+        for rule in &self.rules {
+            let Some(shown) = intersection(rule.range, area) else {
+                continue;
+            };
+            let values = match &rule.rule {
+                Rule::Highlight {
+                    condition: Condition::Formula(_),
+                    ..
+                } => None,
+                _ => Some(self.range_values(rule.range)),
+            };
+            for addr in shown.cells() {
+                let cell = &self.grid[addr];
+                let Some(value) = reading(cell).filter(|_| cell.formula.flag.is_any() != 0) else {
+                    continue;
+                };
+                let (formats, bar) = match &rule.rule {
+                    Rule::Highlight { condition, formats } => {
+                        if !self.holds(rule, condition, addr, value, values.as_ref()) {
+                            continue;
+                        }
+                        (formats.clone(), None)
+                    }
+                    Rule::ColorScale { low, high } => {
+                        let values = values.as_ref().expect("scales read the range");
+                        let (Some(min), Some(max)) = (values.min(), values.max()) else {
+                            continue;
+                        };
+                        let share = match max > min {
+                            true => (value as f64 - min as f64) / (max as f64 - min as f64),
+                            false => 0.5,
+                        };
+                        (
+                            vec![Format::FillColor(Some(blend(*low, *high, share)))],
+                            None,
+                        )
+                    }
+                    Rule::DataBar { color } => {
+                        let values = values.as_ref().expect("bars read the range");
+                        let max = values.max().unwrap_or(0);
+                        let length = match max > 0 {
+                            true => (value.max(0) as i64 * 100 / max as i64) as u8,
+                            false => 0,
+                        };
+                        (vec![], Some((length, *color)))
+                    }
+                };
+                let at = match highlights.binary_search_by_key(&addr, |(other, _)| *other) {
+                    Ok(at) => at,
+                    Err(at) => {
+                        highlights.insert(at, (addr, Highlight::default()));
+                        at
+                    }
+                };
+                let highlight = &mut highlights[at].1;
+                highlight.formats.extend(formats);
+                if bar.is_some() {
+                    highlight.bar = bar;
+                }
+            }
+        }
+        highlights
+    }
+
+    /// Returns how a cell looks with its formatting and the rules that hold for it.
+    pub fn display_style(&self, row: usize, col: usize) -> Style {
+        let style = self.style(row, col);
+        let cell = CellAddr::from_index(row, col);
+        match self
+            .highlights(RangeAddr {
+                start: cell,
+                end: cell,
+            })
+            .pop()
+        {
+            Some((_, highlight)) => highlight.apply(style),
+            None => style,
+        }
+    }
+
+    /// Returns `true` if the value of a cell meets the condition of a highlight rule.
+    fn holds(
+        &self,
+        rule: &ConditionalRule,
+        condition: &Condition,
+        addr: CellAddr,
+        value: i32,
+        values: Option<&Values>,
+    ) -> bool {
+        let sorted = values.map_or(&[][..], |values| &values.sorted[..]);
+        match condition {
+            Condition::GreaterThan(limit) => value > *limit,
+            Condition::LessThan(limit) => value < *limit,
+            Condition::Between(low, high) => (*low..=*high).contains(&value),
+            Condition::Equals(expected) => value == *expected,
+            Condition::Top(n) => {
+                *n > 0 && !sorted.is_empty() && {
+                    let at = sorted.len().saturating_sub(*n);
+                    value >= sorted[at]
+                }
+            }
+            Condition::Bottom(n) => {
+                *n > 0 && !sorted.is_empty() && {
+                    let at = (*n).min(sorted.len()) - 1;
+                    value <= sorted[at]
+                }
+            }
+            Condition::Formula(_) => rule
+                .formula
+                .as_ref()
+                .and_then(|formula| self.evaluate_anchored(formula, rule.range.start, addr))
+                .is_some_and(|result| result != 0),
+        }
+    }
+
+    /// Parses the formula of a formula rule or validation, written for the
+    /// first cell of its range.
+    ///
+    /// # Returns
+    /// `Error::InvalidInput` if the formula is invalid, reads another sheet or sleeps.
+    pub(crate) fn parse_anchored(&self, text: &str) -> Result<CommandCall, Error> {
+        let formula = self.parse_checked(text);
+        let reads_other_sheets = [formula.param1, formula.param2]
+            .into_iter()
+            .any(|param| matches!(param, Param::External(..)));
+        let sleeps = formula.flag.type_() >= 2 && formula.flag.cmd() == 5;
+        if formula.flag.error() != 0 || reads_other_sheets || sleeps {
+            return Err(Error::InvalidInput);
+        }
+        Ok(formula)
+    }
+
+    /// Evaluates a formula written for the cell `anchor` for another cell,
+    /// shifting its references by the offset between the two.
+    ///
+    /// # Returns
+    /// `None` if a shifted reference leaves the sheet or the formula results in an error.
+    pub(crate) fn evaluate_anchored(
+        &self,
+        formula: &CommandCall,
+        anchor: CellAddr,
+        addr: CellAddr,
+    ) -> Option<i32> {
+        let rows = addr.row as i64 - anchor.row as i64;
+        let cols = addr.col as i64 - anchor.col as i64;
+        let shifted = formula.shifted(rows, cols)?;
+        let inside = [shifted.param1, shifted.param2]
+            .into_iter()
+            .filter_map(Param::cell)
+            .all(|cell| cell.row as usize <= self.row && cell.col as usize <= self.col);
+        inside
+            .then(|| self.evaluate_formula(&shifted, &EvalOptions::default(), None))?
+            .ok()
+    }
+
+    /// Collects the values rules compare against from a range.
+    fn range_values(&self, range: RangeAddr) -> Values {
+        let readings: Vec<i32> = if range.len() <= self.grid.len() as u64 {
+            range
+                .cells()
+                .filter_map(|addr| {
+                    let cell = &self.grid[addr];
+                    reading(cell).filter(|_| cell.formula.flag.is_any() != 0)
+                })
+                .collect()
+        } else {
+            // Large ranges are mostly empty, so only visit the stored cells
+            self.grid
+                .iter()
+                .filter(|(addr, cell)| range.contains(*addr) && cell.formula.flag.is_any() != 0)
+                .filter_map(|(_, cell)| reading(cell))
+                .collect()
+        };
+        let mut sorted = readings;
+        sorted.sort_unstable();
+        Values { sorted }
+    }
+}
+
+/// Returns the cells two ranges share, if any.
+fn intersection(range: RangeAddr, other: RangeAddr) -> Option<RangeAddr> {
+    RangeAddr::new(
+        CellAddr::new(
+            range.start.row.max(other.start.row),
+            range.start.col.max(other.start.col),
+        ),
+        CellAddr::new(
+            range.end.row.min(other.end.row),
+            range.end.col.min(other.end.col),
+        ),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn column_sheet(values: &[i32]) -> Sheet {
+        let mut test_sheet = Sheet::new(20, 5);
+        test_sheet.transaction(|batch| {
+            for (row, value) in values.iter().enumerate() {
+                batch.set(row + 1, 1, value.to_string());
+            }
+        });
+        test_sheet
+    }
+
+    fn fill_of(highlights: &[(CellAddr, Highlight)], name: &str) -> Option<Color> {
+        let addr = CellAddr::parse(name).unwrap();
+        let (_, highlight) = highlights.iter().find(|(other, _)| *other == addr)?;
+        highlight.apply(Style::default()).fill_color
+    }
+
+    #[test]
+    fn test_highlight_rules_follow_values() {
+        let mut test_sheet = column_sheet(&[5, 50, 20, 50, 1]);
+        let range = RangeAddr::parse("A1:A10").unwrap();
+        let red = Color::parse("#ff0000").unwrap();
+        test_sheet
+            .add_rule(range, "gt 10 then fill:#ff0000".parse().unwrap())
+            .unwrap();
+        test_sheet
+            .add_rule(range, "top 1 then bold".parse().unwrap())
+            .unwrap();
+        let highlights = test_sheet.highlights(range);
+        assert_eq!(fill_of(&highlights, "A2"), Some(red));
+        assert_eq!(fill_of(&highlights, "A1"), None);
+        // Ties of the top value all hold, and empty cells never do
+        assert!(test_sheet.display_style(4, 1).bold);
+        assert!(!test_sheet.display_style(3, 1).bold);
+        assert_eq!(highlights.len(), 3);
+
+        test_sheet.update_cell_data(1, 1, String::from("99"));
+        assert!(test_sheet.display_style(1, 1).bold);
+        assert!(!test_sheet.display_style(2, 1).bold);
+        assert_eq!(test_sheet.display_style(1, 1).fill_color, Some(red));
+
+        assert_eq!(test_sheet.rules().len(), 2);
+        assert!(test_sheet.remove_rule(1).is_some());
+        assert!(!test_sheet.display_style(1, 1).bold);
+        assert!(test_sheet.remove_rule(1).is_none());
+    }
+
+    #[test]
+    fn test_scales_bars_and_formulas() {
+        let mut test_sheet = column_sheet(&[0, 50, 100]);
+        test_sheet.update_cell_data(1, 2, String::from("1"));
+        let range = RangeAddr::parse("A1:A3").unwrap();
+        test_sheet
+            .add_rule(range, "scale #000000 #ffffff".parse().unwrap())
+            .unwrap();
+        test_sheet
+            .add_rule(range, "bar #0000ff".parse().unwrap())
+            .unwrap();
+        test_sheet
+            .add_rule(range, "formula B1 then italic".parse().unwrap())
+            .unwrap();
+        let highlights = test_sheet.highlights(range);
+        assert_eq!(fill_of(&highlights, "A1"), Color::parse("#000000"));
+        assert_eq!(fill_of(&highlights, "A2"), Color::parse("#808080"));
+        assert_eq!(highlights[1].1.bar, Some((50, Color::new(0, 0, 255))));
+        assert!(test_sheet.display_style(1, 1).italic);
+        assert!(!test_sheet.display_style(2, 1).italic);
+
+        for text in [
+            "between -5 5 then bold:on,fill:#ffeb9c",
+            "scale #f8696b #63be7b",
+        ] {
+            assert_eq!(text.parse::<Rule>().unwrap().to_string(), text);
+        }
+        assert_eq!(
+            test_sheet.add_rule(range, "formula SLEEP(1) then bold".parse().unwrap()),
+            Err(Error::InvalidInput)
+        );
+        assert_eq!("gt x then bold".parse::<Rule>(), Err(Error::InvalidInput));
+    }
+}
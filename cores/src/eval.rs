@@ -0,0 +1,163 @@
+//! Evaluation control for recalculations.
+//!
+//! This module provides the knobs a front end can use to keep a recalculation
+//! from blocking it indefinitely:
+//! - A `CancelToken` that can be triggered from another thread
+//! - A deadline for the whole recalculation
+//! - A time budget for every single cell
+
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+
+/// Granularity used when a long running formula waits, so cancellation and
+/// deadlines are noticed quickly.
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// Shared flag used to cancel a running recalculation.
+///
+/// Cloning the token yields a handle to the same flag, so one clone can be
+/// handed to the evaluating thread while another one is kept to cancel it.
+#[derive(Clone, Debug, Default)]
+pub struct CancelToken(Arc<AtomicBool>);
+
+impl CancelToken {
+    /// Creates a token that is not cancelled.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Requests cancellation of every recalculation using this token.
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::SeqCst);
+    }
+
+    /// Returns `true` once `cancel` has been called.
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::SeqCst)
+    }
+}
+
+/// Limits applied while recalculating cells.
+///
+/// The default options never cancel and never time out.
+#[derive(Clone, Debug, Default)]
+pub struct EvalOptions {
+    /// Token checked between cells and while a formula is waiting
+    pub cancel: CancelToken,
+    /// Point in time after which no formula may keep running
+    pub deadline: Option<Instant>,
+    /// Maximum time a single cell may take to evaluate
+    pub cell_budget: Option<Duration>,
+    /// Number of threads used for large recalculations; `None` uses every core
+    pub threads: Option<usize>,
+}
+
+/// Reason a wait was interrupted.
+#[derive(Debug, PartialEq)]
+pub(crate) enum Interrupt {
+    /// The cancel token was triggered
+    Cancelled,
+    /// The deadline or the cell budget was exceeded
+    TimedOut,
+}
+
+impl EvalOptions {
+    /// Creates options whose deadline is `timeout` from now.
+    ///
+    /// # Parameters
+    /// * `timeout` - Time the whole recalculation may take
+    pub fn with_timeout(timeout: Duration) -> Self {
+        Self {
+            deadline: Some(Instant::now() + timeout),
+            ..Self::default()
+        }
+    }
+
+    /// Returns the point in time at which a cell started at `start` runs out of time.
+    pub(crate) fn cell_limit(&self, start: Instant) -> Option<Instant> {
+        let budget_end = self.cell_budget.map(|budget| start + budget);
+        match (budget_end, self.deadline) {
+            (Some(a), Some(b)) => Some(a.min(b)),
+            (a, b) => a.or(b),
+        }
+    }
+
+    /// Waits for `duration` while honouring cancellation and the given limit.
+    ///
+    /// # Parameters
+    /// * `duration` - How long to wait
+    /// * `limit` - Point in time at which the wait counts as timed out
+    pub(crate) fn wait(&self, duration: Duration, limit: Option<Instant>) -> Result<(), Interrupt> {
+        let end = Instant::now() + duration;
+        loop {
+            if self.cancel.is_cancelled() {
+                return Err(Interrupt::Cancelled);
+            }
+            let now = Instant::now();
+            if now >= end {
+                return Ok(());
+            }
+            if limit.is_some_and(|limit| now >= limit) {
+                return Err(Interrupt::TimedOut);
+            }
+            let mut step = (end - now).min(POLL_INTERVAL);
+            if let Some(limit) = limit {
+                step = step.min(limit - now);
+            }
+            std::thread::sleep(step);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cancel_token_is_shared() {
+        let token = CancelToken::new();
+        let clone = token.clone();
+        assert!(!token.is_cancelled());
+        clone.cancel();
+        assert!(token.is_cancelled());
+    }
+
+    #[test]
+    fn test_cell_limit() {
+        let start = Instant::now();
+        assert_eq!(EvalOptions::default().cell_limit(start), None);
+
+        let options = EvalOptions {
+            cell_budget: Some(Duration::from_secs(1)),
+            deadline: Some(start + Duration::from_secs(5)),
+            ..EvalOptions::default()
+        };
+        assert_eq!(
+            options.cell_limit(start),
+            Some(start + Duration::from_secs(1))
+        );
+    }
+
+    #[test]
+    fn test_wait_times_out() {
+        let options = EvalOptions::default();
+        let start = Instant::now();
+        let limit = Some(start + Duration::from_millis(20));
+        assert_eq!(
+            options.wait(Duration::from_secs(5), limit),
+            Err(Interrupt::TimedOut)
+        );
+        assert!(start.elapsed() < Duration::from_secs(1));
+    }
+
+    #[test]
+    fn test_wait_cancelled() {
+        let options = EvalOptions::default();
+        options.cancel.cancel();
+        assert_eq!(
+            options.wait(Duration::from_secs(5), None),
+            Err(Interrupt::Cancelled)
+        );
+    }
+}
@@ -0,0 +1,417 @@
+//! References to other sheets of a workbook.
+//!
+//! A formula of a workbook sheet may read another sheet, as in `Sheet2!A1` or
+//! `'Q3 Data'!B2:B9`. Such a reference is stored as `Param::External`, naming
+//! the other sheet by its id, which stays the same when sheets are renamed or
+//! moved. The sheet keeps the current value of every cell and range function
+//! it reads from other sheets, which the workbook copies in, and recalculates
+//! the cells reading them when they change. Which cells of other sheets read
+//! a cell is recorded by the workbook in `Links`.
+//!
+//! Formula text is translated on its way in and out of the sheet: `Sheet2!A1`
+//! is parsed as `1!A1` if `Sheet2` has the id 1, and shown as `Sheet2!A1`
+//! again. A reference to a sheet that was deleted is shown as `#REF!`.
+
+use crate::addr::{CellAddr, RangeAddr};
+use crate::eval::EvalOptions;
+use crate::parse::{CommandCall, Param, unparse_command};
+use crate::range_index::RangeIndex;
+use crate::sheet::{Error, Sheet};
+use fxhash::{FxHashMap, FxHashSet};
+use regex::{Captures, Regex};
+use std::borrow::Cow;
+use std::sync::LazyLock;
+
+/// A reference to another sheet: a quoted or plain sheet name, `!` and a cell or range.
+static SHEET_REF: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(r"(?:'([^']+)'|([A-Za-z_][A-Za-z0-9_.]*))!([A-Z]+[0-9]+)(?::([A-Z]+[0-9]+))?")
+        .expect("valid regex")
+});
+
+/// A reference to another sheet by its id, as stored: the id, `!` and a cell
+/// or range whose end repeats the id.
+static ID_REF: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(r"([0-9]+)!([A-Z]+[0-9]+)(?::[0-9]+!([A-Z]+[0-9]+))?").expect("valid regex")
+});
+
+/// A sheet id where formula text may only name sheets.
+static TYPED_ID: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"(?:^|[^A-Za-z0-9_.'])[0-9]+!").expect("valid regex"));
+
+/// A value a sheet reads from another sheet of the workbook.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub(crate) enum Import {
+    /// A cell, by the id of its sheet
+    Cell(u32, CellAddr),
+    /// A range function over a range, by the id of its sheet, with the type
+    /// and the operation code of the function
+    Range(u32, RangeAddr, u8, u8),
+}
+
+impl Import {
+    /// Id of the sheet the value is read from.
+    pub(crate) fn id(self) -> u32 {
+        match self {
+            Self::Cell(id, _) | Self::Range(id, ..) => id,
+        }
+    }
+}
+
+/// The sheets of the workbook a sheet belongs to, and what it reads from them.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct Imports {
+    /// Id of the sheet itself
+    pub(crate) id: u32,
+    /// Ids and names of every sheet of the workbook, the sheet itself included
+    pub(crate) book: Vec<(u32, String)>,
+    /// Current value of everything the formulas of the sheet read from other sheets
+    pub(crate) values: FxHashMap<Import, Result<i32, Error>>,
+    /// Cells that may have changed since the workbook last carried the
+    /// changes to the sheets reading them
+    pub(crate) changed: Vec<CellAddr>,
+}
+
+/// Returns what a formula reads from other sheets.
+pub(crate) fn imports_of(formula: &CommandCall) -> Vec<Import> {
+    if formula.flag.error() == 3 {
+        return vec![];
+    }
+    if let Some((id, range)) = formula.external_range() {
+        let function = (formula.flag.type_(), formula.flag.cmd());
+        return vec![Import::Range(id, range, function.0, function.1)];
+    }
+    [formula.param1, formula.param2]
+        .into_iter()
+        .filter_map(|param| match param {
+            Param::External(id, addr) => Some(Import::Cell(id, addr)),
+            _ => None,
+        })
+        .collect()
+}
+
+/// The cells of each sheet of a workbook read by other sheets.
+#[derive(Clone, Default)]
+pub(crate) struct Links {
+    /// Cells of other sheets reading a cell, by the id of its sheet and the cell
+    cells: FxHashMap<(u32, CellAddr), Vec<(u32, CellAddr)>>,
+    /// Ranges read by range functions, by the id of the sheet read and the id
+    /// of the sheet reading them
+    ranges: FxHashMap<(u32, u32), RangeIndex>,
+    /// Number of references to each sheet, by its id
+    counts: FxHashMap<u32, usize>,
+}
+
+impl Links {
+    /// Records what the formula of a cell of the sheet with id `reader` reads
+    /// from other sheets.
+    pub(crate) fn insert(&mut self, reader: u32, addr: CellAddr, formula: &CommandCall) {
+        for import in imports_of(formula) {
+            match import {
+                Import::Cell(id, source) => {
+                    let readers = self.cells.entry((id, source)).or_default();
+                    if readers.contains(&(reader, addr)) {
+                        continue;
+                    }
+                    readers.push((reader, addr));
+                }
+                Import::Range(id, range, ..) => {
+                    let index = self.ranges.entry((id, reader)).or_default();
+                    if !index.insert(range, addr) {
+                        continue;
+                    }
+                }
+            }
+            *self.counts.entry(import.id()).or_default() += 1;
+        }
+    }
+
+    /// Removes the record of what the formula of a cell reads, as made by `insert`.
+    pub(crate) fn remove(&mut self, reader: u32, addr: CellAddr, formula: &CommandCall) {
+        for import in imports_of(formula) {
+            match import {
+                Import::Cell(id, source) => {
+                    let Some(readers) = self.cells.get_mut(&(id, source)) else {
+                        continue;
+                    };
+                    let Some(at) = readers.iter().position(|&other| other == (reader, addr)) else {
+                        continue;
+                    };
+                    readers.swap_remove(at);
+                    if readers.is_empty() {
+                        self.cells.remove(&(id, source));
+                    }
+                }
+                Import::Range(id, range, ..) => {
+                    let Some(index) = self.ranges.get_mut(&(id, reader)) else {
+                        continue;
+                    };
+                    if !index.remove(range, addr) {
+                        continue;
+                    }
+                    if index.is_empty() {
+                        self.ranges.remove(&(id, reader));
+                    }
+                }
+            }
+            if let Some(count) = self.counts.get_mut(&import.id()) {
+                *count -= 1;
+                if *count == 0 {
+                    self.counts.remove(&import.id());
+                }
+            }
+        }
+    }
+
+    /// Returns `true` if a formula of another sheet reads the sheet with the given id.
+    pub(crate) fn is_read(&self, id: u32) -> bool {
+        self.counts.contains_key(&id)
+    }
+
+    /// Iterates over the cells of other sheets reading a cell of the sheet
+    /// with the given id, by the id of their sheet.
+    pub(crate) fn readers_of(
+        &self,
+        id: u32,
+        addr: CellAddr,
+    ) -> impl Iterator<Item = (u32, CellAddr)> + '_ {
+        let ranges = self
+            .ranges
+            .iter()
+            .filter(move |((source, _), _)| *source == id)
+            .flat_map(move |((_, reader), index)| {
+                index.dependents_of(addr).map(move |cell| (*reader, cell))
+            });
+        self.cells
+            .get(&(id, addr))
+            .into_iter()
+            .flatten()
+            .copied()
+            .chain(ranges)
+    }
+}
+
+/// Writes a sheet name the way a formula refers to it, quoted unless it is a
+/// plain word such as `Sheet2`.
+pub fn quote_sheet_name(name: &str) -> String {
+    let plain = name.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_')
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '.');
+    if plain {
+        name.to_string()
+    } else {
+        format!("'{}'", name)
+    }
+}
+
+impl Sheet {
+    /// Returns the name of the other sheet of the workbook with the given id,
+    /// or `None` if there is no such sheet.
+    pub(crate) fn sheet_name(&self, id: u32) -> Option<&str> {
+        self.imports
+            .book
+            .iter()
+            .find(|(other, _)| *other == id && id != self.imports.id)
+            .map(|(_, name)| name.as_str())
+    }
+
+    /// Replaces the references to other sheets in formula text by references
+    /// naming the sheets by their ids.
+    ///
+    /// References to the sheet itself become plain references.
+    ///
+    /// # Returns
+    /// `None` if the text names a sheet that is not in the workbook, or a
+    /// sheet by its id.
+    pub(crate) fn internalize<'a>(&self, text: &'a str) -> Option<Cow<'a, str>> {
+        if !text.contains('!') {
+            return Some(Cow::Borrowed(text));
+        }
+        if TYPED_ID.is_match(text) {
+            return None;
+        }
+        let mut known = true;
+        let replaced = SHEET_REF.replace_all(text, |caps: &Captures| {
+            let name = caps.get(1).or(caps.get(2)).map_or("", |name| name.as_str());
+            let id = self
+                .imports
+                .book
+                .iter()
+                .find(|(_, other)| other.eq_ignore_ascii_case(name))
+                .map(|(id, _)| *id);
+            let mut cells = vec![&caps[3]];
+            cells.extend(caps.get(4).map(|end| end.as_str()));
+            let names: Option<Vec<String>> = cells
+                .into_iter()
+                .map(|cell| {
+                    let id = id?;
+                    let addr = CellAddr::parse(cell)?;
+                    if id == self.imports.id {
+                        return Some(addr.to_string());
+                    }
+                    Some(Param::External(id, addr).to_string())
+                })
+                .collect();
+            match names {
+                Some(names) => names.join(":"),
+                None => {
+                    known = false;
+                    String::new()
+                }
+            }
+        });
+        known.then_some(replaced)
+    }
+
+    /// Writes out a formula the way it is typed, naming the other sheets it
+    /// reads, so it can be set on a cell again.
+    pub(crate) fn formula_text(&self, formula: &CommandCall) -> String {
+        self.externalize(unparse_command(formula))
+    }
+
+    /// Replaces the references naming other sheets by their ids in formula
+    /// text by references naming them.
+    pub(crate) fn externalize(&self, text: String) -> String {
+        if !text.contains('!') {
+            return text;
+        }
+        ID_REF
+            .replace_all(&text, |caps: &Captures| {
+                let name = caps[1].parse().ok().and_then(|id| self.sheet_name(id));
+                let Some(name) = name else {
+                    return "#REF!".to_string();
+                };
+                let mut external = format!("{}!{}", quote_sheet_name(name), &caps[2]);
+                if let Some(end) = caps.get(3) {
+                    external.push_str(&format!(":{}", end.as_str()));
+                }
+                external
+            })
+            .into_owned()
+    }
+
+    /// Returns the current value of something read from another sheet.
+    ///
+    /// # Returns
+    /// `Error::Ref` if the sheet was deleted.
+    pub(crate) fn imported(&self, import: Import) -> Result<i32, Error> {
+        if self.sheet_name(import.id()).is_none() {
+            return Err(Error::Ref);
+        }
+        self.imports.values.get(&import).copied().unwrap_or(Ok(0))
+    }
+
+    /// Returns every cell whose formula reads another sheet.
+    pub(crate) fn importing_cells(&self) -> Vec<CellAddr> {
+        self.grid
+            .iter()
+            .filter(|(_, cell)| cell.formula.flag.is_any() != 0)
+            .map(|(addr, _)| addr)
+            .filter(|&addr| !imports_of(&self.formula_of(addr)).is_empty())
+            .collect()
+    }
+
+    /// Stores the current values of things read from other sheets and
+    /// recalculates the given cells reading those that changed, and the cells
+    /// depending on them.
+    ///
+    /// # Parameters
+    /// * `values` - What was read, with its value
+    /// * `readers` - Cells reading any of the values, sorted
+    pub(crate) fn set_imported(
+        &mut self,
+        values: Vec<(Import, Result<i32, Error>)>,
+        readers: &[CellAddr],
+    ) {
+        let mut changed: FxHashSet<Import> = FxHashSet::default();
+        for (import, value) in values {
+            if self.imports.values.insert(import, value) != Some(value) {
+                changed.insert(import);
+            }
+        }
+        if changed.is_empty() {
+            return;
+        }
+        let targets: Vec<CellAddr> = readers
+            .iter()
+            .copied()
+            .filter(|&addr| {
+                imports_of(&self.formula_of(addr))
+                    .iter()
+                    .any(|import| changed.contains(import))
+            })
+            .collect();
+        if targets.is_empty() {
+            return;
+        }
+        self.notify(|sheet| {
+            // Loops through other sheets are rejected before they are made
+            if let Ok(plan) = sheet.plan_recalc(&targets) {
+                let _ = sheet.recalculate(plan, &EvalOptions::default());
+            }
+        });
+    }
+
+    /// Keeps only the values read from other sheets that the formulas still read.
+    pub(crate) fn retain_imported(&mut self, read: &FxHashSet<Import>) {
+        self.imports
+            .values
+            .retain(|import, _| read.contains(import));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn book_sheet() -> Sheet {
+        let mut test_sheet = Sheet::new(10, 10);
+        test_sheet.imports = Imports {
+            id: 0,
+            book: vec![(0, "Sheet1".to_string()), (1, "Q3 Data".to_string())],
+            ..Imports::default()
+        };
+        test_sheet
+    }
+
+    #[test]
+    fn test_references_are_translated() {
+        let test_sheet = book_sheet();
+        let internal = test_sheet.internalize("SUM('q3 data'!B2:B9)").unwrap();
+        assert_eq!(internal, "SUM(1!B2:1!B9)");
+        assert_eq!(
+            test_sheet.externalize(internal.into_owned()),
+            "SUM('Q3 Data'!B2:B9)"
+        );
+        assert_eq!(test_sheet.internalize("Sheet1!A1+1").unwrap(), "A1+1");
+        assert!(test_sheet.internalize("Sheet3!A1").is_none());
+        // Sheets are only named by their ids inside the sheet
+        assert!(test_sheet.internalize("1!A1").is_none());
+        assert_eq!(test_sheet.internalize("A1*2").unwrap(), "A1*2");
+        assert_eq!(test_sheet.externalize("7!A1".to_string()), "#REF!");
+    }
+
+    #[test]
+    fn test_imported_values_feed_formulas() {
+        let mut test_sheet = book_sheet();
+        assert_eq!(
+            test_sheet
+                .update_cell_data(1, 1, String::from("SUM('Q3 Data'!A1:A3)"))
+                .error,
+            crate::sheet::Error::None
+        );
+        assert_eq!(test_sheet.get_formula(1, 1), "SUM('Q3 Data'!A1:A3)");
+        test_sheet.update_cell_data(2, 1, String::from("'Q3 Data'!J10*2"));
+        assert_eq!(test_sheet.importing_cells().len(), 2);
+
+        let total = Import::Range(1, RangeAddr::parse("A1:A3").unwrap(), 2, 2);
+        let cell = Import::Cell(1, CellAddr::parse("J10").unwrap());
+        let readers = test_sheet.importing_cells();
+        test_sheet.set_imported(vec![(total, Ok(12)), (cell, Ok(4))], &readers);
+        assert_eq!(test_sheet.get_value(1, 1), 12);
+        assert_eq!(test_sheet.get_value(2, 1), 8);
+        test_sheet.set_imported(vec![(cell, Err(Error::DivByZero))], &readers);
+        assert_eq!(test_sheet.get_error(2, 1), Some(Error::DivByZero));
+        assert_eq!(test_sheet.get_value(1, 1), 12);
+    }
+}
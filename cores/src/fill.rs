@@ -0,0 +1,401 @@
+//! Filling ranges from the cells at their start.
+//!
+//! The cells written at the start of each column (or row) of the range are the
+//! seed. When the seed holds two or more literal values forming a series, the
+//! series is continued: linear steps (1, 3, 5), growth by a constant factor
+//! (2, 6, 18) and dates written as `YYYYMMDD` stepping by days or months.
+//! Otherwise the seed is repeated, with every formula reference adjusted
+//! relative to the cell it is copied into.
+
+use crate::addr::{CellAddr, RangeAddr};
+use crate::parse::{CommandCall, Param};
+use crate::sheet::{Error, Sheet};
+
+/// Direction in which a range is filled.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FillDirection {
+    /// Each column is filled downwards from its top cells
+    Down,
+    /// Each row is filled to the right from its leftmost cells
+    Right,
+}
+
+/// A series of literal values detected in the seed of a fill.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Series {
+    /// Each value adds `step` to the one before
+    Linear { first: i64, step: i64 },
+    /// Each value multiplies the one before by `ratio`
+    Growth { first: i64, ratio: i64 },
+    /// Dates `step` days apart, starting at day number `first`
+    Days { first: i64, step: i64 },
+    /// Dates `step` months apart, keeping the day of the month of the first date
+    Months { first: Date, step: i64 },
+}
+
+/// A calendar date, written in cells as the number `YYYYMMDD`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct Date {
+    year: i64,
+    month: i64,
+    day: i64,
+}
+
+impl Date {
+    /// Reads a cell value as a date, if it is a valid `YYYYMMDD` number.
+    fn from_value(value: i32) -> Option<Self> {
+        let value = value as i64;
+        let date = Self {
+            year: value / 10000,
+            month: value / 100 % 100,
+            day: value % 100,
+        };
+        let valid = (1000..=9999).contains(&date.year)
+            && (1..=12).contains(&date.month)
+            && (1..=days_in_month(date.year, date.month)).contains(&date.day);
+        valid.then_some(date)
+    }
+
+    /// Returns the date as the number `YYYYMMDD`, if its year has four digits.
+    fn value(self) -> Option<i32> {
+        if !(1000..=9999).contains(&self.year) {
+            return None;
+        }
+        i32::try_from(self.year * 10000 + self.month * 100 + self.day).ok()
+    }
+
+    /// Number of days since 1970-01-01.
+    fn days(self) -> i64 {
+        let year = if self.month <= 2 {
+            self.year - 1
+        } else {
+            self.year
+        };
+        let era = year.div_euclid(400);
+        let year_of_era = year - era * 400;
+        let day_of_year = (153 * ((self.month + 9) % 12) + 2) / 5 + self.day - 1;
+        let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+        era * 146097 + day_of_era - 719468
+    }
+
+    /// The date `days` days after 1970-01-01.
+    fn from_days(days: i64) -> Self {
+        let days = days + 719468;
+        let era = days.div_euclid(146097);
+        let day_of_era = days - era * 146097;
+        let year_of_era =
+            (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+        let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+        let shifted_month = (5 * day_of_year + 2) / 153;
+        let month = if shifted_month < 10 {
+            shifted_month + 3
+        } else {
+            shifted_month - 9
+        };
+        Self {
+            year: year_of_era + era * 400 + i64::from(month <= 2),
+            month,
+            day: day_of_year - (153 * shifted_month + 2) / 5 + 1,
+        }
+    }
+
+    /// The date `months` months later, with the day clamped to the length of the month.
+    fn add_months(self, months: i64) -> Self {
+        let total = self.year * 12 + self.month - 1 + months;
+        let (year, month) = (total.div_euclid(12), total.rem_euclid(12) + 1);
+        Self {
+            year,
+            month,
+            day: self.day.min(days_in_month(year, month)),
+        }
+    }
+}
+
+/// Returns the step between consecutive values if it is always the same.
+fn constant_step(mut steps: impl Iterator<Item = i64>) -> Option<i64> {
+    let first = steps.next()?;
+    steps.all(|step| step == first).then_some(first)
+}
+
+/// Number of days in a month of the Gregorian calendar.
+fn days_in_month(year: i64, month: i64) -> i64 {
+    match month {
+        2 if year % 4 == 0 && (year % 100 != 0 || year % 400 == 0) => 29,
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        _ => 31,
+    }
+}
+
+impl Series {
+    /// Finds the series continued by the given values.
+    ///
+    /// Two values always form a linear series unless both are dates; growth
+    /// needs at least three values to be told apart from a linear step.
+    fn detect(values: &[i32]) -> Option<Self> {
+        if values.len() < 2 {
+            return None;
+        }
+        let values: Vec<i64> = values.iter().map(|&value| value as i64).collect();
+        let dates: Option<Vec<Date>> = values
+            .iter()
+            .map(|&value| Date::from_value(value as i32))
+            .collect();
+        if let Some(dates) = dates {
+            let first = dates[0];
+            let months = (dates[1].year - first.year) * 12 + dates[1].month - first.month;
+            if months != 0
+                && dates
+                    .iter()
+                    .enumerate()
+                    .all(|(i, &date)| first.add_months(months * i as i64) == date)
+            {
+                return Some(Series::Months {
+                    first,
+                    step: months,
+                });
+            }
+            let days = constant_step(dates.windows(2).map(|w| w[1].days() - w[0].days()));
+            if let Some(step) = days.filter(|&step| step != 0) {
+                return Some(Series::Days {
+                    first: first.days(),
+                    step,
+                });
+            }
+        }
+
+        if let Some(step) = constant_step(values.windows(2).map(|w| w[1] - w[0])) {
+            return Some(Series::Linear {
+                first: values[0],
+                step,
+            });
+        }
+        if values.len() >= 3 && values[0] != 0 && values[1] % values[0] == 0 {
+            let ratio = values[1] / values[0];
+            if values
+                .windows(2)
+                .all(|w| w[0].checked_mul(ratio) == Some(w[1]))
+            {
+                return Some(Series::Growth {
+                    first: values[0],
+                    ratio,
+                });
+            }
+        }
+        None
+    }
+
+    /// Returns the value at position `n` of the series, counting from 0.
+    ///
+    /// # Returns
+    /// `None` if the value does not fit in an i32 or is not a valid date.
+    fn nth(self, n: usize) -> Option<i32> {
+        let n = i64::try_from(n).ok()?;
+        match self {
+            Series::Linear { first, step } => {
+                i32::try_from(first.checked_add(step.checked_mul(n)?)?).ok()
+            }
+            Series::Growth { first, ratio } => {
+                let factor = ratio.checked_pow(u32::try_from(n).ok()?)?;
+                i32::try_from(first.checked_mul(factor)?).ok()
+            }
+            Series::Days { first, step } => {
+                Date::from_days(first.checked_add(step.checked_mul(n)?)?).value()
+            }
+            Series::Months { first, step } => first.add_months(step.checked_mul(n)?).value(),
+        }
+    }
+}
+
+/// Returns the literal value of a formula, if it is a plain number.
+fn literal(formula: &CommandCall) -> Option<i32> {
+    match formula.param1 {
+        Param::Value(value) if formula.flag.type_() == 0 && formula.flag.error() == 0 => {
+            Some(value)
+        }
+        _ => None,
+    }
+}
+
+impl Sheet {
+    /// Fills a range from the cells written at its start.
+    ///
+    /// Each column (for `Down`) or row (for `Right`) of the range is filled
+    /// separately. Its seed is the run of written cells at its start, or only
+    /// its first cell if every cell is written. A seed of literal values that
+    /// forms a series is continued; any other seed is repeated with formula
+    /// references adjusted relative to each filled cell. References that
+    /// would leave the sheet become `#REF!`, and series values that do not fit
+    /// in a cell are left out.
+    ///
+    /// The fill is applied as one batch and undone as one step.
+    ///
+    /// # Parameters
+    /// * `range` - The range to fill, including the seed
+    /// * `direction` - Whether columns are filled down or rows to the right
+    ///
+    /// # Returns
+    /// `Error::InvalidInput` if the range does not lie inside the sheet, or
+    /// `Error::CycleDetected` if the filled formulas would read themselves; the
+    /// sheet is then unchanged.
+    pub fn fill(&mut self, range: RangeAddr, direction: FillDirection) -> Result<(), Error> {
+        if range.start.row == 0
+            || range.start.col == 0
+            || range.end.row as usize > self.row
+            || range.end.col as usize > self.col
+        {
+            return Err(Error::InvalidInput);
+        }
+        let lines: Vec<Vec<CellAddr>> = match direction {
+            FillDirection::Down => (range.start.col..=range.end.col)
+                .map(|col| {
+                    (range.start.row..=range.end.row)
+                        .map(|row| CellAddr::new(row, col))
+                        .collect()
+                })
+                .collect(),
+            FillDirection::Right => (range.start.row..=range.end.row)
+                .map(|row| {
+                    (range.start.col..=range.end.col)
+                        .map(|col| CellAddr::new(row, col))
+                        .collect()
+                })
+                .collect(),
+        };
+
+        let mut batch = self.begin_batch();
+        for line in lines {
+            let written = line
+                .iter()
+                .take_while(|addr| {
+                    self.grid
+                        .get(addr.row as usize, addr.col as usize)
+                        .is_some_and(|cell| cell.formula.flag.is_any() != 0)
+                })
+                .count();
+            let seed_len = match written {
+                0 => continue,
+                _ if written == line.len() => 1,
+                _ => written,
+            };
+            let seed: Vec<CommandCall> = line[..seed_len]
+                .iter()
+                .map(|&addr| self.formula_of(addr))
+                .collect();
+            let values: Option<Vec<i32>> = seed.iter().map(literal).collect();
+            let series = values.as_deref().and_then(Series::detect);
+
+            for (i, &addr) in line.iter().enumerate().skip(seed_len) {
+                let (row, col) = addr.index();
+                if let Some(series) = series {
+                    if let Some(value) = series.nth(i) {
+                        batch.set(row, col, value.to_string());
+                    }
+                    continue;
+                }
+                let source = line[i % seed_len];
+                let moved = seed[i % seed_len]
+                    .shifted(
+                        addr.row as i64 - source.row as i64,
+                        addr.col as i64 - source.col as i64,
+                    )
+                    .filter(|formula| {
+                        // The corners of a range are its params, so checking
+                        // the params checks every cell it reads.
+                        [formula.param1, formula.param2]
+                            .into_iter()
+                            .all(|param| match param {
+                                Param::Cell(reference) | Param::External(_, reference) => {
+                                    reference.row as usize <= self.row
+                                        && reference.col as usize <= self.col
+                                }
+                                _ => true,
+                            })
+                    });
+                let text = match moved {
+                    Some(formula) => self.formula_text(&formula),
+                    None => self.formula_text(&CommandCall::ref_error()),
+                };
+                batch.set(row, col, text);
+            }
+        }
+
+        let result = self.commit(batch);
+        if result.error != Error::None {
+            return Err(result.error);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn range(text: &str) -> RangeAddr {
+        RangeAddr::parse(text).unwrap()
+    }
+
+    #[test]
+    fn test_detect_series() {
+        let continued = |values: &[i32], n: usize| Series::detect(values).and_then(|s| s.nth(n));
+        assert_eq!(continued(&[1, 3], 2), Some(5));
+        assert_eq!(continued(&[10, 7, 4], 5), Some(-5));
+        assert_eq!(continued(&[2, 6, 18], 3), Some(54));
+        assert_eq!(continued(&[2, 6], 2), Some(10));
+        assert_eq!(continued(&[1, 2, 4, 7], 4), None);
+        assert_eq!(continued(&[5], 1), None);
+        assert_eq!(continued(&[i32::MAX - 1, i32::MAX], 2), None);
+
+        // Dates step over month ends and keep the day of the month
+        assert_eq!(continued(&[20240130, 20240131], 2), Some(20240201));
+        assert_eq!(continued(&[20231231, 20240107], 2), Some(20240114));
+        assert_eq!(continued(&[20240131, 20240229], 2), Some(20240331));
+        assert_eq!(continued(&[20240131, 20240229], 3), Some(20240430));
+        assert_eq!(continued(&[20200229, 20210228], 4), Some(20240229));
+    }
+
+    #[test]
+    fn test_fill_down_formulas() {
+        let mut test_sheet = Sheet::new(10, 10);
+        test_sheet.update_cell_data(1, 1, String::from("1"));
+        test_sheet.update_cell_data(1, 2, String::from("A1*2"));
+        test_sheet
+            .fill(range("B1:B10"), FillDirection::Down)
+            .unwrap();
+        assert_eq!(test_sheet.get_formula(10, 2), "A10*2");
+        test_sheet
+            .fill(range("A1:A10"), FillDirection::Down)
+            .unwrap();
+        assert_eq!(test_sheet.get_formula(10, 1), "1");
+        assert_eq!(test_sheet.get_value(10, 2), 2);
+
+        // Shared blocks are formed by the fill, and the fill is one undo step
+        assert_eq!(test_sheet.shared_formula_count(), 1);
+        assert!(test_sheet.undo());
+        assert_eq!(test_sheet.get_formula(10, 1), "0");
+        assert_eq!(test_sheet.get_value(10, 2), 0);
+    }
+
+    #[test]
+    fn test_fill_right_series_and_pattern() {
+        let mut test_sheet = Sheet::new(10, 10);
+        test_sheet.transaction(|batch| {
+            batch.set(1, 1, "1");
+            batch.set(1, 2, "3");
+            batch.set(2, 1, "J1");
+            batch.set(3, 1, "4");
+            batch.set(3, 2, "A3+1");
+        });
+        test_sheet
+            .fill(range("A1:J3"), FillDirection::Right)
+            .unwrap();
+        assert_eq!(test_sheet.get_value(1, 10), 19);
+        // The reference of row 2 would leave the sheet
+        assert_eq!(test_sheet.get_formula(2, 2), "#REF!");
+        // A seed mixing values and formulas is repeated
+        assert_eq!(test_sheet.get_formula(3, 3), "4");
+        assert_eq!(test_sheet.get_formula(3, 4), "C3+1");
+        assert_eq!(test_sheet.get_value(3, 10), 5);
+    }
+}
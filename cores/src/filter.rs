@@ -0,0 +1,340 @@
+//! AutoFilter views.
+//!
+//! A filter is attached to a header row. Each column of the header may hold
+//! a criterion, and the data rows below the header that fail any criterion
+//! are hidden. Hiding a row never changes its cells; it only affects what the
+//! front ends show and what `SUBTOTAL` reads. Like in other spreadsheets the
+//! hidden rows are worked out when a criterion changes, not on every edit, so
+//! an edited row does not disappear under the cursor. The data ends at the
+//! first empty row below the header.
+
+use crate::addr::{CellAddr, RangeAddr};
+use crate::eval::EvalOptions;
+use crate::sheet::{Error, Sheet, reading};
+use fxhash::FxHashSet;
+use std::collections::BTreeMap;
+
+/// A condition the value of a filtered column has to meet for its row to be shown.
+///
+/// Cells holding an error value never meet a condition.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Criterion {
+    /// The value equals the given number
+    Equals(i32),
+    /// The value is larger than the given number
+    GreaterThan(i32),
+    /// The value is among the `n` largest values of the column; ties are all shown
+    TopN(usize),
+    /// The value, written out in decimal, contains the given text
+    Contains(String),
+}
+
+/// A filter attached to a header row.
+#[derive(Clone, Debug)]
+pub struct AutoFilter {
+    /// The header cells; the data rows lie below them
+    pub(crate) header: RangeAddr,
+    /// Criteria by column
+    pub(crate) criteria: BTreeMap<u32, Criterion>,
+    /// Rows hidden by the criteria
+    pub(crate) hidden: FxHashSet<u32>,
+}
+
+impl AutoFilter {
+    /// The header cells of the filter.
+    pub fn header(&self) -> RangeAddr {
+        self.header
+    }
+
+    /// Returns the criterion of a column, if it has one.
+    pub fn criterion(&self, col: u32) -> Option<&Criterion> {
+        self.criteria.get(&col)
+    }
+
+    /// Number of hidden rows.
+    pub fn hidden_count(&self) -> usize {
+        self.hidden.len()
+    }
+}
+
+impl Sheet {
+    /// Attaches a filter to a header row, replacing the current one.
+    ///
+    /// The new filter has no criteria, so every row is shown.
+    ///
+    /// # Parameters
+    /// * `header` - The header cells, a single row above the data
+    ///
+    /// # Returns
+    /// `Error::InvalidInput` if the header spans several rows or does not lie
+    /// inside the sheet.
+    pub fn set_auto_filter(&mut self, header: RangeAddr) -> Result<(), Error> {
+        if header.start.row != header.end.row
+            || header.start.row == 0
+            || header.start.col == 0
+            || header.end.row as usize > self.row
+            || header.end.col as usize > self.col
+        {
+            return Err(Error::InvalidInput);
+        }
+        self.remove_auto_filter();
+        self.filter = Some(AutoFilter {
+            header,
+            criteria: BTreeMap::new(),
+            hidden: FxHashSet::default(),
+        });
+        Ok(())
+    }
+
+    /// Removes the filter, showing every row again.
+    pub fn remove_auto_filter(&mut self) {
+        if self
+            .filter
+            .take()
+            .is_some_and(|filter| !filter.hidden.is_empty())
+        {
+            self.recalculate_subtotals();
+        }
+    }
+
+    /// Returns the filter of the sheet, if any.
+    pub fn auto_filter(&self) -> Option<&AutoFilter> {
+        self.filter.as_ref()
+    }
+
+    /// Sets or clears the criterion of one column and filters the rows again.
+    ///
+    /// # Parameters
+    /// * `col` - Column of the header (1-based, `A` = 1)
+    /// * `criterion` - The new criterion, or `None` to show every value of the column
+    ///
+    /// # Returns
+    /// `Error::InvalidInput` if the sheet has no filter or the column is not
+    /// part of its header.
+    pub fn set_filter_criterion(
+        &mut self,
+        col: u32,
+        criterion: Option<Criterion>,
+    ) -> Result<(), Error> {
+        let Some(filter) = &mut self.filter else {
+            return Err(Error::InvalidInput);
+        };
+        if !(filter.header.start.col..=filter.header.end.col).contains(&col) {
+            return Err(Error::InvalidInput);
+        }
+        match criterion {
+            Some(criterion) => filter.criteria.insert(col, criterion),
+            None => filter.criteria.remove(&col),
+        };
+        self.reapply_filter();
+        Ok(())
+    }
+
+    /// Works out the hidden rows again from the current values.
+    pub fn reapply_filter(&mut self) {
+        let Some(filter) = &self.filter else {
+            return;
+        };
+        let header = filter.header;
+        // The data reaches down to the first empty row below the header
+        let written: FxHashSet<u32> = self
+            .grid
+            .iter()
+            .filter(|(addr, cell)| {
+                (header.start.col..=header.end.col).contains(&addr.col)
+                    && cell.formula.flag.is_any() != 0
+            })
+            .map(|(addr, _)| addr.row)
+            .collect();
+        let mut last = header.start.row;
+        while written.contains(&(last + 1)) {
+            last += 1;
+        }
+        let rows = header.start.row + 1..=last;
+
+        let mut hidden = FxHashSet::default();
+        for (&col, criterion) in &filter.criteria {
+            let value = |row| reading(&self.grid[CellAddr::new(row, col)]);
+            // Smallest value still among the top n
+            let threshold = match *criterion {
+                Criterion::TopN(n) => {
+                    let mut values: Vec<i32> = rows.clone().filter_map(value).collect();
+                    values.sort_unstable_by(|a, b| b.cmp(a));
+                    n.checked_sub(1)
+                        .and_then(|i| values.get(i).or(values.last()).copied())
+                }
+                _ => None,
+            };
+            for row in rows.clone() {
+                let shown = value(row).is_some_and(|value| match criterion {
+                    Criterion::Equals(expected) => value == *expected,
+                    Criterion::GreaterThan(bound) => value > *bound,
+                    Criterion::TopN(_) => threshold.is_some_and(|threshold| value >= threshold),
+                    Criterion::Contains(text) => value.to_string().contains(text.as_str()),
+                });
+                if !shown {
+                    hidden.insert(row);
+                }
+            }
+        }
+
+        let filter = self.filter.as_mut().expect("checked above");
+        if filter.hidden != hidden {
+            filter.hidden = hidden;
+            self.recalculate_subtotals();
+        }
+    }
+
+    /// Returns `true` if a row is hidden by the filter.
+    pub fn is_row_hidden(&self, row: usize) -> bool {
+        self.filter
+            .as_ref()
+            .is_some_and(|filter| filter.hidden.contains(&(row as u32)))
+    }
+
+    /// Returns the first row at or after `row` that is not hidden, if any.
+    pub fn next_visible_row(&self, row: usize) -> Option<usize> {
+        (row.max(1)..=self.row).find(|&row| !self.is_row_hidden(row))
+    }
+
+    /// Recalculates every `SUBTOTAL` formula and the cells reading them, after
+    /// the hidden rows changed.
+    fn recalculate_subtotals(&mut self) {
+        let mut subtotals: Vec<CellAddr> = self
+            .grid
+            .iter()
+            .filter(|(_, cell)| cell.formula.flag.type_() == 3)
+            .map(|(addr, _)| addr)
+            .collect();
+        subtotals.sort_unstable();
+        if let Ok(plan) = self.plan_recalc(&subtotals) {
+            let _ = self.notify(|sheet| sheet.recalculate(plan, &EvalOptions::default()));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn range(text: &str) -> RangeAddr {
+        RangeAddr::parse(text).unwrap()
+    }
+
+    fn visible(test_sheet: &Sheet) -> Vec<usize> {
+        (2..=7)
+            .filter(|&row| !test_sheet.is_row_hidden(row))
+            .collect()
+    }
+
+    fn filtered_sheet() -> Sheet {
+        let mut test_sheet = Sheet::new(10, 10);
+        test_sheet.transaction(|batch| {
+            for (row, (group, value)) in [(1, 40), (2, 15), (1, 7), (3, 120), (2, 15), (1, 9)]
+                .into_iter()
+                .enumerate()
+            {
+                batch.set(row + 2, 1, group.to_string());
+                batch.set(row + 2, 2, value.to_string());
+            }
+            batch.set(9, 2, "SUBTOTAL(109,B2:B7)");
+            batch.set(10, 2, "B9*2");
+        });
+        test_sheet.set_auto_filter(range("A1:B1")).unwrap();
+        test_sheet
+    }
+
+    #[test]
+    fn test_criteria_hide_rows() {
+        let mut test_sheet = filtered_sheet();
+        assert_eq!(visible(&test_sheet), [2, 3, 4, 5, 6, 7]);
+
+        test_sheet
+            .set_filter_criterion(1, Some(Criterion::Equals(1)))
+            .unwrap();
+        assert_eq!(visible(&test_sheet), [2, 4, 7]);
+        test_sheet
+            .set_filter_criterion(2, Some(Criterion::GreaterThan(8)))
+            .unwrap();
+        assert_eq!(visible(&test_sheet), [2, 7]);
+
+        test_sheet.set_filter_criterion(1, None).unwrap();
+        test_sheet
+            .set_filter_criterion(2, Some(Criterion::TopN(2)))
+            .unwrap();
+        assert_eq!(visible(&test_sheet), [2, 5]);
+        // Ties with the last of the top values are all shown
+        test_sheet
+            .set_filter_criterion(2, Some(Criterion::TopN(3)))
+            .unwrap();
+        assert_eq!(visible(&test_sheet), [2, 3, 5, 6]);
+        test_sheet
+            .set_filter_criterion(2, Some(Criterion::Contains(String::from("5"))))
+            .unwrap();
+        assert_eq!(visible(&test_sheet), [3, 6]);
+        assert_eq!(test_sheet.next_visible_row(4), Some(6));
+
+        // The data is untouched
+        assert_eq!(test_sheet.get_value(2, 2), 40);
+        assert_eq!(
+            test_sheet.set_filter_criterion(3, Some(Criterion::Equals(1))),
+            Err(Error::InvalidInput)
+        );
+    }
+
+    #[test]
+    fn test_subtotal_ignores_hidden_rows() {
+        let mut test_sheet = filtered_sheet();
+        assert_eq!(test_sheet.get_value(9, 2), 206);
+
+        test_sheet
+            .set_filter_criterion(1, Some(Criterion::Equals(2)))
+            .unwrap();
+        assert_eq!(test_sheet.get_value(9, 2), 30);
+        assert_eq!(test_sheet.get_value(10, 2), 60);
+        // The plain codes skip the rows hidden by the filter too
+        test_sheet.update_cell_data(9, 3, String::from("SUBTOTAL(9,B2:B7)"));
+        test_sheet.update_cell_data(10, 3, String::from("SUBTOTAL(4,B2:B7)"));
+        assert_eq!(test_sheet.get_value(9, 3), 30);
+        assert_eq!(test_sheet.get_value(10, 3), 15);
+
+        // Editing a shown row updates the subtotal; hidden rows stay out of it
+        test_sheet.update_cell_data(3, 2, String::from("20"));
+        assert_eq!(test_sheet.get_value(9, 2), 35);
+        assert_eq!(test_sheet.get_value(9, 3), 35);
+        test_sheet.update_cell_data(2, 2, String::from("1000"));
+        assert_eq!(test_sheet.get_value(9, 2), 35);
+
+        test_sheet.remove_auto_filter();
+        assert_eq!(test_sheet.get_value(9, 2), 1171);
+        assert_eq!(test_sheet.get_value(10, 2), 2342);
+    }
+
+    #[test]
+    fn test_loading_removes_the_filter() {
+        let ss_path = std::env::temp_dir().join("cores_filter_load.ss");
+        let csv_path = std::env::temp_dir().join("cores_filter_load.csv");
+        let source = filtered_sheet();
+        source.write_file(ss_path.to_str().unwrap()).unwrap();
+        source.write_csv_file(csv_path.to_str().unwrap()).unwrap();
+
+        let mut test_sheet = filtered_sheet();
+        test_sheet
+            .set_filter_criterion(1, Some(Criterion::Equals(2)))
+            .unwrap();
+        test_sheet.read_file(ss_path.to_str().unwrap()).unwrap();
+        assert!(test_sheet.auto_filter().is_none());
+        assert_eq!(visible(&test_sheet), [2, 3, 4, 5, 6, 7]);
+        assert_eq!(test_sheet.get_value(9, 2), 206);
+
+        test_sheet.set_auto_filter(range("A1:B1")).unwrap();
+        test_sheet
+            .set_filter_criterion(1, Some(Criterion::Equals(2)))
+            .unwrap();
+        test_sheet
+            .read_csv_file(csv_path.to_str().unwrap())
+            .unwrap();
+        assert!(test_sheet.auto_filter().is_none());
+        assert_eq!(visible(&test_sheet), [2, 3, 4, 5, 6, 7]);
+    }
+}
@@ -0,0 +1,557 @@
+//! Undo and redo history of a sheet.
+//!
+//! Every change to the formulas of a sheet is recorded as a step holding the
+//! formula of each changed cell before and after the change. Undoing a step
+//! applies the earlier formulas again as one batch, and redoing it applies the
+//! later ones. Compound operations, such as copying a row, are grouped into a
+//! single step.
+//!
+//! Inserted and deleted rows and columns are recorded as the structural change
+//! itself. Undoing one applies the inverse change and then puts back the
+//! formulas it destroyed, along with the formatting, rules, validations,
+//! notes and filter the sheet had before it. Moving a block of cells is
+//! recorded along with its edits, so the sheets of a workbook reading the
+//! block follow it back and forth.
+//!
+//! Formatting changes and changes to the conditional formatting rules, the
+//! validations or the notes and comments are recorded as the formatting,
+//! rules, validations or notes of the sheet before and after them.
+
+use crate::addr::{CellAddr, RangeAddr};
+use crate::batch::Batch;
+use crate::comments::Annotations;
+use crate::conditional::ConditionalRule;
+use crate::filter::AutoFilter;
+use crate::sheet::Sheet;
+use crate::structure::{Relocation, StructureOp};
+use crate::style::Format;
+use crate::validation::RangeValidation;
+use std::collections::{BTreeMap, VecDeque};
+
+/// Number of steps kept by a new sheet.
+pub const DEFAULT_HISTORY_DEPTH: usize = 100;
+
+/// The formula of one cell before and after a change.
+#[derive(Clone, Debug)]
+struct Edit {
+    addr: CellAddr,
+    before: String,
+    after: String,
+}
+
+/// One recorded change.
+#[derive(Clone, Debug)]
+enum Change {
+    /// The formula of one cell changed
+    Edit(Edit),
+    /// Rows or columns were inserted or deleted
+    Structure {
+        op: StructureOp,
+        /// Formulas destroyed by the change, by their address before it
+        restore: Vec<(CellAddr, String)>,
+        /// Everything else about the cells, as it was before the change
+        layout: Box<Layout>,
+    },
+    /// A block of cells was moved
+    Move(Relocation),
+    /// The formatting of the sheet changed
+    Formats {
+        before: Vec<(RangeAddr, Format)>,
+        after: Vec<(RangeAddr, Format)>,
+    },
+    /// The conditional formatting rules of the sheet changed
+    Rules {
+        before: Vec<ConditionalRule>,
+        after: Vec<ConditionalRule>,
+    },
+    /// The validations of the sheet changed
+    Validations {
+        before: Vec<RangeValidation>,
+        after: Vec<RangeValidation>,
+    },
+    /// The notes and comments of the sheet changed
+    Notes {
+        before: BTreeMap<CellAddr, Annotations>,
+        after: BTreeMap<CellAddr, Annotations>,
+    },
+}
+
+/// What a sheet keeps about its cells besides their formulas.
+#[derive(Clone, Debug)]
+pub(crate) struct Layout {
+    formats: Vec<(RangeAddr, Format)>,
+    rules: Vec<ConditionalRule>,
+    validations: Vec<RangeValidation>,
+    notes: BTreeMap<CellAddr, Annotations>,
+    filter: Option<AutoFilter>,
+}
+
+/// Everything one user action changed.
+#[derive(Clone, Debug, Default)]
+struct Step {
+    changes: Vec<Change>,
+}
+
+/// Recorded steps of a sheet, oldest first.
+#[derive(Clone, Debug)]
+pub struct History {
+    /// Steps that can be undone, the most recent at the back
+    undo: VecDeque<Step>,
+    /// Steps that were undone and can be redone, the most recent at the back
+    redo: Vec<Step>,
+    /// Largest number of steps kept; 0 disables recording
+    depth: usize,
+    /// Number of groups currently open
+    open_groups: usize,
+    /// Edits recorded since the outermost group was opened
+    pending: Step,
+    /// Set while a step is undone or redone, so that is not recorded again
+    replaying: bool,
+}
+
+impl History {
+    /// Creates an empty history keeping up to `depth` steps.
+    pub fn new(depth: usize) -> Self {
+        Self {
+            undo: VecDeque::new(),
+            redo: Vec::new(),
+            depth,
+            open_groups: 0,
+            pending: Step::default(),
+            replaying: false,
+        }
+    }
+
+    /// Closes the pending step and makes it the most recent one.
+    fn finish_step(&mut self) {
+        if self.pending.changes.is_empty() {
+            return;
+        }
+        self.undo.push_back(std::mem::take(&mut self.pending));
+        while self.undo.len() > self.depth {
+            self.undo.pop_front();
+        }
+        self.redo.clear();
+    }
+}
+
+impl Default for History {
+    fn default() -> Self {
+        Self::new(DEFAULT_HISTORY_DEPTH)
+    }
+}
+
+impl Sheet {
+    /// Returns `true` if changes are currently being recorded.
+    pub(crate) fn is_recording(&self) -> bool {
+        self.history.depth > 0 && !self.history.replaying
+    }
+
+    /// Records that the formula of a cell changed.
+    ///
+    /// Outside of a group the change becomes a step of its own.
+    pub(crate) fn record(&mut self, addr: CellAddr, before: String, after: String) {
+        if !self.is_recording() || before == after {
+            return;
+        }
+        self.push_change(Change::Edit(Edit {
+            addr,
+            before,
+            after,
+        }));
+    }
+
+    /// Takes a copy of everything the sheet keeps about its cells besides
+    /// their formulas.
+    pub(crate) fn layout(&self) -> Layout {
+        Layout {
+            formats: self.formats.ranges.clone(),
+            rules: self.rules.clone(),
+            validations: self.validations.clone(),
+            notes: self.notes.cells.clone(),
+            filter: self.filter.clone(),
+        }
+    }
+
+    fn restore_layout(&mut self, layout: &Layout) {
+        self.formats.ranges = layout.formats.clone();
+        self.rules = layout.rules.clone();
+        self.validations = layout.validations.clone();
+        self.notes.cells = layout.notes.clone();
+        self.filter = layout.filter.clone();
+    }
+
+    /// Records that rows or columns were inserted or deleted.
+    ///
+    /// # Parameters
+    /// * `op` - The applied change
+    /// * `restore` - Formulas the change destroyed, by their address before it
+    /// * `layout` - The layout of the sheet before the change
+    pub(crate) fn record_structure(
+        &mut self,
+        op: StructureOp,
+        restore: Vec<(CellAddr, String)>,
+        layout: Layout,
+    ) {
+        if self.is_recording() {
+            self.push_change(Change::Structure {
+                op,
+                restore,
+                layout: Box::new(layout),
+            });
+        }
+    }
+
+    /// Runs `operation` without recording what it changes.
+    pub(crate) fn unrecorded<R>(&mut self, operation: impl FnOnce(&mut Self) -> R) -> R {
+        let replaying = std::mem::replace(&mut self.history.replaying, true);
+        let result = operation(self);
+        self.history.replaying = replaying;
+        result
+    }
+
+    /// Records that a block of cells was moved, for the sheets of the
+    /// workbook reading it.
+    pub(crate) fn record_move(&mut self, relocation: Relocation) {
+        if self.is_recording() {
+            self.push_change(Change::Move(relocation));
+        }
+    }
+
+    /// Records that the formatting of the sheet changed.
+    ///
+    /// # Parameters
+    /// * `before` - The formatting before the change
+    pub(crate) fn record_formats(&mut self, before: Vec<(RangeAddr, Format)>) {
+        if self.is_recording() && before != self.formats.ranges {
+            let after = self.formats.ranges.clone();
+            self.push_change(Change::Formats { before, after });
+        }
+    }
+
+    /// Records that the conditional formatting rules of the sheet changed.
+    ///
+    /// # Parameters
+    /// * `before` - The rules before the change
+    pub(crate) fn record_rules(&mut self, before: Vec<ConditionalRule>) {
+        if self.is_recording() {
+            let after = self.rules.clone();
+            self.push_change(Change::Rules { before, after });
+        }
+    }
+
+    /// Records that the validations of the sheet changed.
+    ///
+    /// # Parameters
+    /// * `before` - The validations before the change
+    pub(crate) fn record_validations(&mut self, before: Vec<RangeValidation>) {
+        if self.is_recording() {
+            let after = self.validations.clone();
+            self.push_change(Change::Validations { before, after });
+        }
+    }
+
+    /// Records that the notes and comments of the sheet changed.
+    ///
+    /// # Parameters
+    /// * `before` - The notes and comments before the change
+    pub(crate) fn record_notes(&mut self, before: BTreeMap<CellAddr, Annotations>) {
+        if self.is_recording() && before != self.notes.cells {
+            let after = self.notes.cells.clone();
+            self.push_change(Change::Notes { before, after });
+        }
+    }
+
+    fn push_change(&mut self, change: Change) {
+        self.history.pending.changes.push(change);
+        if self.history.open_groups == 0 {
+            self.history.finish_step();
+        }
+    }
+
+    /// Starts grouping changes into one step, until the matching `end_group`.
+    ///
+    /// Groups may be nested; the step ends with the outermost group.
+    pub fn begin_group(&mut self) {
+        self.history.open_groups += 1;
+    }
+
+    /// Ends a group started by `begin_group`.
+    pub fn end_group(&mut self) {
+        self.history.open_groups = self.history.open_groups.saturating_sub(1);
+        if self.history.open_groups == 0 {
+            self.history.finish_step();
+        }
+    }
+
+    /// Runs a compound operation so it is undone and redone as one step.
+    pub fn group<R>(&mut self, operation: impl FnOnce(&mut Self) -> R) -> R {
+        self.notify(|sheet| {
+            sheet.begin_group();
+            let result = operation(sheet);
+            sheet.end_group();
+            result
+        })
+    }
+
+    /// Reverts the most recent step.
+    ///
+    /// # Returns
+    /// `false` if there was nothing to undo.
+    pub fn undo(&mut self) -> bool {
+        let Some(step) = self.history.undo.pop_back() else {
+            return false;
+        };
+        self.begin_changes();
+        self.history.replaying = true;
+        let mut batch = Batch::unchecked();
+        for change in step.changes.iter().rev() {
+            match change {
+                Change::Edit(edit) => {
+                    let (row, col) = edit.addr.index();
+                    batch.set(row, col, edit.before.as_str());
+                }
+                Change::Structure {
+                    op,
+                    restore,
+                    layout,
+                } => {
+                    self.commit(std::mem::replace(&mut batch, Batch::unchecked()));
+                    let _ = self.apply_structure(op.inverse());
+                    self.restore_layout(layout);
+                    for (addr, formula) in restore {
+                        let (row, col) = addr.index();
+                        batch.set(row, col, formula.as_str());
+                    }
+                }
+                Change::Move(relocation) => self.relocated(relocation.inverse()),
+                Change::Formats { before, .. } => self.formats.ranges = before.clone(),
+                Change::Rules { before, .. } => self.rules = before.clone(),
+                Change::Validations { before, .. } => self.validations = before.clone(),
+                Change::Notes { before, .. } => self.notes.cells = before.clone(),
+            }
+        }
+        self.commit(batch);
+        self.history.replaying = false;
+        self.history.redo.push(step);
+        self.end_changes();
+        true
+    }
+
+    /// Applies the most recently undone step again.
+    ///
+    /// # Returns
+    /// `false` if there was nothing to redo.
+    pub fn redo(&mut self) -> bool {
+        let Some(step) = self.history.redo.pop() else {
+            return false;
+        };
+        self.begin_changes();
+        self.history.replaying = true;
+        let mut batch = Batch::unchecked();
+        for change in &step.changes {
+            match change {
+                Change::Edit(edit) => {
+                    let (row, col) = edit.addr.index();
+                    batch.set(row, col, edit.after.as_str());
+                }
+                Change::Structure { op, .. } => {
+                    self.commit(std::mem::replace(&mut batch, Batch::unchecked()));
+                    let _ = self.apply_structure(*op);
+                }
+                Change::Move(relocation) => self.relocated(*relocation),
+                Change::Formats { after, .. } => self.formats.ranges = after.clone(),
+                Change::Rules { after, .. } => self.rules = after.clone(),
+                Change::Validations { after, .. } => self.validations = after.clone(),
+                Change::Notes { after, .. } => self.notes.cells = after.clone(),
+            }
+        }
+        self.commit(batch);
+        self.history.replaying = false;
+        self.history.undo.push_back(step);
+        self.end_changes();
+        true
+    }
+
+    /// Returns `true` if there is a step to undo.
+    pub fn can_undo(&self) -> bool {
+        !self.history.undo.is_empty()
+    }
+
+    /// Returns `true` if there is a step to redo.
+    pub fn can_redo(&self) -> bool {
+        !self.history.redo.is_empty()
+    }
+
+    /// Sets how many steps are kept, dropping the oldest ones beyond it.
+    ///
+    /// A depth of 0 turns recording off.
+    pub fn set_history_depth(&mut self, depth: usize) {
+        self.history.depth = depth;
+        while self.history.undo.len() > depth {
+            self.history.undo.pop_front();
+        }
+        self.history.redo.truncate(depth);
+    }
+
+    /// Forgets every recorded step.
+    pub fn clear_history(&mut self) {
+        let depth = self.history.depth;
+        self.history = History::new(depth);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sheet::Error;
+
+    #[test]
+    fn test_undo_redo_edits() {
+        let mut test_sheet = Sheet::new(10, 10);
+        test_sheet.update_cell_data(1, 1, String::from("5"));
+        test_sheet.update_cell_data(2, 1, String::from("A1*2"));
+        test_sheet.update_cell_data(1, 1, String::from("7"));
+        assert_eq!(test_sheet.get_value(2, 1), 14);
+
+        assert!(test_sheet.undo());
+        assert_eq!(test_sheet.get_value(2, 1), 10);
+        assert!(test_sheet.undo());
+        assert_eq!(test_sheet.get_formula(2, 1), "0");
+        assert_eq!(test_sheet.get_value(2, 1), 0);
+        assert!(test_sheet.redo());
+        assert_eq!(test_sheet.get_value(2, 1), 10);
+
+        // A new edit drops what could be redone
+        test_sheet.update_cell_data(3, 1, String::from("A2+1"));
+        assert!(!test_sheet.can_redo());
+        assert!(!test_sheet.redo());
+        assert_eq!(test_sheet.get_value(3, 1), 11);
+    }
+
+    #[test]
+    fn test_failed_edits_are_not_recorded() {
+        let mut test_sheet = Sheet::new(10, 10);
+        test_sheet.update_cell_data(1, 1, String::from("B1"));
+        test_sheet.update_cell_data(1, 2, String::from("A1"));
+        test_sheet.update_cell_data(1, 3, String::from("A1+"));
+        assert!(test_sheet.undo());
+        assert!(!test_sheet.can_undo());
+        assert_eq!(test_sheet.get_formula(1, 1), "0");
+    }
+
+    #[test]
+    fn test_grouped_operations() {
+        let mut test_sheet = Sheet::new(10, 10);
+        test_sheet.transaction(|batch| {
+            batch.set(1, 1, "1");
+            batch.set(1, 2, "A1+1");
+            batch.set(1, 3, "B1+1");
+        });
+        test_sheet.copy_row(1, 2).unwrap();
+        assert_eq!(test_sheet.get_value(2, 3), 3);
+        test_sheet.clear_row(1);
+
+        assert!(test_sheet.undo());
+        assert_eq!(test_sheet.get_value(1, 3), 3);
+        assert!(test_sheet.undo());
+        assert_eq!(test_sheet.get_formula(2, 2), "0");
+        assert!(test_sheet.undo());
+        assert_eq!(test_sheet.get_value(1, 3), 0);
+        assert!(!test_sheet.can_undo());
+
+        test_sheet.group(|sheet| {
+            sheet.update_cell_data(5, 5, String::from("4"));
+            sheet.update_cell_data(5, 6, String::from("E5*E5"));
+        });
+        assert!(test_sheet.undo());
+        assert_eq!(test_sheet.get_formula(5, 5), "0");
+        assert_eq!(test_sheet.get_formula(5, 6), "0");
+    }
+
+    #[test]
+    fn test_history_depth() {
+        let mut test_sheet = Sheet::new(10, 10);
+        test_sheet.set_history_depth(2);
+        for value in 1..=5 {
+            test_sheet.update_cell_data(1, 1, value.to_string());
+        }
+        assert!(test_sheet.undo());
+        assert!(test_sheet.undo());
+        assert!(!test_sheet.undo());
+        assert_eq!(test_sheet.get_value(1, 1), 3);
+
+        test_sheet.set_history_depth(0);
+        test_sheet.update_cell_data(1, 1, String::from("9"));
+        assert!(!test_sheet.can_undo());
+    }
+
+    #[test]
+    fn test_undo_redo_formats() {
+        let mut test_sheet = Sheet::new(10, 10);
+        let range = RangeAddr::parse("A1:B2").unwrap();
+        test_sheet.update_cell_data(1, 1, String::from("5"));
+        test_sheet.set_format(range, &[Format::Bold(true)]).unwrap();
+        test_sheet
+            .set_format(range, &Format::parse_list("percent:0").unwrap())
+            .unwrap();
+        // Setting what is already there is no step
+        test_sheet.set_format(range, &[Format::Bold(true)]).unwrap();
+        assert_eq!(test_sheet.formatted_value(1, 1), "500%");
+
+        assert!(test_sheet.undo());
+        assert_eq!(test_sheet.formatted_value(1, 1), "5");
+        assert!(test_sheet.style(1, 1).bold);
+        assert!(test_sheet.undo());
+        assert!(!test_sheet.style(1, 1).bold);
+        assert_eq!(test_sheet.get_value(1, 1), 5);
+        assert!(test_sheet.redo());
+        assert!(test_sheet.style(2, 2).bold);
+    }
+
+    #[test]
+    fn test_undo_redo_rules() {
+        let mut test_sheet = Sheet::new(10, 10);
+        let range = RangeAddr::parse("A1:A5").unwrap();
+        test_sheet
+            .add_rule(range, "gt 3 then bold".parse().unwrap())
+            .unwrap();
+        test_sheet
+            .add_rule(range, "bar #638ec6".parse().unwrap())
+            .unwrap();
+        test_sheet.remove_rule(0).unwrap();
+        assert_eq!(test_sheet.rules().len(), 1);
+
+        assert!(test_sheet.undo());
+        assert_eq!(test_sheet.rules().len(), 2);
+        assert!(test_sheet.undo());
+        assert!(test_sheet.undo());
+        assert!(test_sheet.rules().is_empty());
+        assert!(test_sheet.redo());
+        assert_eq!(test_sheet.rules()[0].1.to_string(), "gt 3 then bold:on");
+    }
+
+    #[test]
+    fn test_undo_redo_validations() {
+        let mut test_sheet = Sheet::new(10, 10);
+        let range = RangeAddr::parse("A1:A5").unwrap();
+        test_sheet
+            .add_validation(range, "whole 1 10".parse().unwrap())
+            .unwrap();
+        test_sheet
+            .add_validation(range, "list 1,2".parse().unwrap())
+            .unwrap();
+        test_sheet.remove_validation(1).unwrap();
+
+        assert!(test_sheet.undo());
+        assert_eq!(test_sheet.validations().len(), 2);
+        assert!(test_sheet.undo());
+        assert!(test_sheet.undo());
+        assert!(test_sheet.validations().is_empty());
+        assert!(test_sheet.redo());
+        assert_eq!(
+            test_sheet.update_cell_data(1, 1, String::from("20")).error,
+            Error::ValidationFailed
+        );
+    }
+}
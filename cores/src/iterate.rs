@@ -0,0 +1,328 @@
+//! Iterative calculation of circular references.
+//!
+//! Normally an edit that closes a loop of references is rejected. With
+//! iterative calculation switched on, the cells affected by an edit are split
+//! into strongly connected components, which are recalculated in dependency
+//! order. A component of one cell that does not read itself is evaluated once;
+//! a loop is evaluated round after round until no value in it changes by more
+//! than the epsilon, or the iteration limit is reached. The cells of a loop
+//! that were still changing in the last round are marked as diverged.
+
+use crate::addr::CellAddr;
+use crate::eval::EvalOptions;
+use crate::sheet::{Error, Sheet, reading};
+use fxhash::{FxHashMap, FxHashSet};
+
+/// Settings of iterative calculation.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Iteration {
+    /// Largest number of rounds a loop is evaluated for
+    pub max_iterations: u32,
+    /// Largest change of a value between two rounds that still counts as settled
+    pub epsilon: i32,
+}
+
+impl Default for Iteration {
+    fn default() -> Self {
+        Self {
+            max_iterations: 100,
+            epsilon: 0,
+        }
+    }
+}
+
+/// Cells to recalculate, in evaluation order.
+pub(crate) enum Recalc {
+    /// No loop is reachable; the cells in dependency order
+    Ordered(Vec<CellAddr>),
+    /// Loops are reachable; the strongly connected components in dependency order
+    Components(Vec<Vec<CellAddr>>),
+}
+
+impl Recalc {
+    /// The cells of the plan in evaluation order.
+    pub(crate) fn cells(&self) -> impl Iterator<Item = CellAddr> + '_ {
+        let (order, components): (&[CellAddr], &[Vec<CellAddr>]) = match self {
+            Recalc::Ordered(order) => (order, &[]),
+            Recalc::Components(components) => (&[], components),
+        };
+        order.iter().chain(components.iter().flatten()).copied()
+    }
+}
+
+/// State of Tarjan's algorithm over the dependents of some cells.
+#[derive(Default)]
+struct Tarjan {
+    index: FxHashMap<CellAddr, usize>,
+    low: FxHashMap<CellAddr, usize>,
+    stack: Vec<CellAddr>,
+    on_stack: FxHashSet<CellAddr>,
+    /// Finished components; each one only feeds components finished before it
+    components: Vec<Vec<CellAddr>>,
+}
+
+impl Sheet {
+    /// Switches iterative calculation on with the given settings, or off with `None`.
+    ///
+    /// Switching it off keeps the loops already in the sheet, but any later edit
+    /// reaching one of them is rejected as a cycle.
+    pub fn set_iterative_calculation(&mut self, iteration: Option<Iteration>) {
+        self.iteration = iteration;
+    }
+
+    /// Returns the settings of iterative calculation, or `None` if it is off.
+    pub fn iterative_calculation(&self) -> Option<Iteration> {
+        self.iteration
+    }
+
+    /// Returns `true` if a cell is part of a loop that did not settle in its
+    /// last recalculation.
+    pub fn is_diverged(&self, row: usize, col: usize) -> bool {
+        self.diverged.contains(&CellAddr::from_index(row, col))
+    }
+
+    /// Plans the recalculation of the given cells and everything depending on them.
+    ///
+    /// # Returns
+    /// The plan, or the cells of a loop if one is reachable and iterative
+    /// calculation is off.
+    pub(crate) fn plan_recalc(&self, targets: &[CellAddr]) -> Result<Recalc, Vec<CellAddr>> {
+        match self.toposort_all(targets) {
+            Ok(order) => Ok(Recalc::Ordered(order)),
+            Err(_) if self.iteration.is_some() => Ok(Recalc::Components(self.components(targets))),
+            Err(cycle) => Err(cycle),
+        }
+    }
+
+    /// Recalculates the cells of a plan.
+    ///
+    /// Like `update_cell`, stops with `Err(Error::Cancelled)` when the cancel
+    /// token fires and leaves restoring the values to the caller.
+    ///
+    /// # Returns
+    /// The cells of loops that did not settle within the iteration limit.
+    pub(crate) fn recalculate(
+        &mut self,
+        plan: Recalc,
+        options: &EvalOptions,
+    ) -> Result<Vec<CellAddr>, Error> {
+        if !self.diverged.is_empty() {
+            for addr in plan.cells() {
+                self.diverged.remove(&addr);
+            }
+        }
+        let components = match plan {
+            Recalc::Ordered(order) => {
+                self.update_cell(order, options)?;
+                return Ok(vec![]);
+            }
+            Recalc::Components(components) => components,
+        };
+
+        let settings = self.iteration.unwrap_or_default();
+        let mut diverged = vec![];
+        // Cells outside loops are gathered so they keep being evaluated level by level
+        let mut single = vec![];
+        for component in components {
+            let first = component[0];
+            if component.len() == 1 && !self.dependents_of(first).any(|dep| dep == first) {
+                single.push(first);
+                continue;
+            }
+            self.update_cell(std::mem::take(&mut single), options)?;
+            diverged.extend(self.iterate(&component, settings, options)?);
+        }
+        self.update_cell(single, options)?;
+        self.diverged.extend(diverged.iter().copied());
+        Ok(diverged)
+    }
+
+    /// Evaluates the cells of a loop round after round until they settle.
+    ///
+    /// # Returns
+    /// The cells that still changed by more than the epsilon in the last round.
+    fn iterate(
+        &mut self,
+        component: &[CellAddr],
+        settings: Iteration,
+        options: &EvalOptions,
+    ) -> Result<Vec<CellAddr>, Error> {
+        let mut unsettled = vec![];
+        for _ in 0..settings.max_iterations.max(1) {
+            unsettled.clear();
+            for &addr in component {
+                let before = reading(&self.grid[addr]);
+                self.update_cell(vec![addr], options)?;
+                let after = reading(&self.grid[addr]);
+                let settled = match (before, after) {
+                    (Some(before), Some(after)) => {
+                        (after as i64 - before as i64).abs() <= settings.epsilon.max(0) as i64
+                    }
+                    (before, after) => before == after,
+                };
+                if !settled {
+                    unsettled.push(addr);
+                }
+            }
+            if unsettled.is_empty() {
+                break;
+            }
+        }
+        Ok(unsettled)
+    }
+
+    /// Splits the given cells and everything depending on them into strongly
+    /// connected components.
+    ///
+    /// # Returns
+    /// The components in dependency order; the cells of a component are in the
+    /// order the search reached them.
+    fn components(&self, targets: &[CellAddr]) -> Vec<Vec<CellAddr>> {
+        let mut state = Tarjan::default();
+        for &target in targets {
+            if !state.index.contains_key(&target) {
+                self.connect(target, &mut state);
+            }
+        }
+        state.components.reverse();
+        state.components
+    }
+
+    /// Runs the search from one cell, with an explicit stack of the cells whose
+    /// dependents are being visited, so long chains cannot overflow the call stack.
+    fn connect(&self, cell: CellAddr, state: &mut Tarjan) {
+        state.enter(cell);
+        let mut frames = vec![(cell, self.dependents_of(cell))];
+        while let Some((current, dependents)) = frames.last_mut() {
+            let current = *current;
+            if let Some(dep) = dependents.next() {
+                if !state.index.contains_key(&dep) {
+                    state.enter(dep);
+                    frames.push((dep, self.dependents_of(dep)));
+                } else if state.on_stack.contains(&dep) {
+                    state.lower(current, state.index[&dep]);
+                }
+                continue;
+            }
+            frames.pop();
+            if let Some((parent, _)) = frames.last() {
+                state.lower(*parent, state.low[&current]);
+            }
+            state.close(current);
+        }
+    }
+}
+
+impl Tarjan {
+    /// Numbers a cell reached for the first time and puts it on the stack.
+    fn enter(&mut self, cell: CellAddr) {
+        let index = self.index.len();
+        self.index.insert(cell, index);
+        self.low.insert(cell, index);
+        self.stack.push(cell);
+        self.on_stack.insert(cell);
+    }
+
+    fn lower(&mut self, cell: CellAddr, reached: usize) {
+        let low = self.low[&cell].min(reached);
+        self.low.insert(cell, low);
+    }
+
+    /// Pops the component of a cell whose dependents are all visited, if the
+    /// cell is its root.
+    fn close(&mut self, cell: CellAddr) {
+        if self.low[&cell] != self.index[&cell] {
+            return;
+        }
+        let mut component = vec![];
+        loop {
+            let member = self.stack.pop().expect("the cell is still on the stack");
+            self.on_stack.remove(&member);
+            component.push(member);
+            if member == cell {
+                break;
+            }
+        }
+        component.reverse();
+        self.components.push(component);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_loop_converges() {
+        let mut test_sheet = Sheet::new(10, 10);
+        test_sheet.update_cell_data(1, 1, String::from("100"));
+        test_sheet.update_cell_data(1, 2, String::from("A1+C1"));
+        let result = test_sheet.update_cell_data(1, 3, String::from("B1/10"));
+        assert_eq!(result.error, Error::CycleDetected);
+
+        test_sheet.set_iterative_calculation(Some(Iteration::default()));
+        test_sheet.update_cell_data(1, 4, String::from("C1*2"));
+        let result = test_sheet.update_cell_data(1, 3, String::from("B1/10"));
+        assert_eq!(result.error, Error::None);
+        assert!(result.diverged.is_empty());
+        assert_eq!(test_sheet.get_value(1, 2), 111);
+        assert_eq!(test_sheet.get_value(1, 3), 11);
+        assert_eq!(test_sheet.get_value(1, 4), 22);
+
+        // Edits reaching the loop settle it again
+        test_sheet.update_cell_data(1, 1, String::from("200"));
+        assert_eq!(test_sheet.get_value(1, 2), 222);
+        assert_eq!(test_sheet.get_value(1, 4), 44);
+        assert!(!test_sheet.is_diverged(1, 2));
+    }
+
+    #[test]
+    fn test_divergence_is_reported_per_cell() {
+        let mut test_sheet = Sheet::new(10, 10);
+        test_sheet.set_iterative_calculation(Some(Iteration {
+            max_iterations: 10,
+            epsilon: 0,
+        }));
+        test_sheet.update_cell_data(1, 1, String::from("B1+1"));
+        test_sheet.update_cell_data(1, 3, String::from("A1*0"));
+        let result = test_sheet.update_cell_data(1, 2, String::from("A1+C1"));
+        assert_eq!(result.error, Error::None);
+        let mut diverged = result.diverged.clone();
+        diverged.sort_unstable();
+        assert_eq!(diverged, [CellAddr::new(1, 1), CellAddr::new(1, 2)]);
+        assert!(test_sheet.is_diverged(1, 1));
+        assert!(!test_sheet.is_diverged(1, 3));
+        assert!(test_sheet.get_value(1, 1) >= 10);
+
+        // Breaking the loop clears the marks
+        test_sheet.update_cell_data(1, 1, String::from("5"));
+        assert!(!test_sheet.is_diverged(1, 1));
+        assert!(!test_sheet.is_diverged(1, 2));
+        assert_eq!(test_sheet.get_value(1, 2), 5);
+    }
+
+    #[test]
+    fn test_loading_clears_the_iteration_state() {
+        let path = std::env::temp_dir().join("cores_iteration_load.ss");
+        let mut source = Sheet::new(10, 10);
+        source.update_cell_data(1, 1, String::from("5"));
+        source.write_file(path.to_str().unwrap()).unwrap();
+
+        let mut test_sheet = Sheet::new(10, 10);
+        test_sheet.set_iterative_calculation(Some(Iteration {
+            max_iterations: 10,
+            epsilon: 0,
+        }));
+        test_sheet.update_cell_data(1, 1, String::from("B1+1"));
+        test_sheet.update_cell_data(1, 2, String::from("A1"));
+        assert!(test_sheet.is_diverged(1, 1));
+
+        test_sheet.read_file(path.to_str().unwrap()).unwrap();
+        assert!(!test_sheet.is_diverged(1, 1));
+        assert!(!test_sheet.is_diverged(1, 2));
+        assert_eq!(test_sheet.iterative_calculation(), None);
+        test_sheet.update_cell_data(1, 2, String::from("A1"));
+        let result = test_sheet.update_cell_data(1, 1, String::from("B1+1"));
+        assert_eq!(result.error, Error::CycleDetected);
+    }
+}
@@ -1,12 +1,52 @@
+pub mod addr;
+pub mod aggregate;
+pub mod batch;
+pub mod cell_store;
+pub mod comments;
+pub mod conditional;
+pub mod eval;
+pub mod external;
+pub mod fill;
+pub mod filter;
+pub mod history;
+pub mod iterate;
 pub mod make_graphs;
+pub mod moving;
+pub mod notify;
 pub mod parse;
+pub mod range_index;
 pub mod read_csv_file;
 pub mod read_ss;
+pub mod recalc;
+pub mod search;
+pub mod shared;
 pub mod sheet;
+pub mod sort;
+pub mod structure;
+pub mod style;
+pub mod trace;
+pub mod validation;
+pub mod workbook;
 pub mod write_csv_file;
 pub mod write_ss;
+pub use addr::{CellAddr, RangeAddr};
+pub use batch::{Batch, BatchResult};
+pub use cell_store::CellStore;
+pub use comments::{Annotations, Comment};
+pub use conditional::{Condition, Highlight, Rule};
+pub use eval::{CancelToken, EvalOptions};
+pub use fill::FillDirection;
+pub use filter::Criterion;
+pub use iterate::Iteration;
+pub use notify::ListenerId;
 pub use parse::convert_to_index;
+pub use search::{FindOptions, LookIn, ReplaceResult};
 pub use sheet::Sheet;
+pub use sort::SortKey;
+pub use style::{Align, Borders, Color, DateFormat, Format, NumberFormat, Style};
+pub use trace::Trace;
+pub use validation::{Alert, Check, Validation};
+pub use workbook::Workbook;
 // pub use sheet::SheetError;
 pub use sheet::CallResult;
 pub use sheet::Error;
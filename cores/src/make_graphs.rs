@@ -109,7 +109,7 @@ impl Sheet {
         let mut values = Vec::new();
         for i in start.0..=end.0 {
             for j in start.1..=end.1 {
-                values.push(self.grid[i][j].value);
+                values.push(self.grid[(i, j)].value);
             }
         }
         Ok(Chart::new()
@@ -152,7 +152,7 @@ impl Sheet {
         let mut values = Vec::new();
         for i in start.0..=end.0 {
             for j in start.1..=end.1 {
-                values.push(self.grid[i][j].value);
+                values.push(self.grid[(i, j)].value);
             }
         }
         Ok(Chart::new()
@@ -181,7 +181,7 @@ impl Sheet {
 
         if start.0 == end.0 {
             for i in start.1..=end.1 {
-                values.push(self.grid[start.0][i].value);
+                values.push(self.grid[(start.0, i)].value);
                 if x_labels.len() <= cnt {
                     x_labels.push(format!("{}", cnt + 1));
                 }
@@ -189,7 +189,7 @@ impl Sheet {
             }
         } else if start.1 == end.1 {
             for i in start.0..=end.0 {
-                values.push(self.grid[i][start.1].value);
+                values.push(self.grid[(i, start.1)].value);
                 if x_labels.len() <= cnt {
                     x_labels.push(format!("{}", cnt + 1));
                 }
@@ -255,8 +255,8 @@ impl Sheet {
         for i in 0..diff1 + 1 {
             for j in 0..diff2 + 1 {
                 let temp_vec: Vec<i32> = vec![
-                    self.grid[start1.0 + i as usize][start1.1 + j as usize].value,
-                    self.grid[start2.0 + i as usize][start2.1 + j as usize].value,
+                    self.grid[(start1.0 + i as usize, start1.1 + j as usize)].value,
+                    self.grid[(start2.0 + i as usize, start2.1 + j as usize)].value,
                 ];
                 values.push(temp_vec);
             }
@@ -0,0 +1,338 @@
+//! Moving blocks of cells.
+//!
+//! Moving a range is a cut and paste: the cells of the block are placed at the
+//! destination and every formula referring into the block is rewritten to
+//! follow it, so formulas inside the block keep their relative meaning.
+//! References to cells overwritten by the block become `#REF!`, like ranges
+//! lying entirely among them; ranges only partly overwritten stay. The move is
+//! applied as one batch, so it is atomic and undone as one step. Formatting,
+//! notes and comments go with the block, and the sheets of a workbook reading
+//! the block follow it on `Workbook::refresh`.
+
+use crate::addr::{CellAddr, RangeAddr};
+use crate::batch::Batch;
+use crate::parse::CommandCall;
+use crate::sheet::{Error, Sheet};
+use crate::structure::{Moving, Relocation};
+
+/// Where the cells of a moved block end up.
+struct Move {
+    /// The block being moved
+    src: RangeAddr,
+    /// The block after the move
+    dest: RangeAddr,
+    /// Rows the block moves by
+    rows: i64,
+    /// Columns the block moves by
+    cols: i64,
+}
+
+impl Move {
+    /// Returns the move of the block `src` by `rows` and `cols`, or `None` if
+    /// it would leave the sheet at the top or left.
+    fn new(src: RangeAddr, rows: i64, cols: i64) -> Option<Self> {
+        let dest = RangeAddr {
+            start: src.start.offset(rows, cols)?,
+            end: src.end.offset(rows, cols)?,
+        };
+        Some(Self {
+            src,
+            dest,
+            rows,
+            cols,
+        })
+    }
+
+    /// Returns where the cell at `addr` of the block ends up.
+    fn target(&self, addr: CellAddr) -> CellAddr {
+        addr.offset(self.rows, self.cols)
+            .expect("the destination block lies inside the sheet")
+    }
+
+    /// Rewrites the references of a formula for the move.
+    ///
+    /// # Parameters
+    /// * `formula` - The formula, expanded if it is shared
+    /// * `moving` - The sheet the block was moved on
+    ///
+    /// # Returns
+    /// `None` if the formula does not refer into either block and stays as it is.
+    fn rewrite(&self, formula: &CommandCall, moving: Moving) -> Option<CommandCall> {
+        if formula.flag.type_() >= 2 && formula.flag.cmd() != 5 {
+            // A range of another sheet than the moving one stays as it is
+            let (Some(start), Some(end)) =
+                (moving.local(formula.param1), moving.local(formula.param2))
+            else {
+                return None;
+            };
+            // Only ranges lying entirely inside the block follow it, and only
+            // those lying entirely inside the overwritten cells are lost
+            let within = |block: RangeAddr| block.contains(start) && block.contains(end);
+            if !within(self.src) {
+                return within(self.dest).then(CommandCall::ref_error);
+            }
+            let mut formula = formula.clone();
+            formula.param1 = moving.place(self.target(start));
+            formula.param2 = moving.place(self.target(end));
+            return Some(formula);
+        }
+
+        let mut moved = formula.clone();
+        let mut changed = false;
+        for param in [&mut moved.param1, &mut moved.param2] {
+            let Some(addr) = moving.local(*param) else {
+                continue;
+            };
+            if self.src.contains(addr) {
+                *param = moving.place(self.target(addr));
+                changed = true;
+            } else if self.dest.contains(addr) {
+                return Some(CommandCall::ref_error());
+            }
+        }
+        changed.then_some(moved)
+    }
+}
+
+impl Sheet {
+    /// Moves the cells of a range so its top-left corner lands on `dest`.
+    ///
+    /// Formulas referring into the range are rewritten to follow the moved
+    /// cells, and references to cells overwritten by the range, or to ranges of
+    /// them, become `#REF!`.
+    /// The source cells not covered by the destination are cleared.
+    ///
+    /// # Parameters
+    /// * `src` - The range to move
+    /// * `dest` - New position of the top-left cell of the range
+    ///
+    /// # Returns
+    /// `Error::InvalidInput` if either block does not lie inside the sheet, or
+    /// `Error::CycleDetected` if the moved formulas would read themselves. The
+    /// sheet is unchanged on error.
+    pub fn move_range(&mut self, src: RangeAddr, dest: CellAddr) -> Result<(), Error> {
+        let limit = CellAddr::from_index(self.row, self.col);
+        let inside = |addr: CellAddr| addr.row <= limit.row && addr.col <= limit.col;
+        let rows = dest.row as i64 - src.start.row as i64;
+        let cols = dest.col as i64 - src.start.col as i64;
+        let Some(mv) = Move::new(src, rows, cols) else {
+            return Err(Error::InvalidInput);
+        };
+        if src.start.row == 0 || src.start.col == 0 || !inside(src.end) || !inside(mv.dest.end) {
+            return Err(Error::InvalidInput);
+        }
+        if rows == 0 && cols == 0 {
+            return Ok(());
+        }
+
+        let mut written: Vec<CellAddr> = self
+            .grid
+            .iter()
+            .filter(|(_, cell)| cell.formula.flag.is_any() != 0)
+            .map(|(addr, _)| addr)
+            .collect();
+        written.sort_unstable();
+        let is_written = |sheet: &Sheet, addr: CellAddr| {
+            sheet
+                .grid
+                .get(addr.row as usize, addr.col as usize)
+                .is_some_and(|cell| cell.formula.flag.is_any() != 0)
+        };
+
+        // Clear the vacated cells and the overwritten cells no moved cell lands on
+        let mut batch = Batch::unchecked();
+        let mut cleared = Vec::new();
+        for &addr in &written {
+            let vacated = mv.src.contains(addr) && !mv.dest.contains(addr);
+            let overwritten = mv.dest.contains(addr)
+                && addr
+                    .offset(-rows, -cols)
+                    .is_none_or(|from| !is_written(self, from));
+            if vacated || overwritten {
+                batch.clear(addr.row as usize, addr.col as usize);
+                cleared.push(addr);
+            }
+        }
+        // Place the moved cells, then follow them from the rest of the sheet
+        for &addr in &written {
+            let formula = self.formula_of(addr);
+            let rewritten = mv.rewrite(&formula, Moving::Own);
+            if mv.src.contains(addr) {
+                let target = mv.target(addr);
+                let text = self.formula_text(rewritten.as_ref().unwrap_or(&formula));
+                batch.set(target.row as usize, target.col as usize, text);
+            } else if !mv.dest.contains(addr)
+                && let Some(rewritten) = rewritten
+            {
+                batch.set(
+                    addr.row as usize,
+                    addr.col as usize,
+                    self.formula_text(&rewritten),
+                );
+            }
+        }
+
+        self.group(|sheet| {
+            let result = sheet.commit(batch);
+            if result.error != Error::None {
+                return Err(result.error);
+            }
+            for addr in cleared {
+                sheet.prune_cell(addr.row as usize, addr.col as usize);
+            }
+            // Formatting, notes and comments go with the block, replacing
+            // those it lands on
+            sheet.relocate_formats(mv.src, mv.dest);
+            sheet.relocate_notes(
+                |addr| mv.src.contains(addr) || mv.dest.contains(addr),
+                |addr| mv.src.contains(addr).then(|| mv.target(addr)),
+            );
+            let relocation = Relocation::Block { src, rows, cols };
+            sheet.record_move(relocation);
+            sheet.relocated(relocation);
+            Ok(())
+        })
+    }
+
+    /// Follows a block moved on another sheet of the workbook: rewrites the
+    /// formulas reading that sheet like `move_range` rewrites those reading
+    /// the block.
+    ///
+    /// The change is not recorded, like the values read from other sheets.
+    pub(crate) fn follow_move(&mut self, id: u32, src: RangeAddr, rows: i64, cols: i64) {
+        let Some(mv) = Move::new(src, rows, cols) else {
+            return;
+        };
+        let mut batch = Batch::unchecked();
+        for addr in self.importing_cells() {
+            if let Some(rewritten) = mv.rewrite(&self.formula_of(addr), Moving::Other(id)) {
+                batch.set(
+                    addr.row as usize,
+                    addr.col as usize,
+                    self.formula_text(&rewritten),
+                );
+            }
+        }
+        if !batch.is_empty() {
+            self.unrecorded(|sheet| sheet.commit(batch));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::style::Format;
+
+    fn range(text: &str) -> RangeAddr {
+        RangeAddr::parse(text).unwrap()
+    }
+
+    #[test]
+    fn test_move_range_rewrites_references() {
+        let mut test_sheet = Sheet::new(10, 10);
+        test_sheet.transaction(|batch| {
+            batch.set(1, 1, "4");
+            batch.set(2, 1, "A1*2");
+            batch.set(3, 1, "SUM(A1:A2)");
+            batch.set(1, 3, "A2+1");
+            batch.set(2, 3, "E1");
+            batch.set(1, 5, "7");
+        });
+        test_sheet
+            .move_range(range("A1:A3"), CellAddr::new(1, 4))
+            .unwrap();
+
+        assert_eq!(test_sheet.get_formula(2, 4), "D1*2");
+        assert_eq!(test_sheet.get_formula(3, 4), "SUM(D1:D2)");
+        assert_eq!(test_sheet.get_formula(1, 3), "D2+1");
+        assert_eq!(test_sheet.get_value(3, 4), 12);
+        assert_eq!(test_sheet.get_formula(1, 1), "0");
+        assert!(test_sheet.grid.get(1, 1).is_none());
+
+        // The moved cells are linked at their new place
+        test_sheet.update_cell_data(1, 4, String::from("5"));
+        assert_eq!(test_sheet.get_value(1, 3), 11);
+
+        // The whole move is one undo step
+        assert!(test_sheet.undo());
+        assert!(test_sheet.undo());
+        assert_eq!(test_sheet.get_formula(1, 3), "A2+1");
+        assert_eq!(test_sheet.get_value(3, 1), 12);
+        assert_eq!(test_sheet.get_formula(1, 4), "0");
+    }
+
+    #[test]
+    fn test_move_over_referenced_cells() {
+        let mut test_sheet = Sheet::new(10, 10);
+        test_sheet.transaction(|batch| {
+            batch.set(1, 1, "1");
+            batch.set(2, 1, "2");
+            batch.set(1, 2, "9");
+            batch.set(3, 3, "B1+A2");
+            batch.set(4, 3, "SUM(B1:B2)");
+            batch.set(5, 3, "SUM(B1:B3)");
+        });
+        test_sheet
+            .move_range(range("A1:A2"), CellAddr::new(1, 2))
+            .unwrap();
+        assert_eq!(test_sheet.get_value(1, 2), 1);
+        assert_eq!(test_sheet.get_formula(3, 3), "#REF!");
+        assert_eq!(test_sheet.get_error(3, 3), Some(Error::Ref));
+        // A range of overwritten cells is lost too; one only partly overwritten stays
+        assert_eq!(test_sheet.get_formula(4, 3), "#REF!");
+        assert_eq!(test_sheet.get_error(4, 3), Some(Error::Ref));
+        assert_eq!(test_sheet.get_formula(5, 3), "SUM(B1:B3)");
+        assert_eq!(test_sheet.get_value(5, 3), 3);
+
+        assert!(test_sheet.undo());
+        assert_eq!(test_sheet.get_value(3, 3), 11);
+        assert_eq!(test_sheet.get_value(4, 3), 9);
+    }
+
+    #[test]
+    fn test_formats_move_with_the_block() {
+        let mut test_sheet = Sheet::new(10, 10);
+        let fill = Format::parse_list("fill:#ffcc00").unwrap();
+        test_sheet.update_cell_data(1, 1, String::from("1"));
+        test_sheet
+            .set_format(range("A1:B2"), &[Format::Bold(true)])
+            .unwrap();
+        test_sheet.set_format(range("C1:C5"), &fill).unwrap();
+        test_sheet
+            .move_range(range("A1:A2"), CellAddr::new(2, 3))
+            .unwrap();
+
+        // The block takes its formatting along and replaces what it lands on
+        assert!(!test_sheet.style(1, 1).bold);
+        assert!(test_sheet.style(1, 2).bold);
+        assert!(test_sheet.style(2, 3).bold);
+        assert!(test_sheet.style(3, 3).fill_color.is_none());
+        assert!(!test_sheet.style(1, 3).bold);
+        assert!(test_sheet.style(1, 3).fill_color.is_some());
+        assert!(test_sheet.style(4, 3).fill_color.is_some());
+
+        assert!(test_sheet.undo());
+        assert!(test_sheet.style(1, 1).bold);
+        assert!(!test_sheet.style(2, 3).bold);
+        assert!(test_sheet.style(3, 3).fill_color.is_some());
+        assert_eq!(test_sheet.get_value(1, 1), 1);
+    }
+
+    #[test]
+    fn test_move_is_atomic() {
+        let mut test_sheet = Sheet::new(10, 10);
+        test_sheet.update_cell_data(1, 1, String::from("SUM(B1:B5)"));
+        test_sheet.update_cell_data(2, 2, String::from("3"));
+        assert_eq!(
+            test_sheet.move_range(range("A1:A1"), CellAddr::new(3, 2)),
+            Err(Error::CycleDetected)
+        );
+        assert_eq!(test_sheet.get_formula(1, 1), "SUM(B1:B5)");
+        assert_eq!(test_sheet.get_value(1, 1), 3);
+        assert_eq!(
+            test_sheet.move_range(range("A1:A1"), CellAddr::new(11, 1)),
+            Err(Error::InvalidInput)
+        );
+    }
+}
@@ -0,0 +1,189 @@
+//! Change notification.
+//!
+//! Listeners subscribe to a sheet and are told which cells changed their value
+//! or formula, so a front end can update exactly those cells and other code can
+//! log or sync edits. The state of every cell an operation touches is saved the
+//! first time it is touched; when the outermost operation ends, the cells that
+//! differ from their saved state are published in one call, row by row. Cells
+//! that end up as they were, such as those of a rejected edit, are left out.
+//!
+//! Nothing is saved while no listener is subscribed, so sheets without
+//! listeners pay nothing for this.
+
+use crate::addr::CellAddr;
+use crate::sheet::{Error, Sheet, value_error};
+use fxhash::FxHashMap;
+
+/// Identifies a listener subscribed to a sheet.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct ListenerId(u64);
+
+/// A function called with the cells changed by an operation.
+type Listener = Box<dyn FnMut(&[CellAddr]) + Send + Sync>;
+
+/// What a listener can see of a cell: its value, error and formula text.
+type CellState = (i32, Option<Error>, String);
+
+/// Listeners of a sheet and the changes of the running operation.
+#[derive(Default)]
+pub(crate) struct Changes {
+    listeners: Vec<(ListenerId, Listener)>,
+    next_id: u64,
+    /// Number of operations currently open
+    open: usize,
+    /// State of every touched cell before the outermost open operation
+    before: FxHashMap<CellAddr, CellState>,
+}
+
+impl Sheet {
+    /// Subscribes a listener to the changes of the sheet.
+    ///
+    /// The listener is called once per operation that changed any cell, with
+    /// the changed cells row by row. Loading a file is not reported.
+    ///
+    /// # Returns
+    /// The id to pass to `unsubscribe`.
+    pub fn subscribe(
+        &mut self,
+        listener: impl FnMut(&[CellAddr]) + Send + Sync + 'static,
+    ) -> ListenerId {
+        let id = ListenerId(self.changes.next_id);
+        self.changes.next_id += 1;
+        self.changes.listeners.push((id, Box::new(listener)));
+        id
+    }
+
+    /// Removes a listener.
+    ///
+    /// # Returns
+    /// `false` if no listener had that id.
+    pub fn unsubscribe(&mut self, id: ListenerId) -> bool {
+        let count = self.changes.listeners.len();
+        self.changes
+            .listeners
+            .retain(|(listener_id, _)| *listener_id != id);
+        self.changes.listeners.len() != count
+    }
+
+    /// Runs an operation and publishes the cells it changed once it ends.
+    ///
+    /// Operations may be nested; the changes are published when the outermost
+    /// one ends.
+    pub(crate) fn notify<R>(&mut self, operation: impl FnOnce(&mut Self) -> R) -> R {
+        self.begin_changes();
+        let result = operation(self);
+        self.end_changes();
+        result
+    }
+
+    /// Starts an operation whose changes are published by the matching `end_changes`.
+    pub(crate) fn begin_changes(&mut self) {
+        self.changes.open += 1;
+    }
+
+    /// Ends an operation started by `begin_changes`.
+    pub(crate) fn end_changes(&mut self) {
+        self.changes.open = self.changes.open.saturating_sub(1);
+        if self.changes.open > 0 || self.changes.before.is_empty() {
+            return;
+        }
+        let before = std::mem::take(&mut self.changes.before);
+        let mut changed: Vec<CellAddr> = before
+            .into_iter()
+            .filter(|(addr, state)| self.cell_state(*addr) != *state)
+            .map(|(addr, _)| addr)
+            .collect();
+        if changed.is_empty() {
+            return;
+        }
+        changed.sort_unstable();
+        for (_, listener) in &mut self.changes.listeners {
+            listener(&changed);
+        }
+    }
+
+    /// Saves the state of a cell that is about to change, if anyone is listening.
+    ///
+    /// Changes outside of an operation, such as dropping a cell that holds
+    /// nothing any more, are not tracked.
+    pub(crate) fn touch(&mut self, addr: CellAddr) {
+        if self.changes.open == 0
+            || self.changes.listeners.is_empty()
+            || self.changes.before.contains_key(&addr)
+        {
+            return;
+        }
+        let state = self.cell_state(addr);
+        self.changes.before.insert(addr, state);
+    }
+
+    fn cell_state(&self, addr: CellAddr) -> CellState {
+        let cell = &self.grid[addr];
+        let (row, col) = addr.index();
+        (cell.value, value_error(cell), self.get_formula(row, col))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+
+    fn listen(test_sheet: &mut Sheet) -> (ListenerId, Arc<Mutex<Vec<Vec<String>>>>) {
+        let published = Arc::new(Mutex::new(Vec::new()));
+        let log = Arc::clone(&published);
+        let id = test_sheet.subscribe(move |cells| {
+            let names = cells.iter().map(|addr| addr.to_string()).collect();
+            log.lock().unwrap().push(names);
+        });
+        (id, published)
+    }
+
+    #[test]
+    fn test_edits_publish_changed_cells() {
+        let mut test_sheet = Sheet::new(10, 10);
+        test_sheet.update_cell_data(1, 1, String::from("1"));
+        test_sheet.update_cell_data(2, 1, String::from("A1*2"));
+        test_sheet.update_cell_data(3, 1, String::from("A1*0"));
+        test_sheet.update_cell_data(1, 3, String::from("SUM(A1:A3)"));
+        let (id, published) = listen(&mut test_sheet);
+
+        test_sheet.update_cell_data(1, 1, String::from("5"));
+        // A3 was recalculated but kept its value
+        assert_eq!(published.lock().unwrap().pop().unwrap(), ["A1", "C1", "A2"]);
+
+        // A rejected edit changes nothing
+        test_sheet.update_cell_data(1, 1, String::from("C1"));
+        test_sheet.update_cell_data(4, 4, String::from("A1+"));
+        assert!(published.lock().unwrap().is_empty());
+
+        test_sheet.transaction(|batch| {
+            batch.set(2, 2, "A2");
+            batch.set(3, 1, "7");
+        });
+        assert_eq!(published.lock().unwrap().pop().unwrap(), ["C1", "B2", "A3"]);
+        assert!(test_sheet.unsubscribe(id));
+        assert!(!test_sheet.unsubscribe(id));
+        test_sheet.update_cell_data(1, 1, String::from("6"));
+        assert!(published.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_compound_operations_publish_once() {
+        let mut test_sheet = Sheet::new(10, 10);
+        test_sheet.update_cell_data(1, 1, String::from("1"));
+        test_sheet.update_cell_data(1, 2, String::from("A1+1"));
+        test_sheet.update_cell_data(2, 1, String::from("3"));
+        let (_, published) = listen(&mut test_sheet);
+
+        test_sheet.clear_row(1);
+        assert_eq!(*published.lock().unwrap(), [["A1", "B1"]]);
+        assert!(test_sheet.undo());
+        assert_eq!(published.lock().unwrap().pop().unwrap(), ["A1", "B1"]);
+
+        // Inserting a row moves every cell below it
+        published.lock().unwrap().clear();
+        test_sheet.insert_rows(1, 1).unwrap();
+        assert_eq!(*published.lock().unwrap(), [["A1", "B1", "A2", "B2", "A3"]]);
+    }
+}
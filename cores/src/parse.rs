@@ -12,35 +12,143 @@
 #![allow(unused_braces)]
 #![allow(clippy::identity_op)]
 
-use modular_bitfield::prelude::*;
+use std::fmt;
 use std::str;
 
+use crate::addr::{CellAddr, RangeAddr};
 use crate::sheet::Cell;
 
-/// Bitfield representing the type and attributes of a spreadsheet formula command.
+pub use flag::CommandFlag;
+
+// `#[bitfield]` emits an empty `const _: () = {}` check for every field,
+// which rustc takes for parentheses around a type, and repeats its own
+// `identity_op` allow next to ours; the allows cover only that generated code.
+#[allow(unused_parens, clippy::duplicated_attributes)]
+mod flag {
+    use modular_bitfield::prelude::*;
+
+    /// Bitfield representing the type and attributes of a spreadsheet formula command.
+    ///
+    /// This structure efficiently stores various flags about a command in a single 16-bit value.
+    #[bitfield]
+    #[repr(u16)]
+    #[derive(Clone, Debug, serde::Serialize, Default)]
+    #[allow(clippy::identity_op)]
+    pub struct CommandFlag {
+        /// Command type: 0 = value/cell, 1 = arithmetic, 2 = range function,
+        /// 3 = range function over the rows not hidden by a filter (`SUBTOTAL`)
+        pub type_: B2, // 2 bits
+        /// Operation code (depends on type_):
+        /// - For arithmetic: 0 = add, 1 = subtract, 2 = multiply, 3 = divide
+        /// - For range functions: 0 = MIN, 1 = MAX, 2 = SUM, 3 = AVG, 4 = STDEV, 5 = SLEEP
+        /// - For `SUBTOTAL`: the range function, as above
+        pub cmd: B3, // 3 bits
+        /// Parameter 1 type: 0 = value, 1 = cell reference
+        pub type1: B1, // 1 bit
+        /// Parameter 2 type: 0 = value, 1 = cell reference
+        pub type2: B1, // 1 bit
+        /// Error code: 0 = no error, 1 = invalid input, 2 = cycle detected,
+        /// 3 = the formula referred to a deleted cell (`#REF!`)
+        pub error: B2, // 2 bits
+        /// Division by zero flag: 1 = division by zero occurred
+        pub is_div_by_zero: B1, // 1 bit
+        /// Overflow flag: 1 = the result does not fit in an i32 (#NUM!)
+        pub is_overflow: B1, // 1 bit
+        /// Timeout flag: 1 = evaluation exceeded its time budget
+        pub is_timeout: B1, // 1 bit
+        /// Reference flag: 1 = the value depends on a deleted cell (#REF!)
+        pub is_ref: B1, // 1 bit
+        /// Set once the cell was written to
+        pub is_any: B1,
+        /// Plain code flag: 1 = `SUBTOTAL` names its function by a code from 1 to 11
+        /// instead of 101 to 111
+        pub is_plain_code: B1,
+        /// Shared flag: 1 = the parameters name a shared formula instead of holding the formula
+        pub is_shared: B1,
+    }
+}
+
+impl CommandFlag {
+    /// Copies the error code and the error value bits of another flag, leaving
+    /// the bits describing the formula unchanged.
+    pub fn copy_status(&mut self, other: &CommandFlag) {
+        self.set_error(other.error());
+        self.set_is_div_by_zero(other.is_div_by_zero());
+        self.set_is_overflow(other.is_overflow());
+        self.set_is_timeout(other.is_timeout());
+        self.set_is_ref(other.is_ref());
+    }
+}
+
+/// A parameter of a formula: either a direct value or a cell reference.
 ///
-/// This structure efficiently stores various flags about a command in a single 16-bit value.
-#[bitfield]
-#[repr(u16)]
-#[derive(Clone, Debug, serde::Serialize, Default)]
-#[allow(clippy::identity_op)]
-pub struct CommandFlag {
-    /// Command type: 0 = value/cell, 1 = arithmetic, 2 = range function
-    pub type_: B2, // 2 bits
-    /// Operation code (depends on type_):
-    /// - For arithmetic: 0 = add, 1 = subtract, 2 = multiply, 3 = divide
-    /// - For range functions: 0 = MIN, 1 = MAX, 2 = SUM, 3 = AVG, 4 = STDEV, 5 = SLEEP
-    pub cmd: B3, // 3 bits
-    /// Parameter 1 type: 0 = value, 1 = cell reference
-    pub type1: B1, // 1 bit
-    /// Parameter 2 type: 0 = value, 1 = cell reference
-    pub type2: B1, // 1 bit
-    /// Error code: 0 = no error, 1 = invalid input, 2 = cycle detected
-    pub error: B2, // 2 bits
-    /// Division by zero flag: 1 = division by zero occurred
-    pub is_div_by_zero: B1, // 1 bit
-    /// Reserved bits for future use
-    pub is_any: B6,
+/// The matching `type1`/`type2` bit of the `CommandFlag` is 0 for values and 1
+/// for references.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, serde::Serialize)]
+pub enum Param {
+    /// A literal integer
+    Value(i32),
+    /// A reference to another cell
+    Cell(CellAddr),
+    /// A reference to a cell of another sheet of the workbook, by the id of
+    /// that sheet, written as `2!B7` (see `external`)
+    External(u32, CellAddr),
+}
+
+impl Param {
+    /// Returns the referenced cell, if this parameter is a reference to a
+    /// cell of the same sheet.
+    pub fn cell(self) -> Option<CellAddr> {
+        match self {
+            Param::Cell(addr) => Some(addr),
+            Param::Value(_) | Param::External(..) => None,
+        }
+    }
+
+    /// Parses a reference: a cell name such as `B7`, or a cell of another
+    /// sheet named by the id of the sheet, such as `2!B7`.
+    pub fn parse_ref(text: &str) -> Option<Param> {
+        match text.split_once('!') {
+            Some((id, name)) => {
+                if id.is_empty() || !id.chars().all(is_digit) || !is_valid_cell(name) {
+                    return None;
+                }
+                Some(Param::External(id.parse().ok()?, CellAddr::parse(name)?))
+            }
+            None if is_valid_cell(text) => CellAddr::parse(text).map(Param::Cell),
+            None => None,
+        }
+    }
+
+    /// Returns the parameter moved by the given offset, keeping the sheet it reads.
+    ///
+    /// # Returns
+    /// `None` if a reference would leave the sheet's address space.
+    fn offset(self, rows: i64, cols: i64) -> Option<Param> {
+        match self {
+            Param::Cell(addr) => addr.offset(rows, cols).map(Param::Cell),
+            Param::External(id, addr) => addr
+                .offset(rows, cols)
+                .map(|addr| Param::External(id, addr)),
+            value => Some(value),
+        }
+    }
+}
+
+impl Default for Param {
+    fn default() -> Self {
+        Param::Value(0)
+    }
+}
+
+impl fmt::Display for Param {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Param::Value(value) => write!(f, "{}", value),
+            Param::Cell(addr) => write!(f, "{}", addr),
+            Param::External(id, addr) => write!(f, "{}!{}", id, addr),
+        }
+    }
 }
 
 /// A structure representing a parsed formula command.
@@ -51,10 +159,81 @@ pub struct CommandFlag {
 pub struct CommandCall {
     /// Flag bits indicating the command type and attributes
     pub flag: CommandFlag, // 16 bits
-    /// First parameter - either a direct value or an encoded cell reference
-    pub param1: i32, // 4 bytes
-    /// Second parameter - either a direct value or an encoded cell reference
-    pub param2: i32, // 4 bytes
+    /// First parameter - either a direct value or a cell reference
+    pub param1: Param,
+    /// Second parameter - either a direct value or a cell reference
+    pub param2: Param,
+}
+
+impl CommandCall {
+    /// Returns the range read by a range function (MIN, MAX, SUM, AVG, STDEV, SUBTOTAL).
+    pub fn range(&self) -> Option<RangeAddr> {
+        if self.flag.type_() < 2 || self.flag.cmd() == 5 {
+            return None;
+        }
+        match (self.param1, self.param2) {
+            (Param::Cell(start), Param::Cell(end)) => RangeAddr::new(start, end),
+            _ => None,
+        }
+    }
+
+    /// Returns the id of the other sheet and the range read by a range
+    /// function over another sheet of the workbook.
+    pub fn external_range(&self) -> Option<(u32, RangeAddr)> {
+        if self.flag.type_() < 2 || self.flag.cmd() == 5 {
+            return None;
+        }
+        match (self.param1, self.param2) {
+            (Param::External(id, start), Param::External(other, end)) if id == other => {
+                Some((id, RangeAddr::new(start, end)?))
+            }
+            _ => None,
+        }
+    }
+
+    /// Returns the formula with every reference moved by the given offset, as
+    /// when the formula is filled into another cell.
+    ///
+    /// # Returns
+    /// `None` if a reference would leave the sheet's address space.
+    pub fn shifted(&self, rows: i64, cols: i64) -> Option<CommandCall> {
+        Some(CommandCall {
+            flag: self.flag.clone(),
+            param1: self.param1.offset(rows, cols)?,
+            param2: self.param2.offset(rows, cols)?,
+        })
+    }
+
+    /// Returns the `#REF!` formula that replaces a formula whose reference was lost.
+    pub fn ref_error() -> CommandCall {
+        let mut flag = CommandFlag::new();
+        flag.set_error(3);
+        CommandCall {
+            flag,
+            param1: Param::Value(0),
+            param2: Param::Value(0),
+        }
+    }
+
+    /// Returns `true` if both commands compute the same formula, ignoring the
+    /// error and state bits of their flags.
+    pub fn same_formula(&self, other: &CommandCall) -> bool {
+        self.flag.type_() == other.flag.type_()
+            && self.flag.cmd() == other.flag.cmd()
+            && self.param1 == other.param1
+            && self.param2 == other.param2
+    }
+
+    /// Returns every cell whose value the formula reads.
+    pub fn precedents(&self) -> Vec<CellAddr> {
+        match self.range() {
+            Some(range) => range.cells().collect(),
+            None => [self.param1, self.param2]
+                .into_iter()
+                .filter_map(Param::cell)
+                .collect(),
+        }
+    }
 }
 
 // Utility functions for character checking
@@ -96,10 +275,14 @@ fn is_sign(c: char) -> bool {
 pub fn parse_formula(input: &str) -> CommandCall {
     let mut cell = CommandCall {
         flag: CommandFlag::new(),
-        param1: 0,
-        param2: 0,
+        param1: Param::Value(0),
+        param2: Param::Value(0),
     };
 
+    // A formula that lost a reference, as written by `unparse_command`
+    if input.trim() == "#REF!" {
+        return CommandCall::ref_error();
+    }
     parse_expression(input, &mut cell);
     cell
 }
@@ -142,13 +325,12 @@ pub fn parse_sleep(input: &str, container: &mut CommandCall) {
     if input.starts_with("SLEEP(") && input.ends_with(")") {
         // Extract the part between parentheses
         let sleep_time = &input[6..input.len() - 1].trim();
-        let is_cell_ref = is_valid_cell(sleep_time);
 
-        if is_cell_ref {
-            container.param1 = encode_cell(sleep_time.to_string());
+        if let Some(reference) = Param::parse_ref(sleep_time) {
+            container.param1 = reference;
             container.flag.set_type1(1);
         } else if let Ok(value) = sleep_time.parse::<i32>() {
-            container.param1 = value;
+            container.param1 = Param::Value(value);
             container.flag.set_type1(0);
         } else {
             container.flag.set_error(1);
@@ -183,6 +365,9 @@ pub fn Arithmatic(input: &str, container: &mut CommandCall) {
         } else if is_sign(c) && !in_operand {
             // Sign at the beginning is ok
             continue;
+        } else if c == '!' && in_operand {
+            // Reference to another sheet
+            continue;
         } else if c == ' ' {
             // Skip spaces
             continue;
@@ -198,12 +383,11 @@ pub fn Arithmatic(input: &str, container: &mut CommandCall) {
         let operator = input.chars().nth(pos).unwrap();
         let right = input[pos + 1..].trim();
         // Process left operand
-        let is_cell_ref = is_valid_cell(left);
-        if is_cell_ref {
-            container.param1 = encode_cell(left.to_string());
+        if let Some(reference) = Param::parse_ref(left) {
+            container.param1 = reference;
             container.flag.set_type1(1);
         } else if let Ok(value) = left.parse::<i32>() {
-            container.param1 = value;
+            container.param1 = Param::Value(value);
             container.flag.set_type1(0);
         } else {
             container.flag.set_error(1);
@@ -224,12 +408,11 @@ pub fn Arithmatic(input: &str, container: &mut CommandCall) {
         container.flag.set_cmd(cmd);
 
         // Process right operand
-        let is_cell_ref = is_valid_cell(right);
-        if is_cell_ref {
-            container.param2 = encode_cell(right.to_string());
+        if let Some(reference) = Param::parse_ref(right) {
+            container.param2 = reference;
             container.flag.set_type2(1);
         } else if let Ok(value) = right.parse::<i32>() {
-            container.param2 = value;
+            container.param2 = Param::Value(value);
             container.flag.set_type2(0);
         } else {
             container.flag.set_error(1);
@@ -239,9 +422,18 @@ pub fn Arithmatic(input: &str, container: &mut CommandCall) {
     }
 }
 
+/// `SUBTOTAL` function codes, indexed by the range function they select
+/// (MIN, MAX, SUM, AVG, STDEV).
+///
+/// The plain codes 1 to 11 select the same functions as 101 to 111. Elsewhere
+/// they differ in whether rows hidden by hand are read, but the only rows
+/// hidden here are those of a filter, which both skip.
+const SUBTOTAL_CODES: [u32; 5] = [105, 104, 109, 101, 107];
+
 /// Parses a range operation (e.g., "SUM(A1:B5)") and populates the CommandCall structure.
 ///
-/// This handles functions that operate on a range of cells, such as MIN, MAX, SUM, AVG, and STDEV.
+/// This handles functions that operate on a range of cells, such as MIN, MAX, SUM, AVG, and STDEV,
+/// and `SUBTOTAL(code,START:END)`, which applies one of them to the rows not hidden by a filter.
 ///
 /// # Parameters
 /// * `input` - A string slice containing the range function expression
@@ -262,7 +454,18 @@ pub fn rangeoper(input: &str, container: &mut CommandCall) {
     // Extract range
     let range_start = func_end + 1;
     let range_end = input.rfind(')').unwrap();
-    let range = &input[range_start..range_end];
+    let mut range = &input[range_start..range_end];
+
+    // SUBTOTAL names its function by a code before the range
+    let mut subtotal_code = None;
+    if func_name == "SUBTOTAL" {
+        let Some((code, rest)) = range.split_once(',') else {
+            container.flag.set_error(1);
+            return;
+        };
+        subtotal_code = Some(code.trim());
+        range = rest;
+    }
 
     // Split range into start and end cells
     let parts: Vec<&str> = range.split(':').collect();
@@ -273,43 +476,58 @@ pub fn rangeoper(input: &str, container: &mut CommandCall) {
 
     let start_cell = parts[0].trim();
     let end_cell = parts[1].trim();
-    let is_cell_ref_start = is_valid_cell(start_cell);
-    let is_cell_ref_end = is_valid_cell(end_cell);
-    if !is_cell_ref_start || !is_cell_ref_end {
+    let (Some(start), Some(end)) = (Param::parse_ref(start_cell), Param::parse_ref(end_cell))
+    else {
         container.flag.set_error(1);
         return;
-    }
+    };
 
-    // Encode cell references
-    container.param1 = encode_cell(start_cell.to_string());
-    container.param2 = encode_cell(end_cell.to_string());
-    if container.param1 == 0 || container.param2 == 0 {
-        container.flag.set_error(1);
-        container.param1 = 0;
-        container.param2 = 0;
-        container.flag.set_type_(0);
-        container.flag.set_type1(0);
-        container.flag.set_type2(0);
-        container.flag.set_cmd(0);
-        return;
-    }
-    // Check if range is valid
-    let (row1, col1) = convert_to_index_int(container.param1);
-    let (row2, col2) = convert_to_index_int(container.param2);
-    if row1 > row2 || col1 > col2 {
+    // Check if range is valid; both ends must lie on the same sheet
+    let range = match (start, end) {
+        (Param::Cell(start), Param::Cell(end)) => RangeAddr::new(start, end)
+            .map(|range| (Param::Cell(range.start), Param::Cell(range.end))),
+        (Param::External(id, start), Param::External(other, end)) if id == other => {
+            RangeAddr::new(start, end).map(|range| {
+                (
+                    Param::External(id, range.start),
+                    Param::External(id, range.end),
+                )
+            })
+        }
+        _ => None,
+    };
+    let Some((start, end)) = range else {
         container.flag.set_error(1);
-        container.param1 = 0;
-        container.param2 = 0;
+        container.param1 = Param::Value(0);
+        container.param2 = Param::Value(0);
         container.flag.set_type_(0);
         container.flag.set_type1(0);
         container.flag.set_type2(0);
         container.flag.set_cmd(0);
         return;
-    }
+    };
+    container.param1 = start;
+    container.param2 = end;
 
     container.flag.set_type1(1);
     container.flag.set_type2(1);
     // Set function type
+    if let Some(code) = subtotal_code {
+        let code = code.parse::<u32>().ok();
+        let plain = code.is_some_and(|code| code < 100);
+        let cmd = code.and_then(|code| {
+            let code = if plain { code + 100 } else { code };
+            SUBTOTAL_CODES.iter().position(|&known| known == code)
+        });
+        let Some(cmd) = cmd else {
+            container.flag.set_error(1);
+            return;
+        };
+        container.flag.set_type_(3);
+        container.flag.set_cmd(cmd as u8);
+        container.flag.set_is_plain_code(plain as u8);
+        return;
+    }
     let cmd = match func_name {
         "MIN" => 0,
         "MAX" => 1,
@@ -348,15 +566,14 @@ pub fn parse_expression(input: &str, container: &mut CommandCall) {
         && trimmed
             .chars()
             .all(|c| is_digit(c) || (c == '-' || c == '+') && trimmed.starts_with(c))
+        && let Ok(value) = trimmed.parse::<i32>()
     {
-        if let Ok(value) = trimmed.parse::<i32>() {
-            container.param1 = value;
-            container.param2 = 0;
-            container.flag.set_type_(0);
-            container.flag.set_cmd(0);
-            container.flag.set_type1(0);
-            return;
-        }
+        container.param1 = Param::Value(value);
+        container.param2 = Param::Value(0);
+        container.flag.set_type_(0);
+        container.flag.set_cmd(0);
+        container.flag.set_type1(0);
+        return;
     }
 
     // Check for arithmetic operations
@@ -381,27 +598,8 @@ pub fn parse_expression(input: &str, container: &mut CommandCall) {
     }
 
     // Check if it's a cell reference
-    let mut is_cell_ref = true;
-    let mut has_letter = false;
-    let mut has_digit = false;
-
-    for c in trimmed.chars() {
-        if is_uppercase_letter(c) {
-            if has_digit {
-                is_cell_ref = false;
-                break;
-            }
-            has_letter = true;
-        } else if is_digit(c) {
-            has_digit = true;
-        } else {
-            is_cell_ref = false;
-            break;
-        }
-    }
-
-    if is_cell_ref && has_letter && has_digit {
-        container.param1 = encode_cell(trimmed.to_string());
+    if let Some(reference) = Param::parse_ref(trimmed) {
+        container.param1 = reference;
         container.flag.set_type_(0);
         container.flag.set_cmd(0);
         container.flag.set_type1(1);
@@ -419,102 +617,7 @@ pub fn parse_expression(input: &str, container: &mut CommandCall) {
 /// # Returns
 /// A tuple of (row, column) indices, where both are 1-based
 pub fn convert_to_index(cell: String) -> (usize, usize) {
-    let mut col_str = String::new();
-    let mut row_str = String::new();
-    let mut processing_col = true;
-
-    for c in cell.chars() {
-        if is_uppercase_letter(c) && processing_col {
-            col_str.push(c);
-        } else if is_digit(c) {
-            if processing_col {
-                processing_col = false;
-            }
-            row_str.push(c);
-        } else {
-            return (0, 0); // Invalid cell reference
-        }
-    }
-
-    if col_str.is_empty() || row_str.is_empty() {
-        return (0, 0);
-    }
-
-    // Convert column letters to number
-    let mut col = 0;
-    for c in col_str.chars() {
-        col = col * 26 + (c as usize - 'A' as usize + 1);
-    }
-    let x = row_str.len();
-    let row_str = row_str.trim_start_matches('0'); // Remove leading zeros
-    let y = row_str.len();
-    if x - y > 0 {
-        return (0, 0);
-    }
-    // Parse row number
-    match row_str.parse::<usize>() {
-        Ok(row) => (row, col),
-        Err(_) => (0, 0),
-    }
-}
-
-/// Shift value used for encoding cell references into a single integer.
-///
-/// This constant determines how many columns can be represented in a sheet.
-pub const ENCODE_SHIFT: usize = 100000;
-
-/// Encodes a cell reference (e.g., "A1") into a single integer value.
-///
-/// # Parameters
-/// * `cell` - The cell reference string to encode
-///
-/// # Returns
-/// An integer encoding of the cell reference
-pub fn encode_cell(cell: String) -> i32 {
-    let (row, col) = convert_to_index(cell);
-    let encoded = row * (ENCODE_SHIFT) + col;
-    encoded as i32
-}
-
-/// Decodes an encoded cell reference back to a string (e.g., "A1").
-///
-/// # Parameters
-/// * `encoded` - The encoded cell value to decode
-///
-/// # Returns
-/// The string representation of the cell reference
-pub fn decode_cell(encoded: i32) -> String {
-    let mut col = (encoded % (ENCODE_SHIFT as i32)) as usize;
-    let row = (encoded / (ENCODE_SHIFT as i32)) as usize;
-    let mut cell = String::new();
-
-    if col == 0 {
-        return String::new();
-    }
-
-    while col > 0 {
-        let mut temp = col % 26;
-        if temp == 0 {
-            temp = 26;
-        }
-        cell.insert(0, (temp as u8 + b'A' - 1) as char);
-        col = (col - temp) / 26;
-    }
-
-    cell.push_str(&row.to_string());
-    cell
-}
-
-/// Converts an encoded cell reference to row and column indices.
-///
-/// # Parameters
-/// * `encode` - The encoded cell value
-///
-/// # Returns
-/// A tuple of (row, column) indices
-pub fn convert_to_index_int(encode: i32) -> (usize, usize) {
-    let inp = decode_cell(encode);
-    convert_to_index(inp)
+    CellAddr::parse(&cell).map_or((0, 0), CellAddr::index)
 }
 
 /// Converts a Cell structure back to its formula string representation.
@@ -528,37 +631,29 @@ pub fn convert_to_index_int(encode: i32) -> (usize, usize) {
 /// A string representation of the cell's formula
 #[allow(dead_code)]
 pub fn unparse(cell: Cell) -> String {
-    match cell.formula.flag.type_() {
-        0 => {
-            // Constant
-            if cell.formula.flag.type1() == 0 {
-                cell.formula.param1.to_string()
-            } else {
-                decode_cell(cell.formula.param1)
-            }
-        }
+    unparse_command(&cell.formula)
+}
+
+/// Converts a formula command back to its string representation.
+pub fn unparse_command(formula: &CommandCall) -> String {
+    if formula.flag.error() == 3 {
+        return "#REF!".to_string();
+    }
+    match formula.flag.type_() {
+        // Constant or cell reference
+        0 => formula.param1.to_string(),
         1 => {
-            let sym = match cell.formula.flag.cmd() {
+            let sym = match formula.flag.cmd() {
                 0 => "+",
                 1 => "-",
                 2 => "*",
                 3 => "/",
                 _ => "",
             };
-            let left = if cell.formula.flag.type1() == 0 {
-                cell.formula.param1.to_string()
-            } else {
-                decode_cell(cell.formula.param1)
-            };
-            let right = if cell.formula.flag.type2() == 0 {
-                cell.formula.param2.to_string()
-            } else {
-                decode_cell(cell.formula.param2)
-            };
-            format!("{}{}{}", left, sym, right)
+            format!("{}{}{}", formula.param1, sym, formula.param2)
         }
         2 => {
-            let func = match cell.formula.flag.cmd() {
+            let func = match formula.flag.cmd() {
                 0 => "MIN",
                 1 => "MAX",
                 2 => "SUM",
@@ -568,21 +663,23 @@ pub fn unparse(cell: Cell) -> String {
                 _ => "",
             };
 
-            if cell.formula.flag.cmd() == 5 {
+            if formula.flag.cmd() == 5 {
                 // SLEEP function has different format
-                let param = if cell.formula.flag.type1() == 0 {
-                    cell.formula.param1.to_string()
-                } else {
-                    decode_cell(cell.formula.param1)
-                };
-                format!("{}({})", func, param)
+                format!("{}({})", func, formula.param1)
             } else {
                 // Range functions
-                let start = decode_cell(cell.formula.param1);
-                let end = decode_cell(cell.formula.param2);
-                format!("{}({}:{})", func, start, end)
+                format!("{}({}:{})", func, formula.param1, formula.param2)
             }
         }
+        3 => match SUBTOTAL_CODES.get(formula.flag.cmd() as usize) {
+            Some(code) => format!(
+                "SUBTOTAL({},{}:{})",
+                code - 100 * formula.flag.is_plain_code() as u32,
+                formula.param1,
+                formula.param2
+            ),
+            None => "".to_string(),
+        },
         _ => "".to_string(),
     }
 }
@@ -591,14 +688,18 @@ pub fn unparse(cell: Cell) -> String {
 mod tests {
     use super::*;
 
+    fn cell_ref(name: &str) -> Param {
+        Param::Cell(CellAddr::parse(name).unwrap())
+    }
+
     #[test]
     fn test_parse_formula() {
         let input = "A1 + B2";
         let result = parse_formula(input);
         assert_eq!(result.flag.type_(), 1);
         assert_eq!(result.flag.cmd(), 0);
-        assert_eq!(result.param1, encode_cell("A1".to_string()));
-        assert_eq!(result.param2, encode_cell("B2".to_string()));
+        assert_eq!(result.param1, cell_ref("A1"));
+        assert_eq!(result.param2, cell_ref("B2"));
     }
 
     #[test]
@@ -606,34 +707,34 @@ mod tests {
         let input = "SLEEP(5)";
         let mut container = CommandCall {
             flag: CommandFlag::new(),
-            param1: 0,
-            param2: 0,
+            param1: Param::Value(0),
+            param2: Param::Value(0),
         };
         parse_sleep(input, &mut container);
         assert_eq!(container.flag.type_(), 2);
         assert_eq!(container.flag.cmd(), 5);
-        assert_eq!(container.param1, 5);
+        assert_eq!(container.param1, Param::Value(5));
     }
     #[test]
     fn test_parse_sleep_cell_ref() {
         let input = "SLEEP(A1)";
         let mut container = CommandCall {
             flag: CommandFlag::new(),
-            param1: 0,
-            param2: 0,
+            param1: Param::Value(0),
+            param2: Param::Value(0),
         };
         parse_sleep(input, &mut container);
         assert_eq!(container.flag.type_(), 2);
         assert_eq!(container.flag.cmd(), 5);
-        assert_eq!(container.param1, encode_cell("A1".to_string()));
+        assert_eq!(container.param1, cell_ref("A1"));
     }
     #[test]
     fn test_parse_sleep_invalid() {
         let input = "SLEEP(5A)";
         let mut container = CommandCall {
             flag: CommandFlag::new(),
-            param1: 0,
-            param2: 0,
+            param1: Param::Value(0),
+            param2: Param::Value(0),
         };
         parse_sleep(input, &mut container);
         assert_eq!(container.flag.error(), 1);
@@ -643,36 +744,60 @@ mod tests {
         let input = "A1 + B2";
         let mut container = CommandCall {
             flag: CommandFlag::new(),
-            param1: 0,
-            param2: 0,
+            param1: Param::Value(0),
+            param2: Param::Value(0),
         };
         Arithmatic(input, &mut container);
         assert_eq!(container.flag.type_(), 1);
         assert_eq!(container.flag.cmd(), 0);
-        assert_eq!(container.param1, encode_cell("A1".to_string()));
-        assert_eq!(container.param2, encode_cell("B2".to_string()));
+        assert_eq!(container.param1, cell_ref("A1"));
+        assert_eq!(container.param2, cell_ref("B2"));
     }
     #[test]
     fn test_parse_range() {
         let input = "SUM(A1:B2)";
         let mut container = CommandCall {
             flag: CommandFlag::new(),
-            param1: 0,
-            param2: 0,
+            param1: Param::Value(0),
+            param2: Param::Value(0),
         };
         rangeoper(input, &mut container);
         assert_eq!(container.flag.type_(), 2);
         assert_eq!(container.flag.cmd(), 2);
-        assert_eq!(container.param1, encode_cell("A1".to_string()));
-        assert_eq!(container.param2, encode_cell("B2".to_string()));
+        assert_eq!(container.param1, cell_ref("A1"));
+        assert_eq!(container.param2, cell_ref("B2"));
+    }
+    #[test]
+    fn test_parse_subtotal() {
+        let result = parse_formula("SUBTOTAL(109,A1:A5)");
+        assert_eq!(result.flag.error(), 0);
+        assert_eq!(result.flag.type_(), 3);
+        assert_eq!(result.flag.cmd(), 2);
+        assert_eq!(result.range(), RangeAddr::parse("A1:A5"));
+        assert_eq!(unparse_command(&result), "SUBTOTAL(109,A1:A5)");
+
+        assert_eq!(parse_formula("SUBTOTAL(A1:A5)").flag.error(), 1);
+        assert_eq!(parse_formula("SUBTOTAL(6,A1:A5)").flag.error(), 1);
+        assert_eq!(parse_formula("SUBTOTAL(209,A1:A5)").flag.error(), 1);
+    }
+    #[test]
+    fn test_parse_subtotal_plain_codes() {
+        for (code, cmd) in [(1, 3), (4, 1), (5, 0), (7, 4), (9, 2)] {
+            let text = format!("SUBTOTAL({},B2:B9)", code);
+            let result = parse_formula(&text);
+            assert_eq!(result.flag.error(), 0);
+            assert_eq!(result.flag.type_(), 3);
+            assert_eq!(result.flag.cmd(), cmd);
+            assert_eq!(unparse_command(&result), text);
+        }
     }
     #[test]
     fn test_parse_range_invalid() {
         let input = "SUM(A1:B2:C3)";
         let mut container = CommandCall {
             flag: CommandFlag::new(),
-            param1: 0,
-            param2: 0,
+            param1: Param::Value(0),
+            param2: Param::Value(0),
         };
         rangeoper(input, &mut container);
         assert_eq!(container.flag.error(), 1);
@@ -682,14 +807,14 @@ mod tests {
         let input = "A1 + B2";
         let mut container = CommandCall {
             flag: CommandFlag::new(),
-            param1: 0,
-            param2: 0,
+            param1: Param::Value(0),
+            param2: Param::Value(0),
         };
         parse_expression(input, &mut container);
         assert_eq!(container.flag.type_(), 1);
         assert_eq!(container.flag.cmd(), 0);
-        assert_eq!(container.param1, encode_cell("A1".to_string()));
-        assert_eq!(container.param2, encode_cell("B2".to_string()));
+        assert_eq!(container.param1, cell_ref("A1"));
+        assert_eq!(container.param2, cell_ref("B2"));
     }
     #[test]
     fn test_convert_to_index() {
@@ -699,23 +824,46 @@ mod tests {
         assert_eq!(col, 1);
     }
     #[test]
-    fn test_encode_cell() {
-        let cell = "A1".to_string();
-        let encoded = encode_cell(cell);
-        assert_eq!(encoded, 100001);
+    fn test_parse_large_cell() {
+        let result = parse_formula("XFD1048576+1");
+        assert_eq!(result.flag.error(), 0);
+        assert_eq!(result.param1, Param::Cell(CellAddr::new(1_048_576, 16384)));
+        assert_eq!(result.param2, Param::Value(1));
     }
     #[test]
-    fn test_decode_cell() {
-        let encoded = 100001;
-        let decoded = decode_cell(encoded);
-        assert_eq!(decoded, "A1");
+    fn test_param_display() {
+        assert_eq!(cell_ref("A1").to_string(), "A1");
+        assert_eq!(Param::Value(-7).to_string(), "-7");
+        assert_eq!(Param::External(2, CellAddr::new(7, 2)).to_string(), "2!B7");
     }
     #[test]
-    fn test_convert_to_index_int() {
-        let encoded = 100001;
-        let (row, col) = convert_to_index_int(encoded);
-        assert_eq!(row, 1);
-        assert_eq!(col, 1);
+    fn test_parse_external_references() {
+        let result = parse_formula("1!A1048576*2");
+        assert_eq!(result.flag.error(), 0);
+        assert_eq!(
+            result.param1,
+            Param::External(1, CellAddr::new(1_048_576, 1))
+        );
+        let result = parse_formula("SUM(3!A2:3!B9)");
+        assert_eq!(result.range(), None);
+        assert_eq!(
+            result.external_range(),
+            Some((3, RangeAddr::parse("A2:B9").unwrap()))
+        );
+        assert_eq!(unparse_command(&result), "SUM(3!A2:3!B9)");
+        // A range cannot span several sheets
+        assert_eq!(parse_formula("SUM(3!A1:A2)").flag.error(), 1);
+        assert_eq!(parse_formula("SUM(3!A1:4!A2)").flag.error(), 1);
+        assert_eq!(parse_formula("!A1").flag.error(), 1);
+    }
+    #[test]
+    fn test_precedents() {
+        let result = parse_formula("SUM(A1:B2)");
+        assert_eq!(result.range(), RangeAddr::parse("A1:B2"));
+        assert_eq!(result.precedents().len(), 4);
+        let result = parse_formula("A1*3");
+        assert_eq!(result.range(), None);
+        assert_eq!(result.precedents(), vec![CellAddr::new(1, 1)]);
     }
     #[test]
     fn test_unparse() {
@@ -731,8 +879,8 @@ mod tests {
         let cell = Cell {
             formula: CommandCall {
                 flag,
-                param1: encode_cell("A1".to_string()),
-                param2: encode_cell("B2".to_string()),
+                param1: cell_ref("A1"),
+                param2: cell_ref("B2"),
             },
             value: 0,
             depend: Vec::new(),
@@ -745,8 +893,8 @@ mod tests {
         let cell = Cell {
             formula: CommandCall {
                 flag: CommandFlag::new(),
-                param1: 42,
-                param2: 0,
+                param1: Param::Value(42),
+                param2: Param::Value(0),
             },
             value: 42,
             depend: Vec::new(),
@@ -759,8 +907,8 @@ mod tests {
         let input = "SUM(A1:9)";
         let mut container = CommandCall {
             flag: CommandFlag::new(),
-            param1: 0,
-            param2: 0,
+            param1: Param::Value(0),
+            param2: Param::Value(0),
         };
         rangeoper(input, &mut container);
         assert_eq!(container.flag.error(), 1);
@@ -772,8 +920,8 @@ mod tests {
         let input = "SLEEP 5";
         let mut container = CommandCall {
             flag: CommandFlag::new(),
-            param1: 0,
-            param2: 0,
+            param1: Param::Value(0),
+            param2: Param::Value(0),
         };
         parse_sleep(input, &mut container);
         assert_eq!(container.flag.error(), 1);
@@ -784,8 +932,8 @@ mod tests {
         let input = "SLEEP(A1B)";
         let mut container = CommandCall {
             flag: CommandFlag::new(),
-            param1: 0,
-            param2: 0,
+            param1: Param::Value(0),
+            param2: Param::Value(0),
         };
         parse_sleep(input, &mut container);
         assert_eq!(container.flag.error(), 1);
@@ -796,8 +944,8 @@ mod tests {
         let input = "A1B+C2";
         let mut container = CommandCall {
             flag: CommandFlag::new(),
-            param1: 0,
-            param2: 0,
+            param1: Param::Value(0),
+            param2: Param::Value(0),
         };
         Arithmatic(input, &mut container);
         assert_eq!(container.flag.error(), 1);
@@ -808,8 +956,8 @@ mod tests {
         let input = "A1+C2D";
         let mut container = CommandCall {
             flag: CommandFlag::new(),
-            param1: 0,
-            param2: 0,
+            param1: Param::Value(0),
+            param2: Param::Value(0),
         };
         Arithmatic(input, &mut container);
         assert_eq!(container.flag.error(), 1);
@@ -820,8 +968,8 @@ mod tests {
         let input = "12x+C2";
         let mut container = CommandCall {
             flag: CommandFlag::new(),
-            param1: 0,
-            param2: 0,
+            param1: Param::Value(0),
+            param2: Param::Value(0),
         };
         Arithmatic(input, &mut container);
         assert_eq!(container.flag.error(), 1);
@@ -832,8 +980,8 @@ mod tests {
         let input = "A1+34y";
         let mut container = CommandCall {
             flag: CommandFlag::new(),
-            param1: 0,
-            param2: 0,
+            param1: Param::Value(0),
+            param2: Param::Value(0),
         };
         Arithmatic(input, &mut container);
         assert_eq!(container.flag.error(), 1);
@@ -844,8 +992,8 @@ mod tests {
         let input = "A1+B2C";
         let mut container = CommandCall {
             flag: CommandFlag::new(),
-            param1: 0,
-            param2: 0,
+            param1: Param::Value(0),
+            param2: Param::Value(0),
         };
         Arithmatic(input, &mut container);
         assert_eq!(container.flag.error(), 1);
@@ -856,8 +1004,8 @@ mod tests {
         let input = "A1B2";
         let mut container = CommandCall {
             flag: CommandFlag::new(),
-            param1: 0,
-            param2: 0,
+            param1: Param::Value(0),
+            param2: Param::Value(0),
         };
         Arithmatic(input, &mut container);
         assert_eq!(container.flag.error(), 1);
@@ -868,8 +1016,8 @@ mod tests {
         let input = "SUM A1:B2";
         let mut container = CommandCall {
             flag: CommandFlag::new(),
-            param1: 0,
-            param2: 0,
+            param1: Param::Value(0),
+            param2: Param::Value(0),
         };
         rangeoper(input, &mut container);
         assert_eq!(container.flag.error(), 1);
@@ -880,8 +1028,8 @@ mod tests {
         let input = "SUM(A1;B2)"; // Using semicolon instead of colon
         let mut container = CommandCall {
             flag: CommandFlag::new(),
-            param1: 0,
-            param2: 0,
+            param1: Param::Value(0),
+            param2: Param::Value(0),
         };
         rangeoper(input, &mut container);
         assert_eq!(container.flag.error(), 1);
@@ -892,8 +1040,8 @@ mod tests {
         let input = "SUM(A0:B2)"; // Invalid A0 reference
         let mut container = CommandCall {
             flag: CommandFlag::new(),
-            param1: 0,
-            param2: 0,
+            param1: Param::Value(0),
+            param2: Param::Value(0),
         };
         rangeoper(input, &mut container);
         assert_eq!(container.flag.error(), 1);
@@ -904,8 +1052,8 @@ mod tests {
         let input = "SUM(B2:A1)"; // End cell before start cell
         let mut container = CommandCall {
             flag: CommandFlag::new(),
-            param1: 0,
-            param2: 0,
+            param1: Param::Value(0),
+            param2: Param::Value(0),
         };
         rangeoper(input, &mut container);
         assert_eq!(container.flag.error(), 1);
@@ -916,8 +1064,8 @@ mod tests {
         let input = "UNKNOWN(A1:B2)";
         let mut container = CommandCall {
             flag: CommandFlag::new(),
-            param1: 0,
-            param2: 0,
+            param1: Param::Value(0),
+            param2: Param::Value(0),
         };
         rangeoper(input, &mut container);
         assert_eq!(container.flag.error(), 1);
@@ -928,8 +1076,8 @@ mod tests {
         let input = "42x";
         let mut container = CommandCall {
             flag: CommandFlag::new(),
-            param1: 0,
-            param2: 0,
+            param1: Param::Value(0),
+            param2: Param::Value(0),
         };
         parse_expression(input, &mut container);
         assert_eq!(container.flag.error(), 1);
@@ -940,12 +1088,12 @@ mod tests {
         let input = "-42";
         let mut container = CommandCall {
             flag: CommandFlag::new(),
-            param1: 0,
-            param2: 0,
+            param1: Param::Value(0),
+            param2: Param::Value(0),
         };
         parse_expression(input, &mut container);
         assert_eq!(container.flag.type_(), 0);
-        assert_eq!(container.param1, -42);
+        assert_eq!(container.param1, Param::Value(-42));
     }
 
     #[test]
@@ -953,12 +1101,12 @@ mod tests {
         let input = "+42";
         let mut container = CommandCall {
             flag: CommandFlag::new(),
-            param1: 0,
-            param2: 0,
+            param1: Param::Value(0),
+            param2: Param::Value(0),
         };
         parse_expression(input, &mut container);
         assert_eq!(container.flag.type_(), 0);
-        assert_eq!(container.param1, 42);
+        assert_eq!(container.param1, Param::Value(42));
     }
 
     #[test]
@@ -966,8 +1114,8 @@ mod tests {
         let input = "A1B";
         let mut container = CommandCall {
             flag: CommandFlag::new(),
-            param1: 0,
-            param2: 0,
+            param1: Param::Value(0),
+            param2: Param::Value(0),
         };
         parse_expression(input, &mut container);
         assert_eq!(container.flag.error(), 1);
@@ -978,8 +1126,8 @@ mod tests {
         let input = "A_1";
         let mut container = CommandCall {
             flag: CommandFlag::new(),
-            param1: 0,
-            param2: 0,
+            param1: Param::Value(0),
+            param2: Param::Value(0),
         };
         parse_expression(input, &mut container);
         assert_eq!(container.flag.error(), 1);
@@ -1022,34 +1170,24 @@ mod tests {
     }
 
     #[test]
-    fn test_decode_cell_zero_column() {
-        let result = decode_cell(100000); // col=0, row=1
-        assert_eq!(result, "");
+    fn test_parse_expression_zero_row() {
+        let result = parse_formula("A0");
+        assert_eq!(result.flag.error(), 1);
     }
 
     #[test]
-    fn test_decode_cell_complex_column() {
+    fn test_unparse_complex_column() {
         // Test with column values that require multiple letters
-        let result = decode_cell(102701);
-        assert_eq!(result, "CYW1");
-
-        let result = decode_cell(107701);
-        assert_eq!(result, "KJE1");
-
-        let result = decode_cell(102726);
-        assert_eq!(result, "CZV1");
+        assert_eq!(CellAddr::new(1, 2701).to_string(), "CYW1");
+        assert_eq!(CellAddr::new(1, 7701).to_string(), "KJE1");
+        assert_eq!(CellAddr::new(1, 2726).to_string(), "CZV1");
     }
 
     #[test]
-    fn test_decode_cell_multiples_of_26() {
-        let result = decode_cell(100026); // Column Z
-        assert_eq!(result, "Z1");
-
-        let result = decode_cell(100052); // Column AZ
-        assert_eq!(result, "AZ1");
-
-        let result = decode_cell(100078); // Column BZ
-        assert_eq!(result, "BZ1");
+    fn test_unparse_multiples_of_26() {
+        assert_eq!(CellAddr::new(1, 26).to_string(), "Z1"); // Column Z
+        assert_eq!(CellAddr::new(1, 52).to_string(), "AZ1"); // Column AZ
+        assert_eq!(CellAddr::new(1, 78).to_string(), "BZ1"); // Column BZ
     }
 
     #[test]
@@ -1063,8 +1201,8 @@ mod tests {
         let cell = Cell {
             formula: CommandCall {
                 flag,
-                param1: encode_cell("A1".to_string()),
-                param2: encode_cell("B2".to_string()),
+                param1: cell_ref("A1"),
+                param2: cell_ref("B2"),
             },
             value: 0,
             depend: Vec::new(),
@@ -1084,8 +1222,8 @@ mod tests {
         let cell = Cell {
             formula: CommandCall {
                 flag,
-                param1: 5,
-                param2: 0,
+                param1: Param::Value(5),
+                param2: Param::Value(0),
             },
             value: 0,
             depend: Vec::new(),
@@ -1105,8 +1243,8 @@ mod tests {
         let cell = Cell {
             formula: CommandCall {
                 flag,
-                param1: encode_cell("A1".to_string()),
-                param2: 0,
+                param1: cell_ref("A1"),
+                param2: Param::Value(0),
             },
             value: 0,
             depend: Vec::new(),
@@ -1119,13 +1257,14 @@ mod tests {
     #[test]
     fn test_unparse_unknown_type() {
         let mut flag = CommandFlag::new();
-        flag.set_type_(3); // Invalid type
+        flag.set_type_(3);
+        flag.set_cmd(7); // No SUBTOTAL function has this code
 
         let cell = Cell {
             formula: CommandCall {
                 flag,
-                param1: 0,
-                param2: 0,
+                param1: Param::Value(0),
+                param2: Param::Value(0),
             },
             value: 0,
             depend: Vec::new(),
@@ -1146,8 +1285,8 @@ mod tests {
         let cell = Cell {
             formula: CommandCall {
                 flag,
-                param1: encode_cell("A1".to_string()),
-                param2: encode_cell("B2".to_string()),
+                param1: cell_ref("A1"),
+                param2: cell_ref("B2"),
             },
             value: 0,
             depend: Vec::new(),
@@ -1169,8 +1308,8 @@ mod tests {
         let cell = Cell {
             formula: CommandCall {
                 flag,
-                param1: encode_cell("A1".to_string()),
-                param2: encode_cell("B2".to_string()),
+                param1: cell_ref("A1"),
+                param2: cell_ref("B2"),
             },
             value: 0,
             depend: Vec::new(),
@@ -0,0 +1,242 @@
+//! Spatial index of the ranges read by range functions.
+//!
+//! Instead of adding a formula like `SUM(A1:Z1000)` to the dependent list of
+//! each of its 26,000 cells, the range is recorded once and registered in the
+//! fixed-size blocks of the sheet it overlaps. Finding the formulas that read
+//! a changed cell then only looks at the ranges registered in that cell's block.
+//!
+//! Each range also keeps the running totals of its cells once a range function
+//! has been evaluated over it, see `aggregate`.
+
+use crate::addr::{CellAddr, RangeAddr};
+use crate::aggregate::Aggregate;
+use fxhash::FxHashMap;
+
+/// Number of rows covered by one block of the index.
+pub(crate) const BLOCK_ROWS: u32 = 256;
+/// Number of columns covered by one block of the index.
+const BLOCK_COLS: u32 = 16;
+/// Ranges overlapping more blocks than this are kept in a separate list that
+/// is checked on every lookup, so huge ranges do not flood the blocks.
+const MAX_BLOCKS_PER_RANGE: u64 = 4096;
+
+/// What the index records about one range.
+#[derive(Clone, Default)]
+struct Entry {
+    /// Cells reading the range, in the order they were added
+    dependents: Vec<CellAddr>,
+    /// Running totals of the range, once they were computed
+    aggregate: Option<Aggregate>,
+}
+
+/// Index from ranges to the cells whose formulas read them.
+#[derive(Clone, Default)]
+pub struct RangeIndex {
+    /// Dependents and totals of each range
+    entries: FxHashMap<RangeAddr, Entry>,
+    /// Ranges overlapping each block, keyed by block row and block column
+    blocks: FxHashMap<(u32, u32), Vec<RangeAddr>>,
+    /// Ranges too large to be registered block by block
+    wide: Vec<RangeAddr>,
+}
+
+impl RangeIndex {
+    /// Creates an empty index.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Records that the formula of `dependent` reads `range`.
+    ///
+    /// # Returns
+    /// `false` if that was already recorded.
+    pub fn insert(&mut self, range: RangeAddr, dependent: CellAddr) -> bool {
+        let dependents = &mut self.entries.entry(range).or_default().dependents;
+        if dependents.is_empty() {
+            if Self::block_count(range) > MAX_BLOCKS_PER_RANGE {
+                self.wide.push(range);
+            } else {
+                for block in Self::blocks(range) {
+                    self.blocks.entry(block).or_default().push(range);
+                }
+            }
+        }
+        if dependents.contains(&dependent) {
+            return false;
+        }
+        dependents.push(dependent);
+        true
+    }
+
+    /// Removes the record that the formula of `dependent` reads `range`.
+    ///
+    /// # Returns
+    /// `false` if that was not recorded.
+    pub fn remove(&mut self, range: RangeAddr, dependent: CellAddr) -> bool {
+        let Some(entry) = self.entries.get_mut(&range) else {
+            return false;
+        };
+        let count = entry.dependents.len();
+        entry.dependents.retain(|&x| x != dependent);
+        if entry.dependents.len() == count {
+            return false;
+        }
+        if !entry.dependents.is_empty() {
+            return true;
+        }
+        self.entries.remove(&range);
+        if Self::block_count(range) > MAX_BLOCKS_PER_RANGE {
+            self.wide.retain(|&x| x != range);
+        } else {
+            for block in Self::blocks(range) {
+                if let Some(ranges) = self.blocks.get_mut(&block) {
+                    ranges.retain(|&x| x != range);
+                    if ranges.is_empty() {
+                        self.blocks.remove(&block);
+                    }
+                }
+            }
+        }
+        true
+    }
+
+    /// Iterates over the cells whose formulas read a range containing `addr`.
+    pub fn dependents_of(&self, addr: CellAddr) -> impl Iterator<Item = CellAddr> + '_ {
+        self.ranges_containing(addr)
+            .flat_map(move |range| self.entries[range].dependents.iter().copied())
+    }
+
+    /// Iterates over the recorded ranges containing `addr`.
+    fn ranges_containing(&self, addr: CellAddr) -> impl Iterator<Item = &RangeAddr> {
+        let block = (addr.row / BLOCK_ROWS, addr.col / BLOCK_COLS);
+        self.blocks
+            .get(&block)
+            .into_iter()
+            .flatten()
+            .chain(self.wide.iter())
+            .filter(move |range| range.contains(addr))
+    }
+
+    /// Returns the running totals of a range, if they were computed.
+    pub fn aggregate(&self, range: RangeAddr) -> Option<&Aggregate> {
+        self.entries.get(&range)?.aggregate.as_ref()
+    }
+
+    /// Stores freshly scanned totals for a recorded range.
+    pub fn set_aggregate(&mut self, range: RangeAddr, aggregate: Aggregate) {
+        if let Some(entry) = self.entries.get_mut(&range) {
+            entry.aggregate = Some(aggregate);
+        }
+    }
+
+    /// Adjusts the totals of every range containing `addr` after its value changed.
+    ///
+    /// # Parameters
+    /// * `addr` - The changed cell
+    /// * `before` - The previous value, or `None` if the cell held an error
+    /// * `after` - The new value, or `None` if the cell holds an error
+    pub fn update_aggregates(&mut self, addr: CellAddr, before: Option<i32>, after: Option<i32>) {
+        if before == after {
+            return;
+        }
+        let Self {
+            entries,
+            blocks,
+            wide,
+        } = self;
+        let block = (addr.row / BLOCK_ROWS, addr.col / BLOCK_COLS);
+        let ranges = blocks.get(&block).into_iter().flatten().chain(wide.iter());
+        for range in ranges.filter(|range| range.contains(addr)) {
+            if let Some(aggregate) = entries
+                .get_mut(range)
+                .and_then(|entry| entry.aggregate.as_mut())
+            {
+                aggregate.update(before, after);
+            }
+        }
+    }
+
+    /// Number of distinct ranges in the index.
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Returns `true` if no range is recorded.
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Removes every range.
+    pub fn clear(&mut self) {
+        self.entries.clear();
+        self.blocks.clear();
+        self.wide.clear();
+    }
+
+    /// Number of blocks a range overlaps.
+    fn block_count(range: RangeAddr) -> u64 {
+        let rows = (range.end.row / BLOCK_ROWS - range.start.row / BLOCK_ROWS + 1) as u64;
+        let cols = (range.end.col / BLOCK_COLS - range.start.col / BLOCK_COLS + 1) as u64;
+        rows * cols
+    }
+
+    /// Iterates over the blocks a range overlaps.
+    fn blocks(range: RangeAddr) -> impl Iterator<Item = (u32, u32)> {
+        let cols = range.start.col / BLOCK_COLS..=range.end.col / BLOCK_COLS;
+        (range.start.row / BLOCK_ROWS..=range.end.row / BLOCK_ROWS)
+            .flat_map(move |row| cols.clone().map(move |col| (row, col)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn range(text: &str) -> RangeAddr {
+        RangeAddr::parse(text).unwrap()
+    }
+
+    #[test]
+    fn test_lookup() {
+        let mut index = RangeIndex::new();
+        let a10 = CellAddr::new(10, 1);
+        let b1 = CellAddr::new(1, 2);
+        index.insert(range("A1:Z1000"), a10);
+        index.insert(range("A1:Z1000"), b1);
+        index.insert(range("C5:C6"), a10);
+        assert_eq!(index.len(), 2);
+
+        let found: Vec<CellAddr> = index.dependents_of(CellAddr::new(500, 26)).collect();
+        assert_eq!(found, vec![a10, b1]);
+        let found: Vec<CellAddr> = index.dependents_of(CellAddr::new(5, 3)).collect();
+        assert_eq!(found.len(), 3);
+        assert_eq!(index.dependents_of(CellAddr::new(1001, 1)).count(), 0);
+        assert_eq!(index.dependents_of(CellAddr::new(1, 27)).count(), 0);
+    }
+
+    #[test]
+    fn test_remove() {
+        let mut index = RangeIndex::new();
+        let a10 = CellAddr::new(10, 1);
+        index.insert(range("A1:B2"), a10);
+        index.insert(range("A1:B2"), a10);
+        index.remove(range("A1:B2"), a10);
+        assert!(index.is_empty());
+        assert_eq!(index.dependents_of(CellAddr::new(1, 1)).count(), 0);
+        assert!(index.blocks.is_empty());
+    }
+
+    #[test]
+    fn test_wide_range() {
+        let mut index = RangeIndex::new();
+        let a1 = CellAddr::new(1, 1);
+        index.insert(range("B1:XFD1048576"), a1);
+        assert!(index.blocks.is_empty());
+        assert_eq!(
+            index.dependents_of(CellAddr::new(1_048_576, 16384)).count(),
+            1
+        );
+        index.remove(range("B1:XFD1048576"), a1);
+        assert!(index.wide.is_empty());
+    }
+}
@@ -5,8 +5,7 @@
 //! only loads cell values, treating all cells as constants without formulas.
 //! This is useful for importing data from other spreadsheet applications.
 
-use crate::parse::CommandFlag;
-use crate::{parse::CommandCall, sheet::*};
+use crate::sheet::*;
 
 impl Sheet {
     /// Imports spreadsheet data from a CSV file
@@ -42,19 +41,11 @@ impl Sheet {
     /// - Empty cells in the CSV file remain as 0 in the sheet
     /// - All imported cells are treated as constants (no formulas)
     pub fn read_csv_file(&mut self, filename: &str) -> Result<(), std::io::Error> {
-        for i in 0..self.row {
-            for j in 0..self.col {
-                self.grid[i][j] = Cell {
-                    value: 0,
-                    formula: CommandCall {
-                        flag: CommandFlag::new(),
-                        param1: 0,
-                        param2: 0,
-                    },
-                    depend: Vec::new(),
-                };
-            }
-        }
+        self.grid.clear();
+        self.filter = None;
+        self.clear_history();
+        self.ranges.clear();
+        self.shared.clear();
         let file = std::fs::File::open(filename)?;
         let mut reader = csv::Reader::from_reader(file);
         let mut row = 1;
@@ -71,7 +62,7 @@ impl Sheet {
                 if col >= self.col {
                     break;
                 }
-                self.grid[row][col].value = item.parse::<i32>().unwrap();
+                self.grid[(row, col)].value = item.parse::<i32>().unwrap();
             }
             row += 1;
         }
@@ -87,6 +78,6 @@ mod tests {
     fn test_read_csv_file() {
         let mut test_sheet = Sheet::new(6, 6);
         test_sheet.read_csv_file("../temp/test_output.csv").unwrap();
-        assert!(test_sheet.grid[1][1].value == 10);
+        assert!(test_sheet.grid[(1, 1)].value == 10);
     }
 }
@@ -4,31 +4,52 @@
 //! It allows loading a complete spreadsheet state, including cell values, formulas,
 //! and dependencies from a .ss file.
 
-use crate::parse::CommandFlag;
+use crate::addr::{CellAddr, RangeAddr};
+use crate::comments::{Comment, Notes};
+use crate::conditional::Rule;
+use crate::parse::{CommandFlag, Param};
+use crate::style::{Format, Formats};
+use crate::validation::Validation;
 use crate::{parse::CommandCall, sheet::*};
 use serde::{self, Deserialize};
 
+/// Shift older .ss files used to pack a formula reference into `param1`/`param2`.
+const LEGACY_PARAM_SHIFT: u32 = 100000;
+/// Shift older .ss files used to pack a dependent cell into the `depend` list.
+const LEGACY_DEPEND_SHIFT: u32 = 10000;
+
 /// Temporary structure for deserializing .ss records.
 ///
 /// This structure maps directly to the .ss file columns and is used as an
 /// intermediate step before converting to the Cell structure.
 #[derive(Debug, Deserialize)]
-struct TempRecord {
-    /// Row index of the cell
-    row: i32,
+pub(crate) struct TempRecord {
+    /// Row index of the cell; 0 starts a sheet of a workbook
+    pub(crate) row: u32,
     /// Column index of the cell
-    col: i32,
-    /// Calculated value of the cell
-    value: i32,
+    col: u32,
+    /// Calculated value of the cell, or the id of the sheet a record of row 0 starts
+    pub(crate) value: i32,
     /// String representation of the CommandFlag bitfield
     /// Format: "type:X,cmd:Y,type1:Z,..."
     flag: String,
-    /// First parameter of the cell's formula
-    param1: i32,
-    /// Second parameter of the cell's formula
-    param2: i32,
+    /// First parameter of the cell's formula (a number or a cell name)
+    param1: String,
+    /// Second parameter of the cell's formula (a number or a cell name)
+    param2: String,
     /// Comma-separated list of cell dependencies
     depend: String,
+    /// Name of the sheet of the cell; only workbook files have this column
+    #[serde(default)]
+    pub(crate) sheet: String,
+}
+
+/// Reads every record of a .ss file.
+pub(crate) fn read_ss_records(
+    file_path: &str,
+) -> Result<Vec<TempRecord>, Box<dyn std::error::Error>> {
+    let mut rdr = csv::Reader::from_path(file_path)?;
+    Ok(rdr.deserialize().collect::<Result<Vec<TempRecord>, _>>()?)
 }
 
 impl Sheet {
@@ -51,48 +72,111 @@ impl Sheet {
     /// - col: Column index (0-based)
     /// - value: The calculated cell value
     /// - flag: String encoding of the CommandFlag bitfield (comma-separated key-value pairs)
-    /// - param1: First parameter of the cell formula
-    /// - param2: Second parameter of the cell formula
-    /// - depend: Comma-separated list of cell indices that depend on this cell
+    /// - param1: First parameter of the cell formula, a number or a cell name such as `B7`,
+    ///   which is preceded by the id of its sheet if it lies on another sheet, as in `2!B7`
+    /// - param2: Second parameter of the cell formula, a number or a cell name
+    /// - depend: Comma-separated list of the names of the cells that depend on this cell
+    ///
+    /// A flag of `shared:<anchor>` marks a cell whose formula is the anchor's,
+    /// shifted down to the cell, a flag of `format` a formatted range, a flag of
+    /// `rule` a conditional formatting rule, a flag of `validation` a validation
+    /// and a flag of `note` or `comment` a note or comment of a cell. Files written before cell names were used,
+    /// which store references as packed integers, are still accepted. Files
+    /// of a whole workbook are read with `Workbook::read_file`.
     pub fn read_file(&mut self, file_path: &str) -> Result<(), Box<dyn std::error::Error>> {
-        let mut rdr = csv::Reader::from_path(file_path)?;
+        let records = read_ss_records(file_path)?;
+        self.read_records(records)
+    }
 
+    /// Replaces the cells of the sheet with those of the given .ss records.
+    pub(crate) fn read_records(
+        &mut self,
+        records: Vec<TempRecord>,
+    ) -> Result<(), Box<dyn std::error::Error>> {
         // Reset the current sheet state
-        for i in 0..self.row {
-            for j in 0..self.col {
-                self.grid[i][j] = Cell {
-                    value: 0,
-                    formula: CommandCall {
-                        flag: CommandFlag::new(),
-                        param1: 0,
-                        param2: 0,
-                    },
-                    depend: Vec::new(),
-                };
-            }
-        }
+        self.grid.clear();
+        self.formats = Formats::default();
+        self.rules.clear();
+        self.validations.clear();
+        self.notes = Notes::default();
+        self.filter = None;
+        self.iteration = None;
+        self.diverged.clear();
+        self.clear_history();
+        let mut shared: Vec<(CellAddr, CellAddr)> = Vec::new();
 
         // Read and process each record from the .ss file
-        for result in rdr.deserialize() {
-            let record: TempRecord = result?;
+        for record in records {
+            // Records starting the sheets of a workbook hold no cell
+            if record.row == 0 {
+                continue;
+            }
+            if record.flag == "format" {
+                let range = RangeAddr::parse(&format!("{}:{}", record.param1, record.param2))
+                    .ok_or_else(|| format!("invalid formatted range {}", record.param1))?;
+                let format = record
+                    .depend
+                    .parse::<Format>()
+                    .map_err(|_| format!("invalid format '{}'", record.depend))?;
+                self.formats.ranges.push((range, format));
+                continue;
+            }
+            if record.flag == "rule" {
+                let range = RangeAddr::parse(&format!("{}:{}", record.param1, record.param2))
+                    .ok_or_else(|| format!("invalid rule range {}", record.param1))?;
+                record
+                    .depend
+                    .parse::<Rule>()
+                    .and_then(|rule| self.add_rule(range, rule))
+                    .map_err(|_| format!("invalid rule '{}'", record.depend))?;
+                continue;
+            }
+            if record.flag == "validation" {
+                let range = RangeAddr::parse(&format!("{}:{}", record.param1, record.param2))
+                    .ok_or_else(|| format!("invalid validation range {}", record.param1))?;
+                record
+                    .depend
+                    .parse::<Validation>()
+                    .and_then(|validation| self.add_validation(range, validation))
+                    .map_err(|_| format!("invalid validation '{}'", record.depend))?;
+                continue;
+            }
+            if record.flag == "note" || record.flag == "comment" {
+                let addr = CellAddr::new(record.row, record.col);
+                let comment = Comment {
+                    author: record.param1,
+                    time: record
+                        .param2
+                        .parse()
+                        .map_err(|_| format!("invalid time '{}' of a note", record.param2))?,
+                    text: record.depend,
+                };
+                match record.flag.as_str() {
+                    "note" => self.set_note(addr, comment),
+                    _ => self.add_comment(addr, comment),
+                }
+                .map_err(|_| format!("note outside the sheet at {}", addr))?;
+                continue;
+            }
             let mut new_cell = Cell {
                 value: record.value,
                 formula: CommandCall {
                     flag: CommandFlag::new(),
-                    param1: record.param1,
-                    param2: record.param2,
+                    param1: Param::Value(0),
+                    param2: Param::Value(0),
                 },
                 depend: Vec::new(),
             };
             new_cell.value = record.value;
-            new_cell.formula.param1 = record.param1;
-            new_cell.formula.param2 = record.param2;
 
             // Parse the flag string and set the appropriate flags
+            let addr = CellAddr::new(record.row, record.col);
+            let mut anchor = None;
             let flag_parts: Vec<&str> = record.flag.split(",").collect();
             for i in flag_parts {
                 if let [flag_type, value_of_flag] = i.split(":").collect::<Vec<&str>>().as_slice() {
                     match *flag_type {
+                        "shared" => anchor = CellAddr::parse(value_of_flag),
                         "type" => new_cell
                             .formula
                             .flag
@@ -117,30 +201,102 @@ impl Sheet {
                             .formula
                             .flag
                             .set_is_div_by_zero(value_of_flag.parse::<u8>().unwrap()),
+                        "overflow" => new_cell
+                            .formula
+                            .flag
+                            .set_is_overflow(value_of_flag.parse::<u8>().unwrap()),
+                        "timeout" => new_cell
+                            .formula
+                            .flag
+                            .set_is_timeout(value_of_flag.parse::<u8>().unwrap()),
+                        "ref" => new_cell
+                            .formula
+                            .flag
+                            .set_is_ref(value_of_flag.parse::<u8>().unwrap()),
+                        "plain" => new_cell
+                            .formula
+                            .flag
+                            .set_is_plain_code(value_of_flag.parse::<u8>().unwrap()),
                         _ => {}
                     }
                 }
             }
 
+            // Only cells that were edited are written, so mark the cell as such again
+            new_cell.formula.flag.set_is_any(1);
+
+            // The reference bits of the flag tell how to read the parameters
+            if let Some(anchor) = anchor {
+                shared.push((addr, anchor));
+            } else {
+                new_cell.formula.param1 =
+                    read_param(&record.param1, new_cell.formula.flag.type1() == 1)?;
+                new_cell.formula.param2 =
+                    read_param(&record.param2, new_cell.formula.flag.type2() == 1)?;
+            }
+
             // Parse and set cell dependencies
             if record.depend.is_empty() {
                 new_cell.depend = Vec::new();
             } else {
                 let depend_parts: Vec<&str> = record.depend.split(",").collect();
                 for i in depend_parts {
-                    if let Ok(index) = i.parse::<usize>() {
-                        new_cell.depend.push(index);
+                    if let Some(addr) = CellAddr::parse(i) {
+                        new_cell.depend.push(addr);
+                    } else if let Ok(index) = i.parse::<u32>() {
+                        new_cell.depend.push(CellAddr::new(
+                            index / LEGACY_DEPEND_SHIFT,
+                            index % LEGACY_DEPEND_SHIFT,
+                        ));
                     }
                 }
             }
 
             // Update the cell in the grid
-            self.grid[record.row as usize][record.col as usize] = new_cell;
+            self.grid
+                .insert(record.row as usize, record.col as usize, new_cell);
         }
+
+        // Expand the formulas shared with an anchor
+        for (addr, anchor) in shared {
+            let template = self.grid[anchor].formula.clone();
+            let mut formula = template
+                .shifted(addr.row as i64 - anchor.row as i64, 0)
+                .filter(|_| anchor.col == addr.col && anchor.row < addr.row)
+                .ok_or_else(|| format!("invalid shared formula anchor {} for {}", anchor, addr))?;
+            formula.flag.copy_status(&self.grid[addr].formula.flag);
+            self.grid[addr].formula = formula;
+        }
+
+        // Derive the dependencies from the formulas, whatever the file recorded
+        self.rebuild_dependencies();
         Ok(())
     }
 }
 
+/// Reads one formula parameter of a .ss record.
+///
+/// # Parameters
+/// * `text` - The stored parameter
+/// * `is_ref` - Whether the flag marks the parameter as a cell reference
+fn read_param(text: &str, is_ref: bool) -> Result<Param, Box<dyn std::error::Error>> {
+    let text = text.trim();
+    if let Some(reference) = Param::parse_ref(text) {
+        return Ok(reference);
+    }
+    let value = text
+        .parse::<i32>()
+        .map_err(|_| format!("invalid formula parameter '{}'", text))?;
+    if is_ref {
+        let value = value as u32;
+        return Ok(Param::Cell(CellAddr::new(
+            value / LEGACY_PARAM_SHIFT,
+            value % LEGACY_PARAM_SHIFT,
+        )));
+    }
+    Ok(Param::Value(value))
+}
+
 #[test]
 fn test_read_ss() {
     let mut test_sheet = Sheet::new(6, 6);
@@ -162,3 +318,168 @@ fn test_read_ss() {
     assert_eq!(new_sheet.get_value(2, 2), 500);
     assert_eq!(new_sheet.get_value(1, 1), 31);
 }
+
+#[test]
+fn test_read_ss_legacy_references() {
+    let path = std::env::temp_dir().join("cores_legacy_references.ss");
+    std::fs::write(
+        &path,
+        "row,col,value,flag,param1,param2,depend\n\
+         1,1,7,\"type:0,cmd:0,type1:0,type2:0,error:0,div_by_zero:0\",7,0,20001\n\
+         2,1,8,\"type:1,cmd:0,type1:1,type2:0,error:0,div_by_zero:0\",100001,1,\n",
+    )
+    .unwrap();
+    let mut test_sheet = Sheet::new(6, 6);
+    test_sheet.read_file(path.to_str().unwrap()).unwrap();
+    assert_eq!(test_sheet.get_formula(2, 1), "A1+1");
+    assert_eq!(test_sheet.grid[(1, 1)].depend, vec![CellAddr::new(2, 1)]);
+    test_sheet.update_cell_data(1, 1, String::from("10"));
+    assert_eq!(test_sheet.get_value(2, 1), 11);
+}
+
+#[test]
+fn test_ss_round_trip_large_sheet() {
+    let path = std::env::temp_dir().join("cores_large_sheet.ss");
+    let mut test_sheet = Sheet::new(1_048_576, 16384);
+    test_sheet.update_cell_data(1_048_576, 16384, String::from("5"));
+    test_sheet.update_cell_data(1, 1, String::from("XFD1048576*2"));
+    test_sheet.write_file(path.to_str().unwrap()).unwrap();
+
+    let mut loaded = Sheet::new(1_048_576, 16384);
+    loaded.read_file(path.to_str().unwrap()).unwrap();
+    assert_eq!(loaded.get_formula(1, 1), "XFD1048576*2");
+    assert_eq!(loaded.get_value(1, 1), 10);
+    loaded.update_cell_data(1_048_576, 16384, String::from("6"));
+    assert_eq!(loaded.get_value(1, 1), 12);
+}
+
+#[test]
+fn test_ss_round_trip_shared_formulas() {
+    let path = std::env::temp_dir().join("cores_shared_formulas.ss");
+    let mut test_sheet = Sheet::new(2000, 10);
+    test_sheet.transaction(|batch| {
+        batch.set(1, 1, "3");
+        for row in 2..=1000 {
+            batch.set(row, 1, format!("A{}+1", row - 1));
+            batch.set(row, 2, format!("MAX(A{}:A{})", row - 1, row));
+        }
+        batch.set(500, 1, "1/0");
+    });
+    test_sheet.write_file(path.to_str().unwrap()).unwrap();
+    let contents = std::fs::read_to_string(&path).unwrap();
+    assert_eq!(contents.matches("shared:").count(), 997 + 997);
+    assert!(contents.contains("\"shared:A501,div_by_zero:1\""));
+
+    let mut loaded = Sheet::new(2000, 10);
+    loaded.read_file(path.to_str().unwrap()).unwrap();
+    assert_eq!(
+        loaded.shared_formula_count(),
+        test_sheet.shared_formula_count()
+    );
+    for row in 1..=1000 {
+        for col in 1..=2 {
+            assert_eq!(
+                loaded.get_formula(row, col),
+                test_sheet.get_formula(row, col)
+            );
+            assert_eq!(
+                loaded.get_value(row as i32, col as i32),
+                test_sheet.get_value(row as i32, col as i32)
+            );
+            assert_eq!(
+                loaded.get_error(row as i32, col as i32),
+                test_sheet.get_error(row as i32, col as i32)
+            );
+        }
+    }
+    loaded.update_cell_data(500, 1, String::from("0"));
+    assert_eq!(loaded.get_value(1000, 1), 500);
+    assert_eq!(loaded.get_value(1000, 2), 500);
+}
+
+#[test]
+fn test_ss_round_trip_formats() {
+    let path = std::env::temp_dir().join("cores_formats.ss");
+    let mut test_sheet = Sheet::new(10, 10);
+    test_sheet.update_cell_data(2, 2, String::from("1500"));
+    let formats = Format::parse_list("currency:$:2,bold,fill:#ffcc00,border:top+left").unwrap();
+    test_sheet
+        .set_format(RangeAddr::parse("B1:B10").unwrap(), &formats)
+        .unwrap();
+    test_sheet
+        .set_format(RangeAddr::parse("B2:C2").unwrap(), &[Format::Bold(false)])
+        .unwrap();
+    test_sheet.write_file(path.to_str().unwrap()).unwrap();
+
+    let mut loaded = Sheet::new(10, 10);
+    loaded.read_file(path.to_str().unwrap()).unwrap();
+    assert_eq!(loaded.format_count(), 5);
+    for (row, col) in [(2, 2), (3, 2), (2, 3), (2, 4)] {
+        assert_eq!(loaded.style(row, col), test_sheet.style(row, col));
+    }
+    assert_eq!(loaded.formatted_value(2, 2), "$1,500.00");
+}
+
+#[test]
+fn test_ss_round_trip_rules() {
+    let path = std::env::temp_dir().join("cores_rules.ss");
+    let mut test_sheet = Sheet::new(10, 10);
+    test_sheet.update_cell_data(1, 1, String::from("7"));
+    test_sheet.update_cell_data(2, 1, String::from("3"));
+    let range = RangeAddr::parse("A1:A5").unwrap();
+    for rule in [
+        "gt 5 then fill:#ffc7ce,color:#9c0006",
+        "formula A1-3 then italic:on",
+        "bar #638ec6",
+    ] {
+        test_sheet.add_rule(range, rule.parse().unwrap()).unwrap();
+    }
+    test_sheet.write_file(path.to_str().unwrap()).unwrap();
+
+    let mut loaded = Sheet::new(10, 10);
+    loaded.read_file(path.to_str().unwrap()).unwrap();
+    assert_eq!(loaded.rules(), test_sheet.rules());
+    assert_eq!(loaded.highlights(range), test_sheet.highlights(range));
+    assert!(!loaded.display_style(2, 1).italic);
+}
+
+#[test]
+fn test_ss_round_trip_validations() {
+    let path = std::env::temp_dir().join("cores_validations.ss");
+    let mut test_sheet = Sheet::new(10, 10);
+    test_sheet.update_cell_data(1, 2, String::from("4"));
+    let range = RangeAddr::parse("A1:A5").unwrap();
+    for validation in ["list B1:B3", "warn length 2", "formula A1-1"] {
+        test_sheet
+            .add_validation(range, validation.parse().unwrap())
+            .unwrap();
+    }
+    test_sheet.write_file(path.to_str().unwrap()).unwrap();
+
+    let mut loaded = Sheet::new(10, 10);
+    loaded.read_file(path.to_str().unwrap()).unwrap();
+    assert_eq!(loaded.validations(), test_sheet.validations());
+    assert_eq!(
+        loaded.update_cell_data(1, 1, String::from("1")).error,
+        Error::ValidationFailed
+    );
+}
+
+#[test]
+fn test_ss_round_trip_notes() {
+    let path = std::env::temp_dir().join("cores_notes.ss");
+    let mut test_sheet = Sheet::new(10, 10);
+    let cell = CellAddr::new(3, 2);
+    test_sheet
+        .set_note(cell, Comment::new("ana", "Totals, \"rounded\"\nto cents"))
+        .unwrap();
+    test_sheet
+        .add_comment(cell, Comment::new("raj", "Checked"))
+        .unwrap();
+    test_sheet.write_file(path.to_str().unwrap()).unwrap();
+
+    let mut loaded = Sheet::new(10, 10);
+    loaded.read_file(path.to_str().unwrap()).unwrap();
+    assert_eq!(loaded.annotations(cell), test_sheet.annotations(cell));
+    assert_eq!(loaded.annotated_cells(), vec![cell]);
+}
@@ -0,0 +1,182 @@
+//! Parallel recalculation of dependency levels.
+//!
+//! The cells to recalculate are grouped into topological levels: a cell's
+//! level is one more than the highest level among the cells it reads. Cells of
+//! the same level never read each other, so a level can be evaluated on many
+//! threads against an unchanged sheet. The results are stored afterwards in a
+//! fixed order, which keeps recalculation deterministic.
+
+use crate::addr::CellAddr;
+use crate::eval::EvalOptions;
+use crate::sheet::{Error, Sheet};
+use fxhash::FxHashMap;
+use std::sync::OnceLock;
+use std::thread;
+use std::time;
+
+/// Smallest level that is split across threads; smaller levels are evaluated
+/// on the calling thread because spawning would cost more than it saves.
+const PARALLEL_THRESHOLD: usize = 512;
+
+/// Number of cores, looked up once: long chains evaluate one level per cell,
+/// and asking the system every time costs more than evaluating the cell.
+fn available_threads() -> usize {
+    static THREADS: OnceLock<usize> = OnceLock::new();
+    *THREADS.get_or_init(|| thread::available_parallelism().map_or(1, |threads| threads.get()))
+}
+
+impl Sheet {
+    /// Groups cells in topological order into levels of independent cells.
+    ///
+    /// # Parameters
+    /// * `order` - Cells in dependency order, as returned by `toposort_all`
+    ///
+    /// # Returns
+    /// The levels in evaluation order; each level keeps the order of `order`.
+    pub(crate) fn levels(&self, order: &[CellAddr]) -> Vec<Vec<CellAddr>> {
+        let mut level_of: FxHashMap<CellAddr, usize> =
+            order.iter().map(|&addr| (addr, 0)).collect();
+        let mut levels: Vec<Vec<CellAddr>> = Vec::new();
+        for &addr in order {
+            let level = level_of[&addr];
+            for dep in self.dependents_of(addr) {
+                if let Some(dep_level) = level_of.get_mut(&dep) {
+                    *dep_level = (*dep_level).max(level + 1);
+                }
+            }
+            if levels.len() <= level {
+                levels.resize_with(level + 1, Vec::new);
+            }
+            levels[level].push(addr);
+        }
+        levels
+    }
+
+    /// Evaluates the cells of one level, in parallel if the level is large.
+    ///
+    /// # Returns
+    /// The result of every cell, in the order of `cells`.
+    pub(crate) fn evaluate_level(
+        &self,
+        cells: &[CellAddr],
+        options: &EvalOptions,
+    ) -> Vec<Result<i32, Error>> {
+        let threads = options.threads.unwrap_or_else(available_threads);
+        if threads <= 1 || cells.len() < PARALLEL_THRESHOLD {
+            return cells
+                .iter()
+                .map(|&addr| self.evaluate_timed(addr, options))
+                .collect();
+        }
+
+        let chunk_size = cells.len().div_ceil(threads);
+        thread::scope(|scope| {
+            let workers: Vec<_> = cells
+                .chunks(chunk_size)
+                .map(|chunk| {
+                    scope.spawn(move || {
+                        chunk
+                            .iter()
+                            .map(|&addr| self.evaluate_timed(addr, options))
+                            .collect::<Vec<_>>()
+                    })
+                })
+                .collect();
+            workers
+                .into_iter()
+                .flat_map(|worker| worker.join().expect("recalculation thread panicked"))
+                .collect()
+        })
+    }
+
+    /// Evaluates one cell with its own time budget, unless cancellation was
+    /// requested or the deadline of the recalculation has passed.
+    fn evaluate_timed(&self, addr: CellAddr, options: &EvalOptions) -> Result<i32, Error> {
+        if options.cancel.is_cancelled() {
+            return Err(Error::Cancelled);
+        }
+        let now = time::Instant::now();
+        // Cells left when the deadline passes time out without being evaluated
+        if options.deadline.is_some_and(|deadline| now >= deadline) {
+            return Err(Error::Timeout);
+        }
+        let (row, col) = addr.index();
+        let limit = options.cell_limit(now);
+        self.evaluate(row, col, options, limit)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::batch::Batch;
+
+    #[test]
+    fn test_levels() {
+        let mut test_sheet = Sheet::new(10, 10);
+        test_sheet.update_cell_data(2, 1, String::from("A1+1"));
+        test_sheet.update_cell_data(3, 1, String::from("A1*2"));
+        test_sheet.update_cell_data(4, 1, String::from("SUM(A1:A3)"));
+        test_sheet.update_cell_data(1, 2, String::from("A4"));
+
+        let order = test_sheet.toposort_all(&[CellAddr::new(1, 1)]).unwrap();
+        let levels = test_sheet.levels(&order);
+        assert_eq!(levels.len(), 4);
+        assert_eq!(levels[0], vec![CellAddr::new(1, 1)]);
+        let mut second = levels[1].clone();
+        second.sort();
+        assert_eq!(second, vec![CellAddr::new(2, 1), CellAddr::new(3, 1)]);
+        assert_eq!(levels[2], vec![CellAddr::new(4, 1)]);
+        assert_eq!(levels[3], vec![CellAddr::new(1, 2)]);
+    }
+
+    #[test]
+    fn test_parallel_matches_serial() {
+        let build = || {
+            let mut test_sheet = Sheet::new(3000, 10);
+            let mut batch = Batch::new();
+            for row in 2..=3000 {
+                let first = (row as i32 - 9).max(2);
+                batch.set(row, 1, format!("A1*{}", row % 7));
+                batch.set(row, 2, format!("SUM(A{}:A{})", first, row));
+                batch.set(row, 3, format!("B{}/A1", row));
+            }
+            test_sheet.commit(batch);
+            test_sheet
+        };
+
+        let mut serial = build();
+        let mut parallel = build();
+        let serial_options = EvalOptions {
+            threads: Some(1),
+            ..EvalOptions::default()
+        };
+        let parallel_options = EvalOptions {
+            threads: Some(4),
+            ..EvalOptions::default()
+        };
+        serial.update_cell_data_with(1, 1, String::from("0"), &serial_options);
+        parallel.update_cell_data_with(1, 1, String::from("0"), &parallel_options);
+        serial.update_cell_data_with(1, 1, String::from("3"), &serial_options);
+        parallel.update_cell_data_with(1, 1, String::from("3"), &parallel_options);
+
+        for row in 1..=3000 {
+            for col in 1..=3 {
+                assert_eq!(
+                    serial.get_value(row, col),
+                    parallel.get_value(row, col),
+                    "({row}, {col})"
+                );
+                assert_eq!(serial.get_error(row, col), parallel.get_error(row, col));
+            }
+        }
+        assert_eq!(
+            parallel.get_value(3000, 2),
+            3 * (2991..=3000).map(|r| r % 7).sum::<i32>()
+        );
+        assert_eq!(
+            parallel.get_value(3000, 3),
+            (2991..=3000).map(|r| r % 7).sum::<i32>()
+        );
+    }
+}
@@ -0,0 +1,290 @@
+//! Finding and replacing cell contents.
+//!
+//! A search matches a pattern against either the displayed value or the
+//! formula text of every written cell in its scope. The pattern is plain text
+//! or a regular expression; either way it is compiled to one `Regex`, so the
+//! match-case and whole-cell options behave the same for both. Cells are
+//! reported row by row.
+
+use crate::addr::{CellAddr, RangeAddr};
+use crate::sheet::{Cell, Error, Sheet, value_error};
+use regex::{NoExpand, Regex, RegexBuilder};
+
+/// What the pattern of a search is matched against.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum LookIn {
+    /// The value shown for the cell, e.g. `42` or `#DIV/0!`
+    #[default]
+    Values,
+    /// The formula text of the cell, e.g. `SUM(A1:A5)`
+    Formulas,
+}
+
+/// Options of a search.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct FindOptions {
+    /// What the pattern is matched against
+    pub look_in: LookIn,
+    /// Range to search, or `None` for the whole sheet
+    pub scope: Option<RangeAddr>,
+    /// Whether upper and lower case letters are told apart
+    pub match_case: bool,
+    /// Whether the pattern has to match the whole text instead of a part of it
+    pub whole_cell: bool,
+    /// Whether the pattern is a regular expression instead of plain text
+    pub regex: bool,
+}
+
+/// Result of a replace operation.
+#[derive(Debug, Default, PartialEq)]
+pub struct ReplaceResult {
+    /// Cells whose formula was rewritten
+    pub replaced: Vec<CellAddr>,
+    /// Cells whose rewritten formula was rejected and left unchanged, with the reason
+    pub failed: Vec<(CellAddr, Error)>,
+}
+
+/// Returns the text shown for the value of a cell.
+fn displayed(cell: &Cell) -> String {
+    match value_error(cell) {
+        Some(Error::DivByZero) => "#DIV/0!".to_string(),
+        Some(Error::Overflow) => "#NUM!".to_string(),
+        Some(Error::Timeout) => "#TIMEOUT".to_string(),
+        Some(Error::Ref) => "#REF!".to_string(),
+        _ => cell.value.to_string(),
+    }
+}
+
+/// Compiles the pattern of a search.
+///
+/// # Returns
+/// `Error::InvalidInput` if the pattern is empty or not a valid regular expression.
+fn matcher(pattern: &str, options: &FindOptions) -> Result<Regex, Error> {
+    if pattern.is_empty() {
+        return Err(Error::InvalidInput);
+    }
+    let mut source = if options.regex {
+        pattern.to_string()
+    } else {
+        regex::escape(pattern)
+    };
+    if options.whole_cell {
+        source = format!("^(?:{})$", source);
+    }
+    RegexBuilder::new(&source)
+        .case_insensitive(!options.match_case)
+        .build()
+        .map_err(|_| Error::InvalidInput)
+}
+
+impl Sheet {
+    /// Finds the cells whose value or formula matches a pattern.
+    ///
+    /// Only cells that were written to are searched.
+    ///
+    /// # Parameters
+    /// * `pattern` - Text or regular expression to look for
+    /// * `options` - What to match against, where, and how
+    ///
+    /// # Returns
+    /// The matching cells row by row, or `Error::InvalidInput` if the pattern
+    /// is empty or not a valid regular expression.
+    pub fn find(&self, pattern: &str, options: &FindOptions) -> Result<Vec<CellAddr>, Error> {
+        let regex = matcher(pattern, options)?;
+        Ok(self.find_with(&regex, options))
+    }
+
+    fn find_with(&self, regex: &Regex, options: &FindOptions) -> Vec<CellAddr> {
+        let mut found: Vec<CellAddr> = self
+            .grid
+            .iter()
+            .filter(|(addr, cell)| {
+                cell.formula.flag.is_any() != 0
+                    && options.scope.is_none_or(|scope| scope.contains(*addr))
+            })
+            .filter(|&(addr, cell)| {
+                let text = match options.look_in {
+                    LookIn::Values => displayed(cell),
+                    LookIn::Formulas => self.get_formula(addr.row as usize, addr.col as usize),
+                };
+                regex.is_match(&text)
+            })
+            .map(|(addr, _)| addr)
+            .collect();
+        found.sort_unstable();
+        found
+    }
+
+    /// Replaces a pattern in the formulas of the matching cells.
+    ///
+    /// Replacing always works on the formula text, whatever `look_in` says.
+    /// Every rewritten formula goes through `update_cell_data`, so it is checked
+    /// and recalculated like typed input; the whole replace is one undo step.
+    /// With `regex` set, the replacement may refer to groups as `$1` or `${name}`.
+    ///
+    /// # Parameters
+    /// * `pattern` - Text or regular expression to replace
+    /// * `replacement` - Text to put in its place
+    /// * `options` - Where and how to match
+    ///
+    /// # Returns
+    /// The rewritten cells and the cells whose new formula was invalid, would
+    /// create a cycle, broke the validation of the cell or was cancelled, or `Error::InvalidInput` if the pattern is empty or not
+    /// a valid regular expression.
+    pub fn replace(
+        &mut self,
+        pattern: &str,
+        replacement: &str,
+        options: &FindOptions,
+    ) -> Result<ReplaceResult, Error> {
+        let options = FindOptions {
+            look_in: LookIn::Formulas,
+            ..options.clone()
+        };
+        let regex = matcher(pattern, &options)?;
+        let edits: Vec<(CellAddr, String)> = self
+            .find_with(&regex, &options)
+            .into_iter()
+            .filter_map(|addr| {
+                let formula = self.get_formula(addr.row as usize, addr.col as usize);
+                let rewritten = if options.regex {
+                    regex.replace_all(&formula, replacement)
+                } else {
+                    regex.replace_all(&formula, NoExpand(replacement))
+                };
+                (rewritten != formula).then(|| (addr, rewritten.into_owned()))
+            })
+            .collect();
+
+        let mut result = ReplaceResult::default();
+        self.group(|sheet| {
+            for (addr, formula) in edits {
+                let (row, col) = addr.index();
+                match sheet.update_cell_data(row, col, formula).error {
+                    err @ (Error::InvalidInput
+                    | Error::CycleDetected
+                    | Error::ValidationFailed
+                    | Error::Cancelled) => result.failed.push((addr, err)),
+                    Error::None
+                    | Error::DivByZero
+                    | Error::Overflow
+                    | Error::Ref
+                    | Error::Timeout => result.replaced.push(addr),
+                }
+            }
+        });
+        Ok(result)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addrs(names: &[&str]) -> Vec<CellAddr> {
+        names
+            .iter()
+            .map(|name| CellAddr::parse(name).unwrap())
+            .collect()
+    }
+
+    fn sample_sheet() -> Sheet {
+        let mut test_sheet = Sheet::new(10, 10);
+        test_sheet.transaction(|batch| {
+            batch.set(1, 1, "12");
+            batch.set(2, 1, "120");
+            batch.set(3, 1, "SUM(A1:A2)");
+            batch.set(1, 2, "A1/0");
+            batch.set(2, 2, "A1*10");
+        });
+        test_sheet
+    }
+
+    #[test]
+    fn test_find() {
+        let test_sheet = sample_sheet();
+        let mut options = FindOptions::default();
+        assert_eq!(
+            test_sheet.find("12", &options),
+            Ok(addrs(&["A1", "A2", "B2"]))
+        );
+        options.whole_cell = true;
+        assert_eq!(test_sheet.find("120", &options), Ok(addrs(&["A2", "B2"])));
+        assert_eq!(test_sheet.find("#div/0!", &options), Ok(addrs(&["B1"])));
+        options.scope = RangeAddr::parse("A1:A3");
+        assert_eq!(test_sheet.find("120", &options), Ok(addrs(&["A2"])));
+
+        let mut options = FindOptions {
+            look_in: LookIn::Formulas,
+            ..FindOptions::default()
+        };
+        assert_eq!(test_sheet.find("sum(", &options), Ok(addrs(&["A3"])));
+        options.match_case = true;
+        assert_eq!(test_sheet.find("sum(", &options), Ok(vec![]));
+        options.regex = true;
+        assert_eq!(
+            test_sheet.find(r"^A1[*/]\d+$", &options),
+            Ok(addrs(&["B1", "B2"]))
+        );
+        assert_eq!(test_sheet.find("(", &options), Err(Error::InvalidInput));
+    }
+
+    #[test]
+    fn test_replace_is_one_step() {
+        let mut test_sheet = sample_sheet();
+        let options = FindOptions::default();
+        let result = test_sheet.replace("A1", "A2", &options).unwrap();
+        assert_eq!(result.replaced, addrs(&["B1", "B2", "A3"]));
+        assert_eq!(result.failed, vec![]);
+        assert_eq!(test_sheet.get_formula(3, 1), "SUM(A2:A2)");
+        assert_eq!(test_sheet.get_value(2, 2), 1200);
+
+        let regex = FindOptions {
+            regex: true,
+            ..FindOptions::default()
+        };
+        let result = test_sheet
+            .replace(r"SUM\((\w+):(\w+)\)", "$2", &regex)
+            .unwrap();
+        assert_eq!(result.replaced, addrs(&["A3"]));
+        assert_eq!(test_sheet.get_formula(3, 1), "A2");
+
+        // B2 would read itself, so only the other cells change
+        let result = test_sheet.replace("A2", "B2", &options).unwrap();
+        assert_eq!(result.replaced, addrs(&["B1", "A3"]));
+        assert_eq!(
+            result.failed,
+            vec![(CellAddr::new(2, 2), Error::CycleDetected)]
+        );
+        assert_eq!(test_sheet.get_formula(2, 2), "A2*10");
+
+        // Each replace is undone as a whole
+        assert!(test_sheet.undo());
+        assert!(test_sheet.undo());
+        assert_eq!(test_sheet.get_formula(3, 1), "SUM(A2:A2)");
+        assert!(test_sheet.undo());
+        assert_eq!(test_sheet.get_formula(3, 1), "SUM(A1:A2)");
+        assert_eq!(test_sheet.get_formula(2, 2), "A1*10");
+    }
+
+    #[test]
+    fn test_replace_reports_rejected_cells() {
+        let mut test_sheet = sample_sheet();
+        test_sheet
+            .add_validation(
+                RangeAddr::parse("A3:A3").unwrap(),
+                "whole 0 200".parse().unwrap(),
+            )
+            .unwrap();
+        // A3 would read 240, which its validation rejects
+        let result = test_sheet
+            .replace("SUM(A1:A2)", "A2*2", &FindOptions::default())
+            .unwrap();
+        assert_eq!(result.replaced, vec![]);
+        assert_eq!(
+            result.failed,
+            vec![(CellAddr::new(3, 1), Error::ValidationFailed)]
+        );
+        assert_eq!(test_sheet.get_formula(3, 1), "SUM(A1:A2)");
+    }
+}
@@ -0,0 +1,508 @@
+//! Shared formulas for filled columns.
+//!
+//! When consecutive cells of a column hold the same formula, each shifted by
+//! one row relative to the cell above (e.g. `B1+1`, `B2+1`, `B3+1`), the
+//! formula is stored once as a template at the first cell, the anchor. The
+//! cells of the block only keep their value and error flags and name the
+//! block; their formula is expanded from the template when it is needed.
+//!
+//! The dependency edges of a block reading single cells are not stored
+//! either. Blocks are indexed by the columns and row blocks of the cells they
+//! read, like ranges in `range_index`, and the cells reading a changed cell
+//! are computed from the template's relative offsets. The ranges read by the
+//! cells of a block are registered in the range index like those of any
+//! other formula, so they keep running totals too.
+
+use crate::addr::CellAddr;
+use crate::parse::{CommandCall, Param};
+use crate::range_index::BLOCK_ROWS;
+use crate::sheet::Sheet;
+use fxhash::FxHashMap;
+
+/// A formula shared by a block of consecutive cells in one column.
+#[derive(Clone, Debug)]
+pub struct SharedFormula {
+    /// Formula of the anchor, the first cell of the block
+    template: CommandCall,
+    /// Column of the block
+    col: u32,
+    /// Row of the anchor
+    start: u32,
+    /// Last row of the block
+    end: u32,
+}
+
+impl SharedFormula {
+    /// The first cell of the block, whose formula is the template.
+    pub fn anchor(&self) -> CellAddr {
+        CellAddr::new(self.start, self.col)
+    }
+
+    /// Number of cells sharing the formula.
+    pub fn len(&self) -> usize {
+        (self.end - self.start + 1) as usize
+    }
+
+    /// Always `false`; a block covers at least one cell.
+    pub fn is_empty(&self) -> bool {
+        false
+    }
+
+    /// Returns `true` if `addr` is one of the cells of the block.
+    pub fn contains(&self, addr: CellAddr) -> bool {
+        addr.col == self.col && (self.start..=self.end).contains(&addr.row)
+    }
+
+    /// Returns the formula of a cell of the block.
+    pub fn expand(&self, at: CellAddr) -> CommandCall {
+        self.template
+            .shifted(at.row as i64 - self.start as i64, 0)
+            .expect("every cell of a shared block has a valid formula")
+    }
+
+    /// Iterates over the cells of the block whose formulas read the single cell `addr`.
+    ///
+    /// Cells reading a range are found through the range index instead.
+    pub fn dependents_of(&self, addr: CellAddr) -> impl Iterator<Item = CellAddr> + use<> {
+        let col = self.col;
+        let (start, end) = (self.start as i64, self.end as i64);
+        let row = addr.row as i64;
+        let refs = [self.template.param1, self.template.param2].map(Param::cell);
+        // Rows of the block reading `addr`, at most one per reference
+        let mut readers = [None, None];
+        for (i, reference) in refs.iter().enumerate() {
+            let Some(reference) = reference else {
+                continue;
+            };
+            if reference.col != addr.col || (i == 1 && refs[0] == refs[1]) {
+                continue;
+            }
+            let reader = row - (reference.row as i64 - start);
+            if (start..=end).contains(&reader) {
+                readers[i] = Some(CellAddr::new(reader as u32, col));
+            }
+        }
+        readers.into_iter().flatten()
+    }
+
+    /// Row blocks of the single cells the block reads, as spans of row
+    /// blocks by column.
+    fn read_spans(&self) -> Vec<(u32, u32, u32)> {
+        if self.template.range().is_some() {
+            return vec![];
+        }
+        let mut spans: Vec<(u32, u32, u32)> = vec![];
+        let refs = [self.template.param1, self.template.param2];
+        for reference in refs.into_iter().filter_map(Param::cell) {
+            let first = reference.row / BLOCK_ROWS;
+            let last = reference.row.saturating_add(self.end - self.start) / BLOCK_ROWS;
+            match spans.iter_mut().find(|span| span.0 == reference.col) {
+                Some(span) => {
+                    span.1 = span.1.min(first);
+                    span.2 = span.2.max(last);
+                }
+                None => spans.push((reference.col, first, last)),
+            }
+        }
+        spans
+    }
+}
+
+/// Iterates over the row blocks of `first..=last` outside the span `kept`.
+fn outside(first: u32, last: u32, kept: Option<(u32, u32)>) -> impl Iterator<Item = u32> {
+    let end = last + 1;
+    match kept {
+        Some((from, to)) => (first..from.min(end)).chain((to + 1).max(first)..end),
+        None => (first..end).chain(0..0),
+    }
+}
+
+/// The shared formulas of a sheet, addressed by the id their cells store.
+#[derive(Clone, Default)]
+pub struct SharedFormulas {
+    /// Blocks by id; removed blocks leave a free slot
+    blocks: Vec<Option<SharedFormula>>,
+    /// Ids of the free slots
+    free: Vec<u32>,
+    /// Ids of the blocks reading single cells, by column and row block of the cells read
+    readers: FxHashMap<(u32, u32), Vec<u32>>,
+}
+
+impl SharedFormulas {
+    /// Creates an empty table.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns the block with the given id.
+    ///
+    /// # Panics
+    /// If no block has this id.
+    pub fn get(&self, id: u32) -> &SharedFormula {
+        self.blocks[id as usize]
+            .as_ref()
+            .expect("cell names a removed shared formula")
+    }
+
+    fn insert(&mut self, block: SharedFormula) -> u32 {
+        let spans = block.read_spans();
+        let id = match self.free.pop() {
+            Some(id) => {
+                self.blocks[id as usize] = Some(block);
+                id
+            }
+            None => {
+                self.blocks.push(Some(block));
+                (self.blocks.len() - 1) as u32
+            }
+        };
+        self.reindex(id, &[], &spans);
+        id
+    }
+
+    fn remove(&mut self, id: u32) {
+        let spans = self.get(id).read_spans();
+        self.reindex(id, &spans, &[]);
+        self.blocks[id as usize] = None;
+        self.free.push(id);
+    }
+
+    /// Changes a block, keeping the index of the cells it reads up to date.
+    fn reshape(&mut self, id: u32, change: impl FnOnce(&mut SharedFormula)) {
+        let before = self.get(id).read_spans();
+        let block = self.blocks[id as usize]
+            .as_mut()
+            .expect("cell names a removed shared formula");
+        change(block);
+        let after = block.read_spans();
+        self.reindex(id, &before, &after);
+    }
+
+    /// Moves the entries of a block from the row blocks it read to those it reads.
+    ///
+    /// Only the row blocks entering or leaving the spans are visited, so
+    /// growing a block one row at a time stays cheap.
+    fn reindex(&mut self, id: u32, before: &[(u32, u32, u32)], after: &[(u32, u32, u32)]) {
+        let span_of = |spans: &[(u32, u32, u32)], col: u32| {
+            spans
+                .iter()
+                .find(|span| span.0 == col)
+                .map(|span| (span.1, span.2))
+        };
+        for &(col, first, last) in before {
+            for row in outside(first, last, span_of(after, col)) {
+                if let Some(ids) = self.readers.get_mut(&(col, row)) {
+                    ids.retain(|&other| other != id);
+                    if ids.is_empty() {
+                        self.readers.remove(&(col, row));
+                    }
+                }
+            }
+        }
+        for &(col, first, last) in after {
+            for row in outside(first, last, span_of(before, col)) {
+                self.readers.entry((col, row)).or_default().push(id);
+            }
+        }
+    }
+
+    /// Iterates over the cells of every block whose formulas read the single cell `addr`.
+    pub fn dependents_of(&self, addr: CellAddr) -> impl Iterator<Item = CellAddr> + '_ {
+        self.readers
+            .get(&(addr.col, addr.row / BLOCK_ROWS))
+            .into_iter()
+            .flatten()
+            .flat_map(move |&id| self.get(id).dependents_of(addr))
+    }
+
+    /// Number of blocks.
+    pub fn len(&self) -> usize {
+        self.blocks.len() - self.free.len()
+    }
+
+    /// Returns `true` if there is no block.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Removes every block.
+    pub fn clear(&mut self) {
+        self.blocks.clear();
+        self.free.clear();
+        self.readers.clear();
+    }
+}
+
+/// Returns `true` if a formula reads other cells and can be shared by a block.
+fn is_shareable(command: &CommandCall) -> bool {
+    match command.flag.type_() {
+        0 | 1 => command.param1.cell().is_some() || command.param2.cell().is_some(),
+        _ => command.range().is_some(),
+    }
+}
+
+impl Sheet {
+    /// Returns the id of the shared formula of a cell, if it belongs to a block.
+    pub(crate) fn shared_id(&self, addr: CellAddr) -> Option<u32> {
+        let formula = &self.grid[addr].formula;
+        match (formula.flag.is_shared(), formula.param1) {
+            (1, Param::Value(id)) => Some(id as u32),
+            _ => None,
+        }
+    }
+
+    /// Returns the formula of a cell, expanding it if the cell shares its formula.
+    ///
+    /// The flag keeps the cell's own error bits.
+    pub fn formula_of(&self, addr: CellAddr) -> CommandCall {
+        let stored = &self.grid[addr].formula;
+        let Some(id) = self.shared_id(addr) else {
+            return stored.clone();
+        };
+        let mut formula = self.shared.get(id).expand(addr);
+        formula.flag = stored.flag.clone();
+        formula.flag.set_is_shared(0);
+        formula
+    }
+
+    /// Number of shared formula blocks in the sheet.
+    pub fn shared_formula_count(&self) -> usize {
+        self.shared.len()
+    }
+
+    /// Records the dependencies of a cell whose formula was just stored.
+    ///
+    /// If the cell above holds the same formula shifted by one row, the cell
+    /// joins or starts a shared block instead of keeping its own formula. The
+    /// range it reads, if any, is registered in the range index all the same.
+    pub(crate) fn attach(&mut self, target: CellAddr, command: &CommandCall) {
+        if is_shareable(command) && target.row > 1 {
+            let above = CellAddr::new(target.row - 1, target.col);
+            if let Some(id) = self.shared_id(above) {
+                let block = self.shared.get(id);
+                if block.end == above.row && block.expand(target).same_formula(command) {
+                    self.shared.reshape(id, |block| block.end = target.row);
+                    self.share(target, id);
+                    self.link_range(target, command);
+                    return;
+                }
+            } else {
+                let above_formula = self.grid[above].formula.clone();
+                if is_shareable(&above_formula)
+                    && above_formula
+                        .shifted(1, 0)
+                        .is_some_and(|shifted| shifted.same_formula(command))
+                {
+                    if above_formula.range().is_none() {
+                        self.unlink(above, &above_formula);
+                    }
+                    let id = self.shared.insert(SharedFormula {
+                        template: above_formula,
+                        col: target.col,
+                        start: above.row,
+                        end: target.row,
+                    });
+                    self.share(above, id);
+                    self.share(target, id);
+                    self.link_range(target, command);
+                    return;
+                }
+            }
+        }
+        self.link(target, command);
+    }
+
+    /// Removes the dependencies of a cell whose formula is about to change.
+    ///
+    /// A cell leaving a shared block gets its own formula back; the rest of
+    /// the block is split around it.
+    pub(crate) fn detach(&mut self, target: CellAddr) {
+        let Some(id) = self.shared_id(target) else {
+            let current = self.grid[target].formula.clone();
+            self.unlink(target, &current);
+            return;
+        };
+        let formula = self.formula_of(target);
+        if let Some(range) = formula.range() {
+            self.ranges.remove(range, target);
+        }
+        self.grid[target].formula = formula;
+
+        let block = self.shared.get(id).clone();
+        let below = (target.row < block.end).then(|| CellAddr::new(target.row + 1, block.col));
+        if target.row > block.start {
+            // The cells above keep the block
+            self.shared.reshape(id, |block| block.end = target.row - 1);
+            if let Some(below) = below {
+                let lower = self.shared.insert(SharedFormula {
+                    template: block.expand(below),
+                    col: block.col,
+                    start: below.row,
+                    end: block.end,
+                });
+                for row in below.row..=block.end {
+                    self.share(CellAddr::new(row, block.col), lower);
+                }
+                self.unshare_single(lower);
+            }
+            self.unshare_single(id);
+        } else if let Some(below) = below {
+            // The anchor left, so the block starts one row lower
+            self.shared.reshape(id, |upper| {
+                upper.template = block.expand(below);
+                upper.start = below.row;
+            });
+            self.unshare_single(id);
+        } else {
+            self.shared.remove(id);
+        }
+    }
+
+    /// Gives the only cell of a one-cell block its own formula again.
+    fn unshare_single(&mut self, id: u32) {
+        let block = self.shared.get(id);
+        if block.len() > 1 {
+            return;
+        }
+        let addr = block.anchor();
+        let formula = self.formula_of(addr);
+        self.shared.remove(id);
+        self.link(addr, &formula);
+        self.grid[addr].formula = formula;
+    }
+
+    /// Registers the range a cell of a shared block reads, like `link` does
+    /// for cells with their own formula.
+    fn link_range(&mut self, target: CellAddr, command: &CommandCall) {
+        if let Some(range) = command.range() {
+            self.ranges.insert(range, target);
+        }
+    }
+
+    /// Makes a cell name a shared formula instead of holding its own.
+    fn share(&mut self, addr: CellAddr, id: u32) {
+        let formula = &mut self.grid[addr].formula;
+        formula.flag.set_is_shared(1);
+        formula.param1 = Param::Value(id as i32);
+        formula.param2 = Param::Value(0);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fill_down(test_sheet: &mut Sheet, col: usize, rows: std::ops::RangeInclusive<usize>) {
+        let name = crate::addr::column_name(col as u32);
+        test_sheet.transaction(|batch| {
+            for row in rows {
+                batch.set(row, col, format!("{}{}+1", name, row - 1));
+            }
+        });
+    }
+
+    #[test]
+    fn test_fill_down_shares_formula() {
+        let mut test_sheet = Sheet::new(2000, 5);
+        test_sheet.update_cell_data(1, 2, String::from("1"));
+        fill_down(&mut test_sheet, 2, 2..=1000);
+        test_sheet.update_cell_data(1, 3, String::from("SUM(B1:B3)"));
+        test_sheet.update_cell_data(2, 3, String::from("SUM(B2:B4)"));
+        test_sheet.update_cell_data(3, 3, String::from("SUM(B3:B5)"));
+
+        assert_eq!(test_sheet.shared_formula_count(), 2);
+        assert!(
+            test_sheet
+                .grid
+                .iter()
+                .all(|(_, cell)| cell.depend.is_empty())
+        );
+        // The ranges of a shared block are indexed like any other, with their totals
+        assert_eq!(test_sheet.ranges.len(), 3);
+        assert_eq!(test_sheet.get_formula(500, 2), "B499+1");
+        assert_eq!(test_sheet.get_formula(3, 3), "SUM(B3:B5)");
+        assert_eq!(test_sheet.get_value(1000, 2), 1000);
+        assert_eq!(test_sheet.get_value(3, 3), 12);
+
+        test_sheet.update_cell_data(1, 2, String::from("11"));
+        assert_eq!(test_sheet.get_value(1000, 2), 1010);
+        assert_eq!(test_sheet.get_value(1, 3), 36);
+        assert_eq!(test_sheet.get_value(3, 3), 42);
+        let range = crate::addr::RangeAddr::parse("B3:B5").unwrap();
+        assert!(test_sheet.ranges.aggregate(range).is_some());
+    }
+
+    #[test]
+    fn test_edit_splits_block() {
+        let mut test_sheet = Sheet::new(100, 5);
+        fill_down(&mut test_sheet, 1, 2..=10);
+        test_sheet.update_cell_data(5, 1, String::from("100"));
+        assert_eq!(test_sheet.shared_formula_count(), 2);
+        assert_eq!(test_sheet.get_formula(4, 1), "A3+1");
+        assert_eq!(test_sheet.get_formula(5, 1), "100");
+        assert_eq!(test_sheet.get_formula(6, 1), "A5+1");
+        assert_eq!(test_sheet.get_value(10, 1), 105);
+
+        // Editing the anchor moves the block down; a lone cell gets its own formula
+        test_sheet.update_cell_data(6, 1, String::from("A5*2"));
+        test_sheet.update_cell_data(3, 1, String::from("7"));
+        assert_eq!(test_sheet.shared_formula_count(), 1);
+        assert_eq!(test_sheet.get_formula(2, 1), "A1+1");
+        assert_eq!(test_sheet.get_value(4, 1), 8);
+        assert_eq!(test_sheet.grid[(1, 1)].depend, vec![CellAddr::new(2, 1)]);
+        assert_eq!(test_sheet.get_value(10, 1), 204);
+        test_sheet.update_cell_data(5, 1, String::from("1"));
+        assert_eq!(test_sheet.get_value(10, 1), 6);
+
+        // Restoring the formula joins the block again
+        test_sheet.update_cell_data(6, 1, String::from("A5+1"));
+        assert_eq!(test_sheet.shared_formula_count(), 1);
+    }
+
+    #[test]
+    fn test_cycle_through_shared_block() {
+        let mut test_sheet = Sheet::new(100, 5);
+        fill_down(&mut test_sheet, 1, 2..=10);
+        let result = test_sheet.update_cell_data(1, 1, String::from("A10"));
+        assert_eq!(result.error, crate::sheet::Error::CycleDetected);
+        assert_eq!(test_sheet.get_formula(1, 1), "0");
+        test_sheet.update_cell_data(1, 1, String::from("2"));
+        assert_eq!(test_sheet.get_value(10, 1), 11);
+    }
+
+    #[test]
+    fn test_blocks_are_indexed_by_what_they_read() {
+        let mut test_sheet = Sheet::new(2000, 5);
+        fill_down(&mut test_sheet, 2, 2..=1000);
+        // B2:B1000 read B1:B999, which lie in four row blocks
+        assert_eq!(test_sheet.shared.readers.len(), 4);
+        let found: Vec<CellAddr> = test_sheet
+            .shared
+            .dependents_of(CellAddr::new(600, 2))
+            .collect();
+        assert_eq!(found, [CellAddr::new(601, 2)]);
+        assert_eq!(
+            test_sheet
+                .shared
+                .dependents_of(CellAddr::new(600, 3))
+                .count(),
+            0
+        );
+
+        // Splitting the block keeps both halves indexed
+        test_sheet.update_cell_data(500, 2, String::from("7"));
+        assert_eq!(test_sheet.shared_formula_count(), 2);
+        assert_eq!(test_sheet.get_value(1000, 2), 507);
+        test_sheet.update_cell_data(1, 2, String::from("5"));
+        assert_eq!(test_sheet.get_value(499, 2), 503);
+        assert_eq!(test_sheet.get_value(1000, 2), 507);
+
+        test_sheet.transaction(|batch| {
+            for row in 1..=1000 {
+                batch.clear(row, 2);
+            }
+        });
+        assert_eq!(test_sheet.shared_formula_count(), 0);
+        assert!(test_sheet.shared.readers.is_empty());
+    }
+}
@@ -1,12 +1,26 @@
+use crate::addr::{CellAddr, RangeAddr};
+use crate::aggregate::{self, Aggregate};
+use crate::batch::Batch;
+use crate::cell_store::CellStore;
+use crate::comments::Notes;
+use crate::conditional::ConditionalRule;
+use crate::eval::{EvalOptions, Interrupt};
+use crate::external::{Import, Imports};
+use crate::filter::AutoFilter;
+use crate::history::History;
+use crate::iterate::Iteration;
+use crate::notify::Changes;
 use crate::parse::*;
+use crate::range_index::RangeIndex;
+use crate::shared::SharedFormulas;
+use crate::structure::Relocation;
+use crate::style::Formats;
+use crate::validation::{Alert, RangeValidation, Validation};
 use fxhash::FxHashSet;
-use std::{thread, time};
+use std::time;
 
 const DEBUG: bool = false;
 
-/// Constant used for encoding cell indices
-pub const ENCODE_SHIFT: usize = 10000;
-
 /// A cell in the spreadsheet.
 ///
 /// Each cell holds a value, a formula, and a list of cells that depend on it.
@@ -17,18 +31,72 @@ pub struct Cell {
     /// The formula assigned to the cell
     pub formula: CommandCall,
     /// List of cells that depend on this cell's value
-    pub depend: Vec<usize>,
+    pub depend: Vec<CellAddr>,
+}
+
+/// Returns the error value currently held by a cell, if any.
+pub fn value_error(cell: &Cell) -> Option<Error> {
+    if cell.formula.flag.is_div_by_zero() == 1 {
+        Some(Error::DivByZero)
+    } else if cell.formula.flag.is_overflow() == 1 {
+        Some(Error::Overflow)
+    } else if cell.formula.flag.is_timeout() == 1 {
+        Some(Error::Timeout)
+    } else if cell.formula.flag.is_ref() == 1 {
+        Some(Error::Ref)
+    } else {
+        None
+    }
+}
+
+/// Returns the value a range function reads from a cell, or `None` if it holds an error.
+pub(crate) fn reading(cell: &Cell) -> Option<i32> {
+    match value_error(cell) {
+        Some(_) => None,
+        None => Some(cell.value),
+    }
+}
+
+/// Applies an arithmetic command to two operands using checked arithmetic.
+///
+/// # Parameters
+/// * `cmd` - Operation code: 0 = add, 1 = subtract, 2 = multiply, 3 = divide
+/// * `left` - Left operand
+/// * `right` - Right operand
+///
+/// # Returns
+/// The result, `Error::DivByZero` for a zero divisor or `Error::Overflow` if the
+/// result does not fit in an i32.
+pub fn arithmetic(cmd: u8, left: i32, right: i32) -> Result<i32, Error> {
+    let result = match cmd {
+        0 => left.checked_add(right),
+        1 => left.checked_sub(right),
+        2 => left.checked_mul(right),
+        _ if right == 0 => return Err(Error::DivByZero),
+        _ => left.checked_div(right),
+    };
+    result.ok_or(Error::Overflow)
 }
 
 /// Error types that can occur during spreadsheet operations.
-#[derive(Debug, PartialEq)]
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub enum Error {
     /// Division by zero error
     DivByZero,
+    /// Arithmetic overflow: the result does not fit in an i32 (`#NUM!`)
+    Overflow,
+    /// A formula refers to a cell that was deleted (`#REF!`)
+    Ref,
     /// Invalid input error when parsing formula
     InvalidInput,
     /// Cyclic dependency detected
     CycleDetected,
+    /// A formula exceeded its evaluation time budget
+    Timeout,
+    /// The recalculation was cancelled and the sheet was left unchanged
+    Cancelled,
+    /// The input broke the validation of the cell and was rejected
+    ValidationFailed,
     /// No error
     None,
 }
@@ -40,16 +108,68 @@ pub struct CallResult {
     pub time: f64,
     /// Error that occurred during the operation, if any
     pub error: Error,
+    /// Cells forming the loop when `error` is `Error::CycleDetected`, starting
+    /// at the updated cell; each cell reads the next one and the last reads the first
+    pub cycle: Vec<CellAddr>,
+    /// Cells of loops that did not settle within the iteration limit, when
+    /// iterative calculation is on
+    pub diverged: Vec<CellAddr>,
+    /// Validation of the cell the input broke; the input was rejected if
+    /// `error` is `Error::ValidationFailed`, and only warned about otherwise
+    pub violation: Option<Validation>,
+}
+
+impl CallResult {
+    /// Describes the loop of a rejected update, e.g. `A1 -> B1 -> A1`.
+    ///
+    /// # Returns
+    /// `None` if the update did not create a cycle.
+    pub fn cycle_path(&self) -> Option<String> {
+        let first = self.cycle.first()?;
+        let mut names: Vec<String> = self.cycle.iter().map(|addr| addr.to_string()).collect();
+        names.push(first.to_string());
+        Some(names.join(" -> "))
+    }
 }
 
 /// A structure representing a spreadsheet with cells that can contain values and formulas.
 ///
-/// The spreadsheet consists of a grid of cells, each capable of storing a value,
+/// The spreadsheet consists of a sparse grid of cells, each capable of storing a value,
 /// a formula, and dependencies on other cells. Operations performed on the spreadsheet
 /// ensure that all dependencies are properly maintained and cycles are detected.
+///
+/// Single cell references are tracked in the `depend` list of the referenced cell,
+/// while ranges read by range functions are tracked in a spatial index. Filled
+/// columns share one formula, whose dependencies are computed from the template.
 pub struct Sheet {
-    /// The grid of cells in the spreadsheet, represented as a 2D vector.
-    pub grid: Vec<Vec<Cell>>,
+    /// The cells in the spreadsheet, indexed by `(row, col)`. Only used cells are stored.
+    pub grid: CellStore,
+    /// Ranges read by range functions and the cells whose formulas read them.
+    pub(crate) ranges: RangeIndex,
+    /// Formulas shared by blocks of filled cells.
+    pub(crate) shared: SharedFormulas,
+    /// Recorded changes for undo and redo.
+    pub(crate) history: History,
+    /// Filter hiding rows of the sheet, if any.
+    pub(crate) filter: Option<AutoFilter>,
+    /// Settings of iterative calculation, or `None` if loops are rejected.
+    pub(crate) iteration: Option<Iteration>,
+    /// Cells of loops that did not settle in their last recalculation.
+    pub(crate) diverged: FxHashSet<CellAddr>,
+    /// Listeners of the sheet and the changes of the running operation.
+    pub(crate) changes: Changes,
+    /// The sheets of the workbook the sheet belongs to, which its formulas may read.
+    pub(crate) imports: Imports,
+    /// Moves of cells not yet carried to the other sheets of the workbook.
+    pub(crate) restructured: Vec<Relocation>,
+    /// Formatting attributes set on ranges, oldest first.
+    pub(crate) formats: Formats,
+    /// Conditional formatting rules, in the order they apply.
+    pub(crate) rules: Vec<ConditionalRule>,
+    /// Validations checking input, oldest first.
+    pub(crate) validations: Vec<RangeValidation>,
+    /// Notes and comments of the cells.
+    pub(crate) notes: Notes,
     /// Number of rows in the spreadsheet.
     pub row: usize,
     /// Number of columns in the spreadsheet.
@@ -64,24 +184,27 @@ impl Sheet {
     /// * `col` - Number of columns in the spreadsheet
     ///
     /// # Returns
-    /// A new `Sheet` instance with all cells initialized to zero.
+    /// A new `Sheet` instance with all cells initialized to zero. No memory is
+    /// used for cells until they are written to.
     pub fn new(row: usize, col: usize) -> Self {
-        let grid: Vec<Vec<Cell>> = vec![
-            vec![
-                Cell {
-                    value: 0,
-                    formula: CommandCall {
-                        flag: CommandFlag::new(),
-                        param1: 0,
-                        param2: 0,
-                    },
-                    depend: Vec::new(),
-                };
-                col + 1
-            ];
-            row + 1
-        ];
-        Self { grid, row, col }
+        Self {
+            grid: CellStore::new(),
+            ranges: RangeIndex::new(),
+            shared: SharedFormulas::new(),
+            history: History::default(),
+            filter: None,
+            iteration: None,
+            diverged: FxHashSet::default(),
+            changes: Changes::default(),
+            imports: Imports::default(),
+            restructured: Vec::new(),
+            formats: Formats::default(),
+            rules: Vec::new(),
+            validations: Vec::new(),
+            notes: Notes::default(),
+            row,
+            col,
+        }
     }
 
     /// Returns the formula string for a specific cell.
@@ -94,76 +217,75 @@ impl Sheet {
     /// A string representation of the cell's formula.
     #[allow(dead_code)]
     pub fn get_formula(&self, row: usize, col: usize) -> String {
-        unparse(self.grid[row][col].clone())
+        let mut cell = self.grid[(row, col)].clone();
+        cell.formula = self.formula_of(CellAddr::from_index(row, col));
+        self.externalize(unparse(cell))
     }
 
-    /// Adds additional rows to the spreadsheet.
-    ///
-    /// # Parameters
-    /// * `no_of_row` - Number of rows to add
-    ///     Adds additional columns to the spreadsheet.
+    /// Copies every formula of one row into another row.
     ///
     /// # Parameters
-    /// * `no_of_col` - Number of columns to add
+    /// * `copy_from` - Row to copy from
+    /// * `copy_to` - Row to copy into
     #[allow(dead_code)]
     pub fn copy_row(&mut self, copy_from: usize, copy_to: usize) -> Result<(), Error> {
+        // Only columns used in either row can change
+        let mut cols = self.grid.cols_in_row(copy_from);
+        cols.extend(self.grid.cols_in_row(copy_to));
+        cols.sort_unstable();
+        cols.dedup();
+
         // Save original state of destination row in case we need to rollback
-        let original_row: Vec<Cell> = self.grid[copy_to].clone();
+        let original_row = self.snapshot(cols.iter().map(|&i| (copy_to, i)));
 
-        for i in 0..self.col {
-            match self
-                .update_cell_data(copy_to, i, self.get_formula(copy_from, i))
-                .error
-            {
-                Error::None => {}
-                Error::DivByZero => {
-                    self.grid[copy_to][i].formula.flag.set_is_div_by_zero(1);
-                }
-                Error::InvalidInput => {
-                    self.grid[copy_to][i].formula.flag.set_error(1);
-                }
-                Error::CycleDetected => {
-                    // Rollback all changes to prevent corrupted state
-                    self.grid[copy_to] = original_row;
-                    return Err(Error::CycleDetected);
-                }
-            }
+        // Apply the whole row as one batch with a single recalculation
+        let mut batch = self.begin_batch();
+        for i in cols {
+            batch.set(copy_to, i, self.get_formula(copy_from, i));
         }
-
-        Ok(())
+        self.commit_copy(batch, original_row)
     }
 
     #[allow(dead_code)]
     pub fn copy_col(&mut self, copy_from: usize, copy_to: usize) -> Result<(), Error> {
+        // Only rows used in either column can change
+        let mut rows = self.grid.rows_in_col(copy_from);
+        rows.extend(self.grid.rows_in_col(copy_to));
+        rows.retain(|&row| row <= self.row);
+        rows.sort_unstable();
+        rows.dedup();
+
         // Save original state of destination column in case we need to rollback
-        let mut original_col: Vec<Cell> = Vec::with_capacity(self.row);
-        for i in 0..self.row {
-            original_col.push(self.grid[i][copy_to].clone());
+        let original_col = self.snapshot(rows.iter().map(|&i| (i, copy_to)));
+
+        // Apply the whole column as one batch with a single recalculation
+        let mut batch = self.begin_batch();
+        for i in rows {
+            batch.set(i, copy_to, self.get_formula(i, copy_from));
         }
+        self.commit_copy(batch, original_col)
+    }
 
-        // Perform the copy operation
-        for i in 0..self.row {
-            match self
-                .update_cell_data(i, copy_to, self.get_formula(i, copy_from))
-                .error
-            {
-                Error::None => {}
-                Error::DivByZero => {
-                    self.grid[i][copy_to].formula.flag.set_is_div_by_zero(1);
-                }
-                Error::InvalidInput => {
-                    self.grid[i][copy_to].formula.flag.set_error(1);
-                }
-                Error::CycleDetected => {
-                    // Rollback all changes to prevent corrupted state
-                    for (i, item) in original_col.iter().enumerate().take(self.row) {
-                        self.grid[i][copy_to] = item.clone();
-                    }
-                    return Err(Error::CycleDetected);
-                }
+    /// Commits the batch of a row or column copy.
+    ///
+    /// Cells whose formula cannot be copied are marked invalid. On a cycle the
+    /// destination cells are restored from `original`.
+    fn commit_copy(
+        &mut self,
+        batch: Batch,
+        original: Vec<((usize, usize), Option<Cell>)>,
+    ) -> Result<(), Error> {
+        let result = self.commit(batch);
+        if result.error == Error::CycleDetected {
+            // Rollback all changes to prevent corrupted state
+            self.restore(original);
+            return Err(Error::CycleDetected);
+        }
+        for (addr, err) in result.failed {
+            if err == Error::InvalidInput {
+                self.grid[addr].formula.flag.set_error(1);
             }
         }
-
         Ok(())
     }
 
@@ -176,7 +298,7 @@ impl Sheet {
         copy_to_col: usize,
     ) -> Result<(), Error> {
         // Save original state of destination cell
-        let original_cell = self.grid[copy_to_row][copy_to_col].clone();
+        let original_cell = self.snapshot(std::iter::once((copy_to_row, copy_to_col)));
 
         // Perform the copy operation
         match self
@@ -189,520 +311,722 @@ impl Sheet {
         {
             Error::None => {}
             Error::DivByZero => {
-                self.grid[copy_to_row][copy_to_col]
+                self.grid[(copy_to_row, copy_to_col)]
                     .formula
                     .flag
                     .set_is_div_by_zero(1);
             }
+            Error::Overflow => {
+                self.grid[(copy_to_row, copy_to_col)]
+                    .formula
+                    .flag
+                    .set_is_overflow(1);
+            }
+            Error::Ref => {
+                self.grid[(copy_to_row, copy_to_col)]
+                    .formula
+                    .flag
+                    .set_is_ref(1);
+            }
+            Error::Timeout | Error::Cancelled => {}
             Error::InvalidInput => {
-                self.grid[copy_to_row][copy_to_col]
+                self.grid[(copy_to_row, copy_to_col)]
                     .formula
                     .flag
                     .set_error(1);
             }
             Error::CycleDetected => {
                 // Rollback all changes to prevent corrupted state
-                self.grid[copy_to_row][copy_to_col] = original_cell;
+                self.restore(original_cell);
                 return Err(Error::CycleDetected);
             }
+            Error::ValidationFailed => return Err(Error::ValidationFailed),
         }
 
         Ok(())
     }
 
     pub fn clear_row(&mut self, row: usize) {
-        // Clear the entire row
-        for col in 0..self.col {
-            self.clear_cell(row, col);
-        }
+        // Clear the entire row as one undo step; unused cells are already empty
+        let cols = self.grid.cols_in_row(row);
+        self.notify(|sheet| {
+            sheet.group(|sheet| {
+                for &col in &cols {
+                    sheet.apply_update(row, col, "0".to_string(), &EvalOptions::default());
+                }
+            });
+            for col in cols {
+                sheet.prune_cell(row, col);
+            }
+        });
     }
 
     pub fn clear_col(&mut self, col: usize) {
-        // Clear the entire column
-        for row in 0..self.row {
-            self.clear_cell(row, col);
-        }
+        // Clear the entire column as one undo step; unused cells are already empty
+        let mut rows = self.grid.rows_in_col(col);
+        rows.retain(|&row| row <= self.row);
+        self.notify(|sheet| {
+            sheet.group(|sheet| {
+                for &row in &rows {
+                    sheet.apply_update(row, col, "0".to_string(), &EvalOptions::default());
+                }
+            });
+            for row in rows {
+                sheet.prune_cell(row, col);
+            }
+        });
     }
 
     pub fn clear_cell(&mut self, row: usize, col: usize) {
-        // Clear the cell's value and formula
-        self.update_cell_data(row, col, "0".to_string());
+        // Clear the cell's value and formula; validations do not check clearing
+        self.notify(|sheet| {
+            sheet.apply_update(row, col, "0".to_string(), &EvalOptions::default());
+            sheet.prune_cell(row, col);
+        });
     }
 
-    fn set_dependicies_cell(&mut self, row: usize, col: usize, command: CommandCall) {
-        if command.flag.type_() == 0 {
-            if command.flag.type1() == 0 {
-                self.grid[row][col].value = command.param1;
-            } else if command.flag.type1() == 1 {
-                let (param1_row, param1_col) = convert_to_index_int(command.param1);
-                if !(self.grid[param1_row][param1_col]
-                    .depend
-                    .contains(&(row * ENCODE_SHIFT + col)))
-                {
-                    self.grid[param1_row][param1_col]
-                        .depend
-                        .push(row * ENCODE_SHIFT + col);
-                }
-            }
-        } else if command.flag.type_() == 1 {
-            if command.flag.type1() == 0 {
-                if command.flag.type2() == 0 {
-                    if command.flag.cmd() == 0 {
-                        self.grid[row][col].value = command.param1 + command.param2;
-                    } else if command.flag.cmd() == 1 {
-                        self.grid[row][col].value = command.param1 - command.param2;
-                    } else if command.flag.cmd() == 2 {
-                        self.grid[row][col].value = command.param1 * command.param2;
-                    } else if command.param2 == 0 {
-                        self.grid[row][col].formula.flag.set_is_div_by_zero(1);
-                    } else {
-                        self.grid[row][col].value = command.param1 / command.param2;
-                    }
-                } else {
-                    let (param2_row, param2_col) = convert_to_index_int(command.param2);
-                    if !(self.grid[param2_row][param2_col]
-                        .depend
-                        .contains(&(row * ENCODE_SHIFT + col)))
-                    {
-                        self.grid[param2_row][param2_col]
-                            .depend
-                            .push(row * ENCODE_SHIFT + col);
-                    }
+    /// Stops storing a cleared cell once nothing depends on it.
+    pub(crate) fn prune_cell(&mut self, row: usize, col: usize) {
+        if self.grid[(row, col)].depend.is_empty() {
+            self.track(CellAddr::from_index(row, col), |sheet| {
+                sheet.grid.remove(row, col);
+            });
+        }
+    }
+
+    /// Saves the current state of the given cells, including whether they are stored at all.
+    fn snapshot(
+        &self,
+        positions: impl Iterator<Item = (usize, usize)>,
+    ) -> Vec<((usize, usize), Option<Cell>)> {
+        positions
+            .map(|(row, col)| ((row, col), self.grid.get(row, col).cloned()))
+            .collect()
+    }
+
+    /// Puts back the values and flags of cells saved by `snapshot`.
+    ///
+    /// Formulas and dependencies are not touched; the failed operation already
+    /// restored them.
+    fn restore(&mut self, saved: Vec<((usize, usize), Option<Cell>)>) {
+        for ((row, col), cell) in saved {
+            self.track(CellAddr::from_index(row, col), |sheet| match cell {
+                Some(saved) => {
+                    let cell = &mut sheet.grid[(row, col)];
+                    cell.value = saved.value;
+                    cell.formula.flag.copy_status(&saved.formula.flag);
                 }
-            } else if command.flag.type1() == 1 {
-                let (param1_row, param1_col) = convert_to_index_int(command.param1);
-                if !(self.grid[param1_row][param1_col]
-                    .depend
-                    .contains(&(row * ENCODE_SHIFT + col)))
-                {
-                    self.grid[param1_row][param1_col]
-                        .depend
-                        .push(row * ENCODE_SHIFT + col);
+                None => {
+                    sheet.grid.remove(row, col);
                 }
-                if command.flag.type2() == 0 {
-                } else if command.flag.type2() == 1 {
-                    let (param2_row, param2_col) = convert_to_index_int(command.param2);
-                    if !(self.grid[param2_row][param2_col]
-                        .depend
-                        .contains(&(row * ENCODE_SHIFT + col)))
-                    {
-                        self.grid[param2_row][param2_col]
-                            .depend
-                            .push(row * ENCODE_SHIFT + col);
-                    }
+            });
+        }
+    }
+
+    /// Applies a change to the sheet that may alter the value of the cell at `addr`,
+    /// keeping the totals of the ranges covering that cell up to date.
+    pub(crate) fn track<R>(&mut self, addr: CellAddr, change: impl FnOnce(&mut Self) -> R) -> R {
+        self.touch(addr);
+        let before = reading(&self.grid[addr]);
+        let result = change(self);
+        let after = reading(&self.grid[addr]);
+        self.ranges.update_aggregates(addr, before, after);
+        // Other sheets of the workbook may read the cell
+        if self.imports.book.len() > 1 {
+            self.imports.changed.push(addr);
+        }
+        result
+    }
+
+    fn set_dependicies_cell(&mut self, row: usize, col: usize, command: CommandCall) {
+        let target = CellAddr::from_index(row, col);
+        self.track(target, |sheet| {
+            let cell = &mut sheet.grid[target];
+            if command.flag.type_() == 0 {
+                if let Param::Value(value) = command.param1 {
+                    cell.value = value;
                 }
-            }
-        } else if command.flag.cmd() == 5 {
-            let (param1_row, param1_col) = convert_to_index_int(command.param1);
-            if !(self.grid[param1_row][param1_col]
-                .depend
-                .contains(&(row * ENCODE_SHIFT + col)))
+            } else if command.flag.type_() == 1
+                && let (Param::Value(left), Param::Value(right)) = (command.param1, command.param2)
             {
-                self.grid[param1_row][param1_col]
-                    .depend
-                    .push(row * ENCODE_SHIFT + col);
-            }
-        } else {
-            let t = row * ENCODE_SHIFT + col;
-            let (param1_row, param1_col) = convert_to_index_int(command.param1);
-            let (param2_row, param2_col) = convert_to_index_int(command.param2);
-            for i in param1_row..(param2_row + 1) {
-                for j in param1_col..(param2_col + 1) {
-                    let depend_vec = &mut self.grid[i][j].depend;
-                    if !depend_vec.contains(&t) {
-                        depend_vec.push(t);
-                    }
+                match arithmetic(command.flag.cmd(), left, right) {
+                    Ok(value) => cell.value = value,
+                    Err(Error::DivByZero) => cell.formula.flag.set_is_div_by_zero(1),
+                    Err(_) => cell.formula.flag.set_is_overflow(1),
                 }
             }
+            cell.formula = command.clone();
+        });
+        self.attach(target, &command);
+    }
+
+    /// Registers `target` as a dependent of every cell its formula reads.
+    pub(crate) fn link(&mut self, target: CellAddr, command: &CommandCall) {
+        if let Some(range) = command.range() {
+            self.ranges.insert(range, target);
+            return;
+        }
+        for addr in command.precedents() {
+            let depend_vec = &mut self.grid[addr].depend;
+            if !depend_vec.contains(&target) {
+                depend_vec.push(target);
+            }
         }
+    }
 
-        self.grid[row][col].formula = command;
+    /// Removes `target` from the dependents of every cell its formula reads.
+    pub(crate) fn unlink(&mut self, target: CellAddr, command: &CommandCall) {
+        if let Some(range) = command.range() {
+            self.ranges.remove(range, target);
+            return;
+        }
+        for addr in command.precedents() {
+            let depend_vec = &mut self.grid[addr].depend;
+            depend_vec.retain(|&x| x != target);
+        }
     }
 
-    fn toposort(&self, target_cell: usize) -> Vec<usize> {
-        let mut visited: FxHashSet<usize> = FxHashSet::default();
-        let mut stack: FxHashSet<usize> = FxHashSet::default();
-        let mut result: Vec<usize> = vec![];
-        let is_cycle = self.dfs(target_cell, &mut visited, &mut stack, &mut result);
+    /// Iterates over the cells whose formulas read `addr` directly.
+    pub(crate) fn dependents_of(&self, addr: CellAddr) -> impl Iterator<Item = CellAddr> + '_ {
+        self.grid[addr]
+            .depend
+            .iter()
+            .copied()
+            .chain(self.ranges.dependents_of(addr))
+            .chain(self.shared.dependents_of(addr))
+    }
 
-        if is_cycle {
-            if DEBUG {
-                println!("Cycle detected in the graph");
+    /// Recreates every dependency from the stored formulas.
+    ///
+    /// Used after loading a file, so the dependency lists never depend on what
+    /// the file recorded.
+    pub(crate) fn rebuild_dependencies(&mut self) {
+        let mut formulas: Vec<(CellAddr, CommandCall)> = self
+            .grid
+            .iter()
+            .map(|(addr, _)| (addr, self.formula_of(addr)))
+            .collect();
+        self.ranges.clear();
+        self.shared.clear();
+        for (addr, formula) in &formulas {
+            let cell = &mut self.grid[*addr];
+            cell.depend.clear();
+            cell.formula = formula.clone();
+        }
+        // Column by column, so filled columns share their formula again
+        formulas.sort_unstable_by_key(|(addr, _)| (addr.col, addr.row));
+        for (addr, formula) in formulas {
+            self.attach(addr, &formula);
+        }
+    }
+
+    /// Orders the given cells and everything depending on them for recalculation.
+    ///
+    /// # Returns
+    /// The cells in dependency order, or the cells of a cycle reachable from them.
+    /// The cycle starts at the cell it was entered from; each cell reads the next
+    /// one and the last reads the first.
+    pub(crate) fn toposort_all(
+        &self,
+        targets: &[CellAddr],
+    ) -> Result<Vec<CellAddr>, Vec<CellAddr>> {
+        let mut visited: FxHashSet<CellAddr> = FxHashSet::default();
+        let mut result: Vec<CellAddr> = vec![];
+        for &target_cell in targets {
+            if let Some(mut cycle) = self.dfs(target_cell, &mut visited, &mut result) {
+                if DEBUG {
+                    println!("Cycle detected in the graph");
+                }
+                // The search follows dependents, so the loop is reversed to follow references
+                cycle[1..].reverse();
+                return Err(cycle);
             }
-            return vec![];
         }
 
         result.reverse();
-        result
+        Ok(result)
     }
+
+    /// Visits a cell and its dependents depth first, appending them in post-order.
+    ///
+    /// The chain of dependents being visited is kept in `path`, with the
+    /// dependents left to visit of each, so long chains cannot overflow the
+    /// call stack.
+    ///
+    /// # Returns
+    /// The cells of the first cycle found, in the order the search followed them.
     fn dfs(
         &self,
-        cell: usize,
-        visited: &mut FxHashSet<usize>,
-        stack: &mut FxHashSet<usize>,
-        result: &mut Vec<usize>,
-    ) -> bool {
-        if stack.contains(&cell) {
-            return true;
+        cell: CellAddr,
+        visited: &mut FxHashSet<CellAddr>,
+        result: &mut Vec<CellAddr>,
+    ) -> Option<Vec<CellAddr>> {
+        if !visited.insert(cell) {
+            return None;
         }
-        if visited.contains(&cell) {
-            return false;
-        }
-
-        visited.insert(cell);
-
-        let col = cell % ENCODE_SHIFT;
-        let row = cell / ENCODE_SHIFT;
-        let mut is_cycle = false;
-        stack.insert(cell);
-        for &dep in &self.grid[row][col].depend {
-            is_cycle = is_cycle || self.dfs(dep, visited, stack, result);
-        }
-        stack.remove(&cell);
-        result.push(cell);
-        is_cycle
-    }
-
-    fn minimum(&self, row1: usize, row2: usize, col1: usize, col2: usize) -> i32 {
-        let mut min = i32::MAX;
-        for i in row1..(row2 + 1) {
-            for j in col1..(col2 + 1) {
-                // No need to check for negative values as `i` and `j` are of type `usize`
-                if self.grid[i][j].value < min {
-                    min = self.grid[i][j].value;
+        // Cells of `path`
+        let mut on_path: FxHashSet<CellAddr> = FxHashSet::default();
+        on_path.insert(cell);
+        let mut path = vec![cell];
+        let mut frames = vec![self.dependents_of(cell)];
+        while let Some(dependents) = frames.last_mut() {
+            match dependents.next() {
+                Some(dep) if on_path.contains(&dep) => {
+                    let start = path.iter().rposition(|&addr| addr == dep)?;
+                    return Some(path[start..].to_vec());
                 }
-            }
-        }
-        min
-    }
-    fn maximum(&self, row1: usize, row2: usize, col1: usize, col2: usize) -> i32 {
-        let mut max = i32::MIN;
-        for i in row1..(row2 + 1) {
-            for j in col1..(col2 + 1) {
-                // println!("{}",self.grid[i as usize][j as usize].value);
-                if self.grid[i][j].value > max {
-                    max = self.grid[i][j].value;
+                Some(dep) if visited.insert(dep) => {
+                    on_path.insert(dep);
+                    path.push(dep);
+                    frames.push(self.dependents_of(dep));
+                }
+                Some(_) => {}
+                None => {
+                    frames.pop();
+                    let done = path.pop()?;
+                    on_path.remove(&done);
+                    result.push(done);
                 }
             }
         }
-        max
-    }
-    fn average(&self, row1: usize, row2: usize, col1: usize, col2: usize) -> i32 {
-        let mut sum = 0;
-        let mut count = 0;
-        for i in row1..(row2 + 1) {
-            for j in col1..(col2 + 1) {
-                sum += self.grid[i][j].value;
-                count += 1;
+        None
+    }
+
+    /// Folds over the values of a rectangular range.
+    ///
+    /// Stops at the first cell that holds an error value and returns that error,
+    /// so range functions propagate `#DIV/0!` and `#NUM!` from any cell they cover.
+    /// With `visible_only`, the rows hidden by the filter are skipped.
+    fn fold_range<T>(
+        &self,
+        range: RangeAddr,
+        visible_only: bool,
+        init: T,
+        mut f: impl FnMut(T, i32) -> T,
+    ) -> Result<T, Error> {
+        let mut acc = init;
+        for addr in range
+            .cells()
+            .filter(|addr| !visible_only || !self.is_row_hidden(addr.row as usize))
+        {
+            let cell = &self.grid[addr];
+            if let Some(err) = value_error(cell) {
+                return Err(err);
             }
+            acc = f(acc, cell.value);
         }
-        if count == 0 {
-            return 0;
-        }
-        (sum as f32 / count as f32) as i32
+        Ok(acc)
+    }
+
+    // A range whose rows are all hidden has no extreme value and yields 0
+    fn minimum(&self, range: RangeAddr, visible_only: bool) -> Result<i32, Error> {
+        let min = self.fold_range(range, visible_only, None, |min: Option<i32>, v| {
+            Some(min.map_or(v, |min| min.min(v)))
+        })?;
+        Ok(min.unwrap_or(0))
     }
-    fn sum(&self, row1: usize, row2: usize, col1: usize, col2: usize) -> i32 {
-        let mut sum = 0;
-        for i in row1..(row2 + 1) {
-            for j in col1..(col2 + 1) {
-                sum += self.grid[i][j].value;
+    fn maximum(&self, range: RangeAddr, visible_only: bool) -> Result<i32, Error> {
+        let max = self.fold_range(range, visible_only, None, |max: Option<i32>, v| {
+            Some(max.map_or(v, |max| max.max(v)))
+        })?;
+        Ok(max.unwrap_or(0))
+    }
+    fn average(&self, range: RangeAddr, visible_only: bool) -> Result<i32, Error> {
+        let (sum, count) =
+            self.fold_range(range, visible_only, (0i128, 0i128), |(sum, count), v| {
+                (sum + v as i128, count + 1)
+            })?;
+        Ok(aggregate::average_value(sum, count))
+    }
+    fn sum(&self, range: RangeAddr, visible_only: bool) -> Result<i32, Error> {
+        let sum = self.fold_range(range, visible_only, 0i128, |sum, v| sum + v as i128)?;
+        aggregate::sum_value(sum)
+    }
+    fn stddev(&self, range: RangeAddr, visible_only: bool) -> Result<i32, Error> {
+        let (sum, sum_sq, count) = self.fold_range(
+            range,
+            visible_only,
+            (0i128, 0i128, 0i128),
+            |(sum, sum_sq, count), v| {
+                (
+                    sum + v as i128,
+                    sum_sq + (v as i128) * (v as i128),
+                    count + 1,
+                )
+            },
+        )?;
+        aggregate::stddev_value(sum, sum_sq, count)
+    }
+
+    /// Makes sure the totals of the ranges read by the given cells can answer
+    /// their range functions, scanning the ranges that have none yet or whose
+    /// needed minimum or maximum went stale.
+    fn prepare_aggregates(&mut self, cells: &[CellAddr]) {
+        for &addr in cells {
+            let formula = &self.formula_of(addr);
+            let cmd = formula.flag.cmd();
+            let Some(range) = formula.range() else {
+                continue;
+            };
+            if formula.flag.type_() == 3
+                || self
+                    .ranges
+                    .aggregate(range)
+                    .is_some_and(|aggregate| !aggregate.is_stale(cmd))
+            {
+                continue;
             }
+            let scanned = Aggregate::scan(range.cells().map(|cell| reading(&self.grid[cell])));
+            self.ranges.set_aggregate(range, scanned);
         }
-        sum
-    }
-    fn stddev(&self, row1: usize, row2: usize, col1: usize, col2: usize) -> i32 {
-        let mut mean = 0;
-        let mut sum = 0;
-        let mut count = 0;
-        for i in row1..(row2 + 1) {
-            for j in col1..(col2 + 1) {
-                let value = self.grid[i][j].value;
-                sum += (value) * (value);
-                mean += value;
-                count += 1;
+    }
+
+    /// Resolves one operand of a formula.
+    ///
+    /// Literal operands are returned as-is. Cell references yield the referenced
+    /// cell's value, or the error that cell currently holds.
+    fn operand(&self, param: Param) -> Result<i32, Error> {
+        match param {
+            Param::Value(value) => Ok(value),
+            Param::Cell(addr) => {
+                let cell = &self.grid[addr];
+                match value_error(cell) {
+                    Some(err) => Err(err),
+                    None => Ok(cell.value),
+                }
             }
+            Param::External(id, addr) => self.imported(Import::Cell(id, addr)),
         }
-        if count == 0 {
-            return 0;
+    }
+
+    /// Computes the value of a single cell from its formula.
+    ///
+    /// Only reads the sheet; the caller is responsible for storing the result
+    /// and the matching error flags on the cell.
+    ///
+    /// # Parameters
+    /// * `row` - Row index of the cell
+    /// * `col` - Column index of the cell
+    /// * `options` - Cancellation token and limits of the running recalculation
+    /// * `limit` - Point in time at which this cell runs out of time
+    pub(crate) fn evaluate(
+        &self,
+        row: usize,
+        col: usize,
+        options: &EvalOptions,
+        limit: Option<time::Instant>,
+    ) -> Result<i32, Error> {
+        let formula = self.formula_of(CellAddr::from_index(row, col));
+        self.evaluate_formula(&formula, options, limit)
+    }
+
+    /// Computes the value of a formula from the current values of the cells it reads.
+    pub(crate) fn evaluate_formula(
+        &self,
+        formula: &CommandCall,
+        options: &EvalOptions,
+        limit: Option<time::Instant>,
+    ) -> Result<i32, Error> {
+        let flag = &formula.flag;
+        if flag.error() == 3 {
+            return Err(Error::Ref);
         }
-        let avg = ((mean * mean) as f64) / ((count * count) as f64);
-        let mut x = (sum as f64) / (count as f64);
-        x -= avg;
-        ((x).sqrt()).round() as i32
-    }
-
-    fn update_cell(&mut self, list_fpr_update: Vec<usize>) {
-        for i in list_fpr_update {
-            let col = i % ENCODE_SHIFT;
-            let row = i / ENCODE_SHIFT;
-            self.grid[row][col].formula.flag.set_is_div_by_zero(0);
-            if self.grid[row][col].formula.flag.type_() == 0 {
-                // value
-
-                if self.grid[row][col].formula.flag.type1() == 0 {
-                    self.grid[row][col].value = self.grid[row][col].formula.param1;
-                } else if self.grid[row][col].formula.flag.type1() == 1 {
-                    let (param1_row, param1_col) =
-                        convert_to_index_int(self.grid[row][col].formula.param1);
-                    if self.grid[param1_row][param1_col]
-                        .formula
-                        .flag
-                        .is_div_by_zero()
-                        == 1
-                    {
-                        self.grid[row][col].formula.flag.set_is_div_by_zero(1);
-                    }
-                    self.grid[row][col].value = self.grid[param1_row][param1_col].value;
+        match flag.type_() {
+            // value or cell reference
+            0 => self.operand(formula.param1),
+            // arithmatic
+            1 => {
+                let left = self.operand(formula.param1)?;
+                let right = self.operand(formula.param2)?;
+                arithmetic(flag.cmd(), left, right)
+            }
+            _ if flag.cmd() == 5 => {
+                let value = self.operand(formula.param1)?;
+                let duration = time::Duration::from_secs(value.max(0) as u64);
+                match options.wait(duration, limit) {
+                    Ok(()) => Ok(value),
+                    Err(Interrupt::Cancelled) => Err(Error::Cancelled),
+                    Err(Interrupt::TimedOut) => Err(Error::Timeout),
                 }
-            } else if self.grid[row][col].formula.flag.type_() == 1 {
-                // arithmatic
-                if self.grid[row][col].formula.flag.type1() == 0 {
-                    if self.grid[row][col].formula.flag.type2() == 0 {
-                        if self.grid[row][col].formula.flag.cmd() == 0 {
-                            self.grid[row][col].value = self.grid[row][col].formula.param1
-                                + self.grid[row][col].formula.param2;
-                        } else if self.grid[row][col].formula.flag.cmd() == 1 {
-                            self.grid[row][col].value = self.grid[row][col].formula.param1
-                                - self.grid[row][col].formula.param2;
-                        } else if self.grid[row][col].formula.flag.cmd() == 2 {
-                            self.grid[row][col].value = self.grid[row][col].formula.param1
-                                * self.grid[row][col].formula.param2;
-                        } else if self.grid[row][col].formula.param2 == 0 {
-                            self.grid[row][col].formula.flag.set_is_div_by_zero(1);
-                        } else {
-                            self.grid[row][col].value = self.grid[row][col].formula.param1
-                                / self.grid[row][col].formula.param2;
-                        }
-                    } else {
-                        // let param2_row=(self.grid[row][col].formula.param2%1000) as usize;
-                        // let param2_col=(self.grid[row][col].formula.param2/1000) as usize;
-                        let (param2_row, param2_col) =
-                            convert_to_index_int(self.grid[row][col].formula.param2);
-                        if self.grid[param2_row][param2_col]
-                            .formula
-                            .flag
-                            .is_div_by_zero()
-                            == 1
-                        {
-                            self.grid[row][col].formula.flag.set_is_div_by_zero(1);
-                        }
-                        if self.grid[row][col].formula.flag.cmd() == 0 {
-                            self.grid[row][col].value = self.grid[row][col].formula.param1
-                                + self.grid[param2_row][param2_col].value;
-                        } else if self.grid[row][col].formula.flag.cmd() == 1 {
-                            self.grid[row][col].value = self.grid[row][col].formula.param1
-                                - self.grid[param2_row][param2_col].value;
-                        } else if self.grid[row][col].formula.flag.cmd() == 2 {
-                            self.grid[row][col].value = self.grid[row][col].formula.param1
-                                * self.grid[param2_row][param2_col].value;
-                        } else if self.grid[param2_row][param2_col].value == 0 {
-                            self.grid[row][col].formula.flag.set_is_div_by_zero(1);
-                        } else {
-                            self.grid[row][col].value = self.grid[row][col].formula.param1
-                                / self.grid[param2_row][param2_col].value;
-                        }
-                    }
-                } else if self.grid[row][col].formula.flag.type1() == 1 {
-                    let (param1_row, param1_col) =
-                        convert_to_index_int(self.grid[row][col].formula.param1);
-                    if self.grid[param1_row][param1_col]
-                        .formula
-                        .flag
-                        .is_div_by_zero()
-                        == 1
-                    {
-                        self.grid[row][col].formula.flag.set_is_div_by_zero(1);
-                    }
-                    if self.grid[row][col].formula.flag.type2() == 0 {
-                        if self.grid[row][col].formula.flag.cmd() == 0 {
-                            self.grid[row][col].value = self.grid[param1_row][param1_col].value
-                                + self.grid[row][col].formula.param2;
-                        } else if self.grid[row][col].formula.flag.cmd() == 1 {
-                            self.grid[row][col].value = self.grid[param1_row][param1_col].value
-                                - self.grid[row][col].formula.param2;
-                        } else if self.grid[row][col].formula.flag.cmd() == 2 {
-                            self.grid[row][col].value = self.grid[param1_row][param1_col].value
-                                * self.grid[row][col].formula.param2;
-                        } else if self.grid[row][col].formula.param2 == 0 {
-                            self.grid[row][col].formula.flag.set_is_div_by_zero(1);
-                        } else {
-                            self.grid[row][col].value = self.grid[param1_row][param1_col].value
-                                / self.grid[row][col].formula.param2;
-                        }
-                    } else if self.grid[row][col].formula.flag.type2() == 1 {
-                        let (param2_row, param2_col) =
-                            convert_to_index_int(self.grid[row][col].formula.param2);
-                        if self.grid[param2_row][param2_col]
-                            .formula
-                            .flag
-                            .is_div_by_zero()
-                            == 1
-                        {
-                            self.grid[row][col].formula.flag.set_is_div_by_zero(1);
-                        }
-                        if self.grid[row][col].formula.flag.cmd() == 0 {
-                            self.grid[row][col].value = self.grid[param1_row][param1_col].value
-                                + self.grid[param2_row][param2_col].value;
-                        } else if self.grid[row][col].formula.flag.cmd() == 1 {
-                            self.grid[row][col].value = self.grid[param1_row][param1_col].value
-                                - self.grid[param2_row][param2_col].value;
-                        } else if self.grid[row][col].formula.flag.cmd() == 2 {
-                            self.grid[row][col].value = self.grid[param1_row][param1_col].value
-                                * self.grid[param2_row][param2_col].value;
-                        } else if self.grid[param2_row][param2_col].value == 0 {
-                            self.grid[row][col].formula.flag.set_is_div_by_zero(1);
-                        } else {
-                            self.grid[row][col].value = self.grid[param1_row][param1_col].value
-                                / self.grid[param2_row][param2_col].value;
+            }
+            _ => {
+                let Some(range) = formula.range() else {
+                    // A range of another sheet, whose function that sheet computes
+                    return match formula.external_range() {
+                        Some((id, range)) => {
+                            self.imported(Import::Range(id, range, flag.type_(), flag.cmd()))
                         }
-                        // self.grid[row][col].value=self.grid[param1_row][param1_col].value/self.grid[param2_row][param2_col].value;
-                    }
-                }
-            } else {
-                let (param1_row, param1_col) =
-                    convert_to_index_int(self.grid[row][col].formula.param1);
-                if self.grid[param1_row][param1_col]
-                    .formula
-                    .flag
-                    .is_div_by_zero()
-                    == 1
-                {
-                    self.grid[row][col].formula.flag.set_is_div_by_zero(1);
-                }
-                let (param2_row, param2_col) =
-                    convert_to_index_int(self.grid[row][col].formula.param2);
-                if self.grid[param2_row][param2_col]
-                    .formula
-                    .flag
-                    .is_div_by_zero()
-                    == 1
+                        None => Err(Error::InvalidInput),
+                    };
+                };
+                // SUBTOTAL skips hidden rows, which the running totals cannot
+                let visible_only = flag.type_() == 3;
+                // Use the running totals of the range, rescanning only if they cannot answer
+                if !visible_only
+                    && let Some(result) = self
+                        .ranges
+                        .aggregate(range)
+                        .and_then(|aggregate| aggregate.evaluate(flag.cmd()))
                 {
-                    self.grid[row][col].formula.flag.set_is_div_by_zero(1);
+                    return result;
                 }
-                if self.grid[row][col].formula.flag.cmd() == 0 {
-                    self.grid[row][col].value =
-                        self.minimum(param1_row, param2_row, param1_col, param2_col);
-                } else if self.grid[row][col].formula.flag.cmd() == 1 {
-                    self.grid[row][col].value =
-                        self.maximum(param1_row, param2_row, param1_col, param2_col);
-                } else if self.grid[row][col].formula.flag.cmd() == 2 {
-                    self.grid[row][col].value =
-                        self.sum(param1_row, param2_row, param1_col, param2_col);
-                } else if self.grid[row][col].formula.flag.cmd() == 3 {
-                    self.grid[row][col].value =
-                        self.average(param1_row, param2_row, param1_col, param2_col);
-                } else if self.grid[row][col].formula.flag.cmd() == 4 {
-                    self.grid[row][col].value =
-                        self.stddev(param1_row, param2_row, param1_col, param2_col);
-                } else if self.grid[row][col].formula.flag.cmd() == 5 {
-                    if self.grid[row][col].formula.flag.type1() == 1 {
-                        let time_secs = time::Duration::from_secs(
-                            self.grid[param1_row][param1_col].value as u64,
-                        );
-                        thread::sleep(time_secs);
-                        self.grid[row][col].value = self.grid[param1_row][param1_col].value;
-                    } else {
-                        self.grid[row][col].value = self.grid[row][col].formula.param1;
-                        let time_secs = time::Duration::from_secs(self.grid[row][col].value as u64);
-                        thread::sleep(time_secs);
-                    }
+                match flag.cmd() {
+                    0 => self.minimum(range, visible_only),
+                    1 => self.maximum(range, visible_only),
+                    2 => self.sum(range, visible_only),
+                    3 => self.average(range, visible_only),
+                    _ => self.stddev(range, visible_only),
                 }
             }
         }
     }
-    fn remove_old_dependicies(&mut self, row: usize, col: usize, restore_command: CommandCall) {
-        // Remove all dependencies from previous formula
-        let curr_index = row * ENCODE_SHIFT + col;
-        let current_command = &self.grid[row][col].formula.clone();
-        // Remove dependencies based on command type
-        if current_command.flag.type_() == 0 && current_command.flag.type1() == 1 {
-            // Cell reference dependency
-
-            let (param1_row, param1_col) = convert_to_index_int(current_command.param1);
-            let depend_vec = &mut self.grid[param1_row][param1_col].depend;
-            depend_vec.retain(|&x| x != curr_index);
-        } else if current_command.flag.type_() == 1 {
-            // Arithmetic operation dependencies
-            if current_command.flag.type1() == 1 {
-                // First parameter is a cell reference
-                let (param1_row, param1_col) = convert_to_index_int(current_command.param1);
-                let depend_vec = &mut self.grid[param1_row][param1_col].depend;
-                depend_vec.retain(|&x| x != curr_index);
-            }
-            if current_command.flag.type2() == 1 {
-                // Second parameter is a cell reference
-                let (param2_row, param2_col) = convert_to_index_int(current_command.param2);
-                let depend_vec = &mut self.grid[param2_row][param2_col].depend;
-                depend_vec.retain(|&x| x != curr_index);
-                // depend_vec.remove(&curr_index);
-            }
-        } else if current_command.flag.type_() == 2 {
-            // Range function dependencies
-            let (param1_row, param1_col) = convert_to_index_int(current_command.param1);
-            let (param2_row, param2_col) = convert_to_index_int(current_command.param2);
-            for i in param1_row..(param2_row + 1) {
-                for j in param1_col..(param2_col + 1) {
-                    let depend_vec = &mut self.grid[i][j].depend;
-                    depend_vec.retain(|&x| x != curr_index);
-                    // depend_vec.remove(&curr_index);
+
+    /// Recalculates the given cells in order.
+    ///
+    /// The cells are evaluated level by level, where the cells of one level do not
+    /// depend on each other and may be evaluated in parallel. Results are stored in
+    /// the order of `list_fpr_update`, so the outcome does not depend on threading.
+    ///
+    /// Stops as soon as the cancel token of `options` is triggered and returns
+    /// `Err(Error::Cancelled)`; cells already recalculated keep their new values,
+    /// so the caller has to restore them.
+    pub(crate) fn update_cell(
+        &mut self,
+        list_fpr_update: Vec<CellAddr>,
+        options: &EvalOptions,
+    ) -> Result<(), Error> {
+        for level in self.levels(&list_fpr_update) {
+            self.prepare_aggregates(&level);
+            let results = self.evaluate_level(&level, options);
+            for (i, result) in level.into_iter().zip(results) {
+                if result == Err(Error::Cancelled) {
+                    return Err(Error::Cancelled);
                 }
+                self.track(i, |sheet| {
+                    let cell = &mut sheet.grid[i];
+                    cell.formula.flag.set_is_div_by_zero(0);
+                    cell.formula.flag.set_is_overflow(0);
+                    cell.formula.flag.set_is_timeout(0);
+                    cell.formula.flag.set_is_ref(0);
+                    match result {
+                        Ok(value) => cell.value = value,
+                        Err(Error::DivByZero) => cell.formula.flag.set_is_div_by_zero(1),
+                        Err(Error::Overflow) => cell.formula.flag.set_is_overflow(1),
+                        Err(Error::Timeout) => cell.formula.flag.set_is_timeout(1),
+                        Err(Error::Ref) => cell.formula.flag.set_is_ref(1),
+                        Err(_) => {}
+                    }
+                });
             }
         }
+        Ok(())
+    }
+    pub(crate) fn remove_old_dependicies(
+        &mut self,
+        row: usize,
+        col: usize,
+        restore_command: CommandCall,
+    ) {
+        // Remove all dependencies from previous formula
+        self.detach(CellAddr::from_index(row, col));
 
-        // // Set the cell's formula to the restore command
-        // self.grid[row][col].formula = restore_command;
         // Restore the cell's value to the original value
         self.set_dependicies_cell(row, col, restore_command.clone());
     }
+    /// Parses a formula and checks that every cell it references lies inside the sheet.
+    ///
+    /// References to other sheets of the workbook are checked against the same
+    /// bounds, which all sheets of a workbook share.
+    /// Sets error code 1 on the returned command if the formula is invalid.
+    pub(crate) fn parse_checked(&self, new_formula: &str) -> CommandCall {
+        let Some(new_formula) = self.internalize(new_formula) else {
+            let mut command = CommandCall::ref_error();
+            command.flag.set_error(1);
+            return command;
+        };
+        let mut command = parse_formula(&new_formula);
+        // Only cell references need to be inside the sheet, literals may be any i32
+        for param in [command.param1, command.param2] {
+            let (row1, col1) = match param {
+                Param::Value(_) => continue,
+                Param::Cell(addr) => addr.index(),
+                Param::External(id, addr) if self.sheet_name(id).is_some() => addr.index(),
+                Param::External(..) => (usize::MAX, usize::MAX),
+            };
+            if row1 > self.row || col1 > self.col {
+                command.flag.set_error(1);
+            }
+        }
+        command
+    }
+
     pub fn update_cell_data(&mut self, row: usize, col: usize, new_formula: String) -> CallResult {
+        self.update_cell_data_with(row, col, new_formula, &EvalOptions::default())
+    }
+
+    /// Updates a cell's formula and recalculates its dependents under the given limits.
+    ///
+    /// Formulas that run past the deadline or their cell budget are marked with
+    /// `Error::Timeout`. If the cancel token fires, the cell's previous formula and
+    /// every recalculated value are restored and `Error::Cancelled` is returned.
+    /// A formula closing a loop is rejected with `Error::CycleDetected`, unless
+    /// iterative calculation is on; then the loop is evaluated until it settles.
+    /// Input breaking the validation of the cell is rejected with
+    /// `Error::ValidationFailed`, or accepted and reported in `violation` if the
+    /// validation only warns.
+    ///
+    /// # Parameters
+    /// * `row` - Row index of the cell
+    /// * `col` - Column index of the cell
+    /// * `new_formula` - The formula to assign
+    /// * `options` - Cancellation token, deadline and per-cell budget
+    pub fn update_cell_data_with(
+        &mut self,
+        row: usize,
+        col: usize,
+        new_formula: String,
+        options: &EvalOptions,
+    ) -> CallResult {
+        let start = time::Instant::now();
+        let violation = self.violation(row, col, &new_formula);
+        if let Some(validation) = &violation
+            && validation.alert == Alert::Reject
+        {
+            return CallResult {
+                time: start.elapsed().as_millis() as f64,
+                error: Error::ValidationFailed,
+                cycle: vec![],
+                diverged: vec![],
+                violation,
+            };
+        }
+        let mut result = self.notify(|sheet| sheet.apply_update(row, col, new_formula, options));
+        if !matches!(
+            result.error,
+            Error::CycleDetected | Error::InvalidInput | Error::Cancelled
+        ) {
+            result.violation = violation;
+        }
+        result
+    }
+
+    fn apply_update(
+        &mut self,
+        row: usize,
+        col: usize,
+        new_formula: String,
+        options: &EvalOptions,
+    ) -> CallResult {
         // Overall timing
         let start_total = time::Instant::now();
 
         // Stage 1: Parse formula
-        let mut command = parse_formula(&new_formula);
-        let (row1, col1) = convert_to_index_int(command.param1);
-        let (row2, col2) = convert_to_index_int(command.param2);
-        if row1 > self.row || col1 > self.col || row2 > self.row || col2 > self.col {
-            command.flag.set_error(1);
-        }
-        if command.flag.error() == 0 {
+        let mut command = self.parse_checked(&new_formula);
+        if command.flag.error() != 1 {
             command.flag.set_is_any(1);
             // Stage 2: Save old command and set dependencies
-            let old_command = self.grid[row][col].formula.clone();
+            let old_command = self.formula_of(CellAddr::from_index(row, col));
+            let old_value = self.grid[(row, col)].value;
+            let before = self.is_recording().then(|| self.formula_text(&old_command));
             self.remove_old_dependicies(row, col, command.clone());
             // Stage 3: Topological sort
-            let topo_vec = self.toposort(row * ENCODE_SHIFT + col);
+            let target = CellAddr::from_index(row, col);
+            let mut cycle = vec![];
+            let mut diverged = vec![];
             // Stage 4: Update cells
-            if topo_vec.is_empty() {
-                self.grid[row][col].formula.flag.set_error(2);
-            } else {
-                self.update_cell(topo_vec);
+            match self.plan_recalc(&[target]) {
+                Err(found) => {
+                    self.grid[(row, col)].formula.flag.set_error(2);
+                    cycle = found;
+                }
+                Ok(plan) => {
+                    // Remember the current state so a cancelled recalculation can be undone
+                    let snapshot: Vec<(CellAddr, i32, CommandFlag)> = plan
+                        .cells()
+                        .map(|i| {
+                            let cell = &self.grid[i];
+                            (i, cell.value, cell.formula.flag.clone())
+                        })
+                        .collect();
+                    match self.recalculate(plan, options) {
+                        Ok(cells) => diverged = cells,
+                        Err(_) => {
+                            self.remove_old_dependicies(row, col, old_command);
+                            for (i, value, flag) in snapshot {
+                                self.track(i, |sheet| {
+                                    let cell = &mut sheet.grid[i];
+                                    cell.value = value;
+                                    if i != target {
+                                        cell.formula.flag.copy_status(&flag);
+                                    }
+                                });
+                            }
+                            self.track(target, |sheet| sheet.grid[target].value = old_value);
+                            return CallResult {
+                                time: start_total.elapsed().as_millis() as f64,
+                                error: Error::Cancelled,
+                                cycle: vec![],
+                                diverged: vec![],
+                                violation: None,
+                            };
+                        }
+                    }
+                }
             }
             let mut ans = CallResult {
                 time: start_total.elapsed().as_millis() as f64,
                 error: Error::None,
+                cycle: vec![],
+                diverged,
+                violation: None,
             };
 
-            if self.grid[row][col].formula.flag.is_div_by_zero() == 1 {
+            if self.grid[(row, col)].formula.flag.is_div_by_zero() == 1 {
                 ans.error = Error::DivByZero;
-            } else if self.grid[row][col].formula.flag.error() == 1 {
+            } else if self.grid[(row, col)].formula.flag.is_overflow() == 1 {
+                ans.error = Error::Overflow;
+            } else if self.grid[(row, col)].formula.flag.is_timeout() == 1 {
+                ans.error = Error::Timeout;
+            } else if self.grid[(row, col)].formula.flag.is_ref() == 1 {
+                ans.error = Error::Ref;
+            } else if self.grid[(row, col)].formula.flag.error() == 1 {
                 ans.error = Error::InvalidInput;
-            } else if self.grid[row][col].formula.flag.error() == 2 {
+            } else if self.grid[(row, col)].formula.flag.error() == 2 {
                 ans.error = Error::CycleDetected;
+                ans.cycle = cycle;
                 self.remove_old_dependicies(row, col, old_command);
             }
 
+            if let Some(before) = before
+                && !matches!(ans.error, Error::CycleDetected | Error::InvalidInput)
+            {
+                let after = self.get_formula(row, col);
+                self.record(target, before, after);
+            }
+
             ans
         } else {
             CallResult {
                 time: start_total.elapsed().as_millis() as f64,
                 error: Error::InvalidInput,
+                cycle: vec![],
+                diverged: vec![],
+                violation: None,
             }
         }
     }
 
     pub fn get_value(&self, row: i32, col: i32) -> i32 {
-        self.grid[row as usize][col as usize].value
+        self.grid[(row as usize, col as usize)].value
+    }
+
+    /// Returns the error value held by a cell, if any.
+    ///
+    /// # Parameters
+    /// * `row` - Row index of the cell
+    /// * `col` - Column index of the cell
+    pub fn get_error(&self, row: i32, col: i32) -> Option<Error> {
+        value_error(&self.grid[(row as usize, col as usize)])
     }
 }
 
@@ -927,6 +1251,27 @@ mod tests {
         assert_eq!(update_result.error, Error::CycleDetected);
     }
 
+    #[test]
+    fn test_cycle_path() {
+        let mut test_sheet = Sheet::new(10, 10);
+        test_sheet.update_cell_data(1, 2, String::from("C1+1"));
+        test_sheet.update_cell_data(1, 3, String::from("SUM(A1:A5)"));
+        test_sheet.update_cell_data(1, 4, String::from("B1"));
+        let result = test_sheet.update_cell_data(2, 1, String::from("B1*2"));
+        let names: Vec<String> = result.cycle.iter().map(|a| a.to_string()).collect();
+        assert_eq!(result.error, Error::CycleDetected);
+        assert_eq!(names, ["A2", "B1", "C1"]);
+        assert_eq!(result.cycle_path().unwrap(), "A2 -> B1 -> C1 -> A2");
+
+        // A cell reading itself is a loop of one
+        let result = test_sheet.update_cell_data(5, 5, String::from("E5+1"));
+        assert_eq!(result.cycle_path().unwrap(), "E5 -> E5");
+        let result = test_sheet.update_cell_data(6, 1, String::from("D1+7"));
+        assert_eq!(result.error, Error::None);
+        assert!(result.cycle.is_empty());
+        assert_eq!(result.cycle_path(), None);
+    }
+
     #[test]
     fn test_clear_operations() {
         let mut test_sheet = Sheet::new(10, 10);
@@ -995,7 +1340,90 @@ mod tests {
 
         // Test formula that references cell with division by zero
         test_sheet.update_cell_data(1, 5, String::from("D1*2"));
-        assert_eq!(test_sheet.grid[1][5].formula.flag.is_div_by_zero(), 1);
+        assert_eq!(test_sheet.grid[(1, 5)].formula.flag.is_div_by_zero(), 1);
+    }
+
+    #[test]
+    fn test_arithmetic_overflow() {
+        let mut test_sheet = Sheet::new(10, 10);
+
+        // Boundary values themselves are fine
+        test_sheet.update_cell_data(1, 1, String::from("2147483647"));
+        test_sheet.update_cell_data(1, 2, String::from("-2147483648"));
+        assert_eq!(test_sheet.get_value(1, 1), i32::MAX);
+        assert_eq!(test_sheet.get_value(1, 2), i32::MIN);
+        let result = test_sheet.update_cell_data(1, 3, String::from("A1+0"));
+        assert_eq!(result.error, Error::None);
+        assert_eq!(test_sheet.get_value(1, 3), i32::MAX);
+
+        // One past the boundary overflows instead of wrapping
+        let result = test_sheet.update_cell_data(2, 1, String::from("A1+1"));
+        assert_eq!(result.error, Error::Overflow);
+        assert_eq!(test_sheet.get_error(2, 1), Some(Error::Overflow));
+        let result = test_sheet.update_cell_data(2, 2, String::from("B1-1"));
+        assert_eq!(result.error, Error::Overflow);
+        let result = test_sheet.update_cell_data(2, 3, String::from("A1*2"));
+        assert_eq!(result.error, Error::Overflow);
+        let result = test_sheet.update_cell_data(2, 4, String::from("B1/-1"));
+        assert_eq!(result.error, Error::Overflow);
+        let result = test_sheet.update_cell_data(2, 5, String::from("2147483647+1"));
+        assert_eq!(result.error, Error::Overflow);
+
+        // Overflow clears once the inputs are back in range
+        test_sheet.update_cell_data(1, 1, String::from("5"));
+        assert_eq!(test_sheet.get_error(2, 1), None);
+        assert_eq!(test_sheet.get_value(2, 1), 6);
+        assert_eq!(test_sheet.get_value(2, 3), 10);
+    }
+
+    #[test]
+    fn test_overflow_propagation() {
+        let mut test_sheet = Sheet::new(10, 10);
+        test_sheet.update_cell_data(1, 1, String::from("2147483647"));
+        test_sheet.update_cell_data(1, 2, String::from("A1*A1"));
+        test_sheet.update_cell_data(1, 3, String::from("B1"));
+        test_sheet.update_cell_data(1, 4, String::from("C1-5"));
+        test_sheet.update_cell_data(2, 1, String::from("MIN(A1:B1)"));
+        assert_eq!(test_sheet.get_error(1, 2), Some(Error::Overflow));
+        assert_eq!(test_sheet.get_error(1, 3), Some(Error::Overflow));
+        assert_eq!(test_sheet.get_error(1, 4), Some(Error::Overflow));
+        assert_eq!(test_sheet.get_error(2, 1), Some(Error::Overflow));
+    }
+
+    #[test]
+    fn test_range_overflow() {
+        let mut test_sheet = Sheet::new(10, 10);
+        test_sheet.update_cell_data(1, 1, String::from("2147483647"));
+        test_sheet.update_cell_data(2, 1, String::from("2147483647"));
+        test_sheet.update_cell_data(3, 1, String::from("-2147483648"));
+
+        // Intermediate sums may exceed i32 as long as the result fits
+        test_sheet.update_cell_data(1, 2, String::from("SUM(A1:A3)"));
+        assert_eq!(test_sheet.get_error(1, 2), None);
+        assert_eq!(test_sheet.get_value(1, 2), i32::MAX - 1);
+
+        // Sum that does not fit
+        let result = test_sheet.update_cell_data(2, 2, String::from("SUM(A1:A2)"));
+        assert_eq!(result.error, Error::Overflow);
+
+        // Mean and extremes of boundary values always fit
+        test_sheet.update_cell_data(3, 2, String::from("AVG(A1:A2)"));
+        assert_eq!(test_sheet.get_value(3, 2), i32::MAX);
+        test_sheet.update_cell_data(4, 2, String::from("MAX(A1:A3)"));
+        assert_eq!(test_sheet.get_value(4, 2), i32::MAX);
+        test_sheet.update_cell_data(5, 2, String::from("MIN(A1:A3)"));
+        assert_eq!(test_sheet.get_value(5, 2), i32::MIN);
+
+        // Standard deviation of the extremes is larger than i32::MAX
+        test_sheet.update_cell_data(4, 1, String::from("2147483647"));
+        test_sheet.update_cell_data(5, 1, String::from("-2147483648"));
+        let result = test_sheet.update_cell_data(6, 2, String::from("STDEV(A4:A5)"));
+        assert_eq!(result.error, Error::Overflow);
+
+        // Shrinking an input clears the error on the dependent range function
+        test_sheet.update_cell_data(2, 1, String::from("-1"));
+        assert_eq!(test_sheet.get_error(2, 2), None);
+        assert_eq!(test_sheet.get_value(2, 2), i32::MAX - 1);
     }
 
     #[test]
@@ -1045,6 +1473,233 @@ mod tests {
         assert!(elapsed.as_secs() >= 1);
     }
 
+    #[test]
+    fn test_sleep_cell_budget() {
+        let mut test_sheet = Sheet::new(5, 5);
+        test_sheet.update_cell_data(1, 1, String::from("7"));
+        test_sheet.update_cell_data(1, 3, String::from("B1+1"));
+
+        let options = EvalOptions {
+            cell_budget: Some(time::Duration::from_millis(50)),
+            ..EvalOptions::default()
+        };
+        let start = time::Instant::now();
+        let result = test_sheet.update_cell_data_with(1, 2, String::from("SLEEP(A1)"), &options);
+        assert!(start.elapsed() < time::Duration::from_secs(2));
+        assert_eq!(result.error, Error::Timeout);
+        assert_eq!(test_sheet.get_error(1, 2), Some(Error::Timeout));
+        // Dependents of a timed out cell report the timeout as well
+        assert_eq!(test_sheet.get_error(1, 3), Some(Error::Timeout));
+
+        // A short enough sleep fits in the budget
+        test_sheet.update_cell_data(1, 1, String::from("0"));
+        let result = test_sheet.update_cell_data_with(1, 2, String::from("SLEEP(A1)"), &options);
+        assert_eq!(result.error, Error::None);
+        assert_eq!(test_sheet.get_error(1, 3), None);
+        assert_eq!(test_sheet.get_value(1, 3), 1);
+    }
+
+    #[test]
+    fn test_sleep_deadline() {
+        let mut test_sheet = Sheet::new(5, 5);
+        let options = EvalOptions::with_timeout(time::Duration::from_millis(50));
+        let start = time::Instant::now();
+        let result = test_sheet.update_cell_data_with(1, 1, String::from("SLEEP(10)"), &options);
+        assert!(start.elapsed() < time::Duration::from_secs(2));
+        assert_eq!(result.error, Error::Timeout);
+    }
+
+    #[test]
+    fn test_deadline_stops_recalculation() {
+        let mut test_sheet = Sheet::new(5, 5);
+        test_sheet.update_cell_data(1, 2, String::from("A1+1"));
+        test_sheet.update_cell_data(1, 3, String::from("B1*2"));
+        let options = EvalOptions {
+            deadline: Some(time::Instant::now()),
+            ..EvalOptions::default()
+        };
+        let result = test_sheet.update_cell_data_with(1, 1, String::from("4"), &options);
+        assert_eq!(result.error, Error::Timeout);
+        assert_eq!(test_sheet.get_error(1, 3), Some(Error::Timeout));
+        test_sheet.update_cell_data(1, 1, String::from("5"));
+        assert_eq!(test_sheet.get_value(1, 3), 12);
+    }
+
+    #[test]
+    fn test_long_chains_do_not_overflow_the_stack() {
+        let rows = 100_000;
+        let mut test_sheet = Sheet::new(rows, 1);
+        test_sheet.transaction(|batch| {
+            for row in 2..=rows {
+                batch.set(row, 1, format!("A{}+1", row - 1));
+            }
+        });
+        test_sheet.update_cell_data(1, 1, String::from("1"));
+        assert_eq!(test_sheet.get_value(rows as i32, 1), rows as i32);
+
+        // Iterative calculation splits the chain into components
+        test_sheet.set_iterative_calculation(Some(Iteration::default()));
+        test_sheet.update_cell_data(1, 1, String::from("2"));
+        assert_eq!(test_sheet.get_value(rows as i32, 1), rows as i32 + 1);
+    }
+
+    #[test]
+    fn test_cancel_restores_previous_state() {
+        let mut test_sheet = Sheet::new(5, 5);
+        test_sheet.update_cell_data(1, 1, String::from("0"));
+        test_sheet.update_cell_data(1, 2, String::from("SLEEP(A1)"));
+        test_sheet.update_cell_data(1, 3, String::from("B1+1"));
+        test_sheet.update_cell_data(2, 1, String::from("A1*2"));
+
+        // Cancel from another thread while SLEEP is waiting
+        let options = EvalOptions::default();
+        let token = options.cancel.clone();
+        let canceller = std::thread::spawn(move || {
+            std::thread::sleep(time::Duration::from_millis(50));
+            token.cancel();
+        });
+        let start = time::Instant::now();
+        let result = test_sheet.update_cell_data_with(1, 1, String::from("30"), &options);
+        canceller.join().unwrap();
+        assert!(start.elapsed() < time::Duration::from_secs(5));
+        assert_eq!(result.error, Error::Cancelled);
+
+        // Nothing changed: formula, values and dependencies are as before
+        assert_eq!(test_sheet.get_formula(1, 1), "0");
+        assert_eq!(test_sheet.get_value(1, 1), 0);
+        assert_eq!(test_sheet.get_value(1, 2), 0);
+        assert_eq!(test_sheet.get_value(1, 3), 1);
+        assert_eq!(test_sheet.get_value(2, 1), 0);
+        test_sheet.update_cell_data(1, 1, String::from("0"));
+        test_sheet.update_cell_data(2, 2, String::from("A1+3"));
+        assert_eq!(test_sheet.get_value(2, 2), 3);
+
+        // An already cancelled token leaves the sheet untouched as well
+        let result = test_sheet.update_cell_data_with(3, 3, String::from("A1+B1"), &options);
+        assert_eq!(result.error, Error::Cancelled);
+        assert_eq!(test_sheet.get_formula(3, 3), "0");
+        test_sheet.update_cell_data(1, 1, String::from("0"));
+        assert_eq!(test_sheet.grid[(1, 1)].depend.len(), 3);
+    }
+
+    #[test]
+    fn test_large_sheet_is_sparse() {
+        let mut test_sheet = Sheet::new(1000, 18279);
+        assert!(test_sheet.grid.is_empty());
+
+        test_sheet.update_cell_data(1, 1, String::from("5"));
+        test_sheet.update_cell_data(1, 2, String::from("A1+1"));
+        test_sheet.update_cell_data(3, 1, String::from("SUM(A1:B1)"));
+        assert_eq!(test_sheet.get_value(3, 1), 11);
+        assert_eq!(test_sheet.get_value(999, 9000), 0);
+
+        // Only the written cells and the cells of the referenced range are stored
+        assert_eq!(test_sheet.grid.len(), 3);
+
+        test_sheet.clear_cell(3, 1);
+        assert_eq!(test_sheet.grid.len(), 2);
+        test_sheet.clear_row(1);
+        assert!(test_sheet.grid.is_empty());
+    }
+
+    #[test]
+    fn test_addresses_beyond_old_encoding() {
+        let mut test_sheet = Sheet::new(1_000_000, 16384);
+        // Under the old packed encoding (1, 10001) and (2, 1) were the same dependent
+        let far = CellAddr::new(1, 10001).to_string();
+        test_sheet.update_cell_data(1, 1, String::from("3"));
+        test_sheet.update_cell_data(1, 10001, String::from("A1*2"));
+        test_sheet.update_cell_data(2, 1, String::from("A1+1"));
+        test_sheet.update_cell_data(1_000_000, 16384, format!("{}+1", far));
+        test_sheet.update_cell_data(1, 1, String::from("5"));
+        assert_eq!(test_sheet.get_value(1, 10001), 10);
+        assert_eq!(test_sheet.get_value(2, 1), 6);
+        assert_eq!(test_sheet.get_value(1_000_000, 16384), 11);
+        assert_eq!(test_sheet.get_formula(1_000_000, 16384), "NTQ1+1");
+
+        let result = test_sheet.update_cell_data(1, 2, String::from("XFE1"));
+        assert_eq!(result.error, Error::InvalidInput);
+    }
+
+    #[test]
+    fn test_range_dependencies_are_indexed() {
+        let mut test_sheet = Sheet::new(2000, 100);
+        test_sheet.update_cell_data(1, 30, String::from("SUM(A1:Z1000)"));
+        test_sheet.update_cell_data(2, 30, String::from("MAX(A1:Z1000)"));
+        assert_eq!(test_sheet.grid.len(), 2);
+        assert_eq!(test_sheet.ranges.len(), 1);
+
+        test_sheet.update_cell_data(500, 26, String::from("7"));
+        test_sheet.update_cell_data(1000, 1, String::from("5"));
+        assert_eq!(test_sheet.get_value(1, 30), 12);
+        assert_eq!(test_sheet.get_value(2, 30), 7);
+
+        // A cell outside the range does not trigger the formulas
+        test_sheet.update_cell_data(1001, 1, String::from("AD1"));
+        assert_eq!(test_sheet.get_value(1001, 1), 12);
+        let result = test_sheet.update_cell_data(1, 30, String::from("SUM(A1000:A1001)"));
+        assert_eq!(result.error, Error::CycleDetected);
+        assert_eq!(test_sheet.get_formula(1, 30), "SUM(A1:Z1000)");
+
+        test_sheet.update_cell_data(1, 30, String::from("A1000"));
+        assert_eq!(test_sheet.ranges.len(), 1);
+        test_sheet.update_cell_data(2, 30, String::from("0"));
+        assert!(test_sheet.ranges.is_empty());
+        test_sheet.update_cell_data(1000, 1, String::from("9"));
+        assert_eq!(test_sheet.get_value(1, 30), 9);
+        assert_eq!(test_sheet.get_value(1001, 1), 9);
+    }
+
+    #[test]
+    fn test_incremental_aggregates_match_rescan() {
+        let mut test_sheet = Sheet::new(300, 10);
+        let range = RangeAddr::parse("A1:B200").unwrap();
+        for (row, function) in ["MIN", "MAX", "SUM", "AVG", "STDEV"].iter().enumerate() {
+            test_sheet.update_cell_data(row + 1, 4, format!("{}(A1:B200)", function));
+        }
+        assert!(test_sheet.ranges.aggregate(range).is_some());
+
+        let mut seed: u32 = 7;
+        for step in 0..400 {
+            seed = seed.wrapping_mul(1_103_515_245).wrapping_add(12345);
+            let row = (seed >> 8) as usize % 200 + 1;
+            let col = (seed >> 20) as usize % 2 + 1;
+            let formula = match step % 5 {
+                0 => String::from("0"),
+                1 => String::from("1/0"),
+                2 => format!("A{}*2", (seed >> 4) % 200 + 1),
+                _ => format!("{}", (seed >> 12) as i32 % 1000 - 500),
+            };
+            test_sheet.update_cell_data(row, col, formula);
+
+            let expected = [
+                test_sheet.minimum(range, false),
+                test_sheet.maximum(range, false),
+                test_sheet.sum(range, false),
+                test_sheet.average(range, false),
+                test_sheet.stddev(range, false),
+            ];
+            for (row, expected) in expected.into_iter().enumerate() {
+                let row = row as i32 + 1;
+                match expected {
+                    Ok(value) => assert_eq!(test_sheet.get_value(row, 4), value, "step {step}"),
+                    Err(err) => assert_eq!(test_sheet.get_error(row, 4), Some(err)),
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_copy_row_rollback_keeps_cells_unstored() {
+        let mut test_sheet = Sheet::new(10, 10);
+        test_sheet.update_cell_data(1, 1, String::from("7"));
+        test_sheet.update_cell_data(1, 2, String::from("B2"));
+        // A2 is written first, then B2 = B2 is a cycle and the row is rolled back
+        assert_eq!(test_sheet.copy_row(1, 2), Err(Error::CycleDetected));
+        assert!(test_sheet.grid.get(2, 1).is_none());
+        assert_eq!(test_sheet.get_value(2, 1), 0);
+    }
+
     #[test]
     fn test_complex_dependencies() {
         let mut test_sheet = Sheet::new(10, 10);
@@ -1131,16 +1786,16 @@ mod tests {
         test_sheet.update_cell_data(3, 5, String::from("STDEV(A1:C1)"));
 
         // Check that div by zero flag propagated
-        assert_eq!(test_sheet.grid[1][3].formula.flag.is_div_by_zero(), 1);
-        assert_eq!(test_sheet.grid[2][1].formula.flag.is_div_by_zero(), 1);
-        assert_eq!(test_sheet.grid[2][2].formula.flag.is_div_by_zero(), 1);
-        assert_eq!(test_sheet.grid[2][3].formula.flag.is_div_by_zero(), 1);
-        assert_eq!(test_sheet.grid[2][4].formula.flag.is_div_by_zero(), 1);
-        assert_eq!(test_sheet.grid[3][1].formula.flag.is_div_by_zero(), 1);
-        assert_eq!(test_sheet.grid[3][2].formula.flag.is_div_by_zero(), 1);
-        assert_eq!(test_sheet.grid[3][3].formula.flag.is_div_by_zero(), 1);
-        assert_eq!(test_sheet.grid[3][4].formula.flag.is_div_by_zero(), 1);
-        assert_eq!(test_sheet.grid[3][5].formula.flag.is_div_by_zero(), 1);
+        assert_eq!(test_sheet.grid[(1, 3)].formula.flag.is_div_by_zero(), 1);
+        assert_eq!(test_sheet.grid[(2, 1)].formula.flag.is_div_by_zero(), 1);
+        assert_eq!(test_sheet.grid[(2, 2)].formula.flag.is_div_by_zero(), 1);
+        assert_eq!(test_sheet.grid[(2, 3)].formula.flag.is_div_by_zero(), 1);
+        assert_eq!(test_sheet.grid[(2, 4)].formula.flag.is_div_by_zero(), 1);
+        assert_eq!(test_sheet.grid[(3, 1)].formula.flag.is_div_by_zero(), 1);
+        assert_eq!(test_sheet.grid[(3, 2)].formula.flag.is_div_by_zero(), 1);
+        assert_eq!(test_sheet.grid[(3, 3)].formula.flag.is_div_by_zero(), 1);
+        assert_eq!(test_sheet.grid[(3, 4)].formula.flag.is_div_by_zero(), 1);
+        assert_eq!(test_sheet.grid[(3, 5)].formula.flag.is_div_by_zero(), 1);
     }
 
     #[test]
@@ -0,0 +1,283 @@
+//! Sorting the rows of a range.
+//!
+//! Sorting moves whole rows of the range. References made by the formulas
+//! inside the sorted rows to cells of those rows follow the data, so a formula
+//! like `C2 = A2*B2` still multiplies the values of its own row afterwards.
+//! References from outside the sorted rows keep their addresses and see
+//! whatever data is sorted into them, as do ranges that are not contained in
+//! a single sorted row. The sort is applied as one batch and undone as one step.
+
+use crate::addr::{CellAddr, RangeAddr};
+use crate::batch::Batch;
+use crate::parse::{CommandCall, Param};
+use crate::sheet::{Error, Sheet, reading};
+use fxhash::{FxHashMap, FxHashSet};
+use std::cmp::Ordering;
+
+/// One column the rows of a range are ordered by.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SortKey {
+    /// Column of the key (1-based, `A` = 1), which must lie inside the range
+    pub col: u32,
+    /// Whether larger values come first
+    pub descending: bool,
+}
+
+impl SortKey {
+    /// Creates a key sorting by `col` in ascending order.
+    pub fn ascending(col: u32) -> Self {
+        Self {
+            col,
+            descending: false,
+        }
+    }
+
+    /// Creates a key sorting by `col` in descending order.
+    pub fn descending(col: u32) -> Self {
+        Self {
+            col,
+            descending: true,
+        }
+    }
+
+    /// Orders two readings of the key column; errors come last in either order.
+    fn compare(&self, a: Option<i32>, b: Option<i32>) -> Ordering {
+        match (a, b) {
+            (Some(a), Some(b)) if self.descending => b.cmp(&a),
+            (Some(a), Some(b)) => a.cmp(&b),
+            (Some(_), None) => Ordering::Less,
+            (None, Some(_)) => Ordering::Greater,
+            (None, None) => Ordering::Equal,
+        }
+    }
+}
+
+impl Sheet {
+    /// Sorts the rows of a range by one or more key columns.
+    ///
+    /// Rows comparing equal on every key keep their order. Cells holding an
+    /// error value sort after all values.
+    ///
+    /// # Parameters
+    /// * `range` - The range whose rows are sorted
+    /// * `keys` - Key columns, the most significant first
+    /// * `header` - Whether the first row of the range is a header that stays in place
+    ///
+    /// # Returns
+    /// `Error::InvalidInput` if the range does not lie inside the sheet, no key
+    /// is given or a key column lies outside the range; the sheet is then unchanged.
+    pub fn sort_range(
+        &mut self,
+        range: RangeAddr,
+        keys: &[SortKey],
+        header: bool,
+    ) -> Result<(), Error> {
+        if range.start.row == 0
+            || range.start.col == 0
+            || range.end.row as usize > self.row
+            || range.end.col as usize > self.col
+            || keys.is_empty()
+            || keys
+                .iter()
+                .any(|key| !(range.start.col..=range.end.col).contains(&key.col))
+        {
+            return Err(Error::InvalidInput);
+        }
+        let first = range.start.row + u32::from(header);
+        if first >= range.end.row {
+            return Ok(());
+        }
+        let block = RangeAddr {
+            start: CellAddr::new(first, range.start.col),
+            end: range.end,
+        };
+
+        // New order of the rows, and where each row goes
+        let mut order: Vec<u32> = (first..=range.end.row).collect();
+        order.sort_by(|&a, &b| {
+            keys.iter()
+                .map(|key| {
+                    let value = |row| reading(&self.grid[CellAddr::new(row, key.col)]);
+                    key.compare(value(a), value(b))
+                })
+                .find(|ordering| ordering.is_ne())
+                .unwrap_or(Ordering::Equal)
+        });
+        let row_of: FxHashMap<u32, u32> = order
+            .iter()
+            .enumerate()
+            .map(|(i, &row)| (row, first + i as u32))
+            .collect();
+        let target = |addr: CellAddr| CellAddr::new(row_of[&addr.row], addr.col);
+
+        let written: Vec<CellAddr> = self
+            .grid
+            .iter()
+            .filter(|(addr, cell)| block.contains(*addr) && cell.formula.flag.is_any() != 0)
+            .map(|(addr, _)| addr)
+            .collect();
+        let placed: FxHashSet<CellAddr> = written.iter().map(|&addr| target(addr)).collect();
+
+        let mut batch = Batch::unchecked();
+        let mut cleared = Vec::new();
+        for &addr in &written {
+            if !placed.contains(&addr) {
+                batch.clear(addr.row as usize, addr.col as usize);
+                cleared.push(addr);
+            }
+        }
+        for &addr in &written {
+            let moved = target(addr);
+            let formula = self.formula_of(addr);
+            let sorted = sorted_formula(&formula, block, &target);
+            // A row that stays only changes if its formulas read rows that moved
+            if moved != addr || !sorted.same_formula(&formula) {
+                batch.set(
+                    moved.row as usize,
+                    moved.col as usize,
+                    self.formula_text(&sorted),
+                );
+            }
+        }
+
+        self.group(|sheet| {
+            let result = sheet.commit(batch);
+            if result.error != Error::None {
+                return Err(result.error);
+            }
+            for addr in cleared {
+                sheet.prune_cell(addr.row as usize, addr.col as usize);
+            }
+            // Notes and comments go with their rows, written or not
+            sheet.relocate_notes(|addr| block.contains(addr), |addr| Some(target(addr)));
+            Ok(())
+        })
+    }
+
+    /// Returns the smallest range containing every written cell, if any.
+    pub fn used_range(&self) -> Option<RangeAddr> {
+        let mut written = self
+            .grid
+            .iter()
+            .filter(|(_, cell)| cell.formula.flag.is_any() != 0)
+            .map(|(addr, _)| addr);
+        let first = written.next()?;
+        let (start, end) = written.fold((first, first), |(start, end), addr| {
+            (
+                CellAddr::new(start.row.min(addr.row), start.col.min(addr.col)),
+                CellAddr::new(end.row.max(addr.row), end.col.max(addr.col)),
+            )
+        });
+        RangeAddr::new(start, end)
+    }
+}
+
+/// Rewrites the references of a formula inside the sorted rows so they follow
+/// the rows they read.
+fn sorted_formula(
+    formula: &CommandCall,
+    block: RangeAddr,
+    target: &impl Fn(CellAddr) -> CellAddr,
+) -> CommandCall {
+    let mut formula = formula.clone();
+    if let Some(range) = formula.range() {
+        // Only a range within a single sorted row moves with it
+        if block.contains(range.start)
+            && block.contains(range.end)
+            && range.start.row == range.end.row
+        {
+            formula.param1 = Param::Cell(target(range.start));
+            formula.param2 = Param::Cell(target(range.end));
+        }
+        return formula;
+    }
+    for param in [&mut formula.param1, &mut formula.param2] {
+        if let Param::Cell(addr) = *param
+            && block.contains(addr)
+        {
+            *param = Param::Cell(target(addr));
+        }
+    }
+    formula
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn range(text: &str) -> RangeAddr {
+        RangeAddr::parse(text).unwrap()
+    }
+
+    fn column(test_sheet: &Sheet, col: i32, rows: std::ops::RangeInclusive<i32>) -> Vec<i32> {
+        rows.map(|row| test_sheet.get_value(row, col)).collect()
+    }
+
+    #[test]
+    fn test_sort_by_several_keys() {
+        let mut test_sheet = Sheet::new(10, 10);
+        test_sheet.transaction(|batch| {
+            for (row, (group, value)) in [(2, 5), (1, 7), (2, 1), (1, 3), (2, 5)]
+                .into_iter()
+                .enumerate()
+            {
+                let row = row + 2;
+                batch.set(row, 1, group.to_string());
+                batch.set(row, 2, value.to_string());
+                batch.set(row, 3, format!("A{row}*B{row}"));
+                batch.set(row, 4, row.to_string());
+            }
+            batch.set(1, 1, "100");
+            batch.set(1, 5, "C2");
+        });
+        test_sheet
+            .sort_range(
+                range("A1:D6"),
+                &[SortKey::ascending(1), SortKey::descending(2)],
+                true,
+            )
+            .unwrap();
+
+        // The header stays in place
+        assert_eq!(test_sheet.get_value(1, 1), 100);
+        assert_eq!(column(&test_sheet, 1, 2..=6), [1, 1, 2, 2, 2]);
+        assert_eq!(column(&test_sheet, 2, 2..=6), [7, 3, 5, 5, 1]);
+        // Equal rows keep their order
+        assert_eq!(column(&test_sheet, 4, 2..=6), [3, 5, 2, 6, 4]);
+        // Formulas in the sorted rows still read their own row
+        assert_eq!(test_sheet.get_formula(2, 3), "A2*B2");
+        assert_eq!(column(&test_sheet, 3, 2..=6), [7, 3, 10, 10, 2]);
+        // Outside references keep their address
+        assert_eq!(test_sheet.get_formula(1, 5), "C2");
+        assert_eq!(test_sheet.get_value(1, 5), 7);
+
+        assert!(test_sheet.undo());
+        assert_eq!(column(&test_sheet, 4, 2..=6), [2, 3, 4, 5, 6]);
+        assert_eq!(test_sheet.get_value(1, 5), 10);
+    }
+
+    #[test]
+    fn test_sort_follows_references_between_rows() {
+        let mut test_sheet = Sheet::new(10, 10);
+        test_sheet.transaction(|batch| {
+            batch.set(1, 1, "3");
+            batch.set(2, 1, "1");
+            batch.set(3, 1, "2");
+            batch.set(1, 2, "A3");
+        });
+        test_sheet
+            .sort_range(range("A1:B3"), &[SortKey::ascending(1)], false)
+            .unwrap();
+        assert_eq!(column(&test_sheet, 1, 1..=3), [1, 2, 3]);
+        // B1 moved to the third row and still reads the cell holding 2
+        assert_eq!(test_sheet.get_formula(3, 2), "A2");
+        assert_eq!(test_sheet.get_value(3, 2), 2);
+        assert!(test_sheet.grid.get(1, 2).is_none());
+
+        assert_eq!(
+            test_sheet.sort_range(range("A1:B3"), &[SortKey::ascending(3)], false),
+            Err(Error::InvalidInput)
+        );
+        assert_eq!(test_sheet.used_range(), Some(range("A1:B3")));
+    }
+}
@@ -0,0 +1,812 @@
+//! Inserting and deleting rows and columns.
+//!
+//! A structural change moves every cell behind the inserted or deleted lines
+//! and rewrites every formula so its references keep pointing at the same
+//! data. References to deleted cells turn the formula into `#REF!`; ranges
+//! that lose some of their lines shrink, and only become `#REF!` once all of
+//! them are gone. The dependencies are rebuilt from the rewritten formulas.
+
+use crate::addr::{CellAddr, RangeAddr};
+use crate::cell_store::CellStore;
+use crate::conditional::{Condition, Rule};
+use crate::eval::EvalOptions;
+use crate::parse::{CommandCall, Param, unparse_command};
+use crate::sheet::{Error, Sheet};
+use crate::validation::Check;
+
+/// Whether a structural change affects rows or columns.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Axis {
+    Rows,
+    Cols,
+}
+
+/// One insertion or deletion of consecutive rows or columns.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum StructureOp {
+    /// `count` empty lines are inserted before line `at`
+    Insert { axis: Axis, at: u32, count: u32 },
+    /// The `count` lines starting at line `at` are removed
+    Delete { axis: Axis, at: u32, count: u32 },
+}
+
+/// A change moving the cells of a sheet, which the other sheets of a
+/// workbook reading the sheet follow on `Workbook::refresh`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Relocation {
+    /// Rows or columns were inserted or deleted
+    Structure(StructureOp),
+    /// The block `src` was moved by `rows` and `cols`, see `Sheet::move_range`
+    Block {
+        src: RangeAddr,
+        rows: i64,
+        cols: i64,
+    },
+}
+
+impl Relocation {
+    /// Returns the change that takes the cells back, apart from lost references.
+    pub(crate) fn inverse(self) -> Self {
+        match self {
+            Self::Structure(op) => Self::Structure(op.inverse()),
+            Self::Block { src, rows, cols } => {
+                let moved = |addr: CellAddr| {
+                    addr.offset(rows, cols)
+                        .expect("the moved block lies inside the sheet")
+                };
+                Self::Block {
+                    src: RangeAddr {
+                        start: moved(src.start),
+                        end: moved(src.end),
+                    },
+                    rows: -rows,
+                    cols: -cols,
+                }
+            }
+        }
+    }
+}
+
+/// Which sheet a change moving cells was made to, as seen by a sheet whose
+/// formulas are rewritten for it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Moving {
+    /// The sheet itself, whose cells move
+    Own,
+    /// The other sheet of the workbook with this id, which the references to it follow
+    Other(u32),
+}
+
+impl Moving {
+    /// Returns the cell of the changed sheet a reference reads, or `None` if
+    /// the reference reads another sheet or no cell.
+    pub(crate) fn local(self, param: Param) -> Option<CellAddr> {
+        match (self, param) {
+            (Self::Own, Param::Cell(addr)) => Some(addr),
+            (Self::Other(id), Param::External(other, addr)) if other == id => Some(addr),
+            _ => None,
+        }
+    }
+
+    /// Returns the reference reading a cell of the changed sheet.
+    pub(crate) fn place(self, local: CellAddr) -> Param {
+        match self {
+            Self::Own => Param::Cell(local),
+            Self::Other(id) => Param::External(id, local),
+        }
+    }
+}
+
+/// How rewriting a formula for a structural change affected it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Rewrite {
+    /// The references only moved along with the cells; the value is unchanged
+    Moved,
+    /// A range gained or lost lines in its middle, so the value has to be recalculated
+    Resized,
+    /// References were lost, so undoing the change has to restore the formula
+    Lossy,
+}
+
+impl StructureOp {
+    /// Returns the change that reverts this one, apart from lost formulas.
+    pub fn inverse(self) -> Self {
+        match self {
+            Self::Insert { axis, at, count } => Self::Delete { axis, at, count },
+            Self::Delete { axis, at, count } => Self::Insert { axis, at, count },
+        }
+    }
+
+    fn axis(self) -> Axis {
+        match self {
+            Self::Insert { axis, .. } | Self::Delete { axis, .. } => axis,
+        }
+    }
+
+    /// Returns where line `line` ends up, or `None` if it is deleted.
+    fn map_line(self, line: u32) -> Option<u32> {
+        match self {
+            Self::Insert { at, count, .. } if line >= at => line.checked_add(count),
+            Self::Delete { at, count, .. } if line >= at => {
+                (line - at >= count).then(|| line - count)
+            }
+            _ => Some(line),
+        }
+    }
+
+    /// Returns where a span of lines ends up, and how the change affected it.
+    ///
+    /// # Returns
+    /// `None` if every line of the span is deleted.
+    fn map_span(self, first: u32, last: u32) -> Option<(u32, u32, Rewrite)> {
+        match self {
+            Self::Insert { at, .. } => {
+                let rewrite = if first < at && at <= last {
+                    Rewrite::Resized
+                } else {
+                    Rewrite::Moved
+                };
+                Some((self.map_line(first)?, self.map_line(last)?, rewrite))
+            }
+            Self::Delete { at, count, .. } => {
+                let end = at + count;
+                if end <= first || last < at {
+                    return Some((self.map_line(first)?, self.map_line(last)?, Rewrite::Moved));
+                }
+                if at <= first && last < end {
+                    return None;
+                }
+                let rewrite = if first < at && end <= last {
+                    Rewrite::Resized
+                } else {
+                    // An edge of the span was deleted; inserting the lines again
+                    // would not bring it back
+                    Rewrite::Lossy
+                };
+                let first = if first < at { first } else { at };
+                let last = if last < end { at - 1 } else { last - count };
+                Some((first, last, rewrite))
+            }
+        }
+    }
+
+    /// Returns where a range of cells ends up, keeping the part inside the sheet.
+    ///
+    /// # Returns
+    /// `None` if every line of the range is deleted or pushed off the sheet.
+    fn map_range(self, range: RangeAddr, limit: CellAddr) -> Option<RangeAddr> {
+        match self.axis() {
+            Axis::Rows => {
+                let (first, last, _) = self.map_span(range.start.row, range.end.row)?;
+                RangeAddr::new(
+                    CellAddr::new(first, range.start.col),
+                    CellAddr::new(last.min(limit.row), range.end.col),
+                )
+            }
+            Axis::Cols => {
+                let (first, last, _) = self.map_span(range.start.col, range.end.col)?;
+                RangeAddr::new(
+                    CellAddr::new(range.start.row, first),
+                    CellAddr::new(range.end.row, last.min(limit.col)),
+                )
+            }
+        }
+    }
+
+    /// Rewrites a formula written for the first cell of a range, such as the
+    /// formula of a conditional formatting rule, for this change.
+    ///
+    /// The formula is rewritten for the first cell of the range that is left,
+    /// which is further on if the first lines of the range are deleted.
+    ///
+    /// # Returns
+    /// `None` if the formula loses a reference.
+    fn rewrite_anchored(
+        self,
+        formula: &CommandCall,
+        range: RangeAddr,
+        limit: CellAddr,
+    ) -> Option<CommandCall> {
+        let (first, rows, cols) = match self.axis() {
+            Axis::Rows => (range.start.row, 1, 0),
+            Axis::Cols => (range.start.col, 0, 1),
+        };
+        let skipped = match self {
+            Self::Delete { at, count, .. } if at <= first && first < at + count => {
+                (at + count - first) as i64
+            }
+            _ => 0,
+        };
+        let anchored = formula.shifted(skipped * rows, skipped * cols)?;
+        let (rewritten, _) = self.rewrite(&anchored, limit, Moving::Own);
+        (rewritten.flag.error() != 3).then_some(rewritten)
+    }
+
+    /// Returns where a cell ends up, or `None` if it is deleted.
+    fn map_addr(self, addr: CellAddr) -> Option<CellAddr> {
+        match self.axis() {
+            Axis::Rows => Some(CellAddr::new(self.map_line(addr.row)?, addr.col)),
+            Axis::Cols => Some(CellAddr::new(addr.row, self.map_line(addr.col)?)),
+        }
+    }
+
+    /// Rewrites the references of a formula for this change.
+    ///
+    /// # Parameters
+    /// * `formula` - The formula, expanded if it is shared
+    /// * `limit` - Last cell of the sheet; references moved beyond it are lost
+    /// * `moving` - The sheet the change was made to
+    ///
+    /// # Returns
+    /// The new formula, which is `#REF!` if a reference was lost, and how it was affected.
+    fn rewrite(
+        self,
+        formula: &CommandCall,
+        limit: CellAddr,
+        moving: Moving,
+    ) -> (CommandCall, Rewrite) {
+        if formula.flag.type_() >= 2 && formula.flag.cmd() != 5 {
+            // A range of another sheet than the changed one stays as it is
+            let (Some(start), Some(end)) =
+                (moving.local(formula.param1), moving.local(formula.param2))
+            else {
+                return (formula.clone(), Rewrite::Moved);
+            };
+            let (first, last) = match self.axis() {
+                Axis::Rows => (start.row, end.row),
+                Axis::Cols => (start.col, end.col),
+            };
+            let moved = self
+                .map_span(first, last)
+                .and_then(|(first, last, rewrite)| {
+                    let (start, end) = match self.axis() {
+                        Axis::Rows => (
+                            CellAddr::new(first, start.col),
+                            CellAddr::new(last, end.col),
+                        ),
+                        Axis::Cols => (
+                            CellAddr::new(start.row, first),
+                            CellAddr::new(end.row, last),
+                        ),
+                    };
+                    let range = RangeAddr::new(start, end)
+                        .filter(|range| range.end.row <= limit.row && range.end.col <= limit.col)?;
+                    Some((moving.place(range.start), moving.place(range.end), rewrite))
+                });
+            return match moved {
+                Some((start, end, rewrite)) => {
+                    let mut formula = formula.clone();
+                    formula.param1 = start;
+                    formula.param2 = end;
+                    (formula, rewrite)
+                }
+                None => (broken(formula), Rewrite::Lossy),
+            };
+        }
+
+        let map = |param: Param| match moving.local(param) {
+            Some(addr) => self
+                .map_addr(addr)
+                .filter(|addr| addr.row <= limit.row && addr.col <= limit.col)
+                .map(|addr| moving.place(addr)),
+            None => Some(param),
+        };
+        match (map(formula.param1), map(formula.param2)) {
+            (Some(param1), Some(param2)) => {
+                let mut formula = formula.clone();
+                formula.param1 = param1;
+                formula.param2 = param2;
+                (formula, Rewrite::Moved)
+            }
+            _ => (broken(formula), Rewrite::Lossy),
+        }
+    }
+}
+
+/// Returns the `#REF!` formula replacing a formula that lost a reference.
+fn broken(formula: &CommandCall) -> CommandCall {
+    let mut broken = CommandCall::ref_error();
+    broken.flag.set_is_any(formula.flag.is_any());
+    broken
+}
+
+impl Sheet {
+    /// Inserts empty rows, moving the rows from `at` on down.
+    ///
+    /// # Parameters
+    /// * `at` - First row of the inserted rows
+    /// * `count` - Number of rows to insert
+    ///
+    /// # Returns
+    /// `Error::InvalidInput` if `at` lies outside the sheet or a used cell
+    /// would be pushed past its last row; the sheet is then unchanged.
+    pub fn insert_rows(&mut self, at: usize, count: usize) -> Result<(), Error> {
+        self.change_structure(Axis::Rows, at, count, true)
+    }
+
+    /// Deletes the rows `at..at + count`, moving the rows below them up.
+    ///
+    /// # Returns
+    /// `Error::InvalidInput` if the rows do not lie inside the sheet.
+    pub fn delete_rows(&mut self, at: usize, count: usize) -> Result<(), Error> {
+        self.change_structure(Axis::Rows, at, count, false)
+    }
+
+    /// Inserts empty columns, moving the columns from `at` on to the right.
+    ///
+    /// # Returns
+    /// `Error::InvalidInput` if `at` lies outside the sheet or a used cell
+    /// would be pushed past its last column; the sheet is then unchanged.
+    pub fn insert_cols(&mut self, at: usize, count: usize) -> Result<(), Error> {
+        self.change_structure(Axis::Cols, at, count, true)
+    }
+
+    /// Deletes the columns `at..at + count`, moving the columns after them left.
+    ///
+    /// # Returns
+    /// `Error::InvalidInput` if the columns do not lie inside the sheet.
+    pub fn delete_cols(&mut self, at: usize, count: usize) -> Result<(), Error> {
+        self.change_structure(Axis::Cols, at, count, false)
+    }
+
+    /// Checks the lines of a structural change and applies it.
+    fn change_structure(
+        &mut self,
+        axis: Axis,
+        at: usize,
+        count: usize,
+        insert: bool,
+    ) -> Result<(), Error> {
+        let lines = match axis {
+            Axis::Rows => self.row,
+            Axis::Cols => self.col,
+        };
+        if at == 0 || count == 0 || at > lines || (!insert && count > lines - at + 1) {
+            return Err(Error::InvalidInput);
+        }
+        let at = u32::try_from(at).map_err(|_| Error::InvalidInput)?;
+        let count = u32::try_from(count).map_err(|_| Error::InvalidInput)?;
+        let op = if insert {
+            StructureOp::Insert { axis, at, count }
+        } else {
+            StructureOp::Delete { axis, at, count }
+        };
+        let layout = self.layout();
+        let restore = self.notify(|sheet| sheet.apply_structure(op))?;
+        self.record_structure(op, restore, layout);
+        Ok(())
+    }
+
+    /// Moves the cells and rewrites the formulas for a structural change.
+    ///
+    /// # Returns
+    /// The formulas the change destroyed, by their address before it: those of
+    /// deleted cells and of cells that lost a reference. Undoing the change
+    /// applies them again after the inverse change.
+    pub(crate) fn apply_structure(
+        &mut self,
+        op: StructureOp,
+    ) -> Result<Vec<(CellAddr, String)>, Error> {
+        let limit = CellAddr::from_index(self.row, self.col);
+        let restore = self.move_cells(op, Moving::Own)?;
+        self.move_filter(op, limit);
+        self.move_formats(op, limit);
+        self.move_rules(op, limit);
+        self.move_validations(op, limit);
+        self.notes.relocate(
+            |_| true,
+            |addr| {
+                op.map_addr(addr)
+                    .filter(|addr| addr.row <= limit.row && addr.col <= limit.col)
+            },
+        );
+        self.relocated(Relocation::Structure(op));
+        Ok(restore)
+    }
+
+    /// Queues a move of cells for the sheets of the workbook reading this one,
+    /// which follow it on `Workbook::refresh`.
+    pub(crate) fn relocated(&mut self, relocation: Relocation) {
+        if !self.imports.book.is_empty() {
+            self.restructured.push(relocation);
+        }
+    }
+
+    /// Follows a structural change of another sheet of the workbook: rewrites
+    /// the formulas reading that sheet so they keep reading the same cells.
+    /// Formulas reading deleted cells become `#REF!`.
+    ///
+    /// The change is not recorded, like the values read from other sheets.
+    pub(crate) fn follow_structure(&mut self, id: u32, op: StructureOp) {
+        self.notify(|sheet| {
+            let _ = sheet.move_cells(op, Moving::Other(id));
+        });
+    }
+
+    /// Moves the cells a structural change of the sheet moves and rewrites the
+    /// formulas reading the changed sheet, then recalculates what changed.
+    ///
+    /// # Returns
+    /// The formulas destroyed, as for `apply_structure`, or
+    /// `Error::InvalidInput` if a cell would be pushed off the sheet.
+    fn move_cells(
+        &mut self,
+        op: StructureOp,
+        moving: Moving,
+    ) -> Result<Vec<(CellAddr, String)>, Error> {
+        let limit = CellAddr::from_index(self.row, self.col);
+        let mut cells: Vec<(CellAddr, CellAddr)> = Vec::new();
+        let mut restore = Vec::new();
+        for (addr, cell) in self.grid.iter() {
+            // Cells that were never written only held dependencies
+            if cell.formula.flag.is_any() == 0 {
+                continue;
+            }
+            // Only a change of the sheet itself moves its cells
+            let moved = match moving {
+                Moving::Own => op.map_addr(addr),
+                Moving::Other(_) => Some(addr),
+            };
+            match moved {
+                Some(moved) if moved.row <= limit.row && moved.col <= limit.col => {
+                    cells.push((addr, moved))
+                }
+                Some(_) => return Err(Error::InvalidInput),
+                None => restore.push((addr, self.formula_text(&self.formula_of(addr)))),
+            }
+        }
+
+        // Both the old and the new place of a moved cell change
+        for &(addr, moved) in &cells {
+            self.touch(addr);
+            self.touch(moved);
+        }
+        for (addr, _) in &restore {
+            self.touch(*addr);
+        }
+
+        let mut grid = CellStore::new();
+        let mut changed = Vec::new();
+        for (addr, moved) in cells {
+            let formula = self.formula_of(addr);
+            let (rewritten, rewrite) = op.rewrite(&formula, limit, moving);
+            if rewrite == Rewrite::Lossy {
+                restore.push((addr, self.formula_text(&formula)));
+            }
+            if rewrite != Rewrite::Moved {
+                changed.push(moved);
+            }
+            let mut cell = self.grid[addr].clone();
+            cell.depend.clear();
+            cell.formula = rewritten;
+            grid.insert(moved.row as usize, moved.col as usize, cell);
+        }
+        self.grid = grid;
+        self.shared.clear();
+        self.rebuild_dependencies();
+
+        // Only formulas reading different cells than before can change their value
+        changed.sort_unstable();
+        if let Ok(plan) = self.plan_recalc(&changed) {
+            let _ = self.recalculate(plan, &EvalOptions::default());
+        }
+        Ok(restore)
+    }
+
+    /// Moves the filter along with its header, dropping it if the header is deleted.
+    fn move_filter(&mut self, op: StructureOp, limit: CellAddr) {
+        let Some(mut filter) = self.filter.take() else {
+            return;
+        };
+        let header = filter.header;
+        let (first, last) = match op.axis() {
+            Axis::Rows => (header.start.row, header.start.row),
+            Axis::Cols => (header.start.col, header.end.col),
+        };
+        let Some((first, last, _)) = op.map_span(first, last) else {
+            return;
+        };
+        let (start, end) = match op.axis() {
+            Axis::Rows => (
+                CellAddr::new(first, header.start.col),
+                CellAddr::new(first, header.end.col),
+            ),
+            Axis::Cols => (
+                CellAddr::new(header.start.row, first),
+                CellAddr::new(header.start.row, last.min(limit.col)),
+            ),
+        };
+        if start.row > limit.row || start.col > limit.col {
+            return;
+        }
+        filter.header = RangeAddr { start, end };
+        match op.axis() {
+            Axis::Rows => {
+                filter.hidden = filter
+                    .hidden
+                    .iter()
+                    .filter_map(|&row| op.map_line(row))
+                    .collect();
+            }
+            Axis::Cols => {
+                filter.criteria = std::mem::take(&mut filter.criteria)
+                    .into_iter()
+                    .filter_map(|(col, criterion)| Some((op.map_line(col)?, criterion)))
+                    .filter(|&(col, _)| col <= end.col)
+                    .collect();
+            }
+        }
+        self.filter = Some(filter);
+        self.reapply_filter();
+    }
+
+    /// Moves the formatted ranges along with their cells, dropping those whose
+    /// lines are all deleted or pushed off the sheet.
+    fn move_formats(&mut self, op: StructureOp, limit: CellAddr) {
+        self.formats.ranges = std::mem::take(&mut self.formats.ranges)
+            .into_iter()
+            .filter_map(|(range, format)| Some((op.map_range(range, limit)?, format)))
+            .collect();
+    }
+
+    /// Moves the ranges of the conditional formatting rules along with their
+    /// cells and rewrites the formulas of formula rules.
+    ///
+    /// Rules are dropped like formatted ranges, and also when their formula
+    /// loses a reference.
+    fn move_rules(&mut self, op: StructureOp, limit: CellAddr) {
+        self.rules = std::mem::take(&mut self.rules)
+            .into_iter()
+            .filter_map(|mut rule| {
+                let range = op.map_range(rule.range, limit)?;
+                if let Some(formula) = &rule.formula {
+                    let rewritten = op.rewrite_anchored(formula, rule.range, limit)?;
+                    if let Rule::Highlight { condition, .. } = &mut rule.rule {
+                        *condition = Condition::Formula(unparse_command(&rewritten));
+                    }
+                    rule.formula = Some(rewritten);
+                }
+                rule.range = range;
+                Some(rule)
+            })
+            .collect();
+    }
+
+    /// Moves the ranges of the validations along with their cells, with the
+    /// ranges of their lists and the formulas of formula validations.
+    ///
+    /// Validations are dropped like formatted ranges, and also when their list
+    /// is deleted or their formula loses a reference.
+    fn move_validations(&mut self, op: StructureOp, limit: CellAddr) {
+        self.validations = std::mem::take(&mut self.validations)
+            .into_iter()
+            .filter_map(|mut entry| {
+                let range = op.map_range(entry.range, limit)?;
+                if let Check::ListRange(list) = &mut entry.validation.check {
+                    *list = op.map_range(*list, limit)?;
+                }
+                if let Some(formula) = &entry.formula {
+                    let rewritten = op.rewrite_anchored(formula, entry.range, limit)?;
+                    entry.validation.check = Check::Formula(unparse_command(&rewritten));
+                    entry.formula = Some(rewritten);
+                }
+                entry.range = range;
+                Some(entry)
+            })
+            .collect();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sheet_with(formulas: &[(usize, usize, &str)]) -> Sheet {
+        let mut test_sheet = Sheet::new(10, 10);
+        test_sheet.transaction(|batch| {
+            for &(row, col, formula) in formulas {
+                batch.set(row, col, formula);
+            }
+        });
+        test_sheet
+    }
+
+    #[test]
+    fn test_insert_rows() {
+        let mut test_sheet = sheet_with(&[
+            (1, 1, "1"),
+            (2, 1, "2"),
+            (3, 1, "3"),
+            (4, 1, "SUM(A1:A3)"),
+            (5, 1, "A3*10"),
+            (1, 2, "A2+A1"),
+        ]);
+        test_sheet.insert_rows(2, 2).unwrap();
+        assert_eq!(test_sheet.get_formula(6, 1), "SUM(A1:A5)");
+        assert_eq!(test_sheet.get_formula(7, 1), "A5*10");
+        assert_eq!(test_sheet.get_formula(1, 2), "A4+A1");
+        assert_eq!(test_sheet.get_value(6, 1), 6);
+
+        // The moved cells are still linked to the cells they read
+        test_sheet.update_cell_data(4, 1, String::from("5"));
+        assert_eq!(test_sheet.get_value(1, 2), 6);
+        test_sheet.update_cell_data(3, 1, String::from("4"));
+        assert_eq!(test_sheet.get_value(6, 1), 13);
+
+        // Used cells may not be pushed off the sheet
+        assert_eq!(test_sheet.insert_rows(1, 4), Err(Error::InvalidInput));
+        assert_eq!(test_sheet.get_formula(7, 1), "A5*10");
+    }
+
+    #[test]
+    fn test_delete_rows() {
+        let mut test_sheet = sheet_with(&[
+            (1, 1, "1"),
+            (2, 1, "2"),
+            (3, 1, "3"),
+            (4, 1, "4"),
+            (5, 1, "SUM(A1:A4)"),
+            (6, 1, "A3+A4"),
+            (7, 1, "A6*2"),
+            (1, 2, "SUM(A2:A3)"),
+        ]);
+        test_sheet.delete_rows(3, 1).unwrap();
+        assert_eq!(test_sheet.get_formula(4, 1), "SUM(A1:A3)");
+        assert_eq!(test_sheet.get_value(4, 1), 7);
+        assert_eq!(test_sheet.get_formula(5, 1), "#REF!");
+        assert_eq!(test_sheet.get_error(5, 1), Some(Error::Ref));
+        assert_eq!(test_sheet.get_error(6, 1), Some(Error::Ref));
+        assert_eq!(test_sheet.get_formula(1, 2), "SUM(A2:A2)");
+        assert_eq!(test_sheet.get_value(1, 2), 2);
+
+        test_sheet.delete_rows(2, 1).unwrap();
+        assert_eq!(test_sheet.get_formula(1, 2), "#REF!");
+        assert_eq!(test_sheet.get_error(1, 2), Some(Error::Ref));
+
+        // Undo restores the deleted cells and the formulas that lost references
+        assert!(test_sheet.undo());
+        assert!(test_sheet.undo());
+        assert_eq!(test_sheet.get_formula(3, 1), "3");
+        assert_eq!(test_sheet.get_formula(6, 1), "A3+A4");
+        assert_eq!(test_sheet.get_formula(5, 1), "SUM(A1:A4)");
+        assert_eq!(test_sheet.get_formula(1, 2), "SUM(A2:A3)");
+        assert_eq!(test_sheet.get_value(7, 1), 14);
+        assert_eq!(test_sheet.get_error(7, 1), None);
+
+        assert!(test_sheet.redo());
+        assert_eq!(test_sheet.get_formula(5, 1), "#REF!");
+    }
+
+    #[test]
+    fn test_insert_and_delete_cols() {
+        let mut test_sheet = sheet_with(&[
+            (1, 1, "1"),
+            (1, 2, "2"),
+            (1, 3, "A1+B1"),
+            (2, 1, "MAX(A1:C1)"),
+        ]);
+        test_sheet.insert_cols(2, 1).unwrap();
+        assert_eq!(test_sheet.get_formula(1, 4), "A1+C1");
+        assert_eq!(test_sheet.get_formula(2, 1), "MAX(A1:D1)");
+        test_sheet.update_cell_data(1, 2, String::from("9"));
+        assert_eq!(test_sheet.get_value(2, 1), 9);
+
+        test_sheet.delete_cols(1, 1).unwrap();
+        assert_eq!(test_sheet.get_formula(1, 3), "#REF!");
+        assert_eq!(test_sheet.get_formula(1, 1), "9");
+        assert!(test_sheet.undo());
+        assert_eq!(test_sheet.get_formula(2, 1), "MAX(A1:D1)");
+        assert_eq!(test_sheet.get_formula(1, 4), "A1+C1");
+        assert!(test_sheet.undo());
+        assert!(test_sheet.undo());
+        assert_eq!(test_sheet.get_formula(1, 3), "A1+B1");
+        assert_eq!(test_sheet.get_value(2, 1), 3);
+    }
+
+    #[test]
+    fn test_formats_move_with_cells() {
+        use crate::style::Format;
+        let mut test_sheet = sheet_with(&[(2, 2, "5")]);
+        let range = RangeAddr::parse("B2:C4").unwrap();
+        test_sheet.set_format(range, &[Format::Bold(true)]).unwrap();
+        test_sheet.insert_rows(1, 2).unwrap();
+        assert!(test_sheet.style(4, 2).bold);
+        assert!(!test_sheet.style(3, 2).bold);
+        test_sheet.delete_cols(2, 1).unwrap();
+        assert!(test_sheet.style(6, 2).bold);
+        assert!(!test_sheet.style(6, 3).bold);
+
+        // Ranges pushed off the sheet shrink, and go once nothing is left
+        test_sheet.insert_rows(1, 4).unwrap();
+        assert!(test_sheet.style(10, 2).bold);
+        test_sheet.delete_rows(8, 3).unwrap();
+        assert_eq!(test_sheet.format_count(), 0);
+    }
+
+    #[test]
+    fn test_rules_move_with_cells() {
+        let mut test_sheet = sheet_with(&[(2, 1, "5"), (3, 1, "1"), (2, 2, "1"), (3, 2, "0")]);
+        let range = RangeAddr::parse("A2:A3").unwrap();
+        test_sheet
+            .add_rule(range, "formula B2 then bold".parse().unwrap())
+            .unwrap();
+        test_sheet.insert_rows(1, 1).unwrap();
+        test_sheet.insert_cols(1, 1).unwrap();
+        let (moved, rule) = test_sheet.rules().remove(0);
+        assert_eq!(moved, RangeAddr::parse("B3:B4").unwrap());
+        assert_eq!(rule.to_string(), "formula C3 then bold:on");
+        assert!(test_sheet.display_style(3, 2).bold);
+        assert!(!test_sheet.display_style(4, 2).bold);
+
+        // The rule is written for the cell that is first once the first row goes
+        test_sheet.delete_rows(3, 1).unwrap();
+        let (_, rule) = test_sheet.rules().remove(0);
+        assert_eq!(rule.to_string(), "formula C3 then bold:on");
+        assert!(!test_sheet.display_style(3, 2).bold);
+
+        // A rule whose formula loses its reference goes
+        test_sheet.delete_cols(3, 1).unwrap();
+        assert!(test_sheet.rules().is_empty());
+    }
+
+    #[test]
+    fn test_validations_move_with_cells() {
+        let mut test_sheet = sheet_with(&[(1, 3, "1"), (2, 3, "2")]);
+        test_sheet
+            .add_validation(
+                RangeAddr::parse("A1:A5").unwrap(),
+                "list C1:C2".parse().unwrap(),
+            )
+            .unwrap();
+        test_sheet.insert_cols(2, 1).unwrap();
+        test_sheet.insert_rows(1, 1).unwrap();
+        let (range, validation) = test_sheet.validations().remove(0);
+        assert_eq!(range, RangeAddr::parse("A2:A6").unwrap());
+        assert_eq!(validation.to_string(), "list D2:D3");
+        assert_eq!(test_sheet.allowed_values(3, 1), Some(vec![1, 2]));
+        assert_eq!(
+            test_sheet.update_cell_data(3, 1, String::from("3")).error,
+            Error::ValidationFailed
+        );
+
+        // Deleting the list deletes the validation
+        test_sheet.delete_cols(4, 1).unwrap();
+        assert!(test_sheet.validations().is_empty());
+    }
+
+    #[test]
+    fn test_undo_delete_restores_layout() {
+        use crate::comments::Comment;
+        use crate::style::Format;
+        let mut test_sheet = sheet_with(&[(2, 1, "5"), (3, 1, "7")]);
+        let range = RangeAddr::parse("A2:A3").unwrap();
+        let cell = CellAddr::parse("A3").unwrap();
+        test_sheet.set_format(range, &[Format::Bold(true)]).unwrap();
+        test_sheet
+            .add_rule(range, "gt 6 then italic".parse().unwrap())
+            .unwrap();
+        test_sheet
+            .add_validation(range, "whole 1 10".parse().unwrap())
+            .unwrap();
+        test_sheet
+            .set_note(cell, Comment::new("ana", "checked"))
+            .unwrap();
+
+        test_sheet.delete_rows(2, 2).unwrap();
+        assert_eq!(test_sheet.format_count(), 0);
+        assert!(test_sheet.rules().is_empty());
+        assert!(test_sheet.validations().is_empty());
+        assert!(test_sheet.annotated_cells().is_empty());
+
+        assert!(test_sheet.undo());
+        assert_eq!(test_sheet.get_value(3, 1), 7);
+        assert!(test_sheet.style(3, 1).bold);
+        assert!(test_sheet.display_style(3, 1).italic);
+        assert_eq!(test_sheet.validations()[0].0, range);
+        assert_eq!(test_sheet.annotated_cells(), vec![cell]);
+
+        assert!(test_sheet.redo());
+        assert!(test_sheet.annotated_cells().is_empty());
+        assert_eq!(test_sheet.format_count(), 0);
+    }
+}
@@ -0,0 +1,670 @@
+//! Cell formatting.
+//!
+//! Formatting changes how a value is shown, never the value itself. It is
+//! stored apart from the cells, as a list of ranges that each set one
+//! attribute, such as bold text or a currency format. The style of a cell is
+//! worked out by applying the attributes of every range holding it in the
+//! order they were set, so formatting a whole column costs one entry. Setting
+//! an attribute on a range drops the older entries of that attribute lying
+//! inside it, which keeps the list short however often a range is formatted.
+//!
+//! Formatting moves along with inserted and deleted rows and columns and is
+//! saved in `.ss` files, but it is not part of the undo history.
+
+use crate::addr::{CellAddr, RangeAddr};
+use crate::sheet::{Error, Sheet};
+use std::fmt;
+use std::mem::discriminant;
+use std::str::FromStr;
+
+/// Largest number of decimals a number format shows.
+const MAX_DECIMALS: u8 = 10;
+
+/// Days from the first day of the date serials, 1899-12-30, to 1970-01-01.
+const UNIX_EPOCH_SERIAL: i64 = 25569;
+
+/// Short month names used by the long date format.
+const MONTHS: [&str; 12] = [
+    "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+];
+
+/// A color given by its red, green and blue parts, written as `#rrggbb`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Color {
+    pub r: u8,
+    pub g: u8,
+    pub b: u8,
+}
+
+impl Color {
+    pub const fn new(r: u8, g: u8, b: u8) -> Self {
+        Self { r, g, b }
+    }
+
+    /// Parses a color written as `#rrggbb`.
+    pub fn parse(text: &str) -> Option<Self> {
+        let hex = text.strip_prefix('#')?;
+        if hex.len() != 6 || !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+            return None;
+        }
+        let part = |at: usize| u8::from_str_radix(&hex[at..at + 2], 16).ok();
+        Some(Self::new(part(0)?, part(2)?, part(4)?))
+    }
+}
+
+impl fmt::Display for Color {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "#{:02x}{:02x}{:02x}", self.r, self.g, self.b)
+    }
+}
+
+/// How a date is written out.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DateFormat {
+    /// `2024-03-15`
+    Iso,
+    /// `15/03/2024`
+    DayMonthYear,
+    /// `03/15/2024`
+    MonthDayYear,
+    /// `15 Mar 2024`
+    Long,
+}
+
+impl DateFormat {
+    /// Every date format, in the order front ends offer them.
+    pub const ALL: [DateFormat; 4] = [
+        DateFormat::Iso,
+        DateFormat::DayMonthYear,
+        DateFormat::MonthDayYear,
+        DateFormat::Long,
+    ];
+
+    /// The pattern naming the format, e.g. `yyyy-mm-dd`.
+    pub fn pattern(self) -> &'static str {
+        match self {
+            DateFormat::Iso => "yyyy-mm-dd",
+            DateFormat::DayMonthYear => "dd/mm/yyyy",
+            DateFormat::MonthDayYear => "mm/dd/yyyy",
+            DateFormat::Long => "d mmm yyyy",
+        }
+    }
+}
+
+/// How the value of a cell is written out.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum NumberFormat {
+    /// The plain value
+    #[default]
+    General,
+    /// The value with a fixed number of decimals, optionally grouped in thousands
+    Number { decimals: u8, thousands: bool },
+    /// The value times 100 with a percent sign
+    Percent { decimals: u8 },
+    /// The value grouped in thousands after a currency symbol
+    Currency { symbol: char, decimals: u8 },
+    /// The value as a number of days since 1899-12-30, so 45366 is 2024-03-15
+    Date(DateFormat),
+}
+
+impl NumberFormat {
+    /// Writes out a value in this format.
+    pub fn apply(self, value: i32) -> String {
+        match self {
+            NumberFormat::General => value.to_string(),
+            NumberFormat::Number {
+                decimals,
+                thousands,
+            } => signed(value as i64, "", decimals, thousands),
+            NumberFormat::Percent { decimals } => {
+                format!("{}%", signed(value as i64 * 100, "", decimals, false))
+            }
+            NumberFormat::Currency { symbol, decimals } => {
+                signed(value as i64, &symbol.to_string(), decimals, true)
+            }
+            NumberFormat::Date(format) => date(value, format),
+        }
+    }
+}
+
+/// Writes out a whole number with a prefix after its sign, e.g. `-$1,234.00`.
+fn signed(value: i64, prefix: &str, decimals: u8, thousands: bool) -> String {
+    let digits = value.unsigned_abs().to_string();
+    let mut text = String::from(if value < 0 { "-" } else { "" });
+    text.push_str(prefix);
+    if thousands {
+        for (index, digit) in digits.chars().enumerate() {
+            if index > 0 && (digits.len() - index).is_multiple_of(3) {
+                text.push(',');
+            }
+            text.push(digit);
+        }
+    } else {
+        text.push_str(&digits);
+    }
+    if decimals > 0 {
+        text.push('.');
+        text.push_str(&"0".repeat(decimals as usize));
+    }
+    text
+}
+
+/// Writes out a date serial, using the proleptic Gregorian calendar.
+fn date(serial: i32, format: DateFormat) -> String {
+    // Civil date from days since 1970-01-01, after Howard Hinnant
+    let days = serial as i64 - UNIX_EPOCH_SERIAL + 719468;
+    let era = days.div_euclid(146097);
+    let day_of_era = days.rem_euclid(146097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let shifted_month = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * shifted_month + 2) / 5 + 1;
+    let month = if shifted_month < 10 {
+        shifted_month + 3
+    } else {
+        shifted_month - 9
+    };
+    let year = year_of_era + era * 400 + (month <= 2) as i64;
+    match format {
+        DateFormat::Iso => format!("{:04}-{:02}-{:02}", year, month, day),
+        DateFormat::DayMonthYear => format!("{:02}/{:02}/{:04}", day, month, year),
+        DateFormat::MonthDayYear => format!("{:02}/{:02}/{:04}", month, day, year),
+        DateFormat::Long => format!("{} {} {}", day, MONTHS[month as usize - 1], year),
+    }
+}
+
+/// Horizontal alignment of the contents of a cell.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Align {
+    /// Whatever the front end shows values with
+    #[default]
+    General,
+    Left,
+    Center,
+    Right,
+}
+
+/// The edges of a cell that have a border.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Borders {
+    pub top: bool,
+    pub right: bool,
+    pub bottom: bool,
+    pub left: bool,
+}
+
+impl Borders {
+    /// A border on every edge.
+    pub const ALL: Borders = Borders {
+        top: true,
+        right: true,
+        bottom: true,
+        left: true,
+    };
+
+    /// Returns `true` if no edge has a border.
+    pub fn is_empty(self) -> bool {
+        self == Borders::default()
+    }
+}
+
+/// One formatting attribute set on a range.
+///
+/// Attributes are written as `bold`, `italic:off`, `number:2:thousands`,
+/// `percent:1`, `currency:$:2`, `date:yyyy-mm-dd`, `color:#c00000`,
+/// `fill:none`, `align:right` or `border:top+bottom`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Format {
+    Number(NumberFormat),
+    Bold(bool),
+    Italic(bool),
+    /// Color of the text; `None` is the front end's default
+    TextColor(Option<Color>),
+    /// Color of the background; `None` is the front end's default
+    FillColor(Option<Color>),
+    Align(Align),
+    Borders(Borders),
+}
+
+impl Format {
+    /// Every attribute at its default; setting these clears the formatting.
+    pub const DEFAULTS: [Format; 7] = [
+        Format::Number(NumberFormat::General),
+        Format::Bold(false),
+        Format::Italic(false),
+        Format::TextColor(None),
+        Format::FillColor(None),
+        Format::Align(Align::General),
+        Format::Borders(Borders {
+            top: false,
+            right: false,
+            bottom: false,
+            left: false,
+        }),
+    ];
+
+    /// Parses a comma separated list of attributes, e.g. `bold,currency:$:2`.
+    ///
+    /// `clear` stands for every attribute at its default.
+    pub fn parse_list(text: &str) -> Option<Vec<Format>> {
+        let mut formats = vec![];
+        for part in text.split(',') {
+            match part.trim() {
+                "clear" => formats.extend(Format::DEFAULTS),
+                part => formats.push(part.parse().ok()?),
+            }
+        }
+        Some(formats)
+    }
+}
+
+/// Parses the number of decimals of a number format.
+fn decimals(text: &str) -> Option<u8> {
+    text.parse()
+        .ok()
+        .filter(|&decimals| decimals <= MAX_DECIMALS)
+}
+
+/// Parses `on` and `off`.
+fn switch(text: &str) -> Option<bool> {
+    match text {
+        "on" => Some(true),
+        "off" => Some(false),
+        _ => None,
+    }
+}
+
+/// Parses a color or `none`.
+fn color(text: &str) -> Option<Option<Color>> {
+    match text {
+        "none" => Some(None),
+        text => Color::parse(text).map(Some),
+    }
+}
+
+impl FromStr for Format {
+    type Err = Error;
+
+    fn from_str(text: &str) -> Result<Self, Error> {
+        let (name, arg) = text.split_once(':').unwrap_or((text, ""));
+        let format = match (name, arg) {
+            ("general", "") => Some(Format::Number(NumberFormat::General)),
+            ("number", arg) => {
+                let (count, grouping) = arg.split_once(':').unwrap_or((arg, ""));
+                let thousands = match grouping {
+                    "" => Some(false),
+                    "thousands" => Some(true),
+                    _ => None,
+                };
+                decimals(count).zip(thousands).map(|(decimals, thousands)| {
+                    Format::Number(NumberFormat::Number {
+                        decimals,
+                        thousands,
+                    })
+                })
+            }
+            ("percent", arg) => {
+                decimals(arg).map(|decimals| Format::Number(NumberFormat::Percent { decimals }))
+            }
+            ("currency", arg) => {
+                let (symbol, count) = arg.split_once(':').unwrap_or((arg, "2"));
+                let mut chars = symbol.chars();
+                match (chars.next(), chars.next(), decimals(count)) {
+                    (Some(symbol), None, Some(decimals)) => {
+                        Some(Format::Number(NumberFormat::Currency { symbol, decimals }))
+                    }
+                    _ => None,
+                }
+            }
+            ("date", arg) => DateFormat::ALL
+                .into_iter()
+                .find(|format| format.pattern() == arg)
+                .map(|format| Format::Number(NumberFormat::Date(format))),
+            ("bold", "") => Some(Format::Bold(true)),
+            ("bold", arg) => switch(arg).map(Format::Bold),
+            ("italic", "") => Some(Format::Italic(true)),
+            ("italic", arg) => switch(arg).map(Format::Italic),
+            ("color", arg) => color(arg).map(Format::TextColor),
+            ("fill", arg) => color(arg).map(Format::FillColor),
+            ("align", "general") => Some(Format::Align(Align::General)),
+            ("align", "left") => Some(Format::Align(Align::Left)),
+            ("align", "center") => Some(Format::Align(Align::Center)),
+            ("align", "right") => Some(Format::Align(Align::Right)),
+            ("border", "none") => Some(Format::Borders(Borders::default())),
+            ("border", "all") => Some(Format::Borders(Borders::ALL)),
+            ("border", arg) => {
+                let mut borders = Borders::default();
+                for edge in arg.split('+') {
+                    match edge {
+                        "top" => borders.top = true,
+                        "right" => borders.right = true,
+                        "bottom" => borders.bottom = true,
+                        "left" => borders.left = true,
+                        _ => return Err(Error::InvalidInput),
+                    }
+                }
+                Some(Format::Borders(borders))
+            }
+            _ => None,
+        };
+        format.ok_or(Error::InvalidInput)
+    }
+}
+
+impl fmt::Display for Format {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let on = |set: bool| if set { "on" } else { "off" };
+        let color = |color: &Option<Color>| match color {
+            Some(color) => color.to_string(),
+            None => String::from("none"),
+        };
+        match self {
+            Format::Number(NumberFormat::General) => write!(f, "general"),
+            Format::Number(NumberFormat::Number {
+                decimals,
+                thousands,
+            }) => match thousands {
+                true => write!(f, "number:{}:thousands", decimals),
+                false => write!(f, "number:{}", decimals),
+            },
+            Format::Number(NumberFormat::Percent { decimals }) => {
+                write!(f, "percent:{}", decimals)
+            }
+            Format::Number(NumberFormat::Currency { symbol, decimals }) => {
+                write!(f, "currency:{}:{}", symbol, decimals)
+            }
+            Format::Number(NumberFormat::Date(format)) => write!(f, "date:{}", format.pattern()),
+            Format::Bold(bold) => write!(f, "bold:{}", on(*bold)),
+            Format::Italic(italic) => write!(f, "italic:{}", on(*italic)),
+            Format::TextColor(text) => write!(f, "color:{}", color(text)),
+            Format::FillColor(fill) => write!(f, "fill:{}", color(fill)),
+            Format::Align(align) => {
+                let name = match align {
+                    Align::General => "general",
+                    Align::Left => "left",
+                    Align::Center => "center",
+                    Align::Right => "right",
+                };
+                write!(f, "align:{}", name)
+            }
+            Format::Borders(borders) => {
+                let edges: Vec<&str> = [
+                    (borders.top, "top"),
+                    (borders.right, "right"),
+                    (borders.bottom, "bottom"),
+                    (borders.left, "left"),
+                ]
+                .into_iter()
+                .filter(|(set, _)| *set)
+                .map(|(_, edge)| edge)
+                .collect();
+                match edges.len() {
+                    0 => write!(f, "border:none"),
+                    4 => write!(f, "border:all"),
+                    _ => write!(f, "border:{}", edges.join("+")),
+                }
+            }
+        }
+    }
+}
+
+/// Everything formatting says about how a cell looks.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Style {
+    pub number: NumberFormat,
+    pub bold: bool,
+    pub italic: bool,
+    pub text_color: Option<Color>,
+    pub fill_color: Option<Color>,
+    pub align: Align,
+    pub borders: Borders,
+}
+
+impl Style {
+    /// Sets one attribute of the style.
+    pub fn apply(&mut self, format: Format) {
+        match format {
+            Format::Number(number) => self.number = number,
+            Format::Bold(bold) => self.bold = bold,
+            Format::Italic(italic) => self.italic = italic,
+            Format::TextColor(color) => self.text_color = color,
+            Format::FillColor(color) => self.fill_color = color,
+            Format::Align(align) => self.align = align,
+            Format::Borders(borders) => self.borders = borders,
+        }
+    }
+}
+
+/// Returns `true` if the range `inner` lies inside the range `outer`.
+fn covers(outer: RangeAddr, inner: RangeAddr) -> bool {
+    outer.contains(inner.start) && outer.contains(inner.end)
+}
+
+/// Returns `true` if two ranges share a cell.
+fn overlaps(range: RangeAddr, other: RangeAddr) -> bool {
+    range.start.row <= other.end.row
+        && other.start.row <= range.end.row
+        && range.start.col <= other.end.col
+        && other.start.col <= range.end.col
+}
+
+/// The formatting of a sheet: attributes set on ranges, oldest first.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct Formats {
+    pub(crate) ranges: Vec<(RangeAddr, Format)>,
+}
+
+impl Formats {
+    /// Sets an attribute on a range, dropping older settings it hides.
+    fn set(&mut self, range: RangeAddr, format: Format) {
+        // The range already looks like this if the newest entry reaching it holds all of it
+        let hidden = self
+            .ranges
+            .iter()
+            .rev()
+            .find(|(other, old)| {
+                discriminant(old) == discriminant(&format) && overlaps(*other, range)
+            })
+            .is_some_and(|(other, old)| *old == format && covers(*other, range));
+        if hidden {
+            return;
+        }
+        self.ranges.retain(|(other, old)| {
+            discriminant(old) != discriminant(&format) || !covers(range, *other)
+        });
+        // Defaults only need storing where they hide something older
+        let overrides = self.ranges.iter().any(|(other, old)| {
+            discriminant(old) == discriminant(&format) && overlaps(*other, range)
+        });
+        if overrides || !Format::DEFAULTS.contains(&format) {
+            self.ranges.push((range, format));
+        }
+    }
+
+    /// Moves the attributes of the block `src` onto `dest`, a block of the
+    /// same size, replacing those of `dest` and leaving `src` unformatted.
+    fn relocate(&mut self, src: RangeAddr, dest: RangeAddr) {
+        let rows = dest.start.row as i64 - src.start.row as i64;
+        let cols = dest.start.col as i64 - src.start.col as i64;
+        let moved: Vec<(RangeAddr, Format)> = self
+            .ranges
+            .iter()
+            .filter(|(range, _)| overlaps(*range, src))
+            .filter_map(|&(range, format)| {
+                // The part of the range inside the block, where it lands
+                let start = CellAddr::new(
+                    range.start.row.max(src.start.row),
+                    range.start.col.max(src.start.col),
+                );
+                let end = CellAddr::new(
+                    range.end.row.min(src.end.row),
+                    range.end.col.min(src.end.col),
+                );
+                let moved = RangeAddr::new(start.offset(rows, cols)?, end.offset(rows, cols)?)?;
+                Some((moved, format))
+            })
+            .collect();
+        for format in Format::DEFAULTS {
+            self.set(src, format);
+            self.set(dest, format);
+        }
+        for (range, format) in moved {
+            self.set(range, format);
+        }
+    }
+}
+
+/// Returns `true` if an attribute can be written out and read back.
+fn is_writable(format: Format) -> bool {
+    match format {
+        Format::Number(
+            NumberFormat::Number { decimals, .. } | NumberFormat::Percent { decimals },
+        ) => decimals <= MAX_DECIMALS,
+        Format::Number(NumberFormat::Currency { symbol, decimals }) => {
+            decimals <= MAX_DECIMALS && !matches!(symbol, ':' | ',') && !symbol.is_whitespace()
+        }
+        _ => true,
+    }
+}
+
+impl Sheet {
+    /// Sets formatting attributes on a range, in order, as one undo step.
+    ///
+    /// # Returns
+    /// `Error::InvalidInput` if the range does not lie inside the sheet, or a
+    /// number format has more than 10 decimals or a currency symbol of `:` or `,`.
+    pub fn set_format(&mut self, range: RangeAddr, formats: &[Format]) -> Result<(), Error> {
+        if range.start.row == 0
+            || range.start.col == 0
+            || range.end.row as usize > self.row
+            || range.end.col as usize > self.col
+            || !formats.iter().all(|&format| is_writable(format))
+        {
+            return Err(Error::InvalidInput);
+        }
+        let before = self.is_recording().then(|| self.formats.ranges.clone());
+        for &format in formats {
+            self.formats.set(range, format);
+        }
+        if let Some(before) = before {
+            self.record_formats(before);
+        }
+        Ok(())
+    }
+
+    /// Moves the formatting of the block `src` onto `dest`, a block of the
+    /// same size, as one recorded change; see `move_range`.
+    pub(crate) fn relocate_formats(&mut self, src: RangeAddr, dest: RangeAddr) {
+        let before = self.is_recording().then(|| self.formats.ranges.clone());
+        self.formats.relocate(src, dest);
+        if let Some(before) = before {
+            self.record_formats(before);
+        }
+    }
+
+    /// Removes every formatting attribute from a range.
+    pub fn clear_format(&mut self, range: RangeAddr) -> Result<(), Error> {
+        self.set_format(range, &Format::DEFAULTS)
+    }
+
+    /// Returns how a cell looks.
+    pub fn style(&self, row: usize, col: usize) -> Style {
+        let addr = CellAddr::from_index(row, col);
+        let mut style = Style::default();
+        for (range, format) in &self.formats.ranges {
+            if range.contains(addr) {
+                style.apply(*format);
+            }
+        }
+        style
+    }
+
+    /// Returns the value of a cell written out in its number format.
+    ///
+    /// Cells holding an error value are written as their plain value; front
+    /// ends show the error instead.
+    pub fn formatted_value(&self, row: i32, col: i32) -> String {
+        let value = self.get_value(row, col);
+        match self.get_error(row, col) {
+            Some(_) => value.to_string(),
+            None => self.style(row as usize, col as usize).number.apply(value),
+        }
+    }
+
+    /// Number of stored formatting entries.
+    pub fn format_count(&self) -> usize {
+        self.formats.ranges.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_number_formats() {
+        let apply = |text: &str, value| {
+            text.parse::<Format>().map(|format| match format {
+                Format::Number(number) => number.apply(value),
+                _ => String::new(),
+            })
+        };
+        assert_eq!(
+            apply("number:2:thousands", -1234567).unwrap(),
+            "-1,234,567.00"
+        );
+        assert_eq!(apply("number:0", 1234).unwrap(), "1234");
+        assert_eq!(apply("percent:1", 5).unwrap(), "500.0%");
+        assert_eq!(apply("currency:$:2", -1234).unwrap(), "-$1,234.00");
+        assert_eq!(apply("currency:€:0", 999).unwrap(), "€999");
+        assert_eq!(apply("date:yyyy-mm-dd", 45366).unwrap(), "2024-03-15");
+        assert_eq!(apply("date:d mmm yyyy", 1).unwrap(), "31 Dec 1899");
+        assert_eq!(apply("date:mm/dd/yyyy", 60).unwrap(), "02/28/1900");
+        assert_eq!(apply("number:11", 1), Err(Error::InvalidInput));
+        assert_eq!(apply("currency:US$:2", 1), Err(Error::InvalidInput));
+
+        for text in [
+            "bold:on",
+            "fill:#ffcc00",
+            "border:top+left",
+            "number:1:thousands",
+        ] {
+            assert_eq!(text.parse::<Format>().unwrap().to_string(), text);
+        }
+        assert_eq!(Format::parse_list("clear").unwrap(), Format::DEFAULTS);
+        assert!(Format::parse_list("bold,size:12").is_none());
+    }
+
+    #[test]
+    fn test_ranges_layer_and_coalesce() {
+        let mut test_sheet = Sheet::new(10, 10);
+        test_sheet.update_cell_data(2, 2, String::from("1500"));
+        let column = RangeAddr::parse("B1:B10").unwrap();
+        let cell = RangeAddr::parse("B2:B2").unwrap();
+        let formats = Format::parse_list("currency:$:0,bold").unwrap();
+        test_sheet.set_format(column, &formats).unwrap();
+        test_sheet.set_format(cell, &[Format::Bold(false)]).unwrap();
+        assert_eq!(test_sheet.formatted_value(2, 2), "$1,500");
+        assert!(!test_sheet.style(2, 2).bold);
+        assert!(test_sheet.style(3, 2).bold);
+        assert_eq!(test_sheet.style(2, 3), Style::default());
+
+        // Formatting the same range again does not grow the list
+        test_sheet.set_format(column, &formats).unwrap();
+        test_sheet.set_format(cell, &formats).unwrap();
+        assert_eq!(test_sheet.format_count(), 2);
+        test_sheet.clear_format(column).unwrap();
+        assert_eq!(test_sheet.format_count(), 0);
+        assert_eq!(test_sheet.formatted_value(2, 2), "1500");
+        assert_eq!(
+            test_sheet.set_format(RangeAddr::parse("A1:K1").unwrap(), &formats),
+            Err(Error::InvalidInput)
+        );
+        let wide = Format::Number(NumberFormat::Percent { decimals: 11 });
+        assert_eq!(
+            test_sheet.set_format(column, &[wide]),
+            Err(Error::InvalidInput)
+        );
+    }
+}
@@ -0,0 +1,247 @@
+//! Tracing precedents and dependents.
+//!
+//! The precedents of a cell are the cells its formula reads, its dependents
+//! the cells whose formulas read it. Both are followed step by step from the
+//! traced cell: the direct ones are one step away, the transitive ones
+//! further. Every cell is listed once, at the step it is first reached, and
+//! the traced cell itself is never listed. A range read by a range function
+//! is listed as one range rather than cell by cell, so tracing a formula over
+//! a whole column takes no more memory than the cells written in it.
+
+use crate::addr::{CellAddr, RangeAddr};
+use crate::sheet::Sheet;
+use fxhash::FxHashSet;
+
+/// Cells reached by tracing the precedents or dependents of a cell.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Trace {
+    /// Cells one step away, row by row
+    pub direct: Vec<CellAddr>,
+    /// Cells more than one step away, up to the requested depth, row by row
+    pub transitive: Vec<CellAddr>,
+    /// Ranges one step away, whose cells are not listed in `direct`
+    pub direct_ranges: Vec<RangeAddr>,
+    /// Ranges more than one step away, whose cells are not listed in `transitive`
+    pub transitive_ranges: Vec<RangeAddr>,
+}
+
+impl Trace {
+    /// Returns `true` if a cell is one step away from the traced cell.
+    pub fn is_direct(&self, addr: CellAddr) -> bool {
+        self.direct.binary_search(&addr).is_ok()
+            || self.direct_ranges.iter().any(|range| range.contains(addr))
+    }
+
+    /// Returns `true` if a cell was reached by the trace.
+    pub fn contains(&self, addr: CellAddr) -> bool {
+        self.is_direct(addr)
+            || self.transitive.binary_search(&addr).is_ok()
+            || self
+                .transitive_ranges
+                .iter()
+                .any(|range| range.contains(addr))
+    }
+
+    /// Returns `true` if no cell was reached.
+    pub fn is_empty(&self) -> bool {
+        self.direct.is_empty()
+            && self.transitive.is_empty()
+            && self.direct_ranges.is_empty()
+            && self.transitive_ranges.is_empty()
+    }
+}
+
+impl Sheet {
+    /// Traces the cells a cell reads, directly or through other formulas.
+    ///
+    /// Every cell of a range read by a range function is a precedent, whether
+    /// it was written to or not; the range is listed in `direct_ranges` or
+    /// `transitive_ranges`. Cells of other sheets are not listed.
+    ///
+    /// # Parameters
+    /// * `cell` - The traced cell
+    /// * `depth` - Largest number of steps to follow; `1` gives the direct
+    ///   precedents only, `usize::MAX` all of them
+    pub fn precedents(&self, cell: CellAddr, depth: usize) -> Trace {
+        self.trace(cell, depth, |addr, ranges| {
+            let formula = self.formula_of(addr);
+            // A formula whose reference was deleted no longer reads anything
+            if formula.flag.error() == 3 {
+                return vec![];
+            }
+            match formula.range() {
+                Some(range) => {
+                    ranges.push(range);
+                    vec![]
+                }
+                None => formula.precedents(),
+            }
+        })
+    }
+
+    /// Traces the cells whose formulas read a cell, directly or through other formulas.
+    ///
+    /// # Parameters
+    /// * `cell` - The traced cell
+    /// * `depth` - Largest number of steps to follow; `1` gives the direct
+    ///   dependents only, `usize::MAX` all of them
+    pub fn dependents(&self, cell: CellAddr, depth: usize) -> Trace {
+        self.trace(cell, depth, |addr, _| self.dependents_of(addr).collect())
+    }
+
+    /// Follows `step` breadth first from `cell` for at most `depth` steps.
+    ///
+    /// `step` returns the cells one step away and adds the ranges one step
+    /// away to its second argument. The trace goes on from the written cells
+    /// of those ranges, which are covered by the range and not listed.
+    fn trace(
+        &self,
+        cell: CellAddr,
+        depth: usize,
+        step: impl Fn(CellAddr, &mut Vec<RangeAddr>) -> Vec<CellAddr>,
+    ) -> Trace {
+        let mut seen: FxHashSet<CellAddr> = FxHashSet::default();
+        seen.insert(cell);
+        let mut seen_ranges: FxHashSet<RangeAddr> = FxHashSet::default();
+        let mut trace = Trace::default();
+        let mut frontier = vec![cell];
+        for level in 0..depth {
+            let mut ranges = vec![];
+            let mut next: Vec<CellAddr> = frontier
+                .iter()
+                .flat_map(|&addr| step(addr, &mut ranges))
+                .filter(|&addr| seen.insert(addr))
+                .collect();
+            ranges.retain(|&range| seen_ranges.insert(range));
+            let listed = next.len();
+            for &range in &ranges {
+                next.extend(
+                    self.written_in(range)
+                        .into_iter()
+                        .filter(|&addr| seen.insert(addr)),
+                );
+            }
+            if next.is_empty() && ranges.is_empty() {
+                break;
+            }
+            let (cells, found) = match level {
+                0 => (&mut trace.direct, &mut trace.direct_ranges),
+                _ => (&mut trace.transitive, &mut trace.transitive_ranges),
+            };
+            cells.extend_from_slice(&next[..listed]);
+            found.extend(ranges);
+            frontier = next;
+        }
+        trace.direct.sort_unstable();
+        trace.transitive.sort_unstable();
+        trace
+    }
+
+    /// Returns the written cells of a range, looking them up one by one or
+    /// going through the written cells, whichever is fewer.
+    fn written_in(&self, range: RangeAddr) -> Vec<CellAddr> {
+        if range.len() <= self.grid.len() as u64 {
+            range
+                .cells()
+                .filter(|addr| {
+                    self.grid
+                        .get(addr.row as usize, addr.col as usize)
+                        .is_some()
+                })
+                .collect()
+        } else {
+            self.grid
+                .iter()
+                .map(|(addr, _)| addr)
+                .filter(|&addr| range.contains(addr))
+                .collect()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addrs(names: &[&str]) -> Vec<CellAddr> {
+        names
+            .iter()
+            .map(|name| CellAddr::parse(name).unwrap())
+            .collect()
+    }
+
+    fn sample_sheet() -> Sheet {
+        let mut test_sheet = Sheet::new(10, 10);
+        test_sheet.transaction(|batch| {
+            batch.set(1, 1, "5");
+            batch.set(2, 1, "A1*2");
+            batch.set(3, 1, "A2+B1");
+            batch.set(1, 3, "SUM(A1:A2)");
+            batch.set(2, 3, "C1+A3");
+            batch.set(3, 3, "C2");
+        });
+        test_sheet
+    }
+
+    #[test]
+    fn test_precedents() {
+        let test_sheet = sample_sheet();
+        let cell = CellAddr::parse("C2").unwrap();
+        let trace = test_sheet.precedents(cell, usize::MAX);
+        assert_eq!(trace.direct, addrs(&["C1", "A3"]));
+        // The cells of `A1:A2` are covered by the range, unless reached on their own
+        assert_eq!(trace.transitive, addrs(&["B1", "A2"]));
+        assert_eq!(
+            trace.transitive_ranges,
+            [RangeAddr::parse("A1:A2").unwrap()]
+        );
+        assert!(trace.contains(CellAddr::parse("A1").unwrap()));
+        assert!(trace.contains(CellAddr::parse("B1").unwrap()));
+        assert!(!trace.contains(cell));
+
+        let trace = test_sheet.precedents(cell, 1);
+        assert_eq!(trace.transitive, vec![]);
+        assert!(test_sheet.precedents(cell, 0).is_empty());
+        assert!(
+            test_sheet
+                .precedents(CellAddr::parse("A1").unwrap(), usize::MAX)
+                .is_empty()
+        );
+    }
+
+    #[test]
+    fn test_precedents_through_whole_column_range() {
+        let mut test_sheet = Sheet::new(1_048_576, 16384);
+        test_sheet.transaction(|batch| {
+            batch.set(1, 1, "SUM(B1:B1048576)");
+            batch.set(5, 2, "C7+1");
+            batch.set(7, 3, "A9");
+        });
+        let trace = test_sheet.precedents(CellAddr::new(1, 1), usize::MAX);
+        assert_eq!(trace.direct, vec![]);
+        assert_eq!(
+            trace.direct_ranges,
+            [RangeAddr::parse("B1:B1048576").unwrap()]
+        );
+        assert_eq!(trace.transitive, addrs(&["C7", "A9"]));
+        assert!(trace.is_direct(CellAddr::new(1_048_576, 2)));
+        assert!(trace.contains(CellAddr::parse("A9").unwrap()));
+        assert!(!trace.contains(CellAddr::parse("A2").unwrap()));
+    }
+
+    #[test]
+    fn test_dependents() {
+        let test_sheet = sample_sheet();
+        let trace = test_sheet.dependents(CellAddr::parse("A1").unwrap(), usize::MAX);
+        assert_eq!(trace.direct, addrs(&["C1", "A2"]));
+        assert_eq!(trace.transitive, addrs(&["C2", "A3", "C3"]));
+
+        let trace = test_sheet.dependents(CellAddr::parse("A1").unwrap(), 2);
+        assert_eq!(trace.transitive, addrs(&["C2", "A3"]));
+        assert!(
+            test_sheet
+                .dependents(CellAddr::parse("C3").unwrap(), usize::MAX)
+                .is_empty()
+        );
+    }
+}
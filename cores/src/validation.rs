@@ -0,0 +1,443 @@
+//! Data validation.
+//!
+//! A validation attached to a range checks what is written into its cells,
+//! by `update_cell_data` or by a batch such as a copy or a fill: a whole
+//! number between bounds, one of a list
+//! of values, given literally or read from a range, a formula that is not
+//! zero, or input no longer than a number of characters. Input breaking the
+//! validation is either rejected, leaving the cell unchanged, or accepted
+//! with a warning; either way the update reports the validation it broke.
+//!
+//! Numbers and lists are checked against the value the input evaluates to,
+//! so `A1+1` passes `whole 1 10` only while `A1` is below 10. A formula
+//! validation is written for the first cell of its range and shifted for the
+//! others, like a formula rule of conditional formatting; it is evaluated
+//! with the new value in place, so `A1` on `A1:A9` rejects zeros. Clearing a
+//! cell is never checked, nor are sorting, moving, undo and redo, which only
+//! rearrange or restore what the cells held. A batch leaves out the edits
+//! its validations reject and applies the rest.
+
+use crate::addr::{CellAddr, RangeAddr};
+use crate::eval::EvalOptions;
+use crate::parse::{CommandCall, unparse_command};
+use crate::sheet::{Error, Sheet, reading};
+use std::fmt;
+use std::str::FromStr;
+
+/// What input has to be for a validation to accept it.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Check {
+    /// A number between the two bounds, both included
+    WholeNumber { min: i32, max: i32 },
+    /// One of the listed numbers
+    List(Vec<i32>),
+    /// One of the values of the range
+    ListRange(RangeAddr),
+    /// The formula, written for the first cell of the range, is not zero
+    Formula(String),
+    /// Input of at most this many characters
+    TextLength(usize),
+}
+
+/// What happens to input breaking a validation.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Alert {
+    /// The input is refused and the cell keeps its formula
+    #[default]
+    Reject,
+    /// The input is accepted and the update warns about it
+    Warn,
+}
+
+/// A check on the input of a range and what happens to input breaking it.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Validation {
+    pub check: Check,
+    pub alert: Alert,
+}
+
+impl FromStr for Validation {
+    type Err = Error;
+
+    /// Parses a validation such as `whole 1 10`, `list 1,2,3`, `list A1:A5`,
+    /// `formula A1-B1` or `length 8`, preceded by `warn` to only warn about
+    /// input breaking it.
+    fn from_str(text: &str) -> Result<Self, Error> {
+        let number = |text: &str| text.parse::<i32>().map_err(|_| Error::InvalidInput);
+        let words: Vec<&str> = text.split_whitespace().collect();
+        let (alert, words) = match words.as_slice() {
+            ["warn", words @ ..] => (Alert::Warn, words),
+            ["reject", words @ ..] => (Alert::Reject, words),
+            words => (Alert::default(), words),
+        };
+        let check = match words {
+            ["whole", min, max] => Check::WholeNumber {
+                min: number(min)?,
+                max: number(max)?,
+            },
+            ["list", values] => match RangeAddr::parse(values) {
+                Some(range) => Check::ListRange(range),
+                None => Check::List(
+                    values
+                        .split(',')
+                        .map(|value| number(value.trim()))
+                        .collect::<Result<_, _>>()?,
+                ),
+            },
+            ["formula", formula] => Check::Formula(formula.to_string()),
+            ["length", length] => {
+                Check::TextLength(length.parse().map_err(|_| Error::InvalidInput)?)
+            }
+            _ => return Err(Error::InvalidInput),
+        };
+        Ok(Validation { check, alert })
+    }
+}
+
+impl fmt::Display for Validation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.alert == Alert::Warn {
+            write!(f, "warn ")?;
+        }
+        match &self.check {
+            Check::WholeNumber { min, max } => write!(f, "whole {} {}", min, max),
+            Check::List(values) => {
+                let values: Vec<String> = values.iter().map(|value| value.to_string()).collect();
+                write!(f, "list {}", values.join(","))
+            }
+            Check::ListRange(range) => write!(f, "list {}", range),
+            Check::Formula(formula) => write!(f, "formula {}", formula),
+            Check::TextLength(length) => write!(f, "length {}", length),
+        }
+    }
+}
+
+/// A validation attached to a range.
+#[derive(Clone, Debug)]
+pub(crate) struct RangeValidation {
+    pub(crate) range: RangeAddr,
+    pub(crate) validation: Validation,
+    /// The formula of a formula validation, as written for the first cell of the range
+    pub(crate) formula: Option<CommandCall>,
+}
+
+impl Sheet {
+    /// Attaches a validation to a range; where validations overlap, the one
+    /// added last checks the input.
+    ///
+    /// # Returns
+    /// `Error::InvalidInput` if the range or the range of a list does not lie
+    /// inside the sheet, the bounds are the wrong way round, or the formula of
+    /// a formula validation is invalid, reads another sheet or sleeps.
+    pub fn add_validation(
+        &mut self,
+        range: RangeAddr,
+        validation: Validation,
+    ) -> Result<(), Error> {
+        let inside = |range: RangeAddr| {
+            range.start.row > 0
+                && range.start.col > 0
+                && range.end.row as usize <= self.row
+                && range.end.col as usize <= self.col
+        };
+        if !inside(range) {
+            return Err(Error::InvalidInput);
+        }
+        let mut validation = validation;
+        let formula = match &validation.check {
+            Check::WholeNumber { min, max } if min > max => return Err(Error::InvalidInput),
+            Check::ListRange(list) if !inside(*list) => return Err(Error::InvalidInput),
+            Check::Formula(text) => {
+                let formula = self.parse_anchored(text)?;
+                // The formula is kept as the sheet writes it out
+                validation.check = Check::Formula(unparse_command(&formula));
+                Some(formula)
+            }
+            _ => None,
+        };
+        let before = self.validations.clone();
+        self.validations.push(RangeValidation {
+            range,
+            validation,
+            formula,
+        });
+        self.record_validations(before);
+        Ok(())
+    }
+
+    /// Returns the validations of the sheet with their ranges, oldest first.
+    pub fn validations(&self) -> Vec<(RangeAddr, Validation)> {
+        self.validations
+            .iter()
+            .map(|entry| (entry.range, entry.validation.clone()))
+            .collect()
+    }
+
+    /// Removes the validation at a position of `validations`.
+    ///
+    /// # Returns
+    /// The removed validation, or `None` if there is no validation at that position.
+    pub fn remove_validation(&mut self, index: usize) -> Option<(RangeAddr, Validation)> {
+        if index >= self.validations.len() {
+            return None;
+        }
+        let before = self.validations.clone();
+        let removed = self.validations.remove(index);
+        self.record_validations(before);
+        Some((removed.range, removed.validation))
+    }
+
+    /// Returns the values a cell with a list validation accepts, in the order
+    /// of the list, for offering them to choose from.
+    ///
+    /// # Returns
+    /// `None` if the input of the cell is not checked against a list.
+    pub fn allowed_values(&self, row: usize, col: usize) -> Option<Vec<i32>> {
+        match &self
+            .validation_of(CellAddr::from_index(row, col))?
+            .validation
+            .check
+        {
+            Check::List(values) => Some(values.clone()),
+            Check::ListRange(range) => Some(self.list_values(*range)),
+            _ => None,
+        }
+    }
+
+    /// Checks input for a cell against the validation of the cell.
+    ///
+    /// # Returns
+    /// The validation the input breaks, or `None` if it passes, has no
+    /// validation to pass, or is not a valid formula.
+    pub(crate) fn violation(&mut self, row: usize, col: usize, input: &str) -> Option<Validation> {
+        let addr = CellAddr::from_index(row, col);
+        let entry = self.validation_of(addr)?.clone();
+        let command = self.parse_checked(input);
+        if command.flag.error() != 0 {
+            return None;
+        }
+        // Input resulting in an error value never passes a check on its value
+        let value = self.evaluate_formula(&command, &EvalOptions::default(), None);
+        let passes = match &entry.validation.check {
+            Check::TextLength(length) => input.trim().chars().count() <= *length,
+            Check::WholeNumber { min, max } => {
+                value.is_ok_and(|value| (*min..=*max).contains(&value))
+            }
+            Check::List(values) => value.is_ok_and(|value| values.contains(&value)),
+            Check::ListRange(range) => {
+                value.is_ok_and(|value| self.list_values(*range).contains(&value))
+            }
+            Check::Formula(_) => value.is_ok_and(|value| {
+                let formula = entry
+                    .formula
+                    .as_ref()
+                    .expect("formula validations keep their formula");
+                // Evaluate with the new value in place, then put the cell back;
+                // both go through `track`, so the totals of ranges follow
+                let saved = self.grid.get(row, col).cloned();
+                self.track(addr, |sheet| {
+                    let cell = &mut sheet.grid[(row, col)];
+                    cell.value = value;
+                    cell.formula = command.clone();
+                    cell.formula.flag.set_is_any(1);
+                });
+                let result = self.evaluate_anchored(formula, entry.range.start, addr);
+                self.track(addr, |sheet| match saved {
+                    Some(cell) => sheet.grid.insert(row, col, cell),
+                    None => {
+                        sheet.grid.remove(row, col);
+                    }
+                });
+                result.is_some_and(|result| result != 0)
+            }),
+        };
+        (!passes).then_some(entry.validation)
+    }
+
+    /// Returns the validation checking the input of a cell, if any.
+    fn validation_of(&self, addr: CellAddr) -> Option<&RangeValidation> {
+        self.validations
+            .iter()
+            .rev()
+            .find(|entry| entry.range.contains(addr))
+    }
+
+    /// Returns the values of the written cells of a range without an error, row by row.
+    fn list_values(&self, range: RangeAddr) -> Vec<i32> {
+        range
+            .cells()
+            .filter_map(|addr| {
+                let cell = self.grid.get(addr.row as usize, addr.col as usize)?;
+                reading(cell).filter(|_| cell.formula.flag.is_any() != 0)
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn add(test_sheet: &mut Sheet, range: &str, validation: &str) {
+        let range = RangeAddr::parse(range).unwrap();
+        test_sheet
+            .add_validation(range, validation.parse().unwrap())
+            .unwrap();
+    }
+
+    #[test]
+    fn test_rejected_input_leaves_cell() {
+        let mut test_sheet = Sheet::new(10, 10);
+        add(&mut test_sheet, "A1:A5", "whole 1 10");
+        assert_eq!(
+            test_sheet.update_cell_data(1, 1, String::from("7")).error,
+            Error::None
+        );
+        let result = test_sheet.update_cell_data(1, 1, String::from("11"));
+        assert_eq!(result.error, Error::ValidationFailed);
+        assert_eq!(result.violation, Some("whole 1 10".parse().unwrap()));
+        assert_eq!(test_sheet.get_value(1, 1), 7);
+        // Formulas are checked by their value, and errors never pass
+        test_sheet.update_cell_data(1, 2, String::from("4"));
+        assert_eq!(
+            test_sheet
+                .update_cell_data(2, 1, String::from("B1*2"))
+                .error,
+            Error::None
+        );
+        let result = test_sheet.update_cell_data(2, 1, String::from("B1/0"));
+        assert_eq!(result.error, Error::ValidationFailed);
+        // Clearing is always allowed
+        test_sheet.clear_cell(1, 1);
+        assert_eq!(test_sheet.get_value(1, 1), 0);
+
+        add(&mut test_sheet, "C1:C5", "warn length 3");
+        let result = test_sheet.update_cell_data(1, 3, String::from("1234"));
+        assert_eq!(result.error, Error::None);
+        assert_eq!(result.violation.map(|found| found.alert), Some(Alert::Warn));
+        assert_eq!(test_sheet.get_value(1, 3), 1234);
+    }
+
+    #[test]
+    fn test_lists_and_formulas() {
+        let mut test_sheet = Sheet::new(10, 10);
+        for (row, value) in [(1, "10"), (2, "20"), (3, "30")] {
+            test_sheet.update_cell_data(row, 4, String::from(value));
+        }
+        add(&mut test_sheet, "A1:A5", "list D1:D3");
+        add(&mut test_sheet, "B1:B5", "list 1,2,3");
+        add(&mut test_sheet, "C2:C5", "formula C2-C1");
+        assert_eq!(test_sheet.allowed_values(2, 1), Some(vec![10, 20, 30]));
+        assert_eq!(test_sheet.allowed_values(2, 2), Some(vec![1, 2, 3]));
+        assert_eq!(test_sheet.allowed_values(2, 3), None);
+        assert_eq!(
+            test_sheet.update_cell_data(1, 1, String::from("20")).error,
+            Error::None
+        );
+        assert_eq!(
+            test_sheet.update_cell_data(1, 1, String::from("25")).error,
+            Error::ValidationFailed
+        );
+        assert_eq!(
+            test_sheet.update_cell_data(1, 2, String::from("4")).error,
+            Error::ValidationFailed
+        );
+
+        // The formula sees the new value: each cell has to differ from the one above
+        test_sheet.update_cell_data(1, 3, String::from("5"));
+        assert_eq!(
+            test_sheet.update_cell_data(2, 3, String::from("5")).error,
+            Error::ValidationFailed
+        );
+        assert_eq!(
+            test_sheet.update_cell_data(2, 3, String::from("6")).error,
+            Error::None
+        );
+        assert_eq!(
+            test_sheet.update_cell_data(3, 3, String::from("6")).error,
+            Error::ValidationFailed
+        );
+        assert_eq!(test_sheet.get_formula(3, 3), "0");
+
+        for text in ["warn whole -5 5", "list 1,2,3", "formula C2-C1", "length 8"] {
+            assert_eq!(text.parse::<Validation>().unwrap().to_string(), text);
+        }
+        assert_eq!(
+            test_sheet.add_validation(
+                RangeAddr::parse("A1:A2").unwrap(),
+                "whole 5 1".parse().unwrap()
+            ),
+            Err(Error::InvalidInput)
+        );
+        assert_eq!(
+            test_sheet.remove_validation(1).map(|(range, _)| range),
+            RangeAddr::parse("B1:B5")
+        );
+        assert_eq!(
+            test_sheet.update_cell_data(1, 2, String::from("4")).error,
+            Error::None
+        );
+    }
+
+    #[test]
+    fn test_formulas_see_range_totals() {
+        let mut test_sheet = Sheet::new(10, 10);
+        // The column may not add up to nothing
+        add(&mut test_sheet, "A1:A3", "formula SUM(A1:A3)");
+        // A cell reading the range keeps a running total of it
+        test_sheet.update_cell_data(1, 2, String::from("SUM(A1:A3)"));
+        assert_eq!(
+            test_sheet.update_cell_data(1, 1, String::from("50")).error,
+            Error::None
+        );
+        assert_eq!(test_sheet.get_value(1, 2), 50);
+        assert_eq!(
+            test_sheet.update_cell_data(1, 1, String::from("0")).error,
+            Error::ValidationFailed
+        );
+        // The tentative value is taken back out of the total
+        assert_eq!(test_sheet.get_value(1, 1), 50);
+        test_sheet.update_cell_data(1, 3, String::from("SUM(A1:A3)"));
+        assert_eq!(test_sheet.get_value(1, 3), 50);
+    }
+
+    #[test]
+    fn test_batches_are_checked() {
+        let mut test_sheet = Sheet::new(10, 10);
+        add(&mut test_sheet, "A1:A5", "whole 1 10");
+        let result = test_sheet.transaction(|batch| {
+            batch.set(1, 1, "4");
+            batch.set(2, 1, "40");
+            batch.set(1, 2, "40");
+        });
+        assert_eq!(
+            result.failed,
+            vec![(CellAddr::new(2, 1), Error::ValidationFailed)]
+        );
+        assert_eq!(test_sheet.get_value(1, 1), 4);
+        assert_eq!(test_sheet.get_formula(2, 1), "0");
+
+        // Copies and fills go through batches too
+        test_sheet.copy_col(2, 1).unwrap();
+        assert_eq!(test_sheet.get_value(1, 1), 4);
+        test_sheet.update_cell_data(1, 3, String::from("9"));
+        test_sheet.update_cell_data(2, 3, String::from("10"));
+        test_sheet
+            .fill(
+                RangeAddr::parse("C1:C5").unwrap(),
+                crate::fill::FillDirection::Down,
+            )
+            .unwrap();
+        test_sheet.copy_col(3, 1).unwrap();
+        assert_eq!(test_sheet.get_value(2, 1), 10);
+        assert_eq!(test_sheet.get_formula(3, 1), "0");
+
+        // Moving and undoing only rearrange what the cells held
+        test_sheet
+            .move_range(RangeAddr::parse("C3:C3").unwrap(), CellAddr::new(5, 1))
+            .unwrap();
+        assert_eq!(test_sheet.get_value(5, 1), 11);
+        assert!(test_sheet.undo());
+        assert_eq!(test_sheet.get_value(3, 3), 11);
+    }
+}
@@ -0,0 +1,833 @@
+//! Workbooks of named sheets.
+//!
+//! A workbook holds sheets of the same size in the order their tabs are shown,
+//! each with a name that is unique within the workbook. Formulas may read
+//! other sheets through references such as `Sheet2!A1` or `'Q3 Data'!B2:B9`
+//! (see `external`); the workbook records which cells of other sheets read
+//! each cell.
+//!
+//! Edits made through `Workbook::update_cell_data` are carried to every sheet
+//! reading the changed cells, sheet after sheet. After changing a sheet
+//! directly, such as undoing an edit or inserting rows, `refresh` brings the
+//! other sheets up to date. Loops through several sheets are always rejected,
+//! even with iterative calculation on.
+//!
+//! When rows or columns of a sheet are inserted or deleted, `refresh` also
+//! rewrites the references of the other sheets to it so they keep reading
+//! the same cells. References to deleted cells become `#REF!`, and stay so
+//! when the change is undone.
+
+use crate::addr::CellAddr;
+use crate::eval::EvalOptions;
+use crate::external::{Import, Imports, Links, imports_of, quote_sheet_name};
+use crate::parse::{CommandCall, CommandFlag, Param};
+use crate::read_ss::read_ss_records;
+use crate::sheet::{CallResult, Error, Sheet, value_error};
+use crate::structure::Relocation;
+use crate::write_ss::write_sheet_record;
+use fxhash::{FxHashMap, FxHashSet};
+use std::time;
+
+/// Most times changes are carried from one sheet to another after an edit.
+///
+/// Only loops made by changing sheets directly can keep changes going that long.
+const MAX_ROUNDS: usize = 1000;
+
+/// A cell of the sheet at an index, and whether a search reached it
+/// through another sheet.
+type Node = (usize, CellAddr, bool);
+
+/// A sheet of a workbook with its name.
+struct NamedSheet {
+    /// Id the formulas of other sheets refer to this one by
+    id: u32,
+    name: String,
+    sheet: Sheet,
+}
+
+/// A collection of named sheets whose formulas may read each other.
+pub struct Workbook {
+    sheets: Vec<NamedSheet>,
+    /// Id of the next sheet added; ids of deleted sheets are not reused
+    next_id: u32,
+    /// Cells of every sheet read by the other sheets
+    links: Links,
+    /// Cells of the loop through other sheets the last update was rejected
+    /// for, by the id of their sheet
+    cycle: Vec<(u32, CellAddr)>,
+    /// Number of rows of every sheet
+    row: usize,
+    /// Number of columns of every sheet
+    col: usize,
+}
+
+impl Workbook {
+    /// Creates a workbook with one empty sheet named `Sheet1`.
+    ///
+    /// # Parameters
+    /// * `row` - Number of rows of every sheet
+    /// * `col` - Number of columns of every sheet
+    pub fn new(row: usize, col: usize) -> Self {
+        let mut book = Self::empty(row, col);
+        book.push("Sheet1".to_string());
+        book.link_sheets();
+        book
+    }
+
+    fn empty(row: usize, col: usize) -> Self {
+        Self {
+            sheets: vec![],
+            next_id: 0,
+            links: Links::default(),
+            cycle: vec![],
+            row,
+            col,
+        }
+    }
+
+    /// Number of sheets in the workbook.
+    pub fn len(&self) -> usize {
+        self.sheets.len()
+    }
+
+    /// Returns `true` if the workbook has no sheets, which never happens.
+    pub fn is_empty(&self) -> bool {
+        self.sheets.is_empty()
+    }
+
+    /// Returns the name of the sheet at `index`.
+    pub fn name(&self, index: usize) -> &str {
+        &self.sheets[index].name
+    }
+
+    /// Iterates over the names of the sheets in order.
+    pub fn names(&self) -> impl Iterator<Item = &str> {
+        self.sheets.iter().map(|named| named.name.as_str())
+    }
+
+    /// Finds a sheet by name, ignoring case.
+    pub fn position(&self, name: &str) -> Option<usize> {
+        self.sheets
+            .iter()
+            .position(|named| named.name.eq_ignore_ascii_case(name))
+    }
+
+    /// Returns the sheet at `index`.
+    pub fn sheet(&self, index: usize) -> &Sheet {
+        &self.sheets[index].sheet
+    }
+
+    /// Returns the sheet at `index` for changes other than cell edits.
+    ///
+    /// Call `refresh` afterwards so other sheets see the changes.
+    pub fn sheet_mut(&mut self, index: usize) -> &mut Sheet {
+        &mut self.sheets[index].sheet
+    }
+
+    /// Returns the first name of the form `SheetN` that no sheet has.
+    pub fn unused_name(&self) -> String {
+        (1..)
+            .map(|n| format!("Sheet{}", n))
+            .find(|name| self.position(name).is_none())
+            .expect("some name is free")
+    }
+
+    /// Adds an empty sheet after the last one.
+    ///
+    /// # Returns
+    /// The index of the new sheet, or `Error::InvalidInput` if the name is
+    /// invalid or taken.
+    pub fn add_sheet(&mut self, name: &str) -> Result<usize, Error> {
+        if !self.is_valid_name(name, None) || self.next_id == u32::MAX {
+            return Err(Error::InvalidInput);
+        }
+        self.push(name.to_string());
+        self.link_sheets();
+        Ok(self.sheets.len() - 1)
+    }
+
+    /// Renames a sheet. Formulas reading it show the new name.
+    ///
+    /// # Returns
+    /// `Error::InvalidInput` if the name is invalid or taken by another sheet.
+    pub fn rename_sheet(&mut self, index: usize, name: &str) -> Result<(), Error> {
+        if index >= self.sheets.len() || !self.is_valid_name(name, Some(index)) {
+            return Err(Error::InvalidInput);
+        }
+        self.sheets[index].name = name.to_string();
+        self.link_sheets();
+        Ok(())
+    }
+
+    /// Deletes a sheet. References to it in other sheets become `#REF!`.
+    ///
+    /// # Returns
+    /// The deleted sheet, or `Error::InvalidInput` if it is the only one.
+    pub fn delete_sheet(&mut self, index: usize) -> Result<Sheet, Error> {
+        if index >= self.sheets.len() || self.sheets.len() == 1 {
+            return Err(Error::InvalidInput);
+        }
+        let deleted = self.sheets.remove(index);
+        self.link_sheets();
+        self.import_from(deleted.id);
+        Ok(deleted.sheet)
+    }
+
+    /// Moves a sheet so it ends up at index `to`.
+    ///
+    /// # Returns
+    /// `Error::InvalidInput` if either index lies outside the workbook.
+    pub fn move_sheet(&mut self, from: usize, to: usize) -> Result<(), Error> {
+        if from >= self.sheets.len() || to >= self.sheets.len() {
+            return Err(Error::InvalidInput);
+        }
+        let named = self.sheets.remove(from);
+        self.sheets.insert(to, named);
+        Ok(())
+    }
+
+    /// Updates a cell of a sheet and recalculates the cells depending on it
+    /// in every sheet.
+    pub fn update_cell_data(
+        &mut self,
+        index: usize,
+        row: usize,
+        col: usize,
+        new_formula: String,
+    ) -> CallResult {
+        self.update_cell_data_with(index, row, col, new_formula, &EvalOptions::default())
+    }
+
+    /// Updates a cell of a sheet under the given limits, like
+    /// `Sheet::update_cell_data_with`, and carries the change to the other sheets.
+    ///
+    /// A formula closing a loop through other sheets is rejected with
+    /// `Error::CycleDetected`. Its `cycle` then lists the cells of the loop
+    /// without their sheets; `cycle_path` names them.
+    pub fn update_cell_data_with(
+        &mut self,
+        index: usize,
+        row: usize,
+        col: usize,
+        new_formula: String,
+        options: &EvalOptions,
+    ) -> CallResult {
+        let start = time::Instant::now();
+        self.cycle.clear();
+        let id = self.sheets[index].id;
+        let target = CellAddr::from_index(row, col);
+        let command = self.sheets[index].sheet.parse_checked(&new_formula);
+        if command.flag.error() != 1 {
+            if let Some(cycle) = self.closes_loop(index, target, &command) {
+                self.cycle = cycle;
+                return CallResult {
+                    time: start.elapsed().as_millis() as f64,
+                    error: Error::CycleDetected,
+                    cycle: self.cycle.iter().map(|&(_, addr)| addr).collect(),
+                    diverged: vec![],
+                    violation: None,
+                };
+            }
+            // The formula reads the current values of other sheets
+            let values = self.read_values(&imports_of(&command));
+            self.sheets[index].sheet.set_imported(values, &[]);
+        }
+
+        let old = self.sheets[index].sheet.formula_of(target);
+        let result = self.sheets[index]
+            .sheet
+            .update_cell_data_with(row, col, new_formula, options);
+        // A rejected update leaves the old formula in place
+        self.links.remove(id, target, &old);
+        let formula = self.sheets[index].sheet.formula_of(target);
+        self.links.insert(id, target, &formula);
+        self.propagate();
+        result
+    }
+
+    /// Describes the loop of an update rejected by a sheet, like
+    /// `CallResult::cycle_path`, naming the cells of other sheets, e.g.
+    /// `A1 -> Sheet2!B1 -> A1`.
+    ///
+    /// # Returns
+    /// `None` if the update did not create a cycle.
+    pub fn cycle_path(&self, index: usize, result: &CallResult) -> Option<String> {
+        let id = self.sheets[index].id;
+        let through_sheets = !self.cycle.is_empty()
+            && self
+                .cycle
+                .iter()
+                .map(|&(_, addr)| addr)
+                .eq(result.cycle.iter().copied());
+        let cycle: Vec<(u32, CellAddr)> = if through_sheets {
+            self.cycle.clone()
+        } else {
+            result.cycle.iter().map(|&addr| (id, addr)).collect()
+        };
+        let first = cycle.first()?.1;
+        let mut names: Vec<String> = cycle
+            .iter()
+            .map(|&(other, addr)| {
+                if other == id {
+                    return addr.to_string();
+                }
+                match self.index_of(other) {
+                    Some(at) => format!("{}!{}", quote_sheet_name(&self.sheets[at].name), addr),
+                    None => "#REF!".to_string(),
+                }
+            })
+            .collect();
+        names.push(first.to_string());
+        Some(names.join(" -> "))
+    }
+
+    /// Reads the current value of every cell read from another sheet and
+    /// recalculates what changed, until all sheets agree.
+    pub fn refresh(&mut self) {
+        self.follow_structures();
+        // A lone sheet reads no other sheet; references to deleted ones were
+        // marked when they were deleted
+        if self.sheets.len() > 1 {
+            self.import_all();
+        }
+    }
+
+    /// Carries the rows and columns inserted into or deleted from each sheet,
+    /// and the blocks moved in it, to the references of the other sheets
+    /// reading it.
+    fn follow_structures(&mut self) {
+        for index in 0..self.sheets.len() {
+            let relocations = std::mem::take(&mut self.sheets[index].sheet.restructured);
+            let id = self.sheets[index].id;
+            for relocation in relocations {
+                for other in (0..self.sheets.len()).filter(|&other| other != index) {
+                    let sheet = &mut self.sheets[other].sheet;
+                    match relocation {
+                        Relocation::Structure(op) => sheet.follow_structure(id, op),
+                        Relocation::Block { src, rows, cols } => {
+                            sheet.follow_move(id, src, rows, cols)
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    /// Records again which cells read other sheets, reads the current value
+    /// of everything read from other sheets and carries the changes on.
+    fn import_all(&mut self) {
+        self.links = Links::default();
+        let mut readers = vec![];
+        for named in &self.sheets {
+            let cells = named.sheet.importing_cells();
+            for &addr in &cells {
+                self.links
+                    .insert(named.id, addr, &named.sheet.formula_of(addr));
+            }
+            readers.push(cells);
+        }
+        for (index, cells) in readers.into_iter().enumerate() {
+            let sheet = &self.sheets[index].sheet;
+            let read: FxHashSet<Import> = cells
+                .iter()
+                .flat_map(|&addr| imports_of(&sheet.formula_of(addr)))
+                .collect();
+            let values = self.read_values(&read.iter().copied().collect::<Vec<_>>());
+            let sheet = &mut self.sheets[index].sheet;
+            sheet.retain_imported(&read);
+            sheet.set_imported(values, &cells);
+        }
+        self.propagate();
+    }
+
+    /// Reads again everything read from the sheet with the given id and
+    /// carries the changes on.
+    fn import_from(&mut self, id: u32) {
+        for index in 0..self.sheets.len() {
+            let sheet = &self.sheets[index].sheet;
+            let mut read = vec![];
+            let mut cells = vec![];
+            for addr in sheet.importing_cells() {
+                let imports = imports_of(&sheet.formula_of(addr));
+                let before = read.len();
+                read.extend(imports.into_iter().filter(|import| import.id() == id));
+                if read.len() > before {
+                    cells.push(addr);
+                }
+            }
+            let values = self.read_values(&read);
+            self.sheets[index].sheet.set_imported(values, &cells);
+        }
+        self.propagate();
+    }
+
+    /// Exports every sheet to one .ss file.
+    ///
+    /// The records are those of `Sheet::write_file` with an extra `sheet`
+    /// column naming their sheet. Each sheet starts with a record for row 0,
+    /// which no cell has, holding the id of the sheet as its value. References
+    /// to other sheets are written with the ids of the sheets.
+    pub fn write_file(&self, file_path: &str) -> Result<(), Box<dyn std::error::Error>> {
+        let mut wtr = csv::Writer::from_path(file_path)?;
+        for named in &self.sheets {
+            write_sheet_record(&mut wtr, named.id, &named.name)?;
+            named.sheet.write_records(&mut wtr, Some(&named.name))?;
+        }
+        wtr.flush()?;
+        Ok(())
+    }
+
+    /// Reads a workbook written by `write_file`, replacing every sheet.
+    ///
+    /// A file written by `Sheet::write_file` is read as a workbook of one
+    /// sheet. Listeners subscribed to the old sheets are dropped.
+    pub fn read_file(&mut self, file_path: &str) -> Result<(), Box<dyn std::error::Error>> {
+        let mut sheets: Vec<(NamedSheet, Vec<_>)> = vec![];
+        for record in read_ss_records(file_path)? {
+            if record.row == 0 {
+                let id = u32::try_from(record.value).map_err(|_| "invalid sheet id")?;
+                let named = NamedSheet {
+                    id,
+                    name: record.sheet.clone(),
+                    sheet: Sheet::new(self.row, self.col),
+                };
+                sheets.push((named, vec![]));
+                continue;
+            }
+            if sheets.is_empty() {
+                // A file of a single sheet
+                let named = NamedSheet {
+                    id: 0,
+                    name: record.sheet.clone(),
+                    sheet: Sheet::new(self.row, self.col),
+                };
+                sheets.push((named, vec![]));
+            }
+            let (_, records) = sheets
+                .iter_mut()
+                .find(|(named, _)| named.name == record.sheet)
+                .ok_or_else(|| format!("unknown sheet '{}'", record.sheet))?;
+            records.push(record);
+        }
+
+        let mut book = Self::empty(self.row, self.col);
+        for (mut named, records) in sheets {
+            if named.name.is_empty() {
+                named.name = book.unused_name();
+            }
+            if !book.is_valid_name(&named.name, None)
+                || book.sheets.iter().any(|other| other.id == named.id)
+                || named.id == u32::MAX
+            {
+                return Err(format!("invalid or repeated sheet '{}'", named.name).into());
+            }
+            named.sheet.read_records(records)?;
+            book.next_id = book.next_id.max(named.id + 1);
+            book.sheets.push(named);
+        }
+        if book.sheets.is_empty() {
+            book.push("Sheet1".to_string());
+        }
+        book.link_sheets();
+        book.import_all();
+        *self = book;
+        Ok(())
+    }
+
+    fn push(&mut self, name: String) {
+        self.sheets.push(NamedSheet {
+            id: self.next_id,
+            name,
+            sheet: Sheet::new(self.row, self.col),
+        });
+        self.next_id += 1;
+    }
+
+    /// Tells every sheet which sheets it can read.
+    fn link_sheets(&mut self) {
+        let book: Vec<(u32, String)> = self
+            .sheets
+            .iter()
+            .map(|named| (named.id, named.name.clone()))
+            .collect();
+        for named in &mut self.sheets {
+            let imports = &mut named.sheet.imports;
+            *imports = Imports {
+                id: named.id,
+                book: book.clone(),
+                values: std::mem::take(&mut imports.values),
+                changed: std::mem::take(&mut imports.changed),
+            };
+        }
+    }
+
+    /// Returns `true` if a sheet may be given the name.
+    ///
+    /// Names must be non-empty, free of `'` and `!` and of surrounding spaces,
+    /// and differ from the names of the other sheets, ignoring case.
+    fn is_valid_name(&self, name: &str, renamed: Option<usize>) -> bool {
+        !name.is_empty()
+            && name.trim() == name
+            && !name.contains(['\'', '!'])
+            && self
+                .position(name)
+                .is_none_or(|index| Some(index) == renamed)
+    }
+
+    fn index_of(&self, id: u32) -> Option<usize> {
+        self.sheets.iter().position(|named| named.id == id)
+    }
+
+    /// Reads the current values of things read from other sheets.
+    ///
+    /// Values of a deleted sheet are `Error::Ref`.
+    fn read_values(&self, imports: &[Import]) -> Vec<(Import, Result<i32, Error>)> {
+        imports
+            .iter()
+            .map(|&import| {
+                let Some(index) = self.index_of(import.id()) else {
+                    return (import, Err(Error::Ref));
+                };
+                let sheet = &self.sheets[index].sheet;
+                let value = match import {
+                    Import::Cell(_, addr) => {
+                        let cell = &sheet.grid[addr];
+                        value_error(cell).map_or(Ok(cell.value), Err)
+                    }
+                    // The sheet read computes the function over its own cells
+                    Import::Range(_, range, type_, cmd) => {
+                        let mut flag = CommandFlag::new();
+                        flag.set_type_(type_);
+                        flag.set_cmd(cmd);
+                        let formula = CommandCall {
+                            flag,
+                            param1: Param::Cell(range.start),
+                            param2: Param::Cell(range.end),
+                        };
+                        sheet.evaluate_formula(&formula, &EvalOptions::default(), None)
+                    }
+                };
+                (import, value)
+            })
+            .collect()
+    }
+
+    /// Carries the cells each sheet changed to the sheets reading them, and
+    /// their changes on to the sheets reading those.
+    fn propagate(&mut self) {
+        for _ in 0..MAX_ROUNDS {
+            let mut carried = false;
+            for index in 0..self.sheets.len() {
+                let mut changed = std::mem::take(&mut self.sheets[index].sheet.imports.changed);
+                if changed.is_empty() {
+                    continue;
+                }
+                changed.sort_unstable();
+                changed.dedup();
+                let id = self.sheets[index].id;
+                let mut readers: FxHashMap<u32, Vec<CellAddr>> = FxHashMap::default();
+                for addr in changed {
+                    for (reader, cell) in self.links.readers_of(id, addr) {
+                        readers.entry(reader).or_default().push(cell);
+                    }
+                }
+                for (reader, mut cells) in readers {
+                    let Some(other) = self.index_of(reader) else {
+                        continue;
+                    };
+                    cells.sort_unstable();
+                    cells.dedup();
+                    let sheet = &self.sheets[other].sheet;
+                    let read: Vec<Import> = cells
+                        .iter()
+                        .flat_map(|&addr| imports_of(&sheet.formula_of(addr)))
+                        .filter(|import| import.id() == id)
+                        .collect();
+                    let values = self.read_values(&read);
+                    self.sheets[other].sheet.set_imported(values, &cells);
+                    carried = true;
+                }
+            }
+            if !carried {
+                return;
+            }
+        }
+    }
+
+    /// Finds the loop a formula of a sheet would close through other sheets.
+    ///
+    /// The search follows the cells depending on `target` across the sheets,
+    /// until one of them is read by the formula. Loops within the sheet are
+    /// left for the sheet to report, and a loop through other sheets has to
+    /// leave the sheet through a cell another sheet reads, so the search only
+    /// runs if there is one.
+    ///
+    /// # Returns
+    /// The cells of the loop with the ids of their sheets, starting at
+    /// `target`, each reading the next. `None` if there is no such loop.
+    fn closes_loop(
+        &self,
+        index: usize,
+        target: CellAddr,
+        command: &CommandCall,
+    ) -> Option<Vec<(u32, CellAddr)>> {
+        if !self.links.is_read(self.sheets[index].id) {
+            return None;
+        }
+        // The cell each visited cell was reached from, `None` for `target`
+        let mut parents: FxHashMap<Node, Option<Node>> = FxHashMap::default();
+        let mut stack: Vec<(Node, Option<Node>)> = vec![((index, target, false), None)];
+        while let Some((node, parent)) = stack.pop() {
+            if parents.contains_key(&node) {
+                continue;
+            }
+            parents.insert(node, parent);
+            let (at, cell, crossed) = node;
+            if crossed && self.reads(index, command, at, cell) {
+                return Some(self.loop_path(&parents, node));
+            }
+            let sheet = &self.sheets[at].sheet;
+            stack.extend(
+                sheet
+                    .dependents_of(cell)
+                    .map(|dependent| ((at, dependent, crossed), Some(node))),
+            );
+            for (reader, dependent) in self.links.readers_of(self.sheets[at].id, cell) {
+                if let Some(other) = self.index_of(reader) {
+                    stack.push(((other, dependent, true), Some(node)));
+                }
+            }
+        }
+        None
+    }
+
+    /// Returns `true` if a formula of the sheet at `index` reads the cell
+    /// `cell` of the sheet at `at`, checking ranges by their bounds.
+    fn reads(&self, index: usize, command: &CommandCall, at: usize, cell: CellAddr) -> bool {
+        if command.flag.error() == 3 {
+            return false;
+        }
+        if at == index {
+            return match command.range() {
+                Some(range) => range.contains(cell),
+                None => [command.param1, command.param2].contains(&Param::Cell(cell)),
+            };
+        }
+        let id = self.sheets[at].id;
+        match command.external_range() {
+            Some((other, range)) => other == id && range.contains(cell),
+            None => [command.param1, command.param2].contains(&Param::External(id, cell)),
+        }
+    }
+
+    /// Follows the cells a loop was found through back to the cell the
+    /// search started at.
+    fn loop_path(
+        &self,
+        parents: &FxHashMap<Node, Option<Node>>,
+        last: Node,
+    ) -> Vec<(u32, CellAddr)> {
+        let mut nodes = vec![];
+        let mut next = Some(last);
+        while let Some(node) = next {
+            nodes.push(node);
+            next = parents[&node];
+        }
+        // The search followed dependents, so the loop is reversed to follow references
+        let target = nodes.pop().expect("the search starts at the target");
+        let mut cycle = vec![target];
+        cycle.extend(nodes);
+        cycle
+            .into_iter()
+            .map(|(at, cell, _)| (self.sheets[at].id, cell))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::addr::RangeAddr;
+    use crate::fill::FillDirection;
+
+    fn sample_book() -> Workbook {
+        let mut book = Workbook::new(10, 10);
+        assert_eq!(book.add_sheet("Q3 Data"), Ok(1));
+        book.update_cell_data(1, 2, 2, String::from("5"));
+        book.update_cell_data(1, 3, 2, String::from("7"));
+        book
+    }
+
+    #[test]
+    fn test_references_follow_other_sheets() {
+        let mut book = sample_book();
+        let result = book.update_cell_data(0, 1, 1, String::from("SUM('Q3 Data'!B2:B9)"));
+        assert_eq!(result.error, Error::None);
+        assert_eq!(book.sheet(0).get_value(1, 1), 12);
+        book.update_cell_data(1, 4, 1, String::from("Sheet1!A1*2"));
+        assert_eq!(book.sheet(1).get_value(4, 1), 24);
+
+        // Changes travel from sheet to sheet
+        book.update_cell_data(1, 2, 2, String::from("10"));
+        assert_eq!(book.sheet(0).get_value(1, 1), 17);
+        assert_eq!(book.sheet(1).get_value(4, 1), 34);
+
+        // A loop through both sheets is rejected
+        let result = book.update_cell_data(1, 3, 2, String::from("A4+1"));
+        assert_eq!(result.error, Error::CycleDetected);
+        assert_eq!(
+            book.cycle_path(1, &result).unwrap(),
+            "B3 -> A4 -> Sheet1!A1 -> B3"
+        );
+        assert_eq!(book.sheet(1).get_formula(3, 2), "7");
+        assert_eq!(
+            book.update_cell_data(0, 2, 1, String::from("Sheet9!A1"))
+                .error,
+            Error::InvalidInput
+        );
+    }
+
+    #[test]
+    fn test_sheet_management() {
+        let mut book = sample_book();
+        book.update_cell_data(0, 1, 1, String::from("'Q3 Data'!B3"));
+        assert_eq!(book.add_sheet("q3 data"), Err(Error::InvalidInput));
+        assert_eq!(book.add_sheet("It's"), Err(Error::InvalidInput));
+
+        book.rename_sheet(1, "Totals").unwrap();
+        assert_eq!(book.sheet(0).get_formula(1, 1), "Totals!B3");
+        book.move_sheet(1, 0).unwrap();
+        assert_eq!(book.names().collect::<Vec<_>>(), ["Totals", "Sheet1"]);
+        assert_eq!(book.position("sheet1"), Some(1));
+
+        book.delete_sheet(0).unwrap();
+        assert_eq!(book.sheet(0).get_formula(1, 1), "#REF!");
+        assert_eq!(book.sheet(0).get_error(1, 1), Some(Error::Ref));
+        assert!(book.delete_sheet(0).is_err());
+    }
+
+    #[test]
+    fn test_write_and_read_workbook() {
+        let mut book = sample_book();
+        book.add_sheet("Empty").unwrap();
+        book.update_cell_data(0, 1, 1, String::from("MAX('Q3 Data'!B2:B3)"));
+        let path = std::env::temp_dir().join("workbook_test.ss");
+        let path = path.to_str().unwrap();
+        book.write_file(path).unwrap();
+
+        let mut loaded = Workbook::new(10, 10);
+        loaded.read_file(path).unwrap();
+        assert_eq!(
+            loaded.names().collect::<Vec<_>>(),
+            ["Sheet1", "Q3 Data", "Empty"]
+        );
+        assert_eq!(loaded.sheet(0).get_formula(1, 1), "MAX('Q3 Data'!B2:B3)");
+        assert_eq!(loaded.sheet(0).get_value(1, 1), 7);
+        loaded.update_cell_data(1, 2, 2, String::from("9"));
+        assert_eq!(loaded.sheet(0).get_value(1, 1), 9);
+    }
+
+    #[test]
+    fn test_references_follow_structural_changes() {
+        let mut book = sample_book();
+        book.update_cell_data(1, 5, 1, String::from("4"));
+        book.update_cell_data(0, 1, 1, String::from("'Q3 Data'!A5+1"));
+        book.update_cell_data(0, 2, 1, String::from("SUM('Q3 Data'!B2:B8)"));
+
+        book.sheet_mut(1).insert_rows(1, 2).unwrap();
+        book.refresh();
+        assert_eq!(book.sheet(0).get_formula(1, 1), "'Q3 Data'!A7+1");
+        assert_eq!(book.sheet(0).get_value(1, 1), 5);
+        assert_eq!(book.sheet(0).get_formula(2, 1), "SUM('Q3 Data'!B4:B10)");
+        book.update_cell_data(1, 7, 1, String::from("6"));
+        assert_eq!(book.sheet(0).get_value(1, 1), 7);
+
+        // Ranges shrink with their lines; references to deleted cells are lost
+        book.sheet_mut(1).delete_rows(5, 3).unwrap();
+        book.refresh();
+        assert_eq!(book.sheet(0).get_formula(1, 1), "#REF!");
+        assert_eq!(book.sheet(0).get_error(1, 1), Some(Error::Ref));
+        assert_eq!(book.sheet(0).get_formula(2, 1), "SUM('Q3 Data'!B4:B7)");
+        assert_eq!(book.sheet(0).get_value(2, 1), 5);
+
+        book.sheet_mut(1).delete_cols(1, 1).unwrap();
+        book.refresh();
+        assert_eq!(book.sheet(0).get_formula(2, 1), "SUM('Q3 Data'!A4:A7)");
+        assert!(book.sheet_mut(1).undo());
+        book.refresh();
+        assert_eq!(book.sheet(0).get_formula(2, 1), "SUM('Q3 Data'!B4:B7)");
+        assert_eq!(book.sheet(0).get_value(2, 1), 5);
+    }
+
+    #[test]
+    fn test_rewritten_formulas_keep_other_sheets() {
+        let mut book = sample_book();
+        book.update_cell_data(0, 1, 1, String::from("'Q3 Data'!B2*2"));
+        book.update_cell_data(0, 1, 1, String::from("'Q3 Data'!B3*2"));
+        assert_eq!(book.sheet(0).get_value(1, 1), 14);
+        assert!(book.sheet_mut(0).undo());
+        book.refresh();
+        assert_eq!(book.sheet(0).get_formula(1, 1), "'Q3 Data'!B2*2");
+        assert_eq!(book.sheet(0).get_value(1, 1), 10);
+
+        let column = RangeAddr::parse("A1:A2").unwrap();
+        book.sheet_mut(0).fill(column, FillDirection::Down).unwrap();
+        book.refresh();
+        assert_eq!(book.sheet(0).get_formula(2, 1), "'Q3 Data'!B3*2");
+        assert_eq!(book.sheet(0).get_value(2, 1), 14);
+    }
+
+    #[test]
+    fn test_references_follow_moved_cells() {
+        let mut book = sample_book();
+        book.update_cell_data(0, 1, 1, String::from("'Q3 Data'!B3+1"));
+        book.update_cell_data(0, 2, 1, String::from("SUM('Q3 Data'!B2:B3)"));
+        book.update_cell_data(0, 3, 1, String::from("'Q3 Data'!D2"));
+
+        let block = RangeAddr::parse("B2:B3").unwrap();
+        book.sheet_mut(1)
+            .move_range(block, CellAddr::new(2, 4))
+            .unwrap();
+        book.refresh();
+        assert_eq!(book.sheet(0).get_formula(1, 1), "'Q3 Data'!D3+1");
+        assert_eq!(book.sheet(0).get_formula(2, 1), "SUM('Q3 Data'!D2:D3)");
+        assert_eq!(book.sheet(0).get_value(2, 1), 12);
+        // The overwritten cell is lost
+        assert_eq!(book.sheet(0).get_formula(3, 1), "#REF!");
+        book.update_cell_data(1, 3, 4, String::from("10"));
+        assert_eq!(book.sheet(0).get_value(1, 1), 11);
+
+        // Undoing the move takes the references back
+        assert!(book.sheet_mut(1).undo());
+        assert!(book.sheet_mut(1).undo());
+        book.refresh();
+        assert_eq!(book.sheet(0).get_formula(1, 1), "'Q3 Data'!B3+1");
+        assert_eq!(book.sheet(0).get_formula(2, 1), "SUM('Q3 Data'!B2:B3)");
+        assert_eq!(book.sheet(0).get_value(1, 1), 8);
+        assert_eq!(book.sheet(0).get_value(2, 1), 12);
+    }
+
+    #[test]
+    fn test_references_reach_every_row() {
+        let mut book = Workbook::new(1_048_576, 16384);
+        book.add_sheet("Sheet2").unwrap();
+        book.update_cell_data(1, 1_048_576, 16384, String::from("9"));
+        let result = book.update_cell_data(0, 1, 1, String::from("Sheet2!XFD1048576+1"));
+        assert_eq!(result.error, Error::None);
+        assert_eq!(book.sheet(0).get_value(1, 1), 10);
+        book.update_cell_data(0, 2, 1, String::from("SUM(Sheet2!XFD1:XFD1048576)"));
+        book.update_cell_data(1, 1_048_576, 16384, String::from("4"));
+        assert_eq!(book.sheet(0).get_value(1, 1), 5);
+        assert_eq!(book.sheet(0).get_value(2, 1), 4);
+
+        // Ids of deleted sheets are not reused, and do not run out
+        for _ in 0..5000 {
+            let index = book.add_sheet("Scratch").unwrap();
+            book.delete_sheet(index).unwrap();
+        }
+        assert_eq!(book.add_sheet("Sheet3"), Ok(2));
+        book.update_cell_data(2, 1, 1, String::from("Sheet1!A1*3"));
+        assert_eq!(book.sheet(2).get_value(1, 1), 15);
+        assert_eq!(book.sheet(2).get_formula(1, 1), "Sheet1!A1*3");
+    }
+}
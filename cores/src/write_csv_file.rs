@@ -70,11 +70,9 @@ impl Sheet {
             header.push(col_letter);
         }
         writer.write_record(&header)?;
-        for i in 1..self.grid.len() {
-            // Use the better approach mentioned in the comment
-            let mut row: Vec<String> = self.grid[i]
-                .iter()
-                .map(|cell| cell.value.to_string())
+        for i in 1..=self.row {
+            let mut row: Vec<String> = (0..=self.col)
+                .map(|j| self.grid[(i, j)].value.to_string())
                 .collect();
             row[0] = (i).to_string();
             writer.write_record(&row)?;
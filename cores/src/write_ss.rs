@@ -4,24 +4,32 @@
 //! It serializes cell values, formulas, and dependencies into a structured .ss format
 //! that can later be imported back into the spreadsheet.
 
+use crate::addr::{CellAddr, RangeAddr};
+use crate::comments::Comment;
+use crate::parse::{CommandCall, CommandFlag, Param};
 use crate::sheet::{Cell, Sheet};
 use serde::ser::{SerializeStruct, Serializer};
 use serde::{self, Serialize};
+use std::io;
 
 /// Structure for serializing a spreadsheet cell to .ss format.
 ///
 /// This wraps a Cell with its position information (row, column)
 /// for proper serialization into the .ss format.
-struct CsvStore {
+struct CsvStore<'a> {
     /// Row index of the cell (0-based)
-    row: i32,
+    row: u32,
     /// Column index of the cell (0-based)
-    col: i32,
+    col: u32,
     /// The cell data to be serialized
     data: Cell,
+    /// Anchor of the shared formula of the cell, unless the cell is the anchor
+    shared: Option<CellAddr>,
+    /// Name of the sheet of the cell, when writing a workbook
+    sheet: Option<&'a str>,
 }
 
-impl Serialize for CsvStore {
+impl Serialize for CsvStore<'_> {
     /// Implements custom serialization for a cell to .ss format.
     ///
     /// This transforms the complex nested structure of a Cell into a flat
@@ -38,8 +46,8 @@ impl Serialize for CsvStore {
     where
         S: Serializer,
     {
-        // We'll output a flat record with seven fields
-        let mut state = serializer.serialize_struct("Cell", 7)?;
+        // We'll output a flat record with seven fields, plus the sheet in a workbook
+        let mut state = serializer.serialize_struct("Cell", 7 + self.sheet.is_some() as usize)?;
 
         // Serialize position information
         state.serialize_field("row", &self.row)?;
@@ -48,36 +56,138 @@ impl Serialize for CsvStore {
         // Serialize the cell value
         state.serialize_field("value", &self.data.value)?;
 
+        // Cells sharing the formula of an anchor only name the anchor, plus any error bits
+        if let Some(anchor) = self.shared {
+            let flag = &self.data.formula.flag;
+            let mut flag_str = format!("shared:{}", anchor);
+            for (name, bit) in [
+                ("error", flag.error()),
+                ("div_by_zero", flag.is_div_by_zero()),
+                ("overflow", flag.is_overflow()),
+                ("timeout", flag.is_timeout()),
+                ("ref", flag.is_ref()),
+            ] {
+                if bit != 0 {
+                    flag_str.push_str(&format!(",{}:{}", name, bit));
+                }
+            }
+            state.serialize_field("flag", &flag_str)?;
+            state.serialize_field("param1", "")?;
+            state.serialize_field("param2", "")?;
+            state.serialize_field("depend", &depend_names(&self.data))?;
+            if let Some(sheet) = self.sheet {
+                state.serialize_field("sheet", sheet)?;
+            }
+            return state.end();
+        }
+
         // Format the nested CommandFlag into a string
         let flag_str = format!(
-            "type:{},cmd:{},type1:{},type2:{},error:{},div_by_zero:{}",
+            "type:{},cmd:{},type1:{},type2:{},error:{},div_by_zero:{},overflow:{},timeout:{},ref:{},plain:{}",
             self.data.formula.flag.type_(),
             self.data.formula.flag.cmd(),
             self.data.formula.flag.type1(),
             self.data.formula.flag.type2(),
             self.data.formula.flag.error(),
             self.data.formula.flag.is_div_by_zero(),
+            self.data.formula.flag.is_overflow(),
+            self.data.formula.flag.is_timeout(),
+            self.data.formula.flag.is_ref(),
+            self.data.formula.flag.is_plain_code(),
         );
         state.serialize_field("flag", &flag_str)?;
 
         // Serialize CommandCall's parameters
-        state.serialize_field("param1", &self.data.formula.param1)?;
-        state.serialize_field("param2", &self.data.formula.param2)?;
-
-        // Convert the dependency vector into a comma-separated string
-        let depend_str = self
-            .data
-            .depend
-            .iter()
-            .map(|d| d.to_string())
-            .collect::<Vec<_>>()
-            .join(",");
-        state.serialize_field("depend", &depend_str)?;
+        // References are written as cell names, values as numbers
+        state.serialize_field("param1", &self.data.formula.param1.to_string())?;
+        state.serialize_field("param2", &self.data.formula.param2.to_string())?;
+
+        // Convert the dependency vector into a comma-separated list of cell names
+        state.serialize_field("depend", &depend_names(&self.data))?;
+        if let Some(sheet) = self.sheet {
+            state.serialize_field("sheet", sheet)?;
+        }
+
+        state.end()
+    }
+}
+
+/// Structure for serializing a formatted range, a conditional formatting
+/// rule or a validation to .ss format.
+///
+/// It has the columns of a cell record: the flag `format`, `rule` or `validation`, the
+/// corners of the range as the parameters and the text of the attribute or
+/// rule in place of the dependencies.
+struct RangeStore<'a> {
+    range: RangeAddr,
+    flag: &'static str,
+    text: String,
+    /// Name of the sheet of the range, when writing a workbook
+    sheet: Option<&'a str>,
+}
 
+impl Serialize for RangeStore<'_> {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        let mut state = serializer.serialize_struct("Range", 7 + self.sheet.is_some() as usize)?;
+        state.serialize_field("row", &self.range.start.row)?;
+        state.serialize_field("col", &self.range.start.col)?;
+        state.serialize_field("value", &0)?;
+        state.serialize_field("flag", self.flag)?;
+        state.serialize_field("param1", &self.range.start.to_string())?;
+        state.serialize_field("param2", &self.range.end.to_string())?;
+        state.serialize_field("depend", &self.text)?;
+        if let Some(sheet) = self.sheet {
+            state.serialize_field("sheet", sheet)?;
+        }
         state.end()
     }
 }
 
+/// Structure for serializing a note or comment of a cell to .ss format.
+///
+/// It has the columns of a cell record: the flag `note` or `comment`, the
+/// author and the time as the parameters and the text in place of the
+/// dependencies.
+struct NoteStore<'a> {
+    addr: CellAddr,
+    flag: &'static str,
+    comment: &'a Comment,
+    /// Name of the sheet of the cell, when writing a workbook
+    sheet: Option<&'a str>,
+}
+
+impl Serialize for NoteStore<'_> {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        let mut state = serializer.serialize_struct("Note", 7 + self.sheet.is_some() as usize)?;
+        state.serialize_field("row", &self.addr.row)?;
+        state.serialize_field("col", &self.addr.col)?;
+        state.serialize_field("value", &0)?;
+        state.serialize_field("flag", self.flag)?;
+        state.serialize_field("param1", &self.comment.author)?;
+        state.serialize_field("param2", &self.comment.time.to_string())?;
+        state.serialize_field("depend", &self.comment.text)?;
+        if let Some(sheet) = self.sheet {
+            state.serialize_field("sheet", sheet)?;
+        }
+        state.end()
+    }
+}
+
+/// Joins the names of the cells depending on a cell with commas.
+fn depend_names(cell: &Cell) -> String {
+    cell.depend
+        .iter()
+        .map(|d| d.to_string())
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
 impl Sheet {
     /// Exports the spreadsheet data to a .ss file.
     ///
@@ -98,42 +208,141 @@ impl Sheet {
     /// - col: Column index (0-based)
     /// - value: The calculated cell value
     /// - flag: String encoding of the CommandFlag bitfield (comma-separated key-value pairs)
-    /// - param1: First parameter of the cell formula
-    /// - param2: Second parameter of the cell formula
-    /// - depend: Comma-separated list of cell indices that depend on this cell
+    /// - param1: First parameter of the cell formula, a number or a cell name such as `B7`,
+    ///   which is preceded by the id of its sheet if it lies on another sheet, as in `2!B7`
+    /// - param2: Second parameter of the cell formula, a number or a cell name
+    /// - depend: Comma-separated list of the names of the cells that depend on this cell
+    ///
+    /// Cells sharing a filled formula are written with the flag `shared:<anchor>`
+    /// and empty parameters; their formula is the anchor's, shifted down.
+    ///
+    /// Formatting is written after the cells, one record per formatted range
+    /// with the flag `format`, the corners of the range as parameters and the
+    /// attribute, such as `currency:$:2`, as the dependencies. Conditional
+    /// formatting rules follow in the same way with the flag `rule` and the
+    /// rule, such as `gt 100 then bold:on`, as the dependencies, and so do
+    /// validations with the flag `validation`, such as `warn whole 1 10`. The
+    /// notes and comments of the cells end the file with the flag `note` or
+    /// `comment`, the author and the time in seconds as parameters and the
+    /// text as the dependencies.
     pub fn write_file(&self, file_path: &str) -> Result<(), Box<dyn std::error::Error>> {
-        // Get the dimensions
-        let num_rows = self.grid.len();
-        let num_cols = if num_rows > 0 { self.grid[0].len() } else { 0 };
         let mut wtr = csv::Writer::from_path(file_path)?;
+        self.write_records(&mut wtr, None)?;
 
-        // Write only non-empty cells to the .ss file
-        for row in 0..num_rows {
-            for col in 0..num_cols {
-                // Skip cells with no flags set (empty cells)
-                if self.grid[row][col].formula.flag.is_any() == 0 {
-                    continue;
-                }
+        // Ensure data is written to disk
+        wtr.flush()?;
+        Ok(())
+    }
 
-                // Create a CsvStore for serialization
-                let cell = &self.grid[row][col];
-                let csv_data = CsvStore {
-                    row: row as i32,
-                    col: col as i32,
-                    data: cell.clone(),
-                };
+    /// Writes the records of the non-empty cells, tagged with the sheet name
+    /// when writing a workbook.
+    pub(crate) fn write_records<W: io::Write>(
+        &self,
+        wtr: &mut csv::Writer<W>,
+        sheet: Option<&str>,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        // Write only non-empty cells to the .ss file, in row-major order
+        for (row, col) in self.grid.positions() {
+            // Skip cells with no flags set (empty cells)
+            let cell = &self.grid[(row, col)];
+            if cell.formula.flag.is_any() == 0 {
+                continue;
+            }
 
-                // Serialize and write the cell to .ss
-                wtr.serialize(csv_data)?;
+            // Create a CsvStore for serialization
+            let addr = CellAddr::from_index(row, col);
+            let anchor = self
+                .shared_id(addr)
+                .map(|id| self.shared.get(id).anchor())
+                .filter(|&anchor| anchor != addr);
+            let mut data = cell.clone();
+            if anchor.is_none() {
+                data.formula = self.formula_of(addr);
             }
+            let csv_data = CsvStore {
+                row: row as u32,
+                col: col as u32,
+                data,
+                shared: anchor,
+                sheet,
+            };
+
+            // Serialize and write the cell to .ss
+            wtr.serialize(csv_data)?;
         }
 
-        // Ensure data is written to disk
-        wtr.flush()?;
+        // Formatting follows the cells, oldest first so it layers the same way
+        for &(range, format) in &self.formats.ranges {
+            wtr.serialize(RangeStore {
+                range,
+                flag: "format",
+                text: format.to_string(),
+                sheet,
+            })?;
+        }
+        // Rules go last, in the order they apply
+        for rule in &self.rules {
+            wtr.serialize(RangeStore {
+                range: rule.range,
+                flag: "rule",
+                text: rule.rule.to_string(),
+                sheet,
+            })?;
+        }
+        for entry in &self.validations {
+            wtr.serialize(RangeStore {
+                range: entry.range,
+                flag: "validation",
+                text: entry.validation.to_string(),
+                sheet,
+            })?;
+        }
+        // Notes and comments come last, each thread in order
+        for (&addr, annotations) in &self.notes.cells {
+            let notes = annotations.note.iter().map(|note| ("note", note));
+            let thread = annotations
+                .thread
+                .iter()
+                .map(|comment| ("comment", comment));
+            for (flag, comment) in notes.chain(thread) {
+                wtr.serialize(NoteStore {
+                    addr,
+                    flag,
+                    comment,
+                    sheet,
+                })?;
+            }
+        }
         Ok(())
     }
 }
 
+/// Writes the record starting a sheet of a workbook: row 0, which no cell
+/// has, with the id of the sheet as its value.
+pub(crate) fn write_sheet_record<W: io::Write>(
+    wtr: &mut csv::Writer<W>,
+    id: u32,
+    name: &str,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let record = CsvStore {
+        row: 0,
+        col: 0,
+        data: Cell {
+            value: id as i32,
+            formula: CommandCall {
+                flag: CommandFlag::new(),
+                param1: Param::Value(0),
+                param2: Param::Value(0),
+            },
+            depend: Vec::new(),
+        },
+        shared: None,
+        sheet: Some(name),
+    };
+    wtr.serialize(record)?;
+    Ok(())
+}
+
 #[test]
 fn test_write_ss() {
     let mut test_sheet = Sheet::new(6, 6);
@@ -1,6 +1,9 @@
+use super::changes::use_cell_version;
 use super::error_display::{show_error, ErrorContext, ErrorType};
+use super::recalc::update_in_background;
+use super::sheet_tabs::edit_sheet;
 use super::spreadsheet::*;
-use cores::Error;
+use cores::{Align, Annotations, CellAddr, Error, FillDirection, RangeAddr, Style};
 use dioxus::prelude::*;
 
 const CELL_STYLE: &str = "
@@ -32,6 +35,118 @@ const CELL_SELECTED_STYLE: &str = "
     outline: none;
     text-align: center;
 ";
+const CELL_FILL_PREVIEW_STYLE: &str = "
+    width: 81px;
+    height: 31px;
+    border: 1px dashed blue;
+    outline: none;
+    text-align: center;
+";
+const LIST_STYLE: &str = "
+    position: absolute;
+    right: 2px;
+    top: 6px;
+    width: 20px;
+    height: 22px;
+    font-size: 11px;
+    z-index: 6;
+    cursor: pointer;
+";
+// Red corner marking a cell with a note or comments
+const NOTE_MARK_STYLE: &str = "
+    position: absolute;
+    right: 1px;
+    top: 1px;
+    width: 0;
+    height: 0;
+    border-top: 7px solid #d32f2f;
+    border-left: 7px solid transparent;
+    pointer-events: none;
+";
+const NOTE_POPUP_STYLE: &str = "
+    position: absolute;
+    left: 100%;
+    top: 0;
+    width: 220px;
+    background-color: #fffde7;
+    border: 1px solid #ccc;
+    border-radius: 4px;
+    box-shadow: 0 2px 5px rgba(0,0,0,0.2);
+    padding: 6px 8px;
+    font-size: 12px;
+    text-align: left;
+    z-index: 20;
+    pointer-events: none;
+";
+// Backgrounds of traced cells: direct and transitive precedents, then dependents
+const PRECEDENT_COLORS: (&str, &str) = ("#bbdefb", "#e3f2fd");
+const DEPENDENT_COLORS: (&str, &str) = ("#ffcc80", "#fff3e0");
+const FILL_HANDLE_STYLE: &str = "
+    position: absolute;
+    right: -3px;
+    bottom: -3px;
+    width: 7px;
+    height: 7px;
+    background-color: blue;
+    border: 1px solid white;
+    cursor: crosshair;
+    z-index: 5;
+";
+
+/// Returns the CSS for the font, colors and alignment of a formatted cell.
+fn look_css(style: &Style) -> String {
+    let mut css = String::new();
+    if style.bold {
+        css.push_str(" font-weight: bold;");
+    }
+    if style.italic {
+        css.push_str(" font-style: italic;");
+    }
+    if let Some(color) = style.text_color {
+        css.push_str(&format!(" color: {color};"));
+    }
+    if let Some(color) = style.fill_color {
+        css.push_str(&format!(" background-color: {color};"));
+    }
+    match style.align {
+        Align::General => {}
+        Align::Left => css.push_str(" text-align: left;"),
+        Align::Center => css.push_str(" text-align: center;"),
+        Align::Right => css.push_str(" text-align: right;"),
+    }
+    css
+}
+
+/// Returns the CSS for the borders of a formatted cell.
+fn border_css(style: &Style) -> String {
+    let borders = style.borders;
+    [
+        (borders.top, "top"),
+        (borders.right, "right"),
+        (borders.bottom, "bottom"),
+        (borders.left, "left"),
+    ]
+    .into_iter()
+    .filter(|(set, _)| *set)
+    .map(|(_, edge)| format!(" border-{edge}: 2px solid black;"))
+    .collect()
+}
+
+/// Returns the range and direction filled by dragging the fill handle of the
+/// selected cell to `(row, col)`, or `None` for drags up or to the left.
+fn fill_target(selected: (i32, i32), row: i32, col: i32) -> Option<(RangeAddr, FillDirection)> {
+    let (sel_row, sel_col) = selected;
+    let start = CellAddr::new(sel_row as u32, sel_col as u32);
+    if row >= sel_row && row - sel_row >= col - sel_col {
+        let end = CellAddr::new(row as u32, sel_col as u32);
+        Some((RangeAddr::new(start, end)?, FillDirection::Down))
+    } else if col > sel_col {
+        let end = CellAddr::new(sel_row as u32, col as u32);
+        Some((RangeAddr::new(start, end)?, FillDirection::Right))
+    } else {
+        None
+    }
+}
 
 #[derive(Props, PartialEq, Clone)]
 pub struct CellProps {
@@ -52,12 +167,20 @@ pub fn Cell(props: CellProps) -> Element {
     // Consume the contexts
     let mut selected_cell = use_context::<SelectedCellContext>();
     let sheet = use_context::<SheetContext>();
+    let book = use_context::<WorkbookContext>();
+    let active_sheet = use_context::<ActiveSheetContext>();
     let mut sheetversion = use_context::<SheetVersionContext>();
+    let recalc = use_context::<RecalcContext>();
     let mut error_ctx = use_context::<ErrorContext>();
+    let mut fill_drag = use_context::<FillDragContext>();
+    let traced_cells = use_context::<TracedCellsContext>();
+    let highlights = use_context::<HighlightsContext>();
 
     let mut is_editing = use_signal(|| false);
     let mut formula = use_signal(String::new);
     let mut value = use_signal(String::new);
+    let mut style = use_signal(Style::default);
+    let mut hovered = use_signal(|| false);
 
     // Check if this cell is selected based on context
     let is_this_cell_selected = {
@@ -65,6 +188,33 @@ pub fn Cell(props: CellProps) -> Element {
         props.row == sel_row && props.col == sel_col
     };
 
+    // Check if this cell lies in the range the fill handle is dragged over
+    let is_in_fill_preview = fill_drag.cloned().is_some_and(|(row, col)| {
+        fill_target(*selected_cell.read(), row, col).is_some_and(|(range, _)| {
+            range.contains(CellAddr::new(props.row as u32, props.col as u32))
+        })
+    });
+
+    // Background of the cell if it is a precedent or dependent of the traced cell
+    let trace_color = traced_cells
+        .read()
+        .as_ref()
+        .and_then(|(precedents, dependents)| {
+            let addr = CellAddr::new(props.row as u32, props.col as u32);
+            [
+                (precedents, PRECEDENT_COLORS),
+                (dependents, DEPENDENT_COLORS),
+            ]
+            .into_iter()
+            .find_map(|(trace, (direct, transitive))| {
+                if trace.is_direct(addr) {
+                    Some(direct)
+                } else {
+                    trace.contains(addr).then_some(transitive)
+                }
+            })
+        });
+
     // Handler for when user starts editing
     let on_focus = {
         let row = props.row;
@@ -76,25 +226,33 @@ pub fn Cell(props: CellProps) -> Element {
         }
     };
 
-    // Handler for when the cell loses focus
-    let on_blur = {
+    // Applies a formula to the cell and reports what went wrong
+    let mut commit = {
         let row = props.row;
         let col = props.col;
-        move |_| {
-            is_editing.set(false);
-
-            // Get the formula from context in case it was updated elsewhere
-            let mut formula_text = formula.read().clone();
+        move |mut formula_text: String| {
             // Evaluate the formula and update the displayed value
             if formula_text.is_empty() {
                 formula_text = "0".to_string();
             }
-            if let Ok(mut sheet_locked) = sheet.cloned().lock() {
-                // Update the cell value in the Sheet object
-                let res =
-                    sheet_locked.update_cell_data(row as usize, col as usize, formula_text.clone());
+            // Update the cell through the workbook, so other sheets reading it follow,
+            // recalculating off the UI thread
+            spawn(async move {
+                let Some((res, cycle_path)) = update_in_background(
+                    sheet,
+                    book,
+                    active_sheet,
+                    recalc,
+                    row as usize,
+                    col as usize,
+                    formula_text.clone(),
+                )
+                .await
+                else {
+                    return;
+                };
                 match res.error {
-                    Error::None | Error::DivByZero => {
+                    Error::None | Error::DivByZero | Error::Overflow | Error::Ref => {
                         sheetversion.set(sheetversion.cloned() + 1);
                     }
                     Error::InvalidInput => {
@@ -106,16 +264,126 @@ pub fn Cell(props: CellProps) -> Element {
                         );
                     }
                     Error::CycleDetected => {
+                        let message = match cycle_path {
+                            Some(path) => format!(
+                                "Cannot apply formula : would create circular reference {}",
+                                path
+                            ),
+                            None => {
+                                "Cannot apply formula : would create circular reference".to_string()
+                            }
+                        };
+                        show_error(&mut error_ctx, &message, ErrorType::Error, Some(5.0));
+                    }
+                    Error::Timeout => {
+                        sheetversion.set(sheetversion.cloned() + 1);
                         show_error(
                             &mut error_ctx,
-                            "Cannot apply formula : would create circular reference",
-                            ErrorType::Error,
+                            "Formula took too long to evaluate and timed out",
+                            ErrorType::Warning,
                             Some(3.0),
                         );
                     }
+                    Error::Cancelled => {}
+                    Error::ValidationFailed => {
+                        let message = match &res.violation {
+                            Some(validation) => format!("Input not allowed here: {}", validation),
+                            None => "Input not allowed here".to_string(),
+                        };
+                        show_error(&mut error_ctx, &message, ErrorType::Error, Some(4.0));
+                    }
+                }
+                // Input breaking a validation that only warns is kept
+                if res.error != Error::ValidationFailed {
+                    if let Some(validation) = &res.violation {
+                        show_error(
+                            &mut error_ctx,
+                            &format!("Input does not match the validation: {}", validation),
+                            ErrorType::Warning,
+                            Some(4.0),
+                        );
+                    }
+                }
+                if !res.diverged.is_empty() {
+                    let names: Vec<String> =
+                        res.diverged.iter().map(|addr| addr.to_string()).collect();
+                    show_error(
+                        &mut error_ctx,
+                        &format!("Circular reference did not converge: {}", names.join(", ")),
+                        ErrorType::Warning,
+                        Some(5.0),
+                    );
                 }
                 // Update the displayed value
                 println!("Updated cell ({}, {}) to: {}", row, col, formula_text);
+            });
+        }
+    };
+
+    // Handler for when the cell loses focus
+    let on_blur = move |_| {
+        is_editing.set(false);
+        // Get the formula from context in case it was updated elsewhere
+        commit(formula.read().clone());
+    };
+
+    // Handlers for dragging the fill handle of the selected cell
+    let on_fill_start = {
+        let row = props.row;
+        let col = props.col;
+        move |e: Event<MouseData>| {
+            // Let the cell lose focus first, so an edit in progress is applied
+            e.stop_propagation();
+            fill_drag.set(Some((row, col)));
+        }
+    };
+
+    // Entering the cell also shows its note and comments
+    let on_enter = {
+        let row = props.row;
+        let col = props.col;
+        move |_| {
+            hovered.set(true);
+            if fill_drag.cloned().is_some() {
+                fill_drag.set(Some((row, col)));
+            }
+        }
+    };
+
+    let on_fill_end = {
+        let row = props.row;
+        let col = props.col;
+        move |_| {
+            if fill_drag.cloned().is_none() {
+                return;
+            }
+            fill_drag.set(None);
+            let Some((range, direction)) = fill_target(selected_cell.cloned(), row, col) else {
+                return;
+            };
+            if let Some(filled) = edit_sheet(sheet, book, active_sheet, |sheet_locked| {
+                sheet_locked.fill(range, direction)
+            }) {
+                match filled {
+                    Ok(()) => {}
+                    Err(Error::CycleDetected) => {
+                        show_error(
+                            &mut error_ctx,
+                            "Cannot fill: would create circular reference",
+                            ErrorType::Error,
+                            Some(3.0),
+                        );
+                    }
+                    Err(_) => {
+                        show_error(
+                            &mut error_ctx,
+                            "Error filling range",
+                            ErrorType::Error,
+                            Some(3.0),
+                        );
+                    }
+                }
+                sheetversion.set(sheetversion.cloned() + 1);
             }
         }
     };
@@ -126,9 +394,12 @@ pub fn Cell(props: CellProps) -> Element {
         formula.set(new_value);
     };
 
+    // Drawn again only when the cell is reported changed, see `changes`
+    let version = use_cell_version(props.row, props.col);
+
     use_effect(move || {
-        let _ = sheetversion.cloned();
-        if let Ok(sheet_locked) = sheet.cloned().lock() {
+        let _ = version.cloned();
+        if let Ok(sheet_locked) = sheet.cloned().try_lock() {
             // Update the cell value in the Sheet object
             formula.set(
                 sheet_locked
@@ -139,12 +410,54 @@ pub fn Cell(props: CellProps) -> Element {
     });
 
     // use_effect(move ||{
-    let _ = sheetversion.cloned();
+    let _ = version.cloned();
 
-    if let Ok(sheet_locked) = sheet.cloned().lock() {
+    if let Ok(sheet_locked) = sheet.cloned().try_lock() {
         // Update the cell value in the Sheet object
-        value.set(sheet_locked.get_value(props.row, props.col).to_string());
+        value.set(match sheet_locked.get_error(props.row, props.col) {
+            Some(Error::DivByZero) => "#DIV/0!".to_string(),
+            Some(Error::Overflow) => "#NUM!".to_string(),
+            Some(Error::Timeout) => "#TIMEOUT".to_string(),
+            Some(Error::Ref) => "#REF!".to_string(),
+            _ => sheet_locked.formatted_value(props.row, props.col),
+        });
+        style.set(sheet_locked.style(props.row as usize, props.col as usize));
+    }
+    // Values to choose from when the input of the selected cell is checked against a list
+    let allowed_values = match (is_this_cell_selected, sheet.cloned().try_lock()) {
+        (true, Ok(sheet_locked)) => {
+            sheet_locked.allowed_values(props.row as usize, props.col as usize)
+        }
+        _ => None,
+    };
+    // Note and comments shown in a popup while the pointer is over the cell
+    let annotations: Option<Annotations> = match sheet.cloned().try_lock() {
+        Ok(sheet_locked) => sheet_locked
+            .annotations(CellAddr::new(props.row as u32, props.col as u32))
+            .cloned(),
+        Err(_) => None,
+    };
+    // Conditional formatting goes on top of the cell's own formatting
+    let addr = CellAddr::new(props.row as u32, props.col as u32);
+    let highlight = highlights
+        .read()
+        .binary_search_by_key(&addr, |(other, _)| *other)
+        .ok()
+        .map(|at| highlights.read()[at].1.clone());
+    if let Some(highlight) = &highlight {
+        let highlighted = highlight.apply(style.cloned());
+        style.set(highlighted);
     }
+    let bar_css = match highlight.and_then(|highlight| highlight.bar) {
+        Some((length, color)) => {
+            let fill = style
+                .read()
+                .fill_color
+                .map_or(String::from("white"), |fill| fill.to_string());
+            format!(" background: linear-gradient(to right, {color} {length}%, {fill} {length}%);")
+        }
+        None => String::new(),
+    };
     // });
 
     if props.is_header {
@@ -159,26 +472,86 @@ pub fn Cell(props: CellProps) -> Element {
         }
     } else {
         rsx! {
-            input {
-                id: "row-{props.row}-col-{props.col}",
-                onfocus: on_focus,
-                onblur: on_blur,
-                oninput: on_input,
-                oncontextmenu: props.oncontextmenu,
-                // Show the formula when editing, otherwise show the result
-                value: if *is_editing.read() {
-                    formula.cloned()
-                } else {
-                    value.cloned()
-                },
-                style: if props.is_header {
-                    CELL_HEADER_STYLE
-                } else if is_editing.cloned() || is_this_cell_selected {
-                    CELL_SELECTED_STYLE
-                } else {
-                    CELL_STYLE
-                },
-                class: "cell"
+            div {
+                style: "position: relative;",
+                onmouseenter: on_enter,
+                onmouseleave: move |_| hovered.set(false),
+                onmouseup: on_fill_end,
+                input {
+                    id: "row-{props.row}-col-{props.col}",
+                    onfocus: on_focus,
+                    onblur: on_blur,
+                    oninput: on_input,
+                    oncontextmenu: props.oncontextmenu,
+                    // Show the formula when editing, otherwise show the result
+                    value: if *is_editing.read() {
+                        formula.cloned()
+                    } else {
+                        value.cloned()
+                    },
+                    // The selection and tracing show over the formatting
+                    style: if props.is_header {
+                        CELL_HEADER_STYLE.to_string()
+                    } else if is_editing.cloned() || is_this_cell_selected {
+                        format!("{CELL_SELECTED_STYLE}{}", look_css(&style.read()))
+                    } else if is_in_fill_preview {
+                        format!("{CELL_FILL_PREVIEW_STYLE}{}", look_css(&style.read()))
+                    } else if let Some(color) = trace_color {
+                        format!(
+                            "{CELL_STYLE}{}{} background-color: {color};",
+                            look_css(&style.read()),
+                            border_css(&style.read())
+                        )
+                    } else {
+                        format!(
+                            "{CELL_STYLE}{}{}{bar_css}",
+                            look_css(&style.read()),
+                            border_css(&style.read())
+                        )
+                    },
+                    class: "cell"
+                }
+                // Drag the handle down or right to fill from the selected cell
+                if is_this_cell_selected {
+                    div {
+                        style: FILL_HANDLE_STYLE,
+                        onmousedown: on_fill_start,
+                    }
+                }
+                if let Some(annotations) = annotations {
+                    div { style: NOTE_MARK_STYLE }
+                    if hovered.cloned() {
+                        div {
+                            style: NOTE_POPUP_STYLE,
+                            if let Some(note) = annotations.note {
+                                div { strong { "{note.author}" } " {note.written_at()}" }
+                                div { "{note.text}" }
+                            }
+                            for (index, comment) in annotations.thread.into_iter().enumerate() {
+                                div {
+                                    key: "{index}",
+                                    style: "margin-top: 4px;",
+                                    div { strong { "{comment.author}" } " {comment.written_at()}" }
+                                    div { "{comment.text}" }
+                                }
+                            }
+                        }
+                    }
+                }
+                if let Some(values) = allowed_values {
+                    select {
+                        style: LIST_STYLE,
+                        title: "Choose an allowed value",
+                        onchange: move |e: Event<FormData>| {
+                            formula.set(e.value());
+                            commit(e.value());
+                        },
+                        option { value: "", selected: true, "▾" }
+                        for value in values {
+                            option { value: "{value}", "{value}" }
+                        }
+                    }
+                }
             }
         }
     }
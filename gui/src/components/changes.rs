@@ -0,0 +1,86 @@
+//! Redrawing the cells that changed.
+//!
+//! Every sheet of the workbook has a listener reporting the cells an edit
+//! changed, see `Sheet::subscribe`. Each cell on screen has its own version,
+//! so when the sheet version moves only the reported cells are drawn again.
+//! Changes the sheets do not report, such as formatting, notes or showing
+//! another sheet, redraw every cell with `redraw_all`.
+
+use super::spreadsheet::*;
+use cores::{CellAddr, Sheet};
+use dioxus::prelude::*;
+use std::cell::Cell;
+use std::collections::HashMap;
+use std::rc::Rc;
+
+/// Subscribes the listener of the window to a sheet entering the workbook.
+pub fn watch(changed: ChangedCellsContext, sheet: &mut Sheet) {
+    let reported = changed.cloned();
+    sheet.subscribe(move |cells| {
+        if let Ok(mut reported) = reported.lock() {
+            reported.extend_from_slice(cells);
+        }
+    });
+}
+
+/// Redraws the cells reported changed since the last call.
+pub fn redraw_changed(changed: ChangedCellsContext, versions: CellVersionsContext) {
+    let reported = match changed.cloned().lock() {
+        Ok(mut reported) => std::mem::take(&mut *reported),
+        Err(_) => return,
+    };
+    let versions = versions.read();
+    for addr in reported {
+        for mut version in versions.get(&addr).into_iter().flatten().copied() {
+            version += 1;
+        }
+    }
+}
+
+/// Redraws every cell on screen.
+pub fn redraw_all(changed: ChangedCellsContext, versions: CellVersionsContext) {
+    if let Ok(mut reported) = changed.cloned().lock() {
+        reported.clear();
+    }
+    for mut version in versions.read().values().flatten().copied() {
+        version += 1;
+    }
+}
+
+/// Gives a cell its own version, which moves whenever the cell it shows is
+/// redrawn.
+pub fn use_cell_version(row: i32, col: i32) -> Signal<u32> {
+    let mut versions = use_context::<CellVersionsContext>();
+    let version = use_signal(|| 0);
+    // Cell the version is registered under, shared with the cleanup below
+    let shown = use_hook(|| Rc::new(Cell::new(None::<CellAddr>)));
+
+    let addr = CellAddr::new(row as u32, col as u32);
+    if shown.get() != Some(addr) {
+        let mut registered = versions.write();
+        if let Some(old) = shown.replace(Some(addr)) {
+            unregister(&mut registered, old, version);
+        }
+        registered.entry(addr).or_default().push(version);
+    }
+
+    use_drop(move || {
+        if let (Some(old), Ok(mut registered)) = (shown.get(), versions.try_write()) {
+            unregister(&mut registered, old, version);
+        }
+    });
+    version
+}
+
+fn unregister(
+    registered: &mut HashMap<CellAddr, Vec<Signal<u32>>>,
+    addr: CellAddr,
+    version: Signal<u32>,
+) {
+    if let Some(list) = registered.get_mut(&addr) {
+        list.retain(|other| *other != version);
+        if list.is_empty() {
+            registered.remove(&addr);
+        }
+    }
+}
@@ -4,9 +4,14 @@
 //! cells, rows, or columns. It supports operations like copy, paste, and
 //! other context-specific actions.
 
+use super::changes::redraw_all;
 use super::error_display::{show_error, ErrorContext, ErrorType};
+use super::sheet_tabs::edit_sheet;
 use super::spreadsheet::*;
+use cores::Criterion;
 use cores::Error;
+use cores::SortKey;
+use cores::{CellAddr, RangeAddr};
 use dioxus::prelude::*;
 
 /// Defines the type of context menu to display
@@ -23,7 +28,8 @@ pub enum MenuType {
 /// The context menu component that provides copy/paste functionality for cells, rows, and columns.
 ///
 /// This component displays different menu items based on the menu type (row, column, or cell).
-/// It supports operations like copying and pasting cells, rows, or columns.
+/// It supports operations like copying and pasting cells, rows, or columns, and inserting or
+/// deleting rows and columns.
 ///
 /// The menu appears at the coordinates specified by the context menu signal and disappears
 /// when an action is performed or the user clicks elsewhere.
@@ -32,13 +38,20 @@ pub enum MenuType {
 pub fn ContextMenu() -> Element {
     let mut context_menu = use_context::<ContextMenuContext>();
     let sheet = use_context::<SheetContext>();
+    let book = use_context::<WorkbookContext>();
+    let active_sheet = use_context::<ActiveSheetContext>();
     let mut sheet_version = use_context::<SheetVersionContext>();
+    let changed_cells = use_context::<ChangedCellsContext>();
+    let cell_versions = use_context::<CellVersionsContext>();
     let mut error_ctx = use_context::<ErrorContext>();
 
     // Get clipboard contexts
     let mut copied_cell = use_context::<CopiedCellContext>();
     let mut copied_row = use_context::<CopiedRowContext>();
     let mut copied_col = use_context::<CopiedColContext>();
+    let mut trace = use_context::<TraceContext>();
+    let mut notes_panel = use_context::<NotesPanelContext>();
+    let is_tracing = trace.read().is_some();
 
     if let Some((x_cord, y_cord, row, col, menu_type)) = context_menu.cloned() {
         match menu_type {
@@ -65,9 +78,11 @@ pub fn ContextMenu() -> Element {
                             },
                             onclick: move |_| {
                                 if let Some(source_row) = *copied_row.read() {
-                                    if let Ok(mut sheet_locked) = sheet.cloned().lock() {
-                                        // Use the Sheet's copy_row method with error handling
-                                        match sheet_locked.copy_row(source_row as usize, row as usize) {
+                                    // Other sheets may read the pasted cells
+                                    if let Some(pasted) = edit_sheet(sheet, book, active_sheet, |sheet_locked| {
+                                        sheet_locked.copy_row(source_row as usize, row as usize)
+                                    }) {
+                                        match pasted {
                                             Ok(_) => {
                                                 sheet_version.set(sheet_version.cloned() + 1);
                                             },
@@ -91,15 +106,74 @@ pub fn ContextMenu() -> Element {
                         div {
                             style: {"padding: 8px 16px; cursor: pointer; &:hover { background-color: #f0f0f0; }"},
                             onclick: move |_| {
-                                if let Ok(mut sheet_locked) = sheet.cloned().lock() {
-                                    sheet_locked.clear_row(row as usize);
+                                if edit_sheet(sheet, book, active_sheet, |sheet_locked| sheet_locked.clear_row(row as usize)).is_some() {
                                     sheet_version.set(sheet_version.cloned() + 1);
-                                    }
+                                }
                                 context_menu.set(None);
 
                             },
                             "Clear Row"
                         }
+                        div {
+                            style: {"padding: 8px 16px; cursor: pointer; &:hover { background-color: #f0f0f0; }"},
+                            onclick: move |_| {
+                                if let Some(changed) = edit_sheet(sheet, book, active_sheet, |sheet_locked| sheet_locked.insert_rows(row as usize, 1)) {
+                                    if changed.is_err() {
+                                        show_error(&mut error_ctx, "Cannot insert row: cells would be pushed off the sheet", ErrorType::Error, Some(3.0));
+                                    }
+                                    // Formatting and notes move along, which is not reported
+                                    redraw_all(changed_cells, cell_versions);
+                                    sheet_version.set(sheet_version.cloned() + 1);
+                                }
+                                context_menu.set(None);
+                            },
+                            "Insert Row Above"
+                        }
+                        div {
+                            style: {"padding: 8px 16px; cursor: pointer; &:hover { background-color: #f0f0f0; }"},
+                            onclick: move |_| {
+                                if let Some(changed) = edit_sheet(sheet, book, active_sheet, |sheet_locked| sheet_locked.delete_rows(row as usize, 1)) {
+                                    if changed.is_err() {
+                                        show_error(&mut error_ctx, "Error deleting row", ErrorType::Error, Some(3.0));
+                                    }
+                                    // Formatting and notes move along, which is not reported
+                                    redraw_all(changed_cells, cell_versions);
+                                    sheet_version.set(sheet_version.cloned() + 1);
+                                }
+                                context_menu.set(None);
+                            },
+                            "Delete Row"
+                        }
+                        div {
+                            style: {"padding: 8px 16px; cursor: pointer; &:hover { background-color: #f0f0f0; }"},
+                            onclick: move |_| {
+                                if let Ok(mut sheet_locked) = sheet.cloned().try_lock() {
+                                    // The header spans every column holding data
+                                    let last_col = sheet_locked.used_range().map_or(1, |range| range.end.col);
+                                    let header = RangeAddr {
+                                        start: CellAddr::new(row as u32, 1),
+                                        end: CellAddr::new(row as u32, last_col),
+                                    };
+                                    if sheet_locked.set_auto_filter(header).is_err() {
+                                        show_error(&mut error_ctx, "Cannot filter below this row", ErrorType::Error, Some(3.0));
+                                    }
+                                    sheet_version.set(sheet_version.cloned() + 1);
+                                }
+                                context_menu.set(None);
+                            },
+                            "Use Row as Filter Header"
+                        }
+                        div {
+                            style: {"padding: 8px 16px; cursor: pointer; &:hover { background-color: #f0f0f0; }"},
+                            onclick: move |_| {
+                                if let Ok(mut sheet_locked) = sheet.cloned().try_lock() {
+                                    sheet_locked.remove_auto_filter();
+                                    sheet_version.set(sheet_version.cloned() + 1);
+                                }
+                                context_menu.set(None);
+                            },
+                            "Remove Filter"
+                        }
                     }
                 }
             }
@@ -127,9 +201,11 @@ pub fn ContextMenu() -> Element {
                             },
                             onclick: move |_| {
                                 if let Some(source_col) = *copied_col.read() {
-                                    if let Ok(mut sheet_locked) = sheet.cloned().lock() {
-                                        // Use the Sheet's copy_col method with error handling
-                                        match sheet_locked.copy_col(source_col as usize, col as usize) {
+                                    // Other sheets may read the pasted cells
+                                    if let Some(pasted) = edit_sheet(sheet, book, active_sheet, |sheet_locked| {
+                                        sheet_locked.copy_col(source_col as usize, col as usize)
+                                    }) {
+                                        match pasted {
                                             Ok(_) => {
                                                 // Update sheet version to trigger rerender
                                                 sheet_version.set(sheet_version.cloned() + 1);
@@ -153,14 +229,88 @@ pub fn ContextMenu() -> Element {
                         div {
                             style: {"padding: 8px 16px; cursor: pointer; &:hover { background-color: #f0f0f0; }"},
                             onclick: move |_| {
-                                if let Ok(mut sheet_locked) = sheet.cloned().lock() {
-                                    sheet_locked.clear_col(col as usize);
+                                if edit_sheet(sheet, book, active_sheet, |sheet_locked| sheet_locked.clear_col(col as usize)).is_some() {
                                     sheet_version.set(sheet_version.cloned() + 1);
-                                    }
+                                }
                                 context_menu.set(None);
                             },
                             "Clear Column"
                         }
+                        div {
+                            style: {"padding: 8px 16px; cursor: pointer; &:hover { background-color: #f0f0f0; }"},
+                            onclick: move |_| {
+                                if let Some(changed) = edit_sheet(sheet, book, active_sheet, |sheet_locked| sheet_locked.insert_cols(col as usize, 1)) {
+                                    if changed.is_err() {
+                                        show_error(&mut error_ctx, "Cannot insert column: cells would be pushed off the sheet", ErrorType::Error, Some(3.0));
+                                    }
+                                    // Formatting and notes move along, which is not reported
+                                    redraw_all(changed_cells, cell_versions);
+                                    sheet_version.set(sheet_version.cloned() + 1);
+                                }
+                                context_menu.set(None);
+                            },
+                            "Insert Column Left"
+                        }
+                        div {
+                            style: {"padding: 8px 16px; cursor: pointer; &:hover { background-color: #f0f0f0; }"},
+                            onclick: move |_| {
+                                if let Some(changed) = edit_sheet(sheet, book, active_sheet, |sheet_locked| sheet_locked.delete_cols(col as usize, 1)) {
+                                    if changed.is_err() {
+                                        show_error(&mut error_ctx, "Error deleting column", ErrorType::Error, Some(3.0));
+                                    }
+                                    // Formatting and notes move along, which is not reported
+                                    redraw_all(changed_cells, cell_versions);
+                                    sheet_version.set(sheet_version.cloned() + 1);
+                                }
+                                context_menu.set(None);
+                            },
+                            "Delete Column"
+                        }
+                        div {
+                            style: {"padding: 8px 16px; cursor: pointer; &:hover { background-color: #f0f0f0; }"},
+                            onclick: move |_| {
+                                if let Some(sorted) = edit_sheet(sheet, book, active_sheet, |sheet_locked| {
+                                    let range = sheet_locked.used_range()?;
+                                    Some(sheet_locked.sort_range(range, &[SortKey::ascending(col as u32)], false))
+                                }) {
+                                    if matches!(sorted, Some(Err(_))) {
+                                        show_error(&mut error_ctx, "Cannot sort by a column outside the data", ErrorType::Error, Some(3.0));
+                                    }
+                                    sheet_version.set(sheet_version.cloned() + 1);
+                                }
+                                context_menu.set(None);
+                            },
+                            "Sort Ascending"
+                        }
+                        div {
+                            style: {"padding: 8px 16px; cursor: pointer; &:hover { background-color: #f0f0f0; }"},
+                            onclick: move |_| {
+                                if let Some(sorted) = edit_sheet(sheet, book, active_sheet, |sheet_locked| {
+                                    let range = sheet_locked.used_range()?;
+                                    Some(sheet_locked.sort_range(range, &[SortKey::descending(col as u32)], false))
+                                }) {
+                                    if matches!(sorted, Some(Err(_))) {
+                                        show_error(&mut error_ctx, "Cannot sort by a column outside the data", ErrorType::Error, Some(3.0));
+                                    }
+                                    sheet_version.set(sheet_version.cloned() + 1);
+                                }
+                                context_menu.set(None);
+                            },
+                            "Sort Descending"
+                        }
+                        div {
+                            style: {"padding: 8px 16px; cursor: pointer; &:hover { background-color: #f0f0f0; }"},
+                            onclick: move |_| {
+                                if let Ok(mut sheet_locked) = sheet.cloned().try_lock() {
+                                    if sheet_locked.set_filter_criterion(col as u32, None).is_err() {
+                                        show_error(&mut error_ctx, "This column is not part of a filter", ErrorType::Error, Some(3.0));
+                                    }
+                                    sheet_version.set(sheet_version.cloned() + 1);
+                                }
+                                context_menu.set(None);
+                            },
+                            "Show All Values"
+                        }
                     }
                 }
             }
@@ -188,14 +338,16 @@ pub fn ContextMenu() -> Element {
                             },
                             onclick: move |_| {
                                 if let Some((source_row, source_col)) = *copied_cell.read() {
-                                    if let Ok(mut sheet_locked) = sheet.cloned().lock() {
-                                        // Use the Sheet's copy_cell method with error handling
-                                        match sheet_locked.copy_cell(
+                                    // Other sheets may read the pasted cell
+                                    if let Some(pasted) = edit_sheet(sheet, book, active_sheet, |sheet_locked| {
+                                        sheet_locked.copy_cell(
                                             source_row as usize,
                                             source_col as usize,
                                             row as usize,
                                             col as usize
-                                        ) {
+                                        )
+                                    }) {
+                                        match pasted {
                                             Ok(_) => {
                                                 sheet_version.set(sheet_version.cloned() + 1);
                                             },
@@ -219,15 +371,54 @@ pub fn ContextMenu() -> Element {
                         div {
                             style: {"padding: 8px 16px; cursor: pointer; &:hover { background-color: #f0f0f0; }"},
                             onclick: move |_| {
-                                if let Ok(mut sheet_locked) = sheet.cloned().lock() {
-                                    sheet_locked.clear_cell(row as usize, col as usize);
+                                if edit_sheet(sheet, book, active_sheet, |sheet_locked| sheet_locked.clear_cell(row as usize, col as usize)).is_some() {
                                     sheet_version.set(sheet_version.cloned() + 1);
-                                    }
+                                }
                                 context_menu.set(None);
 
                             },
                             "Clear Cell"
                         }
+                        div {
+                            style: {"padding: 8px 16px; cursor: pointer; &:hover { background-color: #f0f0f0; }"},
+                            onclick: move |_| {
+                                if let Ok(mut sheet_locked) = sheet.cloned().try_lock() {
+                                    let value = sheet_locked.get_value(row, col);
+                                    if sheet_locked.set_filter_criterion(col as u32, Some(Criterion::Equals(value))).is_err() {
+                                        show_error(&mut error_ctx, "Use a row above the data as filter header first", ErrorType::Error, Some(3.0));
+                                    }
+                                    sheet_version.set(sheet_version.cloned() + 1);
+                                }
+                                context_menu.set(None);
+                            },
+                            "Filter by This Value"
+                        }
+                        div {
+                            style: {"padding: 8px 16px; cursor: pointer; &:hover { background-color: #f0f0f0; }"},
+                            onclick: move |_| {
+                                trace.set(Some((row, col)));
+                                context_menu.set(None);
+                            },
+                            "Trace Precedents and Dependents"
+                        }
+                        div {
+                            style: {"padding: 8px 16px; cursor: pointer; &:hover { background-color: #f0f0f0; }"},
+                            onclick: move |_| {
+                                notes_panel.set(Some((row, col)));
+                                context_menu.set(None);
+                            },
+                            "Notes and Comments"
+                        }
+                        if is_tracing {
+                            div {
+                                style: {"padding: 8px 16px; cursor: pointer; &:hover { background-color: #f0f0f0; }"},
+                                onclick: move |_| {
+                                    trace.set(None);
+                                    context_menu.set(None);
+                                },
+                                "Clear Trace"
+                            }
+                        }
                     }
                 }
             }
@@ -0,0 +1,297 @@
+//! Find and replace dialog.
+//!
+//! A floating panel that searches the sheet through `Sheet::find` and
+//! rewrites formulas through `Sheet::replace`. "Find Next" walks through the
+//! matches row by row starting after the selected cell, scrolling each one
+//! into view.
+
+use super::error_display::{show_error, ErrorContext, ErrorType};
+use super::sheet_tabs::edit_sheet;
+use super::spreadsheet::*;
+use cores::{CellAddr, FindOptions, LookIn, RangeAddr};
+use dioxus::prelude::*;
+
+const PANEL_STYLE: &str = "
+    position: fixed;
+    top: 110px;
+    right: 24px;
+    width: 320px;
+    background-color: white;
+    border: 1px solid #ccc;
+    border-radius: 8px;
+    box-shadow: 0 2px 8px rgba(0,0,0,0.2);
+    padding: 12px;
+    z-index: 950;
+    display: flex;
+    flex-direction: column;
+    gap: 8px;
+    font-size: 14px;
+";
+
+const INPUT_STYLE: &str = "
+    padding: 6px 8px;
+    border: 1px solid #ccc;
+    border-radius: 4px;
+    font-size: 14px;
+";
+
+const OPTIONS_STYLE: &str = "
+    display: grid;
+    grid-template-columns: 1fr 1fr;
+    gap: 4px;
+";
+
+const BUTTONS_STYLE: &str = "
+    display: flex;
+    gap: 6px;
+    justify-content: flex-end;
+";
+
+const BUTTON_STYLE: &str = "
+    border: 1px solid #ccc;
+    padding: 5px 10px;
+    cursor: pointer;
+    border-radius: 4px;
+    background-color: #f0f0f0;
+";
+
+#[component]
+pub fn FindReplaceDialog() -> Element {
+    let mut is_open = use_context::<FindReplaceContext>();
+    let sheet = use_context::<SheetContext>();
+    let book = use_context::<WorkbookContext>();
+    let active_sheet = use_context::<ActiveSheetContext>();
+    let mut sheet_version = use_context::<SheetVersionContext>();
+    let mut selected_cell = use_context::<SelectedCellContext>();
+    let mut start_row_ctx = use_context::<StartRowContext>();
+    let mut start_col_ctx = use_context::<StartColContext>();
+    let mut error_ctx = use_context::<ErrorContext>();
+
+    let mut pattern = use_signal(String::new);
+    let mut replacement = use_signal(String::new);
+    let mut scope = use_signal(String::new);
+    let mut match_case = use_signal(|| false);
+    let mut whole_cell = use_signal(|| false);
+    let mut regex = use_signal(|| false);
+    let mut in_formulas = use_signal(|| false);
+
+    if !is_open.cloned() {
+        return rsx! {};
+    }
+
+    // Options from the current state of the form; `None` if the scope is not a range
+    let options = move || -> Option<FindOptions> {
+        let scope = scope.cloned();
+        let scope = match scope.trim() {
+            "" => None,
+            text => Some(RangeAddr::parse(text)?),
+        };
+        Some(FindOptions {
+            look_in: if in_formulas.cloned() {
+                LookIn::Formulas
+            } else {
+                LookIn::Values
+            },
+            scope,
+            match_case: match_case.cloned(),
+            whole_cell: whole_cell.cloned(),
+            regex: regex.cloned(),
+        })
+    };
+
+    let mut find = move || -> Option<Vec<CellAddr>> {
+        let Some(options) = options() else {
+            show_error(
+                &mut error_ctx,
+                "Invalid range to search in",
+                ErrorType::Error,
+                Some(3.0),
+            );
+            return None;
+        };
+        let found = sheet
+            .cloned()
+            .try_lock()
+            .ok()?
+            .find(&pattern.cloned(), &options);
+        match found {
+            Ok(found) => Some(found),
+            Err(_) => {
+                show_error(
+                    &mut error_ctx,
+                    "Invalid search pattern",
+                    ErrorType::Error,
+                    Some(3.0),
+                );
+                None
+            }
+        }
+    };
+
+    let mut go_to = move |addr: CellAddr| {
+        let (row, col) = (addr.row as i32, addr.col as i32);
+        start_row_ctx.set((row - 1).max(1));
+        start_col_ctx.set((col - 1).max(1));
+        selected_cell.set((row, col));
+        let _ = document::eval(&format!(
+            "document.getElementById('row-{}-col-{}').focus()",
+            row, col
+        ));
+    };
+
+    let on_find_next = move |_| {
+        let Some(found) = find() else {
+            return;
+        };
+        let (row, col) = selected_cell.cloned();
+        let current = CellAddr::new(row.max(0) as u32, col.max(0) as u32);
+        // Wrap around to the first match after the last one
+        match found.iter().find(|&&addr| addr > current).or(found.first()) {
+            Some(&addr) => go_to(addr),
+            None => show_error(
+                &mut error_ctx,
+                "No matching cells",
+                ErrorType::Info,
+                Some(3.0),
+            ),
+        }
+    };
+
+    let on_find_all = move |_| {
+        let Some(found) = find() else {
+            return;
+        };
+        match found.first() {
+            Some(&first) => {
+                let names: Vec<String> =
+                    found.iter().take(10).map(|addr| addr.to_string()).collect();
+                let more = if found.len() > 10 { " ..." } else { "" };
+                show_error(
+                    &mut error_ctx,
+                    &format!("{} found: {}{}", found.len(), names.join(", "), more),
+                    ErrorType::Info,
+                    Some(5.0),
+                );
+                go_to(first);
+            }
+            None => show_error(
+                &mut error_ctx,
+                "No matching cells",
+                ErrorType::Info,
+                Some(3.0),
+            ),
+        }
+    };
+
+    let on_replace_all = move |_| {
+        let Some(options) = options() else {
+            show_error(
+                &mut error_ctx,
+                "Invalid range to search in",
+                ErrorType::Error,
+                Some(3.0),
+            );
+            return;
+        };
+        let replaced = edit_sheet(sheet, book, active_sheet, |sheet_locked| {
+            sheet_locked.replace(&pattern.cloned(), &replacement.cloned(), &options)
+        });
+        if let Some(replaced) = replaced {
+            match replaced {
+                Ok(result) => {
+                    sheet_version.set(sheet_version.cloned() + 1);
+                    let message = if result.failed.is_empty() {
+                        format!("Replaced {} cells", result.replaced.len())
+                    } else {
+                        format!(
+                            "Replaced {} cells; {} left unchanged because the new formula was rejected",
+                            result.replaced.len(),
+                            result.failed.len()
+                        )
+                    };
+                    show_error(&mut error_ctx, &message, ErrorType::Success, Some(4.0));
+                }
+                Err(_) => show_error(
+                    &mut error_ctx,
+                    "Invalid search pattern",
+                    ErrorType::Error,
+                    Some(3.0),
+                ),
+            }
+        }
+    };
+
+    rsx! {
+        div {
+            style: PANEL_STYLE,
+            onkeydown: move |e: Event<KeyboardData>| {
+                // Keep typing in the dialog away from the grid's navigation keys
+                e.stop_propagation();
+                if e.key() == Key::Escape {
+                    is_open.set(false);
+                }
+            },
+            strong { "Find and Replace" }
+            input {
+                style: INPUT_STYLE,
+                placeholder: "Find",
+                value: "{pattern}",
+                oninput: move |e| pattern.set(e.value()),
+            }
+            input {
+                style: INPUT_STYLE,
+                placeholder: "Replace with",
+                value: "{replacement}",
+                oninput: move |e| replacement.set(e.value()),
+            }
+            input {
+                style: INPUT_STYLE,
+                placeholder: "Within range, e.g. A1:C10 (whole sheet if empty)",
+                value: "{scope}",
+                oninput: move |e| scope.set(e.value()),
+            }
+            div {
+                style: OPTIONS_STYLE,
+                label {
+                    input {
+                        r#type: "checkbox",
+                        checked: match_case.cloned(),
+                        onchange: move |e| match_case.set(e.checked()),
+                    }
+                    " Match case"
+                }
+                label {
+                    input {
+                        r#type: "checkbox",
+                        checked: whole_cell.cloned(),
+                        onchange: move |e| whole_cell.set(e.checked()),
+                    }
+                    " Whole cell"
+                }
+                label {
+                    input {
+                        r#type: "checkbox",
+                        checked: regex.cloned(),
+                        onchange: move |e| regex.set(e.checked()),
+                    }
+                    " Regular expression"
+                }
+                label {
+                    input {
+                        r#type: "checkbox",
+                        checked: in_formulas.cloned(),
+                        onchange: move |e| in_formulas.set(e.checked()),
+                    }
+                    " Search formulas"
+                }
+            }
+            div {
+                style: BUTTONS_STYLE,
+                button { style: BUTTON_STYLE, onclick: on_find_next, "Find Next" }
+                button { style: BUTTON_STYLE, onclick: on_find_all, "Find All" }
+                button { style: BUTTON_STYLE, onclick: on_replace_all, "Replace All" }
+                button { style: BUTTON_STYLE, onclick: move |_| is_open.set(false), "Close" }
+            }
+        }
+    }
+}
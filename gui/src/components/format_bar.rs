@@ -0,0 +1,318 @@
+//! Formatting toolbar.
+//!
+//! A row of controls below the toolbar that format the selected cell, or the
+//! range typed into its "Apply to" box, through `Sheet::set_format`. The
+//! controls show the style of the selected cell. The last button opens the
+//! conditional formatting rules.
+
+use super::changes::redraw_all;
+use super::error_display::{show_error, ErrorContext, ErrorType};
+use super::spreadsheet::*;
+use cores::{Align, CellAddr, Color, DateFormat, Format, NumberFormat, RangeAddr};
+use dioxus::prelude::*;
+
+const FORMAT_BAR_STYLE: &str = "
+    height: 36px;
+    display: flex;
+    gap: 6px;
+    align-items: center;
+    padding: 0 25px;
+    background-color: #f7f7f7;
+    border-top: 1px solid #e0e0e0;
+    font-size: 14px;
+";
+
+const BUTTON_STYLE: &str = "
+    border: 1px solid #ccc;
+    padding: 3px 9px;
+    cursor: pointer;
+    border-radius: 4px;
+    background-color: #f0f0f0;
+";
+
+const ACTIVE_BUTTON_STYLE: &str = "
+    border: 1px solid #1a73e8;
+    padding: 3px 9px;
+    cursor: pointer;
+    border-radius: 4px;
+    background-color: #d2e3fc;
+";
+
+const INPUT_STYLE: &str = "
+    padding: 3px 6px;
+    border: 1px solid #ccc;
+    border-radius: 4px;
+    font-size: 14px;
+";
+
+const COLOR_STYLE: &str = "
+    width: 32px;
+    height: 26px;
+    padding: 0;
+    border: 1px solid #ccc;
+    cursor: pointer;
+";
+
+/// Borders offered by the border menu: label and attribute.
+const BORDERS: [(&str, &str); 7] = [
+    ("No borders", "border:none"),
+    ("All borders", "border:all"),
+    ("Top", "border:top"),
+    ("Bottom", "border:bottom"),
+    ("Top and bottom", "border:top+bottom"),
+    ("Left", "border:left"),
+    ("Right", "border:right"),
+];
+
+/// Number formats offered by the number format menu, by label.
+fn number_formats() -> Vec<(String, NumberFormat)> {
+    let mut formats = vec![
+        ("General".to_string(), NumberFormat::General),
+        (
+            "Number".to_string(),
+            NumberFormat::Number {
+                decimals: 2,
+                thousands: false,
+            },
+        ),
+        (
+            "Number 1,000".to_string(),
+            NumberFormat::Number {
+                decimals: 2,
+                thousands: true,
+            },
+        ),
+        ("Percent".to_string(), NumberFormat::Percent { decimals: 0 }),
+        (
+            "Currency".to_string(),
+            NumberFormat::Currency {
+                symbol: '$',
+                decimals: 2,
+            },
+        ),
+    ];
+    for date in DateFormat::ALL {
+        formats.push((format!("Date {}", date.pattern()), NumberFormat::Date(date)));
+    }
+    formats
+}
+
+/// Returns the number format with one decimal more or less, if it has decimals.
+fn change_decimals(number: NumberFormat, more: bool) -> Option<NumberFormat> {
+    let step = |decimals: u8| {
+        if more {
+            decimals.checked_add(1).filter(|&decimals| decimals <= 10)
+        } else {
+            decimals.checked_sub(1)
+        }
+    };
+    match number {
+        NumberFormat::General => more.then_some(NumberFormat::Number {
+            decimals: 1,
+            thousands: false,
+        }),
+        NumberFormat::Number {
+            decimals,
+            thousands,
+        } => Some(NumberFormat::Number {
+            decimals: step(decimals)?,
+            thousands,
+        }),
+        NumberFormat::Percent { decimals } => Some(NumberFormat::Percent {
+            decimals: step(decimals)?,
+        }),
+        NumberFormat::Currency { symbol, decimals } => Some(NumberFormat::Currency {
+            symbol,
+            decimals: step(decimals)?,
+        }),
+        NumberFormat::Date(_) => None,
+    }
+}
+
+#[component]
+pub fn FormatBar() -> Element {
+    let sheet = use_context::<SheetContext>();
+    let mut sheet_version = use_context::<SheetVersionContext>();
+    let changed_cells = use_context::<ChangedCellsContext>();
+    let cell_versions = use_context::<CellVersionsContext>();
+    let selected_cell = use_context::<SelectedCellContext>();
+    let mut error_ctx = use_context::<ErrorContext>();
+    let mut rules_dialog = use_context::<RulesDialogContext>();
+    let mut target = use_signal(String::new);
+
+    let _ = sheet_version.cloned();
+    let (row, col) = selected_cell.cloned();
+    let style = match sheet.cloned().try_lock() {
+        Ok(sheet_locked) => sheet_locked.style(row as usize, col as usize),
+        Err(_) => Default::default(),
+    };
+
+    // Sets attributes on the typed range, or on the selected cell if none is typed
+    let mut apply = move |formats: Vec<Format>| {
+        let target = target.cloned();
+        let range = match target.trim() {
+            "" => {
+                let (row, col) = selected_cell.cloned();
+                let cell = CellAddr::new(row as u32, col as u32);
+                RangeAddr::new(cell, cell)
+            }
+            text => RangeAddr::parse(text).or_else(|| {
+                let cell = CellAddr::parse(text)?;
+                RangeAddr::new(cell, cell)
+            }),
+        };
+        let result = match (range, sheet.cloned().try_lock()) {
+            (Some(range), Ok(mut sheet_locked)) => sheet_locked.set_format(range, &formats),
+            _ => Err(cores::Error::InvalidInput),
+        };
+        match result {
+            Ok(()) => {
+                // Formatting is not reported as a change of the cells
+                redraw_all(changed_cells, cell_versions);
+                sheet_version.set(sheet_version.cloned() + 1);
+            }
+            Err(_) => show_error(
+                &mut error_ctx,
+                "Invalid range to format",
+                ErrorType::Error,
+                Some(3.0),
+            ),
+        }
+    };
+
+    let mut number_options = number_formats();
+    if !number_options
+        .iter()
+        .any(|(_, number)| *number == style.number)
+    {
+        number_options.insert(0, (Format::Number(style.number).to_string(), style.number));
+    }
+    let text_color = style.text_color.unwrap_or(Color::new(0, 0, 0));
+    let fill_color = style.fill_color.unwrap_or(Color::new(255, 255, 255));
+    let current_borders = Format::Borders(style.borders).to_string();
+
+    rsx! {
+        div {
+            style: FORMAT_BAR_STYLE,
+            input {
+                style: "{INPUT_STYLE} width: 90px;",
+                placeholder: "Apply to",
+                title: "Cell or range to format, e.g. B2:D9; the selected cell if empty",
+                value: "{target}",
+                oninput: move |e| target.set(e.value()),
+            }
+            button {
+                style: if style.bold { ACTIVE_BUTTON_STYLE } else { BUTTON_STYLE },
+                title: "Bold",
+                onclick: move |_| apply(vec![Format::Bold(!style.bold)]),
+                b { "B" }
+            }
+            button {
+                style: if style.italic { ACTIVE_BUTTON_STYLE } else { BUTTON_STYLE },
+                title: "Italic",
+                onclick: move |_| apply(vec![Format::Italic(!style.italic)]),
+                i { "I" }
+            }
+            for (label, align) in [("Left", Align::Left), ("Center", Align::Center), ("Right", Align::Right)] {
+                button {
+                    style: if style.align == align { ACTIVE_BUTTON_STYLE } else { BUTTON_STYLE },
+                    title: "Align {label}",
+                    onclick: move |_| {
+                        // Clicking the active alignment goes back to the default
+                        let align = if style.align == align { Align::General } else { align };
+                        apply(vec![Format::Align(align)]);
+                    },
+                    "{label}"
+                }
+            }
+            select {
+                style: INPUT_STYLE,
+                title: "Number format",
+                onchange: move |e: Event<FormData>| {
+                    if let Ok(format) = e.value().parse::<Format>() {
+                        apply(vec![format]);
+                    }
+                },
+                for (label, number) in number_options {
+                    option {
+                        value: Format::Number(number).to_string(),
+                        selected: number == style.number,
+                        "{label}"
+                    }
+                }
+            }
+            button {
+                style: BUTTON_STYLE,
+                title: "More decimals",
+                onclick: move |_| {
+                    if let Some(number) = change_decimals(style.number, true) {
+                        apply(vec![Format::Number(number)]);
+                    }
+                },
+                ".0+"
+            }
+            button {
+                style: BUTTON_STYLE,
+                title: "Fewer decimals",
+                onclick: move |_| {
+                    if let Some(number) = change_decimals(style.number, false) {
+                        apply(vec![Format::Number(number)]);
+                    }
+                },
+                ".0-"
+            }
+            label { title: "Text color", "A" }
+            input {
+                r#type: "color",
+                style: COLOR_STYLE,
+                title: "Text color",
+                value: "{text_color}",
+                onchange: move |e: Event<FormData>| {
+                    if let Some(color) = Color::parse(&e.value()) {
+                        apply(vec![Format::TextColor(Some(color))]);
+                    }
+                },
+            }
+            label { title: "Fill color", "Fill" }
+            input {
+                r#type: "color",
+                style: COLOR_STYLE,
+                title: "Fill color",
+                value: "{fill_color}",
+                onchange: move |e: Event<FormData>| {
+                    if let Some(color) = Color::parse(&e.value()) {
+                        apply(vec![Format::FillColor(Some(color))]);
+                    }
+                },
+            }
+            select {
+                style: INPUT_STYLE,
+                title: "Borders",
+                onchange: move |e: Event<FormData>| {
+                    if let Ok(format) = e.value().parse::<Format>() {
+                        apply(vec![format]);
+                    }
+                },
+                for (label, borders) in BORDERS {
+                    option {
+                        value: borders,
+                        selected: borders == current_borders,
+                        "{label}"
+                    }
+                }
+            }
+            button {
+                style: BUTTON_STYLE,
+                title: "Remove all formatting",
+                onclick: move |_| apply(Format::DEFAULTS.to_vec()),
+                "Clear"
+            }
+            button {
+                style: BUTTON_STYLE,
+                title: "Conditional formatting rules",
+                onclick: move |_| rules_dialog.set(true),
+                "Rules"
+            }
+        }
+    }
+}
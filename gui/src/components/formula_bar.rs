@@ -3,6 +3,7 @@
 //! This module provides a formula bar component that allows viewing and editing
 //! formulas for the currently selected cell.
 
+use super::recalc::update_in_background;
 use super::spreadsheet::*;
 use dioxus::prelude::*;
 
@@ -43,7 +44,10 @@ const FORMULA_BAR_STYLE: &str = "
 pub fn FormulaBar() -> Element {
     // Context signals used by the formula bar
     let sheet = use_context::<SheetContext>();
+    let book = use_context::<WorkbookContext>();
+    let active_sheet = use_context::<ActiveSheetContext>();
     let mut sheetversion = use_context::<SheetVersionContext>();
+    let recalc = use_context::<RecalcContext>();
     let selected_cell = use_context::<SelectedCellContext>();
     let mut formula = use_signal(String::new);
 
@@ -51,7 +55,7 @@ pub fn FormulaBar() -> Element {
     use_effect(move || {
         let _ = sheetversion.cloned();
 
-        if let Ok(sheet_locked) = sheet.cloned().lock() {
+        if let Ok(sheet_locked) = sheet.cloned().try_lock() {
             // Update the cell value in the Sheet object
             let formula_in_sheet = sheet_locked
                 .get_formula(
@@ -67,33 +71,35 @@ pub fn FormulaBar() -> Element {
         }
     });
 
+    // Update the cell through the workbook, so other sheets reading it follow,
+    // recalculating off the UI thread
+    let submit = move || {
+        spawn(async move {
+            let updated = update_in_background(
+                sheet,
+                book,
+                active_sheet,
+                recalc,
+                selected_cell.cloned().0 as usize,
+                selected_cell.cloned().1 as usize,
+                formula.cloned(),
+            )
+            .await;
+            if updated.is_some() {
+                sheetversion.set(sheetversion.cloned() + 1);
+            }
+        });
+    };
+
     // Handle formula submission when Enter key is pressed
     let on_submit = move |e: Event<KeyboardData>| {
         if e.key() == Key::Enter {
-            if let Ok(mut sheet_locked) = sheet.cloned().lock() {
-                // Update the cell value in the Sheet object
-                sheet_locked.update_cell_data(
-                    selected_cell.cloned().0 as usize,
-                    selected_cell.cloned().1 as usize,
-                    formula.cloned(),
-                );
-                sheetversion.set(sheetversion.cloned() + 1);
-            }
+            submit();
         }
     };
 
     // Handle formula update when input loses focus
-    let on_blur = move |_| {
-        if let Ok(mut sheet_locked) = sheet.cloned().lock() {
-            // Update the cell value in the Sheet object
-            sheet_locked.update_cell_data(
-                selected_cell.cloned().0 as usize,
-                selected_cell.cloned().1 as usize,
-                formula.cloned(),
-            );
-            sheetversion.set(sheetversion.cloned() + 1);
-        }
-    };
+    let on_blur = move |_| submit();
 
     rsx! {
         div {
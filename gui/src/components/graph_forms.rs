@@ -57,7 +57,7 @@ pub fn LineChartForm() -> Element {
     let mut error_ctx = use_context::<ErrorContext>();
 
     if show_graph.cloned() {
-        if let Ok(sheet_locked) = sheet.cloned().lock() {
+        if let Ok(sheet_locked) = sheet.cloned().try_lock() {
             // Update the cell value in the Sheet object
             let x = sheet_locked.line_graph(
                 &range.cloned(),
@@ -163,7 +163,7 @@ pub fn BarChartForm() -> Element {
     let mut error_ctx = use_context::<ErrorContext>();
 
     if show_graph.cloned() {
-        if let Ok(sheet_locked) = sheet.cloned().lock() {
+        if let Ok(sheet_locked) = sheet.cloned().try_lock() {
             // Generate bar chart
             let x = sheet_locked.bar_graph(
                 &range.cloned(),
@@ -272,7 +272,7 @@ pub fn PieChartForm() -> Element {
     let mut error_ctx = use_context::<ErrorContext>();
 
     if show_graph.cloned() {
-        if let Ok(sheet_locked) = sheet.cloned().lock() {
+        if let Ok(sheet_locked) = sheet.cloned().try_lock() {
             // Generate pie chart
             let x =
                 sheet_locked.pie_graph(&range.cloned(), &slice_labels.cloned(), &title.cloned());
@@ -386,7 +386,7 @@ pub fn ScatterPlotForm() -> Element {
     let mut error_ctx = use_context::<ErrorContext>();
 
     if show_graph.cloned() {
-        if let Ok(sheet_locked) = sheet.cloned().lock() {
+        if let Ok(sheet_locked) = sheet.cloned().try_lock() {
             // Generate scatter plot
             let x = sheet_locked.scatter_graph(
                 &x_range.cloned(),
@@ -1,10 +1,11 @@
 use super::row::Row;
+use super::sheet_tabs::{edit_sheet, SheetTabs};
 use crate::components::spreadsheet::*;
 use dioxus::{desktop::use_window, prelude::*};
 
 const GRID_STYLE: &str = "
     overflow: hidden;
-    height: calc(100vh - 120px);
+    height: calc(100vh - 156px);
     width: 100%;
     display: block;
     position: relative;
@@ -64,6 +65,11 @@ pub fn Grid(props: GridProps) -> Element {
     let mut start_row_ctx = use_signal(|| 1);
     let mut start_col_ctx = use_signal(|| 1);
     let mut selected_cell = use_context::<SelectedCellContext>();
+    let sheet = use_context::<SheetContext>();
+    let book = use_context::<WorkbookContext>();
+    let active_sheet = use_context::<ActiveSheetContext>();
+    let mut sheetversion = use_context::<SheetVersionContext>();
+    let mut find_replace = use_context::<FindReplaceContext>();
 
     let window_width = use_window().inner_size().width;
     let window_height = use_window().inner_size().height;
@@ -94,8 +100,18 @@ pub fn Grid(props: GridProps) -> Element {
     let max_start_row = (props.num_rows - rows_per_page).max(1);
     let max_start_col = (props.num_cols - cols_per_page).max(1);
 
+    // Rows shown on this page, skipping the rows hidden by a filter
+    let _ = sheetversion.cloned();
+    let visible_rows: Vec<i32> = match sheet.cloned().try_lock() {
+        Ok(sheet_locked) => (start_row..=props.num_rows)
+            .filter(|&row| !sheet_locked.is_row_hidden(row as usize))
+            .take(rows_per_page as usize)
+            .collect(),
+        Err(_) => (start_row..=(start_row + rows_per_page - 1).min(props.num_rows)).collect(),
+    };
+
     // Calculate end positions based on the variables we already have
-    let end_row = (start_row + rows_per_page - 1).min(props.num_rows);
+    let end_row = visible_rows.last().copied().unwrap_or(start_row);
     let end_col = (start_col + cols_per_page - 1).min(props.num_cols);
 
     // Function to check if a cell is visible in the current view
@@ -179,17 +195,55 @@ pub fn Grid(props: GridProps) -> Element {
         move_right_help();
     };
 
-    // Calculate end positions based on the variables we already have
-    let end_row = (start_row + rows_per_page - 1).min(props.num_rows);
-    let end_col = (start_col + cols_per_page - 1).min(props.num_cols);
-
     // Calculate visible range
 
+    // The next row in the given direction that is not hidden by a filter
+    let next_shown_row = move |row: i32, step: i32| -> i32 {
+        let mut next = row + step;
+        if let Ok(sheet_locked) = sheet.cloned().try_lock() {
+            while next > 1 && next < props.num_rows && sheet_locked.is_row_hidden(next as usize) {
+                next += step;
+            }
+        }
+        next
+    };
+
     let on_keydown = {
         let row = selected_cell.cloned().0;
         let col = selected_cell.cloned().1;
         move |e: Event<KeyboardData>| {
             let key = e.key();
+            // Ctrl+F opens find and replace
+            if let Key::Character(c) = &key {
+                if e.modifiers().ctrl() && c.to_lowercase() == "f" {
+                    e.prevent_default();
+                    find_replace.set(true);
+                    return;
+                }
+            }
+            // Ctrl+Z undoes, Ctrl+Y or Ctrl+Shift+Z redoes
+            if let Key::Character(c) = &key {
+                let redo = match c.to_lowercase().as_str() {
+                    "z" => Some(e.modifiers().shift()),
+                    "y" => Some(true),
+                    _ => None,
+                };
+                if let (true, Some(redo)) = (e.modifiers().ctrl(), redo) {
+                    e.prevent_default();
+                    // Undoing may change cells or lines other sheets read
+                    let changed = edit_sheet(sheet, book, active_sheet, |sheet_locked| {
+                        if redo {
+                            sheet_locked.redo()
+                        } else {
+                            sheet_locked.undo()
+                        }
+                    });
+                    if changed == Some(true) {
+                        sheetversion.set(sheetversion.cloned() + 1);
+                    }
+                    return;
+                }
+            }
             if key == Key::Enter
                 || key == Key::ArrowDown
                 || key == Key::ArrowUp
@@ -210,12 +264,12 @@ pub fn Grid(props: GridProps) -> Element {
                     if current_row == end_row && start_row_ctx.cloned() < max_start_row {
                         move_down_help();
                     };
-                    to_row = (row + 1).max(1).min(props.num_rows); // Prevent going past the last row
+                    to_row = next_shown_row(row, 1).min(props.num_rows); // Prevent going past the last row
                 } else if e.key() == Key::ArrowUp {
                     if current_row == start_row && start_row_ctx.cloned() > 1 {
                         move_up_help();
                     };
-                    to_row = (row - 1).max(1).min(props.num_rows); // Prevent going before the first row
+                    to_row = next_shown_row(row, -1).max(1); // Prevent going before the first row
                 }
                 // Handle horizontal movement - only change column, not row
                 else if (e.key() == Key::Tab && e.modifiers().shift())
@@ -266,7 +320,7 @@ pub fn Grid(props: GridProps) -> Element {
                 }
 
                 // Visible rows
-                for i in (start_row)..=(end_row) {
+                for i in visible_rows {
                     Row {
                         row: i,
                         num_cols: cols_per_page,
@@ -318,6 +372,7 @@ pub fn Grid(props: GridProps) -> Element {
                     "→"
                 }
             }
+            SheetTabs {}
         }
     }
 }
@@ -1,8 +1,10 @@
 //! Header component for the spreadsheet application.
 //!
 //! This module provides the header component that displays the application
-//! title, current filename, and contains the toolbar and formula bar.
+//! title, current filename, and contains the toolbar, formatting toolbar and
+//! formula bar.
 
+use super::format_bar::*;
 use super::formula_bar::*;
 use super::toolbar::*;
 use dioxus::prelude::*;
@@ -30,7 +32,8 @@ pub struct HeaderProps {
 
 /// The Header component for the spreadsheet application.
 ///
-/// Displays the application title, current filename, toolbar, and formula bar.
+/// Displays the application title, current filename, toolbar, formatting
+/// toolbar and formula bar.
 ///
 /// # Properties
 /// * `filename` - The name of the current file being edited
@@ -59,6 +62,7 @@ pub fn Header(props: HeaderProps) -> Element {
               num_cols: props.num_cols,
           }
         }
+        FormatBar {}
         FormulaBar {}
       }
     }
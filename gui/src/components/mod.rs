@@ -8,12 +8,19 @@ mod spreadsheet;
 pub use spreadsheet::Spreadsheet;
 
 mod cell;
+mod changes;
 mod context_menu;
 mod error_display;
+mod find_replace;
+mod format_bar;
 mod formula_bar;
 mod graph_forms;
 mod graph_popup;
 mod grid;
 mod header;
+mod notes_panel;
+mod recalc;
 mod row;
+mod rules_dialog;
+mod sheet_tabs;
 mod toolbar;
@@ -0,0 +1,231 @@
+//! Notes and comments panel.
+//!
+//! A floating panel opened from the cell context menu, showing the note and
+//! the comment thread of one cell. The note can be written or deleted, and
+//! comments are added to the end of the thread as replies. Everything is
+//! written under the name of the user running the application.
+
+use super::changes::redraw_all;
+use super::error_display::{show_error, ErrorContext, ErrorType};
+use super::spreadsheet::*;
+use cores::{Annotations, CellAddr, Comment};
+use dioxus::prelude::*;
+
+const PANEL_STYLE: &str = "
+    position: fixed;
+    top: 110px;
+    right: 24px;
+    width: 340px;
+    background-color: white;
+    border: 1px solid #ccc;
+    border-radius: 8px;
+    box-shadow: 0 2px 8px rgba(0,0,0,0.2);
+    padding: 12px;
+    z-index: 950;
+    display: flex;
+    flex-direction: column;
+    gap: 8px;
+    font-size: 14px;
+";
+
+const INPUT_STYLE: &str = "
+    padding: 6px 8px;
+    border: 1px solid #ccc;
+    border-radius: 4px;
+    font-size: 14px;
+";
+
+const THREAD_STYLE: &str = "
+    max-height: 220px;
+    overflow-y: auto;
+    border: 1px solid #eee;
+    border-radius: 4px;
+    padding: 4px;
+    display: flex;
+    flex-direction: column;
+    gap: 6px;
+";
+
+const ROW_STYLE: &str = "
+    display: flex;
+    gap: 6px;
+    align-items: flex-start;
+";
+
+const AUTHOR_STYLE: &str = "
+    font-size: 11px;
+    color: #666;
+";
+
+const BUTTONS_STYLE: &str = "
+    display: flex;
+    gap: 6px;
+    justify-content: flex-end;
+";
+
+const BUTTON_STYLE: &str = "
+    border: 1px solid #ccc;
+    padding: 5px 10px;
+    cursor: pointer;
+    border-radius: 4px;
+    background-color: #f0f0f0;
+";
+
+/// Returns the name notes and comments are written under.
+pub fn author() -> String {
+    std::env::var("USER")
+        .or_else(|_| std::env::var("USERNAME"))
+        .unwrap_or_else(|_| String::from("gui"))
+}
+
+#[component]
+pub fn NotesPanel() -> Element {
+    let mut open_cell = use_context::<NotesPanelContext>();
+    let sheet = use_context::<SheetContext>();
+    let mut sheet_version = use_context::<SheetVersionContext>();
+    let changed_cells = use_context::<ChangedCellsContext>();
+    let cell_versions = use_context::<CellVersionsContext>();
+    let mut error_ctx = use_context::<ErrorContext>();
+
+    let mut note = use_signal(String::new);
+    let mut reply = use_signal(String::new);
+
+    let Some((row, col)) = open_cell.cloned() else {
+        return rsx! {};
+    };
+    let addr = CellAddr::new(row as u32, col as u32);
+
+    let _ = sheet_version.cloned();
+    let annotations: Annotations = match sheet.cloned().try_lock() {
+        Ok(sheet_locked) => sheet_locked.annotations(addr).cloned().unwrap_or_default(),
+        Err(_) => Annotations::default(),
+    };
+
+    // Writes through the sheet and reports a cell outside it
+    let mut write = move |change: &dyn Fn(&mut cores::Sheet) -> Result<(), cores::Error>| {
+        let result = match sheet.cloned().try_lock() {
+            Ok(mut sheet_locked) => change(&mut sheet_locked),
+            Err(_) => Err(cores::Error::InvalidInput),
+        };
+        match result {
+            Ok(()) => {
+                // Notes are not reported as a change of the cells
+                redraw_all(changed_cells, cell_versions);
+                sheet_version.set(sheet_version.cloned() + 1);
+            }
+            Err(_) => show_error(
+                &mut error_ctx,
+                "Cannot write a note here",
+                ErrorType::Error,
+                Some(3.0),
+            ),
+        }
+    };
+
+    let on_save_note = move |_| {
+        let text = note.cloned();
+        if text.trim().is_empty() {
+            return;
+        }
+        write(&|sheet_locked| sheet_locked.set_note(addr, Comment::new(&author(), text.trim())));
+        note.set(String::new());
+    };
+
+    let on_reply = move |_| {
+        let text = reply.cloned();
+        if text.trim().is_empty() {
+            return;
+        }
+        write(&|sheet_locked| sheet_locked.add_comment(addr, Comment::new(&author(), text.trim())));
+        reply.set(String::new());
+    };
+
+    rsx! {
+        div {
+            style: PANEL_STYLE,
+            onkeydown: move |e: Event<KeyboardData>| {
+                // Keep typing in the panel away from the grid's navigation keys
+                e.stop_propagation();
+                if e.key() == Key::Escape {
+                    open_cell.set(None);
+                }
+            },
+            strong { "Notes and Comments on {addr}" }
+            if let Some(current) = annotations.note {
+                div {
+                    style: ROW_STYLE,
+                    div {
+                        style: "flex: 1;",
+                        div { style: AUTHOR_STYLE, "Note by {current.author}, {current.written_at()}" }
+                        div { "{current.text}" }
+                    }
+                    button {
+                        style: BUTTON_STYLE,
+                        title: "Delete note",
+                        onclick: move |_| {
+                            if let Ok(mut sheet_locked) = sheet.cloned().try_lock() {
+                                sheet_locked.remove_note(addr);
+                            }
+                            redraw_all(changed_cells, cell_versions);
+                            sheet_version.set(sheet_version.cloned() + 1);
+                        },
+                        "✕"
+                    }
+                }
+            }
+            div {
+                style: ROW_STYLE,
+                input {
+                    style: "{INPUT_STYLE} flex: 1;",
+                    placeholder: "Write the note",
+                    value: "{note}",
+                    oninput: move |e| note.set(e.value()),
+                }
+                button { style: BUTTON_STYLE, onclick: on_save_note, "Save Note" }
+            }
+            div {
+                style: THREAD_STYLE,
+                if annotations.thread.is_empty() {
+                    div { "No comments" }
+                }
+                for (index, comment) in annotations.thread.into_iter().enumerate() {
+                    div {
+                        key: "{index}",
+                        style: ROW_STYLE,
+                        div {
+                            style: "flex: 1;",
+                            div { style: AUTHOR_STYLE, "{comment.author}, {comment.written_at()}" }
+                            div { "{comment.text}" }
+                        }
+                        button {
+                            style: BUTTON_STYLE,
+                            title: "Delete comment",
+                            onclick: move |_| {
+                                if let Ok(mut sheet_locked) = sheet.cloned().try_lock() {
+                                    sheet_locked.remove_comment(addr, index);
+                                }
+                                redraw_all(changed_cells, cell_versions);
+                                sheet_version.set(sheet_version.cloned() + 1);
+                            },
+                            "✕"
+                        }
+                    }
+                }
+            }
+            div {
+                style: ROW_STYLE,
+                input {
+                    style: "{INPUT_STYLE} flex: 1;",
+                    placeholder: "Reply",
+                    value: "{reply}",
+                    oninput: move |e| reply.set(e.value()),
+                }
+                button { style: BUTTON_STYLE, onclick: on_reply, "Comment" }
+            }
+            div {
+                style: BUTTONS_STYLE,
+                button { style: BUTTON_STYLE, onclick: move |_| open_cell.set(None), "Close" }
+            }
+        }
+    }
+}
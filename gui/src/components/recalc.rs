@@ -0,0 +1,120 @@
+//! Recalculation on a worker thread.
+//!
+//! Editing a cell recalculates every cell depending on it, which can take a
+//! while, e.g. with `SLEEP`. The edit runs on a worker thread so the window
+//! keeps responding: an overlay shows it is running, with a button cancelling
+//! it, and the rest of the window keeps showing the cells as they were. The
+//! window never waits for the sheet or the workbook, see `with_workbook`.
+
+use super::sheet_tabs::swap_shown;
+use super::spreadsheet::*;
+use cores::{CallResult, EvalOptions};
+use dioxus::prelude::*;
+
+const OVERLAY_STYLE: &str = "
+    position: fixed;
+    top: 0;
+    left: 0;
+    right: 0;
+    bottom: 0;
+    z-index: 1100;
+    background-color: rgba(0, 0, 0, 0.15);
+    display: flex;
+    align-items: center;
+    justify-content: center;
+";
+
+const PANEL_STYLE: &str = "
+    background-color: white;
+    border: 1px solid #ccc;
+    border-radius: 8px;
+    box-shadow: 0 2px 8px rgba(0,0,0,0.2);
+    padding: 16px 24px;
+    display: flex;
+    gap: 16px;
+    align-items: center;
+    font-size: 14px;
+";
+
+const BUTTON_STYLE: &str = "
+    padding: 6px 12px;
+    border: 1px solid #ccc;
+    border-radius: 4px;
+    background-color: #f8f8f8;
+    cursor: pointer;
+";
+
+/// Updates a cell of the shown sheet through the workbook on a worker thread,
+/// with the deadline `EVAL_TIMEOUT`, and waits for it without blocking the window.
+///
+/// The cancel token of the update is kept in `RecalcContext` while it runs.
+///
+/// # Returns
+/// The result of the update and the path of the loop it would close, or
+/// `None` if another update is still running or the update could not run.
+pub async fn update_in_background(
+    sheet: SheetContext,
+    book: WorkbookContext,
+    mut active: ActiveSheetContext,
+    mut running: RecalcContext,
+    row: usize,
+    col: usize,
+    formula: String,
+) -> Option<(CallResult, Option<String>)> {
+    if running.peek().is_some() {
+        return None;
+    }
+    let options = EvalOptions::with_timeout(EVAL_TIMEOUT);
+    running.set(Some(options.cancel.clone()));
+    let shared_sheet = sheet.cloned();
+    let shared_book = book.cloned();
+    let shown = active.cloned();
+    let (sender, receiver) = futures_channel::oneshot::channel();
+    std::thread::spawn(move || {
+        let updated = match (shared_sheet.lock(), shared_book.lock()) {
+            (Ok(mut sheet_locked), Ok(mut book_locked)) => Some(swap_shown(
+                &mut sheet_locked,
+                &mut book_locked,
+                shown,
+                |book_locked, index| {
+                    let result =
+                        book_locked.update_cell_data_with(*index, row, col, formula, &options);
+                    let cycle_path = book_locked.cycle_path(*index, &result);
+                    (result, cycle_path)
+                },
+            )),
+            _ => None,
+        };
+        let _ = sender.send(updated);
+    });
+    let updated = receiver.await.ok().flatten();
+    running.set(None);
+    let (result, index) = updated?;
+    active.set(index);
+    Some(result)
+}
+
+/// Overlay shown while a cell edit recalculates, with a button cancelling it.
+///
+/// A cancelled edit leaves the cell and everything depending on it as they were.
+#[component]
+pub fn RecalcStatus() -> Element {
+    let running = use_context::<RecalcContext>();
+    let Some(cancel) = running.cloned() else {
+        return rsx! {};
+    };
+    rsx! {
+        div {
+            style: OVERLAY_STYLE,
+            div {
+                style: PANEL_STYLE,
+                "Recalculating..."
+                button {
+                    style: BUTTON_STYLE,
+                    onclick: move |_| cancel.cancel(),
+                    "Cancel"
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,283 @@
+//! Conditional formatting dialog.
+//!
+//! A floating panel listing the conditional formatting rules of the sheet,
+//! with a button to delete each, and a form adding a rule through
+//! `Sheet::add_rule`. The form writes the rule in its text form, such as
+//! `gt 100 then fill:#ffc7ce,color:#9c0006`, and lets `Rule` parse it.
+
+use super::error_display::{show_error, ErrorContext, ErrorType};
+use super::spreadsheet::*;
+use cores::{CellAddr, RangeAddr, Rule};
+use dioxus::prelude::*;
+
+const PANEL_STYLE: &str = "
+    position: fixed;
+    top: 110px;
+    right: 24px;
+    width: 360px;
+    background-color: white;
+    border: 1px solid #ccc;
+    border-radius: 8px;
+    box-shadow: 0 2px 8px rgba(0,0,0,0.2);
+    padding: 12px;
+    z-index: 950;
+    display: flex;
+    flex-direction: column;
+    gap: 8px;
+    font-size: 14px;
+";
+
+const INPUT_STYLE: &str = "
+    padding: 6px 8px;
+    border: 1px solid #ccc;
+    border-radius: 4px;
+    font-size: 14px;
+";
+
+const ROW_STYLE: &str = "
+    display: flex;
+    gap: 6px;
+    align-items: center;
+";
+
+const LIST_STYLE: &str = "
+    max-height: 160px;
+    overflow-y: auto;
+    border: 1px solid #eee;
+    border-radius: 4px;
+    padding: 4px;
+    font-family: monospace;
+    font-size: 12px;
+";
+
+const BUTTONS_STYLE: &str = "
+    display: flex;
+    gap: 6px;
+    justify-content: flex-end;
+";
+
+const BUTTON_STYLE: &str = "
+    border: 1px solid #ccc;
+    padding: 5px 10px;
+    cursor: pointer;
+    border-radius: 4px;
+    background-color: #f0f0f0;
+";
+
+const COLOR_STYLE: &str = "
+    width: 40px;
+    height: 28px;
+    padding: 0;
+    border: 1px solid #ccc;
+    cursor: pointer;
+";
+
+/// Kinds of rule offered by the form: label and the word starting the rule.
+const KINDS: [(&str, &str); 9] = [
+    ("Greater than", "gt"),
+    ("Less than", "lt"),
+    ("Equal to", "eq"),
+    ("Between", "between"),
+    ("Top N", "top"),
+    ("Bottom N", "bottom"),
+    ("Formula is not zero", "formula"),
+    ("Color scale", "scale"),
+    ("Data bar", "bar"),
+];
+
+/// Writes the rule described by the form in its text form.
+///
+/// Highlight rules fill the cells with `first` and color their text with
+/// `second`; a color scale goes from `first` to `second` and a data bar is
+/// drawn in `first`.
+fn rule_text(kind: &str, value: &str, high: &str, first: &str, second: &str, bold: bool) -> String {
+    let formats = format!(
+        "fill:{},color:{}{}",
+        first,
+        second,
+        if bold { ",bold" } else { "" }
+    );
+    match kind {
+        "scale" => format!("scale {} {}", first, second),
+        "bar" => format!("bar {}", first),
+        "between" => format!("between {} {} then {}", value.trim(), high.trim(), formats),
+        kind => format!("{} {} then {}", kind, value.trim(), formats),
+    }
+}
+
+#[component]
+pub fn RulesDialog() -> Element {
+    let mut is_open = use_context::<RulesDialogContext>();
+    let sheet = use_context::<SheetContext>();
+    let mut sheet_version = use_context::<SheetVersionContext>();
+    let selected_cell = use_context::<SelectedCellContext>();
+    let mut error_ctx = use_context::<ErrorContext>();
+
+    let mut target = use_signal(String::new);
+    let mut kind = use_signal(|| String::from("gt"));
+    let mut value = use_signal(String::new);
+    let mut high = use_signal(String::new);
+    let mut first_color = use_signal(|| String::from("#ffc7ce"));
+    let mut second_color = use_signal(|| String::from("#9c0006"));
+    let mut bold = use_signal(|| false);
+
+    if !is_open.cloned() {
+        return rsx! {};
+    }
+
+    let _ = sheet_version.cloned();
+    let rules: Vec<(RangeAddr, Rule)> = match sheet.cloned().try_lock() {
+        Ok(sheet_locked) => sheet_locked.rules(),
+        Err(_) => vec![],
+    };
+    let current_kind = kind.cloned();
+    let highlights = !matches!(current_kind.as_str(), "scale" | "bar");
+
+    let on_add = move |_| {
+        let target = target.cloned();
+        let range = match target.trim() {
+            "" => {
+                let (row, col) = selected_cell.cloned();
+                let cell = CellAddr::new(row as u32, col as u32);
+                RangeAddr::new(cell, cell)
+            }
+            text => RangeAddr::parse(text).or_else(|| {
+                let cell = CellAddr::parse(text)?;
+                RangeAddr::new(cell, cell)
+            }),
+        };
+        let text = rule_text(
+            &kind.cloned(),
+            &value.cloned(),
+            &high.cloned(),
+            &first_color.cloned(),
+            &second_color.cloned(),
+            bold.cloned(),
+        );
+        let result = match (range, text.parse::<Rule>(), sheet.cloned().try_lock()) {
+            (Some(range), Ok(rule), Ok(mut sheet_locked)) => sheet_locked.add_rule(range, rule),
+            _ => Err(cores::Error::InvalidInput),
+        };
+        match result {
+            Ok(()) => sheet_version.set(sheet_version.cloned() + 1),
+            Err(_) => show_error(
+                &mut error_ctx,
+                "Invalid range or rule",
+                ErrorType::Error,
+                Some(3.0),
+            ),
+        }
+    };
+
+    rsx! {
+        div {
+            style: PANEL_STYLE,
+            onkeydown: move |e: Event<KeyboardData>| {
+                // Keep typing in the dialog away from the grid's navigation keys
+                e.stop_propagation();
+                if e.key() == Key::Escape {
+                    is_open.set(false);
+                }
+            },
+            strong { "Conditional Formatting" }
+            div {
+                style: LIST_STYLE,
+                if rules.is_empty() {
+                    div { "No rules" }
+                }
+                for (index, (range, rule)) in rules.into_iter().enumerate() {
+                    div {
+                        key: "{index}",
+                        style: ROW_STYLE,
+                        span { style: "flex: 1;", "{range} {rule}" }
+                        button {
+                            style: BUTTON_STYLE,
+                            title: "Delete rule",
+                            onclick: move |_| {
+                                if let Ok(mut sheet_locked) = sheet.cloned().try_lock() {
+                                    sheet_locked.remove_rule(index);
+                                }
+                                sheet_version.set(sheet_version.cloned() + 1);
+                            },
+                            "✕"
+                        }
+                    }
+                }
+            }
+            input {
+                style: INPUT_STYLE,
+                placeholder: "Apply to, e.g. A1:A20 (the selected cell if empty)",
+                value: "{target}",
+                oninput: move |e| target.set(e.value()),
+            }
+            select {
+                style: INPUT_STYLE,
+                onchange: move |e: Event<FormData>| kind.set(e.value()),
+                for (label, word) in KINDS {
+                    option {
+                        value: word,
+                        selected: word == current_kind,
+                        "{label}"
+                    }
+                }
+            }
+            if highlights {
+                div {
+                    style: ROW_STYLE,
+                    input {
+                        style: "{INPUT_STYLE} flex: 1;",
+                        placeholder: if current_kind == "formula" { "Formula for the first cell, e.g. A1-B1" } else { "Value" },
+                        value: "{value}",
+                        oninput: move |e| value.set(e.value()),
+                    }
+                    if current_kind == "between" {
+                        input {
+                            style: "{INPUT_STYLE} flex: 1;",
+                            placeholder: "And",
+                            value: "{high}",
+                            oninput: move |e| high.set(e.value()),
+                        }
+                    }
+                }
+            }
+            div {
+                style: ROW_STYLE,
+                label {
+                    if highlights { "Fill " } else if current_kind == "scale" { "Lowest " } else { "Bar " }
+                }
+                input {
+                    r#type: "color",
+                    style: COLOR_STYLE,
+                    value: "{first_color}",
+                    onchange: move |e: Event<FormData>| first_color.set(e.value()),
+                }
+                if current_kind != "bar" {
+                    label {
+                        if highlights { "Text " } else { "Highest " }
+                    }
+                    input {
+                        r#type: "color",
+                        style: COLOR_STYLE,
+                        value: "{second_color}",
+                        onchange: move |e: Event<FormData>| second_color.set(e.value()),
+                    }
+                }
+                if highlights {
+                    label {
+                        input {
+                            r#type: "checkbox",
+                            checked: bold.cloned(),
+                            onchange: move |e| bold.set(e.checked()),
+                        }
+                        " Bold"
+                    }
+                }
+            }
+            div {
+                style: BUTTONS_STYLE,
+                button { style: BUTTON_STYLE, onclick: on_add, "Add Rule" }
+                button { style: BUTTON_STYLE, onclick: move |_| is_open.set(false), "Close" }
+            }
+        }
+    }
+}
@@ -0,0 +1,296 @@
+//! Sheet tabs below the grid.
+//!
+//! One tab per sheet of the workbook, in order. Clicking a tab shows its
+//! sheet, double clicking the shown tab renames it, and the buttons next to
+//! the tabs add a sheet, move the shown one left or right, or delete it.
+//!
+//! The shown sheet lives in `SheetContext`, where every other component edits
+//! it; its place in the workbook holds an empty stand-in meanwhile.
+//! `with_workbook` puts it back for operations that need the whole workbook,
+//! and `edit_sheet` for edits of the shown sheet other sheets may read.
+
+use super::changes::{redraw_all, watch};
+use super::error_display::{show_error, ErrorContext, ErrorType};
+use super::spreadsheet::*;
+use cores::{Sheet, Workbook};
+use dioxus::prelude::*;
+
+const TABS_STYLE: &str = "
+    position: fixed;
+    bottom: 16px;
+    left: 16px;
+    display: flex;
+    gap: 4px;
+    align-items: center;
+    z-index: 100;
+    max-width: 60%;
+    overflow-x: auto;
+";
+
+const TAB_STYLE: &str = "
+    border: 1px solid #ccc;
+    border-radius: 4px 4px 0 0;
+    padding: 5px 12px;
+    cursor: pointer;
+    background-color: rgba(240, 240, 240, 0.9);
+    white-space: nowrap;
+";
+
+const ACTIVE_TAB_STYLE: &str = "
+    border: 1px solid #888;
+    border-bottom: 2px solid #1a73e8;
+    border-radius: 4px 4px 0 0;
+    padding: 5px 12px;
+    cursor: pointer;
+    background-color: white;
+    font-weight: bold;
+    white-space: nowrap;
+";
+
+const TAB_BUTTON_STYLE: &str = "
+    border: 1px solid #ccc;
+    border-radius: 4px;
+    padding: 4px 8px;
+    cursor: pointer;
+    background-color: rgba(255, 255, 255, 0.8);
+";
+
+const RENAME_STYLE: &str = "
+    width: 100px;
+    padding: 4px;
+    font-size: 14px;
+";
+
+/// Runs an operation on the workbook with the shown sheet back in place.
+///
+/// The operation gets the index of the shown sheet and may change it to
+/// show another sheet afterwards.
+///
+/// # Returns
+/// `None` if the sheet or the workbook is busy, such as while a cell edit
+/// recalculates on a worker thread.
+pub fn with_workbook<R>(
+    sheet: SheetContext,
+    book: WorkbookContext,
+    mut active: ActiveSheetContext,
+    operation: impl FnOnce(&mut Workbook, &mut usize) -> R,
+) -> Option<R> {
+    let shared_sheet = sheet.cloned();
+    let mut sheet_locked = shared_sheet.try_lock().ok()?;
+    let shared_book = book.cloned();
+    let mut book_locked = shared_book.try_lock().ok()?;
+    let (result, index) = swap_shown(
+        &mut sheet_locked,
+        &mut book_locked,
+        active.cloned(),
+        operation,
+    );
+    active.set(index);
+    Some(result)
+}
+
+/// Puts the shown sheet back into the workbook for an operation, and takes
+/// the sheet shown afterwards out again.
+///
+/// # Returns
+/// The result of the operation and the index of the sheet shown afterwards.
+pub fn swap_shown<R>(
+    sheet_locked: &mut Sheet,
+    book_locked: &mut Workbook,
+    index: usize,
+    operation: impl FnOnce(&mut Workbook, &mut usize) -> R,
+) -> (R, usize) {
+    let mut index = index.min(book_locked.len() - 1);
+    std::mem::swap(sheet_locked, book_locked.sheet_mut(index));
+    let result = operation(book_locked, &mut index);
+    index = index.min(book_locked.len() - 1);
+    std::mem::swap(sheet_locked, book_locked.sheet_mut(index));
+    (result, index)
+}
+
+/// Runs an edit on the shown sheet and brings the other sheets of the
+/// workbook up to date with it, following rows and columns it inserted or
+/// deleted.
+///
+/// # Returns
+/// `None` if the sheet or the workbook is busy.
+pub fn edit_sheet<R>(
+    sheet: SheetContext,
+    book: WorkbookContext,
+    active: ActiveSheetContext,
+    edit: impl FnOnce(&mut Sheet) -> R,
+) -> Option<R> {
+    with_workbook(sheet, book, active, |book_locked, index| {
+        let result = edit(book_locked.sheet_mut(*index));
+        book_locked.refresh();
+        result
+    })
+}
+
+#[component]
+pub fn SheetTabs() -> Element {
+    let sheet = use_context::<SheetContext>();
+    let book = use_context::<WorkbookContext>();
+    let active = use_context::<ActiveSheetContext>();
+    let mut sheet_version = use_context::<SheetVersionContext>();
+    let changed_cells = use_context::<ChangedCellsContext>();
+    let cell_versions = use_context::<CellVersionsContext>();
+    let mut error_ctx = use_context::<ErrorContext>();
+    let mut trace = use_context::<TraceContext>();
+    let mut renaming = use_signal(|| None::<String>);
+
+    let _ = sheet_version.cloned();
+    let names: Vec<String> = match book.cloned().try_lock() {
+        Ok(book_locked) => book_locked.names().map(str::to_string).collect(),
+        Err(_) => vec![],
+    };
+    let count = names.len();
+    let shown = active.cloned();
+
+    // Runs a workbook operation and shows the sheet it leaves selected
+    let mut change = move |operation: Box<dyn FnOnce(&mut Workbook, &mut usize) -> bool>| {
+        match with_workbook(sheet, book, active, |book_locked, index| {
+            let changed = operation(book_locked, index);
+            // Sheets shown before may have changed cells other sheets read
+            book_locked.refresh();
+            changed
+        }) {
+            Some(true) => {
+                // Traced cells belong to the sheet shown before
+                trace.set(None);
+                redraw_all(changed_cells, cell_versions);
+                sheet_version.set(sheet_version.cloned() + 1);
+            }
+            _ => show_error(
+                &mut error_ctx,
+                "Sheet names must be unique and cannot contain ' or !",
+                ErrorType::Error,
+                Some(3.0),
+            ),
+        }
+    };
+
+    let mut commit_rename = move || {
+        if let Some(name) = renaming.take() {
+            change(Box::new(move |book_locked, index| {
+                book_locked.rename_sheet(*index, name.trim()).is_ok()
+            }));
+        }
+    };
+
+    rsx! {
+        div {
+            style: TABS_STYLE,
+            onkeydown: move |e: Event<KeyboardData>| {
+                // Keep typing a name away from the grid's navigation keys
+                e.stop_propagation();
+            },
+            for (index, name) in names.into_iter().enumerate() {
+                if index == shown && renaming.cloned().is_some() {
+                    input {
+                        key: "{index}",
+                        style: RENAME_STYLE,
+                        autofocus: true,
+                        value: renaming.cloned().unwrap_or_default(),
+                        oninput: move |e| renaming.set(Some(e.value())),
+                        onkeydown: move |e: Event<KeyboardData>| {
+                            if e.key() == Key::Enter {
+                                commit_rename();
+                            } else if e.key() == Key::Escape {
+                                renaming.set(None);
+                            }
+                        },
+                        onblur: move |_| commit_rename(),
+                    }
+                } else {
+                    div {
+                        key: "{index}",
+                        style: if index == shown { ACTIVE_TAB_STYLE } else { TAB_STYLE },
+                        title: "Double click to rename",
+                        onclick: move |_| {
+                            if index != shown {
+                                change(Box::new(move |_, shown| {
+                                    *shown = index;
+                                    true
+                                }));
+                            }
+                        },
+                        ondoubleclick: {
+                            let name = name.clone();
+                            move |_| {
+                                if index == shown {
+                                    renaming.set(Some(name.clone()));
+                                }
+                            }
+                        },
+                        "{name}"
+                    }
+                }
+            }
+            button {
+                style: TAB_BUTTON_STYLE,
+                title: "Add sheet",
+                onclick: move |_| {
+                    change(Box::new(move |book_locked, shown| {
+                        let name = book_locked.unused_name();
+                        match book_locked.add_sheet(&name) {
+                            Ok(index) => {
+                                watch(changed_cells, book_locked.sheet_mut(index));
+                                *shown = index;
+                                true
+                            }
+                            Err(_) => false,
+                        }
+                    }));
+                },
+                "+"
+            }
+            button {
+                style: TAB_BUTTON_STYLE,
+                title: "Move sheet left",
+                disabled: shown == 0,
+                onclick: move |_| {
+                    change(Box::new(|book_locked, shown| {
+                        let to = shown.saturating_sub(1);
+                        let moved = book_locked.move_sheet(*shown, to).is_ok();
+                        *shown = to;
+                        moved
+                    }));
+                },
+                "◀"
+            }
+            button {
+                style: TAB_BUTTON_STYLE,
+                title: "Move sheet right",
+                disabled: shown + 1 >= count,
+                onclick: move |_| {
+                    change(Box::new(|book_locked, shown| {
+                        let to = (*shown + 1).min(book_locked.len() - 1);
+                        let moved = book_locked.move_sheet(*shown, to).is_ok();
+                        *shown = to;
+                        moved
+                    }));
+                },
+                "▶"
+            }
+            button {
+                style: TAB_BUTTON_STYLE,
+                title: "Delete sheet",
+                onclick: move |_| {
+                    let confirmed = rfd::MessageDialog::new()
+                        .set_title("Delete sheet")
+                        .set_description("Delete the shown sheet? Formulas reading it will show #REF!.")
+                        .set_buttons(rfd::MessageButtons::YesNo)
+                        .show()
+                        == rfd::MessageDialogResult::Yes;
+                    if confirmed {
+                        change(Box::new(|book_locked, shown| {
+                            book_locked.delete_sheet(*shown).is_ok()
+                        }));
+                    }
+                },
+                "✕"
+            }
+        }
+    }
+}
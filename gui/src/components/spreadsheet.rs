@@ -1,23 +1,44 @@
-use cores::Sheet;
+use cores::{CancelToken, CellAddr, Highlight, RangeAddr, Sheet, Trace, Workbook};
+use std::collections::HashMap;
 use std::path::PathBuf;
 use std::sync::{Arc, Mutex};
+use std::time::Duration;
 
+use super::changes::{redraw_changed, watch};
 use super::context_menu::{ContextMenu, MenuType};
 use super::error_display::ErrorDisplay;
+use super::find_replace::FindReplaceDialog;
 use super::graph_popup::GraphPopup;
 use super::grid::Grid;
 use super::header::Header;
+use super::notes_panel::NotesPanel;
+use super::recalc::RecalcStatus;
+use super::rules_dialog::RulesDialog;
 use dioxus::prelude::*;
 
+/// Longest time an edit may spend recalculating before the cells left time
+/// out, so a slow formula such as `SLEEP` cannot keep the sheet busy.
+pub const EVAL_TIMEOUT: Duration = Duration::from_secs(2);
+
 // Define explicit types for your contexts
 pub type SelectedCellContext = Signal<(i32, i32)>;
 pub type FormulaContext = Signal<String>;
 pub type CurrentFileContext = Signal<Option<PathBuf>>;
 pub type GraphPopupContext = Signal<bool>;
+pub type FindReplaceContext = Signal<bool>;
+pub type RulesDialogContext = Signal<bool>;
 pub type GraphTypeContext = Signal<GraphType>;
 pub type ContextMenuContext = Signal<Option<(f64, f64, i32, i32, MenuType)>>;
 pub type SheetContext = Signal<Arc<Mutex<Sheet>>>;
+pub type WorkbookContext = Signal<Arc<Mutex<Workbook>>>;
+pub type ActiveSheetContext = Signal<usize>; // index of the shown sheet
 pub type SheetVersionContext = Signal<i32>;
+// Cancels the cell edit recalculating on a worker thread, while one runs
+pub type RecalcContext = Signal<Option<CancelToken>>;
+// Cells the sheets reported changed, not yet redrawn
+pub type ChangedCellsContext = Signal<Arc<Mutex<Vec<CellAddr>>>>;
+// Version of every cell on screen, moved to redraw it
+pub type CellVersionsContext = CopyValue<HashMap<CellAddr, Vec<Signal<u32>>>>;
 pub type StartRowContext = Signal<i32>;
 pub type StartColContext = Signal<i32>;
 pub type MaxStartRowContext = Signal<i32>;
@@ -29,6 +50,19 @@ pub type CopiedRowContext = Signal<Option<i32>>; // row index
 pub type CopiedColContext = Signal<Option<i32>>; // column index
 pub type CopiedCellContext = Signal<Option<(i32, i32)>>; // (row, col)
 
+// Cell the fill handle of the selected cell is dragged to, while dragging
+pub type FillDragContext = Signal<Option<(i32, i32)>>; // (row, col)
+
+// Cell whose precedents and dependents are highlighted, and those cells
+pub type TraceContext = Signal<Option<(i32, i32)>>; // (row, col)
+pub type TracedCellsContext = Memo<Option<(Trace, Trace)>>; // (precedents, dependents)
+
+// How the conditional formatting rules change the look of the cells, row by row
+pub type HighlightsContext = Memo<Vec<(CellAddr, Highlight)>>;
+
+// Cell whose notes and comments are open in the panel
+pub type NotesPanelContext = Signal<Option<(i32, i32)>>; // (row, col)
+
 #[derive(Clone, Copy, PartialEq)]
 pub enum GraphType {
     Line,
@@ -47,17 +81,33 @@ pub fn Spreadsheet() -> Element {
     let formula: FormulaContext = use_signal(String::new);
     let current_file: CurrentFileContext = use_signal(|| None);
     let graph_popup: GraphPopupContext = use_signal(|| false);
+    let find_replace: FindReplaceContext = use_signal(|| false);
+    let rules_dialog: RulesDialogContext = use_signal(|| false);
     let graph_type: GraphTypeContext = use_signal(|| GraphType::Line);
     let context_menu: ContextMenuContext = use_signal(|| None);
+    let changed_cells: ChangedCellsContext = use_signal(|| Arc::new(Mutex::new(vec![])));
+    let cell_versions: CellVersionsContext = use_hook(|| CopyValue::new(HashMap::new()));
+    let book: WorkbookContext = use_signal(|| {
+        let mut new_book = Workbook::new(num_rows, num_cols);
+        watch(changed_cells, new_book.sheet_mut(0));
+        Arc::new(Mutex::new(new_book))
+    });
+    // The shown sheet is taken out of the workbook, see `sheet_tabs`
     let sheet: SheetContext = use_signal(|| {
-        let new_sheet = Sheet::new(num_rows, num_cols); // Create a new Sheet instance
+        let shared = book.cloned();
+        let mut new_sheet = Sheet::new(num_rows, num_cols);
+        if let Ok(mut book_locked) = shared.try_lock() {
+            new_sheet = std::mem::replace(book_locked.sheet_mut(0), Sheet::new(0, 0));
+        }
         Arc::new(Mutex::new(new_sheet))
     });
+    let active_sheet: ActiveSheetContext = use_signal(|| 0);
     let start_row: StartRowContext = use_signal(|| 1);
     let start_col: StartColContext = use_signal(|| 1);
     let max_start_row: MaxStartRowContext = use_signal(|| 1);
     let max_start_col: MaxStartColContext = use_signal(|| 1);
     let sheet_version: SheetVersionContext = use_signal(|| 0);
+    let recalc: RecalcContext = use_signal(|| None);
     let error_ctx: ErrorContext = use_signal(|| None);
     let mut filename = "new_file.ss".to_string();
     if let Some(file) = current_file.cloned() {
@@ -68,16 +118,58 @@ pub fn Spreadsheet() -> Element {
     let copied_cell: CopiedCellContext = use_signal(|| None);
     let copied_row: CopiedRowContext = use_signal(|| None);
     let copied_col: CopiedColContext = use_signal(|| None);
+    let mut fill_drag: FillDragContext = use_signal(|| None);
+    let trace: TraceContext = use_signal(|| None);
+    let notes_panel: NotesPanelContext = use_signal(|| None);
+    // Traced again after every change, so the highlight follows edits
+    let traced_cells: TracedCellsContext = use_memo(move || {
+        let _ = sheet_version.cloned();
+        let (row, col) = trace.cloned()?;
+        let cell = CellAddr::new(row as u32, col as u32);
+        let shared = sheet.cloned();
+        let sheet_locked = shared.try_lock().ok()?;
+        Some((
+            sheet_locked.precedents(cell, usize::MAX),
+            sheet_locked.dependents(cell, usize::MAX),
+        ))
+    });
+
+    // Worked out again after every change, so the rules follow the values
+    let highlights: HighlightsContext = use_memo(move || {
+        let _ = sheet_version.cloned();
+        let shared = sheet.cloned();
+        let Ok(sheet_locked) = shared.try_lock() else {
+            return vec![];
+        };
+        let whole = RangeAddr::new(
+            CellAddr::new(1, 1),
+            CellAddr::from_index(sheet_locked.row.max(1), sheet_locked.col.max(1)),
+        );
+        whole.map_or(vec![], |whole| sheet_locked.highlights(whole))
+    });
+
+    // Only the cells the sheets reported changed are drawn again after an edit
+    use_effect(move || {
+        let _ = sheet_version.cloned();
+        redraw_changed(changed_cells, cell_versions);
+    });
 
     // Provide the contexts to the components
     provide_context(selected_cell);
     provide_context(formula);
     provide_context(current_file);
     provide_context(graph_popup);
+    provide_context(find_replace);
+    provide_context(rules_dialog);
     provide_context(graph_type);
     provide_context(context_menu);
     provide_context(sheet);
+    provide_context(book);
+    provide_context(active_sheet);
     provide_context(sheet_version);
+    provide_context(recalc);
+    provide_context(changed_cells);
+    provide_context(cell_versions);
     provide_context(start_row);
     provide_context(start_col);
     provide_context(max_start_row);
@@ -86,6 +178,11 @@ pub fn Spreadsheet() -> Element {
     provide_context(copied_cell);
     provide_context(copied_row);
     provide_context(copied_col);
+    provide_context(fill_drag);
+    provide_context(trace);
+    provide_context(traced_cells);
+    provide_context(highlights);
+    provide_context(notes_panel);
 
     use_effect(move || {
         let _ = document::eval(
@@ -101,6 +198,8 @@ pub fn Spreadsheet() -> Element {
             // Global keyboard event listener
             tabindex: 0, // Makes the div focusable
             style: "outline: none; width: 100%; height: 100%; overflow: hidden;",
+            // A fill drag released outside the cells is abandoned
+            onmouseup: move |_| fill_drag.set(None),
 
             Header {
                 filename: filename.clone(),
@@ -112,8 +211,12 @@ pub fn Spreadsheet() -> Element {
                 num_cols: num_cols as i32,
             }
             GraphPopup {},
+            FindReplaceDialog {},
+            RulesDialog {},
+            NotesPanel {},
             ContextMenu {},
-            ErrorDisplay {}
+            ErrorDisplay {},
+            RecalcStatus {}
         }
     }
 }
@@ -1,10 +1,11 @@
+use super::changes::{redraw_all, watch};
 use super::error_display::{show_error, ErrorContext, ErrorType};
+use super::sheet_tabs::{edit_sheet, with_workbook};
 use super::spreadsheet::*;
 use cores::convert_to_index;
-use cores::Sheet;
+use cores::Iteration;
+use cores::Workbook;
 use dioxus::prelude::*;
-use std::sync::Arc;
-use std::sync::Mutex;
 
 const OPEN_ICON: Asset = asset!("assets/open.png");
 const SAVE_ICON: Asset = asset!("assets/save.png");
@@ -68,13 +69,24 @@ pub struct ToolbarProps {
 pub fn Toolbar(props: ToolbarProps) -> Element {
     let mut cur_file = use_context::<CurrentFileContext>();
     let mut is_open = use_context::<GraphPopupContext>();
+    let mut find_replace = use_context::<FindReplaceContext>();
     let mut start_row_ctx = use_context::<StartRowContext>();
     let mut start_col_ctx = use_context::<StartColContext>();
     let mut search_term = use_signal(String::new);
     let mut selected_cell = use_context::<SelectedCellContext>();
     let mut error_ctx = use_context::<ErrorContext>();
-    let mut sheet = use_context::<SheetContext>();
+    let sheet = use_context::<SheetContext>();
+    let book = use_context::<WorkbookContext>();
+    let active_sheet = use_context::<ActiveSheetContext>();
     let mut sheetversion = use_context::<SheetVersionContext>();
+    let changed_cells = use_context::<ChangedCellsContext>();
+    let cell_versions = use_context::<CellVersionsContext>();
+    // Subscribe to the version so the toggle shows the state after every change
+    let _ = sheetversion.read();
+    let iterative = sheet
+        .cloned()
+        .try_lock()
+        .is_ok_and(|sheet_locked| sheet_locked.iterative_calculation().is_some());
 
     rsx! {
       div {style : TOOLBAR_STYLE,
@@ -124,6 +136,50 @@ pub fn Toolbar(props: ToolbarProps) -> Element {
               "GO"
           }
       }
+      button { style: BUTTON_STYLE,
+          title: "Find and replace (Ctrl+F)",
+          onclick: move |_| find_replace.set(true),
+          "Find"
+      },
+      button { style: BUTTON_STYLE,
+          title: "Undo (Ctrl+Z)",
+          onclick: move |_| {
+              match edit_sheet(sheet, book, active_sheet, |sheet_locked| sheet_locked.undo()) {
+                  Some(true) => sheetversion.set(sheetversion.cloned() + 1),
+                  Some(false) => show_error(&mut error_ctx, "Nothing to undo", ErrorType::Info, Some(3.0)),
+                  None => {}
+              }
+          },
+          "Undo"
+      },
+      button { style: BUTTON_STYLE,
+          title: "Redo (Ctrl+Y)",
+          onclick: move |_| {
+              match edit_sheet(sheet, book, active_sheet, |sheet_locked| sheet_locked.redo()) {
+                  Some(true) => sheetversion.set(sheetversion.cloned() + 1),
+                  Some(false) => show_error(&mut error_ctx, "Nothing to redo", ErrorType::Info, Some(3.0)),
+                  None => {}
+              }
+          },
+          "Redo"
+      },
+      button { style: BUTTON_STYLE,
+          title: "Evaluate circular references until they settle instead of rejecting them",
+          onclick: move |_| {
+              // Loops settling change cells other sheets may read
+              let toggled = edit_sheet(sheet, book, active_sheet, |sheet_locked| {
+                  let iteration = match sheet_locked.iterative_calculation() {
+                      Some(_) => None,
+                      None => Some(Iteration::default()),
+                  };
+                  sheet_locked.set_iterative_calculation(iteration);
+              });
+              if toggled.is_some() {
+                  sheetversion.set(sheetversion.cloned() + 1);
+              }
+          },
+          if iterative { "Iterative: On" } else { "Iterative: Off" }
+      },
       button { style: BUTTON_STYLE,
 
         img {
@@ -131,8 +187,12 @@ pub fn Toolbar(props: ToolbarProps) -> Element {
             alt: "New File",
             style: "width: 30px; height: 30px;",
             onclick: move |_| {
-                let new_sheet = Sheet::new(1000, 18279);
-                sheet.set(Arc::new(Mutex::new(new_sheet)));
+                with_workbook(sheet, book, active_sheet, |book_locked, index| {
+                    *book_locked = Workbook::new(1000, 18279);
+                    watch(changed_cells, book_locked.sheet_mut(0));
+                    *index = 0;
+                });
+                redraw_all(changed_cells, cell_versions);
                 sheetversion.set(0);
             }
         }
@@ -149,12 +209,21 @@ pub fn Toolbar(props: ToolbarProps) -> Element {
               if let Some(file) = &res {
                   cur_file.set(Some(file.clone()));
                   let file_path = file.as_path().to_string_lossy().to_string();
-                  // Save the current sheet to the selected file
-                  if let Ok(mut sheet_locked) = sheet.cloned().lock() {
-                      // Update the cell value in the Sheet object
-                      let write_result = sheet_locked.read_file(&file_path);
+                  // Every sheet of the file is loaded, the first one is shown
+                  if let Some(read_result) = with_workbook(sheet, book, active_sheet, |book_locked, index| {
+                      let result = book_locked.read_file(&file_path);
+                      if result.is_ok() {
+                          for loaded in 0..book_locked.len() {
+                              watch(changed_cells, book_locked.sheet_mut(loaded));
+                          }
+                          *index = 0;
+                      }
+                      result
+                  }) {
+                      // Loading is not reported as a change of the cells
+                      redraw_all(changed_cells, cell_versions);
                       sheetversion.set(sheetversion.cloned() + 1);
-                      if let Err(e) = write_result {
+                      if let Err(e) = read_result {
                           show_error(&mut error_ctx, &format!("Error reading from file: {}", e), ErrorType::Error, Some(5.0));
                       } else {
                           show_error(&mut error_ctx, "File loaded successfully", ErrorType::Success, Some(5.0));
@@ -181,10 +250,10 @@ pub fn Toolbar(props: ToolbarProps) -> Element {
               // println!("The user choose: {:#?}", res);
               if let Some(file) = &res {
                   let file_path = file.as_path().to_string_lossy().to_string();
-                  // Save the current sheet to the selected file
-                  if let Ok(sheet_locked) = sheet.cloned().lock() {
-                      // Update the cell value in the Sheet object
-                      let write_result = sheet_locked.write_file(&file_path);
+                  // Every sheet of the workbook is saved to the one file
+                  if let Some(write_result) = with_workbook(sheet, book, active_sheet, |book_locked, _| {
+                      book_locked.write_file(&file_path)
+                  }) {
                       sheetversion.set(sheetversion.cloned() + 1);
                       if let Err(e) = write_result {
                           show_error(&mut error_ctx, &format!("Error writing to file: {}", e), ErrorType::Error, Some(5.0));
@@ -227,8 +296,10 @@ pub fn Toolbar(props: ToolbarProps) -> Element {
         if let Some(file) = &res {
             cur_file.set(Some(file.clone()));
             let file_path = file.as_path().to_string_lossy().to_string();
-            if let Ok(mut sheet_locked) = sheet.cloned().lock() {
-                let write_result = sheet_locked.read_csv_file(&file_path);
+            if let Some(write_result) = edit_sheet(sheet, book, active_sheet, |sheet_locked| {
+                sheet_locked.read_csv_file(&file_path)
+            }) {
+                redraw_all(changed_cells, cell_versions);
                 sheetversion.set(sheetversion.cloned() + 1);
                 if let Err(e) = write_result {
                     show_error(&mut error_ctx, &format!("Error reading from file: {}", e), ErrorType::Error, Some(5.0));
@@ -257,7 +328,7 @@ pub fn Toolbar(props: ToolbarProps) -> Element {
             if let Some(file) = &res {
                 let file_path = file.as_path().to_string_lossy().to_string();
                 // Save the current sheet to the selected file
-                if let Ok(sheet_locked) = sheet.cloned().lock() {
+                if let Ok(sheet_locked) = sheet.cloned().try_lock() {
                     // Update the cell value in the Sheet object
                     let write_result = sheet_locked.write_csv_file(&file_path);
                     sheetversion.set(sheetversion.cloned() + 1);